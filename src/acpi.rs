@@ -0,0 +1,1314 @@
+/*
+ * ACPI tables.
+ *
+ * The root system description pointer (RSDP) is either copied by the boot loader
+ * or found in the BIOS area. It points to the RSDT with 32-bit addresses of other
+ * tables or, since ACPI 2.0, to the XSDT with 64-bit ones. Tables are used where
+ * the firmware has left them, only those with a correct checksum are seen. They
+ * can be anywhere in the physical memory, often right below its end, so every
+ * table is mapped on the first access and the mapping is kept.
+ */
+
+use core::mem::size_of;
+
+use spin::Mutex;
+
+use aml::{self, Address, Namespace};
+use boot_info::BootInfo;
+use memory::{MemoryRegion, PAGE_SIZE};
+use paging;
+use port::{inb, inw, inl, outb, outw, outl};
+use timer::{self, Duration};
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature:    [u8; 8],
+    checksum:     u8,
+    oem_id:       [u8; 6],
+    revision:     u8,
+    rsdt_addr:    u32,
+    /* Fields below are present since revision 2 */
+    length:       u32,
+    xsdt_addr:    u64,
+    ext_checksum: u8,
+    reserved:     [u8; 3],
+}
+
+/* Size of the revision 0 RSDP covered by the first checksum */
+const RSDP_V1_SIZE: usize = 20;
+
+const RSDP_SIGNATURE: &'static [u8; 8] = b"RSD PTR ";
+
+/* The RSDP is aligned to 16 bytes */
+const RSDP_ALIGN: usize = 16;
+
+/* Segment of the extended BIOS data area, the RSDP can be in its first 1Kb */
+const EBDA_SEGMENT_ADDR: usize = 0x40E;
+const EBDA_SEARCH_SIZE: usize = 0x400;
+
+/* Read-only BIOS area, searched if the RSDP is not in the EBDA */
+const BIOS_AREA_BEGIN: usize = 0xE0000;
+const BIOS_AREA_END: usize =   0x100000;
+
+/* Common header of all tables except the RSDP */
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature:        [u8; 4],
+    pub length:           u32,
+    pub revision:         u8,
+    checksum:             u8,
+    pub oem_id:           [u8; 6],
+    pub oem_table_id:     [u8; 8],
+    pub oem_revision:     u32,
+    pub creator_id:       u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /* Physical address of the table */
+    pub fn addr(&self) -> usize {
+        let vaddr = self as *const _ as usize;
+        let tables = TABLES.lock();
+        tables.iter().find(|table| table.vaddr == vaddr).map_or(0, |table| table.addr)
+    }
+
+    /* The whole table including the header, tables are never unmapped */
+    fn bytes(&self) -> &'static [u8] {
+        unsafe { ::core::slice::from_raw_parts(self as *const _ as *const u8, self.length as usize) }
+    }
+
+    /* Content following the header, AML code for DSDT and SSDTs */
+    pub fn data(&self) -> &'static [u8] {
+        &self.bytes()[size_of::<SdtHeader>()..]
+    }
+}
+
+/* Tables are never unmapped, this limits the virtual memory they take */
+const MAX_TABLES_COUNT: usize = 64;
+
+#[derive(Clone, Copy)]
+struct MappedTable {
+    /* Physical address, 0 in unused entries */
+    addr:  usize,
+    /* Virtual address, 0 if the table is not valid */
+    vaddr: usize,
+}
+
+static TABLES: Mutex<[MappedTable; MAX_TABLES_COUNT]> = Mutex::new([MappedTable { addr: 0, vaddr: 0 }; MAX_TABLES_COUNT]);
+
+#[derive(Clone, Copy)]
+struct RootTable {
+    addr:       usize,
+    /* 4 bytes for the RSDT, 8 for the XSDT */
+    entry_size: usize,
+}
+
+static ROOT: Mutex<Option<RootTable>> = Mutex::new(None);
+
+/* Finds the root table. Returns false if there is no valid one */
+pub fn init(boot_info: &BootInfo) -> bool {
+    let rsdp = match boot_info.acpi_rsdp().and_then(parse_rsdp).or_else(find_rsdp) {
+        Some(rsdp) => rsdp,
+        None => return false
+    };
+    let root = if rsdp.revision >= 2 && rsdp.xsdt_addr != 0 {
+        RootTable {
+            addr:       rsdp.xsdt_addr as usize,
+            entry_size: 8,
+        }
+    } else {
+        RootTable {
+            addr:       rsdp.rsdt_addr as usize,
+            entry_size: 4,
+        }
+    };
+    if table_at(root.addr).is_none() {
+        return false;
+    }
+    *ROOT.lock() = Some(root);
+    true
+}
+
+fn find_rsdp() -> Option<Rsdp> {
+    let segment = physical_bytes(EBDA_SEGMENT_ADDR, 2);
+    let ebda = ((segment[0] as usize) | (segment[1] as usize) << 8) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = search_rsdp(physical_bytes(ebda, EBDA_SEARCH_SIZE)) {
+            return Some(rsdp);
+        }
+    }
+    search_rsdp(physical_bytes(BIOS_AREA_BEGIN, BIOS_AREA_END - BIOS_AREA_BEGIN))
+}
+
+/* The area should start at an aligned address */
+fn search_rsdp(area: &[u8]) -> Option<Rsdp> {
+    let mut offset = 0;
+    while offset + size_of::<Rsdp>() <= area.len() {
+        if let Some(rsdp) = parse_rsdp(&area[offset..offset + size_of::<Rsdp>()]) {
+            return Some(rsdp);
+        }
+        offset += RSDP_ALIGN;
+    }
+    None
+}
+
+/* Checks the signature and checksums of the RSDP in the bytes and copies it */
+fn parse_rsdp(bytes: &[u8]) -> Option<Rsdp> {
+    if bytes.len() < RSDP_V1_SIZE || &bytes[..RSDP_SIGNATURE.len()] != RSDP_SIGNATURE || !checksum_ok(&bytes[..RSDP_V1_SIZE]) {
+        return None;
+    }
+    let mut rsdp = Rsdp {
+        signature:    [0; 8],
+        checksum:     0,
+        oem_id:       [0; 6],
+        revision:     0,
+        rsdt_addr:    0,
+        length:       0,
+        xsdt_addr:    0,
+        ext_checksum: 0,
+        reserved:     [0; 3],
+    };
+    let copied = ::core::cmp::min(bytes.len(), size_of::<Rsdp>());
+    copy_to(&mut rsdp, &bytes[..copied]);
+    if rsdp.revision >= 2 {
+        let length = rsdp.length as usize;
+        if length < size_of::<Rsdp>() || length > bytes.len() || !checksum_ok(&bytes[..length]) {
+            return None;
+        }
+    }
+    Some(rsdp)
+}
+
+/* Copies bytes over the beginning of the structure, fields beyond them stay intact */
+fn copy_to<T>(dest: &mut T, bytes: &[u8]) {
+    debug_assert!(bytes.len() <= size_of::<T>());
+    unsafe {
+        ::core::ptr::copy_nonoverlapping(bytes.as_ptr(), dest as *mut T as *mut u8, bytes.len());
+    }
+}
+
+/* Maps the memory on every call, only used while looking for the RSDP */
+fn physical_bytes(addr: usize, length: usize) -> &'static [u8] {
+    unsafe { ::core::slice::from_raw_parts(paging::map_firmware(addr, length) as *const u8, length) }
+}
+
+/* All bytes of a table sum to zero */
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/* Returns the table at the physical address if its checksum is correct.
+ * The table is mapped on the first call, the result is remembered. */
+fn table_at(addr: usize) -> Option<&'static SdtHeader> {
+    if addr == 0 {
+        return None;
+    }
+    let mut tables = TABLES.lock();
+    if let Some(table) = tables.iter().find(|table| table.addr == addr) {
+        return header_at(table.vaddr);
+    }
+    let slot = match tables.iter().position(|table| table.addr == 0) {
+        Some(slot) => slot,
+        None => {
+            println!("Too many ACPI tables, the one at 0x{:x} is ignored.", addr);
+            return None;
+        }
+    };
+
+    /* The header tells how much to map for the whole table */
+    let header = unsafe { &*(paging::map_firmware(addr, size_of::<SdtHeader>()) as *const SdtHeader) };
+    let length = header.length as usize;
+    let mut vaddr = 0;
+    if length >= size_of::<SdtHeader>() {
+        let table = unsafe { &*(paging::map_firmware(addr, length) as *const SdtHeader) };
+        if checksum_ok(table.bytes()) {
+            vaddr = table as *const _ as usize;
+        }
+    }
+    tables[slot] = MappedTable {
+        addr:  addr,
+        vaddr: vaddr,
+    };
+    header_at(vaddr)
+}
+
+fn header_at(vaddr: usize) -> Option<&'static SdtHeader> {
+    if vaddr == 0 {
+        None
+    } else {
+        Some(unsafe { &*(vaddr as *const SdtHeader) })
+    }
+}
+
+/* Iterates over valid tables listed in the RSDT or XSDT */
+pub struct TableIterator {
+    root:  Option<RootTable>,
+    index: usize,
+}
+
+impl Iterator for TableIterator {
+    type Item = &'static SdtHeader;
+
+    fn next(&mut self) -> Option<&'static SdtHeader> {
+        let root = match self.root {
+            Some(root) => root,
+            None => return None
+        };
+        /* Checked by init() */
+        let entries = table_at(root.addr).unwrap().data();
+        let count = entries.len() / root.entry_size;
+        while self.index < count {
+            let entry = &entries[self.index * root.entry_size..];
+            self.index += 1;
+            /* XSDT entries are only 4-byte aligned */
+            let mut addr = 0;
+            for (i, byte) in entry[..root.entry_size].iter().enumerate() {
+                addr |= (*byte as usize) << (i * 8);
+            }
+            if let Some(table) = table_at(addr) {
+                return Some(table);
+            }
+        }
+        None
+    }
+}
+
+pub fn tables() -> TableIterator {
+    TableIterator {
+        root:  *ROOT.lock(),
+        index: 0,
+    }
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static SdtHeader> {
+    tables().find(|table| &table.signature == signature)
+}
+
+/*
+ * Entries following the fixed part of MADT and SRAT, each starts with its type and length.
+ */
+
+#[repr(C, packed)]
+struct SubtableHeader {
+    subtable_type: u8,
+    length:        u8,
+}
+
+struct Subtables {
+    /* Virtual addresses of the next entry and the table end */
+    addr: usize,
+    end:  usize,
+}
+
+impl Subtables {
+    fn new(table: &SdtHeader, fixed_size: usize) -> Subtables {
+        let begin = table as *const _ as usize;
+        Subtables {
+            addr: begin + fixed_size,
+            end:  begin + table.length as usize,
+        }
+    }
+}
+
+impl Iterator for Subtables {
+    /* Type, address and length of an entry */
+    type Item = (u8, usize, usize);
+
+    fn next(&mut self) -> Option<(u8, usize, usize)> {
+        if self.addr + size_of::<SubtableHeader>() > self.end {
+            return None;
+        }
+        let header = unsafe { &*(self.addr as *const SubtableHeader) };
+        let length = header.length as usize;
+        if length < size_of::<SubtableHeader>() || self.addr + length > self.end {
+            return None;
+        }
+        let addr = self.addr;
+        self.addr += length;
+        Some((header.subtable_type, addr, length))
+    }
+}
+
+/* Returns the entry as T if it is long enough */
+unsafe fn subtable<T>(addr: usize, length: usize) -> Option<&'static T> {
+    if length < size_of::<T>() {
+        None
+    } else {
+        Some(&*(addr as *const T))
+    }
+}
+
+/*
+ * Multiple APIC description table (MADT).
+ */
+
+#[repr(C, packed)]
+struct MadtHeader {
+    header:          SdtHeader,
+    local_apic_addr: u32,
+    flags:           u32,
+}
+
+/* The legacy 8259 PICs are installed */
+const MADT_PCAT_COMPAT: u32 = 1 << 0;
+
+const MADT_LOCAL_APIC: u8 =          0;
+const MADT_IO_APIC: u8 =             1;
+const MADT_INTERRUPT_OVERRIDE: u8 =  2;
+const MADT_LOCAL_APIC_NMI: u8 =      4;
+const MADT_LOCAL_APIC_ADDRESS: u8 =  5;
+const MADT_LOCAL_X2APIC: u8 =        9;
+const MADT_LOCAL_X2APIC_NMI: u8 =    0xA;
+
+#[repr(C, packed)]
+struct MadtLocalApic {
+    header:       SubtableHeader,
+    processor_id: u8,
+    apic_id:      u8,
+    flags:        u32,
+}
+
+#[repr(C, packed)]
+struct MadtIoApic {
+    header:   SubtableHeader,
+    id:       u8,
+    reserved: u8,
+    addr:     u32,
+    gsi_base: u32,
+}
+
+#[repr(C, packed)]
+struct MadtInterruptOverride {
+    header: SubtableHeader,
+    bus:    u8,
+    source: u8,
+    gsi:    u32,
+    flags:  u16,
+}
+
+#[repr(C, packed)]
+struct MadtLocalApicNmi {
+    header:       SubtableHeader,
+    processor_id: u8,
+    flags:        u16,
+    lint:         u8,
+}
+
+#[repr(C, packed)]
+struct MadtLocalApicAddress {
+    header:   SubtableHeader,
+    reserved: u16,
+    addr:     u64,
+}
+
+#[repr(C, packed)]
+struct MadtLocalX2Apic {
+    header:       SubtableHeader,
+    reserved:     u16,
+    apic_id:      u32,
+    flags:        u32,
+    processor_id: u32,
+}
+
+#[repr(C, packed)]
+struct MadtLocalX2ApicNmi {
+    header:       SubtableHeader,
+    flags:        u16,
+    processor_id: u32,
+    lint:         u8,
+    reserved:     [u8; 3],
+}
+
+/*
+ * MPS INTI flags of interrupt overrides and NMI entries. Zero values mean
+ * the bus defaults: active high and edge triggered for ISA.
+ */
+
+const MPS_POLARITY_MASK: u16 =       0x3;
+const MPS_POLARITY_ACTIVE_LOW: u16 = 0x3;
+const MPS_TRIGGER_MASK: u16 =        0xC;
+const MPS_TRIGGER_LEVEL: u16 =       0xC;
+
+pub fn is_active_low(flags: u16) -> bool {
+    flags & MPS_POLARITY_MASK == MPS_POLARITY_ACTIVE_LOW
+}
+
+pub fn is_level_triggered(flags: u16) -> bool {
+    flags & MPS_TRIGGER_MASK == MPS_TRIGGER_LEVEL
+}
+
+/* The processor can be used */
+const MADT_PROCESSOR_ENABLED: u32 = 1 << 0;
+
+/* Processor id of a local APIC NMI entry applying to all processors */
+pub const MADT_ALL_PROCESSORS: u32 = 0xFFFFFFFF;
+
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    LocalApic { processor_id: u32, apic_id: u32, enabled: bool },
+    IoApic { id: u8, addr: usize, gsi_base: u32 },
+    /* An ISA interrupt is connected to another global system interrupt or
+     * has other polarity or trigger mode (flags are MPS INTI flags) */
+    InterruptOverride { source: u8, gsi: u32, flags: u16 },
+    LocalApicNmi { processor_id: u32, lint: u8, flags: u16 },
+    LocalApicAddress { addr: usize },
+    Other(u8),
+}
+
+pub struct Madt {
+    table: &'static MadtHeader,
+}
+
+impl Madt {
+    /* Physical address of local APICs, possibly overridden by an entry */
+    pub fn local_apic_addr(&self) -> usize {
+        for entry in self.entries() {
+            if let MadtEntry::LocalApicAddress { addr } = entry {
+                return addr;
+            }
+        }
+        self.table.local_apic_addr as usize
+    }
+
+    pub fn has_pic(&self) -> bool {
+        self.table.flags & MADT_PCAT_COMPAT != 0
+    }
+
+    pub fn entries(&self) -> MadtEntryIterator {
+        MadtEntryIterator {
+            subtables: Subtables::new(&self.table.header, size_of::<MadtHeader>()),
+        }
+    }
+}
+
+pub struct MadtEntryIterator {
+    subtables: Subtables,
+}
+
+impl Iterator for MadtEntryIterator {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        let (entry_type, addr, length) = match self.subtables.next() {
+            Some(entry) => entry,
+            None => return None
+        };
+        let parsed = unsafe {
+            match entry_type {
+                MADT_LOCAL_APIC => subtable::<MadtLocalApic>(addr, length).map(|e| MadtEntry::LocalApic {
+                    processor_id: e.processor_id as u32,
+                    apic_id:      e.apic_id as u32,
+                    enabled:      e.flags & MADT_PROCESSOR_ENABLED != 0,
+                }),
+                MADT_IO_APIC => subtable::<MadtIoApic>(addr, length).map(|e| MadtEntry::IoApic {
+                    id:       e.id,
+                    addr:     e.addr as usize,
+                    gsi_base: e.gsi_base,
+                }),
+                MADT_INTERRUPT_OVERRIDE => subtable::<MadtInterruptOverride>(addr, length).map(|e| MadtEntry::InterruptOverride {
+                    source: e.source,
+                    gsi:    e.gsi,
+                    flags:  e.flags,
+                }),
+                MADT_LOCAL_APIC_NMI => subtable::<MadtLocalApicNmi>(addr, length).map(|e| MadtEntry::LocalApicNmi {
+                    processor_id: if e.processor_id == 0xFF { MADT_ALL_PROCESSORS } else { e.processor_id as u32 },
+                    lint:         e.lint,
+                    flags:        e.flags,
+                }),
+                MADT_LOCAL_APIC_ADDRESS => subtable::<MadtLocalApicAddress>(addr, length).map(|e| MadtEntry::LocalApicAddress {
+                    addr: e.addr as usize,
+                }),
+                MADT_LOCAL_X2APIC => subtable::<MadtLocalX2Apic>(addr, length).map(|e| MadtEntry::LocalApic {
+                    processor_id: e.processor_id,
+                    apic_id:      e.apic_id,
+                    enabled:      e.flags & MADT_PROCESSOR_ENABLED != 0,
+                }),
+                MADT_LOCAL_X2APIC_NMI => subtable::<MadtLocalX2ApicNmi>(addr, length).map(|e| MadtEntry::LocalApicNmi {
+                    processor_id: e.processor_id,
+                    lint:         e.lint,
+                    flags:        e.flags,
+                }),
+                _ => None
+            }
+        };
+        Some(parsed.unwrap_or(MadtEntry::Other(entry_type)))
+    }
+}
+
+pub fn madt() -> Option<Madt> {
+    match find_table(b"APIC") {
+        Some(header) if header.length as usize >= size_of::<MadtHeader>() => Some(Madt {
+            table: unsafe { &*(header as *const SdtHeader as *const MadtHeader) },
+        }),
+        _ => None
+    }
+}
+
+/*
+ * Generic address structure describing registers in FADT and HPET tables.
+ */
+
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width:     u8,
+    pub bit_offset:    u8,
+    pub access_size:   u8,
+    pub address:       u64,
+}
+
+/*
+ * Constants for GenericAddress::address_space.
+ */
+
+pub const ADDRESS_SPACE_MEMORY: u8 =     0;
+pub const ADDRESS_SPACE_IO: u8 =         1;
+pub const ADDRESS_SPACE_PCI_CONFIG: u8 = 2;
+
+const EMPTY_ADDRESS: GenericAddress = GenericAddress {
+    address_space: 0,
+    bit_width:     0,
+    bit_offset:    0,
+    access_size:   0,
+    address:       0,
+};
+
+impl GenericAddress {
+    fn io_port(port: u32, bit_width: u8) -> GenericAddress {
+        GenericAddress {
+            address_space: ADDRESS_SPACE_IO,
+            bit_width:     bit_width,
+            bit_offset:    0,
+            access_size:   0,
+            address:       port as u64,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.address != 0
+    }
+
+    /* Width of accesses in bits, 64-bit I/O accesses are done as 32-bit ones */
+    fn access_width(&self) -> u8 {
+        let width = match self.access_size {
+            1 => 8,
+            2 => 16,
+            3 => 32,
+            4 => 64,
+            _ => self.bit_width
+        };
+        match (self.address_space, width) {
+            (ADDRESS_SPACE_IO, 64) => 32,
+            (_, 8) | (_, 16) | (_, 32) | (_, 64) => width,
+            _ => 8
+        }
+    }
+
+    /* Reads the register. Only system memory and I/O spaces are supported */
+    pub fn read(&self) -> Option<u64> {
+        let width = self.access_width();
+        match self.address_space {
+            ADDRESS_SPACE_IO => {
+                let port = self.address as u16;
+                Some(unsafe {
+                    match width {
+                        8 => inb(port) as u64,
+                        16 => inw(port) as u64,
+                        _ => inl(port) as u64
+                    }
+                })
+            },
+            ADDRESS_SPACE_MEMORY => unsafe { read_mmio(self.address as usize, width / 8) },
+            _ => None
+        }
+    }
+
+    /* Writes the register. Returns false if its address space is not supported */
+    pub fn write(&self, value: u64) -> bool {
+        let width = self.access_width();
+        match self.address_space {
+            ADDRESS_SPACE_IO => {
+                let port = self.address as u16;
+                unsafe {
+                    match width {
+                        8 => outb(port, value as u8),
+                        16 => outw(port, value as u16),
+                        _ => outl(port, value as u32)
+                    }
+                }
+                true
+            },
+            ADDRESS_SPACE_MEMORY => unsafe { write_mmio(self.address as usize, width / 8, value) },
+            _ => false
+        }
+    }
+}
+
+/*
+ * Registers in physical memory. Pages are mapped on the first access and
+ * the mappings are kept, as GenericAddress and AML regions access the same
+ * few registers again and again. Mappings cannot be removed, so accesses to
+ * new pages fail once the cache is full.
+ */
+
+const MMIO_PAGES_COUNT: usize = 32;
+
+/* Physical and virtual addresses of mapped pages, zero in unused entries */
+static MMIO_PAGES: Mutex<[(usize, usize); MMIO_PAGES_COUNT]> = Mutex::new([(0, 0); MMIO_PAGES_COUNT]);
+
+/* Returns None if the page is not mapped yet and there is no room for it */
+fn mmio_addr(physical_addr: usize) -> Option<usize> {
+    let page = physical_addr & !(PAGE_SIZE - 1);
+    let offset = physical_addr - page;
+    let mut pages = MMIO_PAGES.lock();
+    if let Some(&(_, virtual_addr)) = pages.iter().find(|entry| entry.1 != 0 && entry.0 == page) {
+        return Some(virtual_addr + offset);
+    }
+    let entry = match pages.iter_mut().find(|entry| entry.1 == 0) {
+        Some(entry) => entry,
+        None => {
+            println!("ACPI: too many register pages, access to 0x{:x} is refused.", physical_addr);
+            return None;
+        }
+    };
+    let virtual_addr = paging::map_mmio(page, PAGE_SIZE);
+    *entry = (page, virtual_addr);
+    Some(virtual_addr + offset)
+}
+
+/* Accesses of 1, 2, 4 or 8 bytes. The register shouldn't cross a page boundary */
+unsafe fn read_mmio(physical_addr: usize, size: u8) -> Option<u64> {
+    let addr = match mmio_addr(physical_addr) {
+        Some(addr) => addr,
+        None => return None
+    };
+    Some(match size {
+        1 => ::core::ptr::read_volatile(addr as *const u8) as u64,
+        2 => ::core::ptr::read_volatile(addr as *const u16) as u64,
+        4 => ::core::ptr::read_volatile(addr as *const u32) as u64,
+        _ => ::core::ptr::read_volatile(addr as *const u64)
+    })
+}
+
+/* Returns false if the register could not be mapped */
+unsafe fn write_mmio(physical_addr: usize, size: u8, value: u64) -> bool {
+    let addr = match mmio_addr(physical_addr) {
+        Some(addr) => addr,
+        None => return false
+    };
+    match size {
+        1 => ::core::ptr::write_volatile(addr as *mut u8, value as u8),
+        2 => ::core::ptr::write_volatile(addr as *mut u16, value as u16),
+        4 => ::core::ptr::write_volatile(addr as *mut u32, value as u32),
+        _ => ::core::ptr::write_volatile(addr as *mut u64, value)
+    }
+    true
+}
+
+/*
+ * Fixed ACPI description table (FADT). Older revisions are shorter, missing
+ * fields are zero in the copy returned by fadt().
+ */
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct Fadt {
+    header:                 SdtHeader,
+    pub firmware_ctrl:      u32,
+    dsdt:                   u32,
+    reserved1:              u8,
+    pub preferred_pm_profile: u8,
+    pub sci_interrupt:      u16,
+    pub smi_command:        u32,
+    pub acpi_enable:        u8,
+    pub acpi_disable:       u8,
+    pub s4bios_request:     u8,
+    pub pstate_control:     u8,
+    pm1a_event_block:       u32,
+    pm1b_event_block:       u32,
+    pm1a_control_block:     u32,
+    pm1b_control_block:     u32,
+    pm2_control_block:      u32,
+    pm_timer_block:         u32,
+    gpe0_block:             u32,
+    gpe1_block:             u32,
+    pm1_event_length:       u8,
+    pm1_control_length:     u8,
+    pm2_control_length:     u8,
+    pm_timer_length:        u8,
+    pub gpe0_length:        u8,
+    pub gpe1_length:        u8,
+    pub gpe1_base:          u8,
+    pub cstate_control:     u8,
+    pub worst_c2_latency:   u16,
+    pub worst_c3_latency:   u16,
+    pub flush_size:         u16,
+    pub flush_stride:       u16,
+    pub duty_offset:        u8,
+    pub duty_width:         u8,
+    /* CMOS RAM indexes of RTC alarm and century fields, zero if not supported */
+    pub day_alarm:          u8,
+    pub month_alarm:        u8,
+    pub century:            u8,
+    pub boot_arch_flags:    u16,
+    reserved2:              u8,
+    pub flags:              u32,
+    reset_register:         GenericAddress,
+    pub reset_value:        u8,
+    pub arm_boot_arch_flags: u16,
+    pub minor_version:      u8,
+    x_firmware_ctrl:        u64,
+    x_dsdt:                 u64,
+    x_pm1a_event_block:     GenericAddress,
+    x_pm1b_event_block:     GenericAddress,
+    x_pm1a_control_block:   GenericAddress,
+    x_pm1b_control_block:   GenericAddress,
+    x_pm2_control_block:    GenericAddress,
+    x_pm_timer_block:       GenericAddress,
+    x_gpe0_block:           GenericAddress,
+    x_gpe1_block:           GenericAddress,
+}
+
+/*
+ * Bits of Fadt::boot_arch_flags (IA-PC).
+ */
+
+pub const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+pub const BOOT_ARCH_8042: u16 =           1 << 1;
+pub const BOOT_ARCH_NO_VGA: u16 =         1 << 2;
+pub const BOOT_ARCH_NO_MSI: u16 =         1 << 3;
+pub const BOOT_ARCH_NO_CMOS_RTC: u16 =    1 << 5;
+
+/*
+ * Bits of Fadt::flags.
+ */
+
+pub const FADT_RESET_REG_SUPPORTED: u32 = 1 << 10;
+pub const FADT_HW_REDUCED_ACPI: u32 =     1 << 20;
+
+impl Fadt {
+    /* Physical address of the DSDT */
+    pub fn dsdt_addr(&self) -> usize {
+        if self.x_dsdt != 0 {
+            self.x_dsdt as usize
+        } else {
+            self.dsdt as usize
+        }
+    }
+
+    pub fn pm1a_control(&self) -> GenericAddress {
+        choose_block(self.x_pm1a_control_block, self.pm1a_control_block, self.pm1_control_length)
+    }
+
+    pub fn pm1b_control(&self) -> GenericAddress {
+        choose_block(self.x_pm1b_control_block, self.pm1b_control_block, self.pm1_control_length)
+    }
+
+    pub fn pm1a_event(&self) -> GenericAddress {
+        choose_block(self.x_pm1a_event_block, self.pm1a_event_block, self.pm1_event_length)
+    }
+
+    pub fn pm_timer(&self) -> GenericAddress {
+        choose_block(self.x_pm_timer_block, self.pm_timer_block, self.pm_timer_length)
+    }
+
+    pub fn reset_register(&self) -> Option<GenericAddress> {
+        let register = self.reset_register;
+        if self.flags & FADT_RESET_REG_SUPPORTED != 0 && register.is_valid() {
+            Some(register)
+        } else {
+            None
+        }
+    }
+}
+
+/* The extended 64-bit block is preferred if present, the legacy one is an I/O port */
+fn choose_block(extended: GenericAddress, legacy: u32, length: u8) -> GenericAddress {
+    if extended.is_valid() {
+        extended
+    } else if legacy != 0 {
+        GenericAddress::io_port(legacy, length.wrapping_mul(8))
+    } else {
+        EMPTY_ADDRESS
+    }
+}
+
+pub fn fadt() -> Option<Fadt> {
+    find_table(b"FACP").map(|header| {
+        let mut fadt = Fadt {
+            header:                 *header,
+            firmware_ctrl:          0,
+            dsdt:                   0,
+            reserved1:              0,
+            preferred_pm_profile:   0,
+            sci_interrupt:          0,
+            smi_command:            0,
+            acpi_enable:            0,
+            acpi_disable:           0,
+            s4bios_request:         0,
+            pstate_control:         0,
+            pm1a_event_block:       0,
+            pm1b_event_block:       0,
+            pm1a_control_block:     0,
+            pm1b_control_block:     0,
+            pm2_control_block:      0,
+            pm_timer_block:         0,
+            gpe0_block:             0,
+            gpe1_block:             0,
+            pm1_event_length:       0,
+            pm1_control_length:     0,
+            pm2_control_length:     0,
+            pm_timer_length:        0,
+            gpe0_length:            0,
+            gpe1_length:            0,
+            gpe1_base:              0,
+            cstate_control:         0,
+            worst_c2_latency:       0,
+            worst_c3_latency:       0,
+            flush_size:             0,
+            flush_stride:           0,
+            duty_offset:            0,
+            duty_width:             0,
+            day_alarm:              0,
+            month_alarm:            0,
+            century:                0,
+            boot_arch_flags:        0,
+            reserved2:              0,
+            flags:                  0,
+            reset_register:         EMPTY_ADDRESS,
+            reset_value:            0,
+            arm_boot_arch_flags:    0,
+            minor_version:          0,
+            x_firmware_ctrl:        0,
+            x_dsdt:                 0,
+            x_pm1a_event_block:     EMPTY_ADDRESS,
+            x_pm1b_event_block:     EMPTY_ADDRESS,
+            x_pm1a_control_block:   EMPTY_ADDRESS,
+            x_pm1b_control_block:   EMPTY_ADDRESS,
+            x_pm2_control_block:    EMPTY_ADDRESS,
+            x_pm_timer_block:       EMPTY_ADDRESS,
+            x_gpe0_block:           EMPTY_ADDRESS,
+            x_gpe1_block:           EMPTY_ADDRESS,
+        };
+        let length = ::core::cmp::min(header.length as usize, size_of::<Fadt>());
+        copy_to(&mut fadt, &header.bytes()[..length]);
+        fadt
+    })
+}
+
+/* Differentiated system description table with the main AML code */
+pub fn dsdt() -> Option<&'static SdtHeader> {
+    fadt().and_then(|fadt| table_at(fadt.dsdt_addr()))
+}
+
+/*
+ * High precision event timer description table (HPET).
+ */
+
+#[repr(C, packed)]
+struct HpetTable {
+    header:           SdtHeader,
+    event_timer_id:   u32,
+    base_address:     GenericAddress,
+    number:           u8,
+    min_tick:         u16,
+    page_protection:  u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hpet {
+    /* Physical address of the registers */
+    pub addr:           usize,
+    pub number:         u8,
+    pub comparators:    u8,
+    pub counter_64bit:  bool,
+    pub legacy_replacement: bool,
+    pub vendor_id:      u16,
+    /* Minimum period in ticks for periodic mode without lost interrupts */
+    pub min_tick:       u16,
+}
+
+pub fn hpet() -> Option<Hpet> {
+    match find_table(b"HPET") {
+        Some(header) if header.length as usize >= size_of::<HpetTable>() => {
+            let table = unsafe { &*(header as *const SdtHeader as *const HpetTable) };
+            let base_address = table.base_address;
+            if base_address.address_space != ADDRESS_SPACE_MEMORY {
+                return None;
+            }
+            let id = table.event_timer_id;
+            Some(Hpet {
+                addr:               base_address.address as usize,
+                number:             table.number,
+                comparators:        ((id >> 8) & 0x1F) as u8 + 1,
+                counter_64bit:      id & (1 << 13) != 0,
+                legacy_replacement: id & (1 << 15) != 0,
+                vendor_id:          (id >> 16) as u16,
+                min_tick:           table.min_tick,
+            })
+        },
+        _ => None
+    }
+}
+
+/*
+ * PCI Express memory mapped configuration space table (MCFG).
+ */
+
+#[repr(C, packed)]
+struct McfgHeader {
+    header:   SdtHeader,
+    reserved: u64,
+}
+
+#[repr(C, packed)]
+struct McfgAllocation {
+    base_address: u64,
+    segment:      u16,
+    start_bus:    u8,
+    end_bus:      u8,
+    reserved:     u32,
+}
+
+/* Configuration space of buses start_bus..end_bus (inclusive) of the PCI segment */
+#[derive(Clone, Copy, Debug)]
+pub struct McfgEntry {
+    pub addr:      usize,
+    pub segment:   u16,
+    pub start_bus: u8,
+    pub end_bus:   u8,
+}
+
+pub struct McfgIterator {
+    addr: usize,
+    end:  usize,
+}
+
+impl Iterator for McfgIterator {
+    type Item = McfgEntry;
+
+    fn next(&mut self) -> Option<McfgEntry> {
+        if self.addr + size_of::<McfgAllocation>() > self.end {
+            return None;
+        }
+        let allocation = unsafe { &*(self.addr as *const McfgAllocation) };
+        self.addr += size_of::<McfgAllocation>();
+        Some(McfgEntry {
+            addr:      allocation.base_address as usize,
+            segment:   allocation.segment,
+            start_bus: allocation.start_bus,
+            end_bus:   allocation.end_bus,
+        })
+    }
+}
+
+pub fn mcfg() -> Option<McfgIterator> {
+    match find_table(b"MCFG") {
+        Some(header) if header.length as usize >= size_of::<McfgHeader>() => {
+            let begin = header as *const _ as usize;
+            Some(McfgIterator {
+                addr: begin + size_of::<McfgHeader>(),
+                end:  begin + header.length as usize,
+            })
+        },
+        _ => None
+    }
+}
+
+/*
+ * System resource affinity table (SRAT): NUMA proximity domains of processors
+ * and memory.
+ */
+
+#[repr(C, packed)]
+struct SratHeader {
+    header:    SdtHeader,
+    reserved1: u32,
+    reserved2: u64,
+}
+
+const SRAT_PROCESSOR: u8 =        0;
+const SRAT_MEMORY: u8 =           1;
+const SRAT_X2APIC_PROCESSOR: u8 = 2;
+
+#[repr(C, packed)]
+struct SratProcessor {
+    header:           SubtableHeader,
+    proximity_low:    u8,
+    apic_id:          u8,
+    flags:            u32,
+    sapic_eid:        u8,
+    proximity_high:   [u8; 3],
+    clock_domain:     u32,
+}
+
+#[repr(C, packed)]
+struct SratMemory {
+    header:         SubtableHeader,
+    proximity:      u32,
+    reserved1:      u16,
+    base:           u64,
+    length:         u64,
+    reserved2:      u32,
+    flags:          u32,
+    reserved3:      u64,
+}
+
+#[repr(C, packed)]
+struct SratX2ApicProcessor {
+    header:       SubtableHeader,
+    reserved1:    u16,
+    proximity:    u32,
+    apic_id:      u32,
+    flags:        u32,
+    clock_domain: u32,
+    reserved2:    u32,
+}
+
+/* Both processor and memory entries have this in flags */
+const SRAT_ENABLED: u32 =       1 << 0;
+const SRAT_HOT_PLUGGABLE: u32 = 1 << 1;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SratEntry {
+    Processor { apic_id: u32, domain: u32, enabled: bool },
+    Memory { region: MemoryRegion, domain: u32, enabled: bool, hot_pluggable: bool },
+    Other(u8),
+}
+
+pub struct SratEntryIterator {
+    subtables: Subtables,
+}
+
+impl Iterator for SratEntryIterator {
+    type Item = SratEntry;
+
+    fn next(&mut self) -> Option<SratEntry> {
+        let (entry_type, addr, length) = match self.subtables.next() {
+            Some(entry) => entry,
+            None => return None
+        };
+
+        let parsed = unsafe {
+            match entry_type {
+                SRAT_PROCESSOR => subtable::<SratProcessor>(addr, length).map(|e| SratEntry::Processor {
+                    apic_id: e.apic_id as u32,
+                    domain:  e.proximity_low as u32 |
+                             (e.proximity_high[0] as u32) << 8 |
+                             (e.proximity_high[1] as u32) << 16 |
+                             (e.proximity_high[2] as u32) << 24,
+                    enabled: e.flags & SRAT_ENABLED != 0,
+                }),
+                SRAT_MEMORY => subtable::<SratMemory>(addr, length).map(|e| SratEntry::Memory {
+                    region:        MemoryRegion {
+                        addr: e.base as usize,
+                        size: e.length as usize,
+                    },
+                    domain:        e.proximity,
+                    enabled:       e.flags & SRAT_ENABLED != 0,
+                    hot_pluggable: e.flags & SRAT_HOT_PLUGGABLE != 0,
+                }),
+                SRAT_X2APIC_PROCESSOR => subtable::<SratX2ApicProcessor>(addr, length).map(|e| SratEntry::Processor {
+                    apic_id: e.apic_id,
+                    domain:  e.proximity,
+                    enabled: e.flags & SRAT_ENABLED != 0,
+                }),
+                _ => None
+            }
+        };
+        Some(parsed.unwrap_or(SratEntry::Other(entry_type)))
+    }
+}
+
+pub fn srat() -> Option<SratEntryIterator> {
+    match find_table(b"SRAT") {
+        Some(header) if header.length as usize >= size_of::<SratHeader>() => Some(SratEntryIterator {
+            subtables: Subtables::new(header, size_of::<SratHeader>()),
+        }),
+        _ => None
+    }
+}
+
+/*
+ * ACPI namespace built by the AML interpreter from the DSDT and SSDTs.
+ */
+
+pub static NAMESPACE: Mutex<Namespace> = Mutex::new(Namespace::new());
+
+/* Argument of \_PIC telling the firmware which controller delivers interrupts */
+const PIC_MODE_PIC: u64 =  0;
+const PIC_MODE_APIC: u64 = 1;
+
+/*
+ * PCI configuration space mechanism #1.
+ */
+
+const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const PCI_CONFIG_DATA_PORT: u16 =    0xCFC;
+const PCI_CONFIG_ENABLE: u32 =       1 << 31;
+
+/* Hardware access for the interpreter */
+pub struct KernelHandler;
+
+pub static HANDLER: KernelHandler = KernelHandler;
+
+/* Selects the register of the device. Only the first segment is reachable through
+ * the ports, the others need the memory mapped configuration space (MCFG). */
+fn select_pci_register(address: Address) -> Option<u16> {
+    match address {
+        Address::PciConfig { segment: 0, bus, device, function, offset } if offset < 0x100 => {
+            unsafe {
+                outl(PCI_CONFIG_ADDRESS_PORT, PCI_CONFIG_ENABLE | (bus as u32) << 16 | (device as u32) << 11 |
+                     (function as u32) << 8 | (offset as u32 & 0xFC));
+            }
+            Some(PCI_CONFIG_DATA_PORT + (offset & 0x3))
+        },
+        _ => None
+    }
+}
+
+impl aml::Handler for KernelHandler {
+    fn read(&self, address: Address, width: u8) -> Option<u64> {
+        let port = match address {
+            Address::Memory(addr) => return unsafe { read_mmio(addr as usize, width) },
+            Address::Io(port) => port,
+            Address::PciConfig { .. } => match select_pci_register(address) {
+                Some(port) => port,
+                None => return None
+            }
+        };
+        unsafe {
+            match width {
+                1 => Some(inb(port) as u64),
+                2 => Some(inw(port) as u64),
+                4 => Some(inl(port) as u64),
+                _ => None
+            }
+        }
+    }
+
+    fn write(&self, address: Address, width: u8, value: u64) -> bool {
+        let port = match address {
+            Address::Memory(addr) => return unsafe { write_mmio(addr as usize, width, value) },
+            Address::Io(port) => port,
+            Address::PciConfig { .. } => match select_pci_register(address) {
+                Some(port) => port,
+                None => return false
+            }
+        };
+        unsafe {
+            match width {
+                1 => outb(port, value as u8),
+                2 => outw(port, value as u16),
+                4 => outl(port, value as u32),
+                _ => return false
+            }
+        }
+        true
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        timer::sleep(Duration::from_millis(milliseconds));
+    }
+
+    fn stall(&self, microseconds: u64) {
+        timer::delay(Duration::from_micros(microseconds));
+    }
+}
+
+/* Loads AML code of the DSDT and SSDTs and runs _INI methods of devices */
+pub fn load_namespace() {
+    let mut namespace = NAMESPACE.lock();
+    let ssdts = tables().filter(|table| &table.signature == b"SSDT");
+    for table in dsdt().into_iter().chain(ssdts) {
+        if let Err(error) = namespace.load(table.data(), table.revision, &HANDLER) {
+            println!("Unable to load AML code of {} at 0x{:x}: {:?}.",
+                printable(&table.signature), table.addr(), error);
+        }
+    }
+    let (_, errors) = namespace.initialize_devices(&HANDLER);
+    if errors != 0 {
+        println!("ACPI: {} methods initializing devices have failed.", errors);
+    }
+}
+
+/* Calls \_PIC if the firmware has it, _PRT returns routing for the chosen controller */
+pub fn set_interrupt_model(apic: bool) {
+    let mut namespace = NAMESPACE.lock();
+    if namespace.find("\\_PIC").is_none() {
+        return;
+    }
+    let mode = aml::Value::Integer(if apic { PIC_MODE_APIC } else { PIC_MODE_PIC });
+    if let Err(error) = namespace.evaluate("\\_PIC", &[mode], &HANDLER) {
+        println!("Unable to evaluate \\_PIC: {:?}.", error);
+    }
+}
+
+pub fn display_namespace_info() {
+    let namespace = NAMESPACE.lock();
+    let mut devices = 0;
+    namespace.walk(aml::ROOT_NODE, 0, &mut |node, _| {
+        if namespace.is_device(node) {
+            devices += 1;
+        }
+    });
+    println!("ACPI namespace: {} objects, {} devices.", namespace.nodes_count(), devices);
+}
+
+/* Prints tables and a summary of the ones the kernel uses */
+pub fn display_acpi_info() {
+    println!("ACPI tables:");
+    for table in tables() {
+        println!("  {} at 0x{:x}, {} bytes, revision {}, OEM \"{}\"",
+            printable(&table.signature),
+            table.addr(),
+            table.length,
+            table.revision,
+            printable(&table.oem_id));
+    }
+
+    if let Some(madt) = madt() {
+        let cpus = madt.entries().filter(|entry| match *entry {
+            MadtEntry::LocalApic { enabled, .. } => enabled,
+            _ => false
+        }).count();
+        println!("  MADT: {} processors, local APIC at 0x{:x}{}.", cpus, madt.local_apic_addr(),
+            if madt.has_pic() { ", 8259 PIC present" } else { "" });
+    }
+    if let Some(fadt) = fadt() {
+        println!("  FADT: DSDT at 0x{:x}, SCI interrupt {}, boot flags 0x{:x}, flags 0x{:x}.",
+            fadt.dsdt_addr(), fadt.sci_interrupt, fadt.boot_arch_flags, fadt.flags);
+    }
+    if let Some(hpet) = hpet() {
+        println!("  HPET: at 0x{:x}, {} comparators, {}-bit counter.",
+            hpet.addr, hpet.comparators, if hpet.counter_64bit { 64 } else { 32 });
+    }
+    if let Some(entries) = mcfg() {
+        for entry in entries {
+            println!("  MCFG: segment {}, buses {} - {} at 0x{:x}.",
+                entry.segment, entry.start_bus, entry.end_bus, entry.addr);
+        }
+    }
+    if let Some(entries) = srat() {
+        for entry in entries {
+            match entry {
+                SratEntry::Processor { apic_id, domain, enabled: true } =>
+                    println!("  SRAT: APIC {} in domain {}.", apic_id, domain),
+                SratEntry::Memory { region, domain, enabled: true, .. } =>
+                    println!("  SRAT: 0x{:016x} - 0x{:016x} in domain {}.", region.addr, region.end_addr(), domain),
+                _ => {}
+            }
+        }
+    }
+}
+
+/* Table signatures and OEM ids are supposed to be ASCII */
+fn printable(bytes: &[u8]) -> &str {
+    match ::core::str::from_utf8(bytes) {
+        Ok(string) => string.trim_right_matches(|c| c == ' ' || c == '\0'),
+        Err(_) => "?"
+    }
+}
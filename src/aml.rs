@@ -0,0 +1,2608 @@
+/*
+ * AML interpreter.
+ *
+ * The DSDT and SSDTs contain AML bytecode building the ACPI namespace: a tree of
+ * named objects like devices, data and methods. load() executes the top level
+ * code of a table creating the nodes, bodies of methods are parsed only when
+ * they are evaluated.
+ *
+ * There is no heap, so nodes and values live in fixed arrays of the Namespace.
+ * Values created while evaluate() runs are temporary and stay valid until its
+ * next call. Values stored to named objects are copied to the permanent part.
+ *
+ * The interpreter depends only on core and accesses hardware through the Handler
+ * trait, so it can be built on the host and run against dumps of the tables
+ * (see tools/amlrun.rs).
+ */
+
+use core::cell::Cell;
+use core::cmp::{max, min, Ordering};
+
+/* Capacity of the namespace */
+const MAX_NODES: usize =         2048;
+const MAX_VALUES: usize =        8192;
+const MAX_BYTES: usize =         0x8000;
+/* Names in packages which have to be resolved after the table is loaded */
+const MAX_PENDING_NAMES: usize = 512;
+
+const MAX_CALL_DEPTH: usize = 16;
+/* While loops are stopped after this many iterations */
+const MAX_LOOP_ITERATIONS: usize = 0x100000;
+
+const LOCALS_COUNT: usize = 8;
+const ARGS_COUNT: usize =   7;
+
+const NAME_SEG_SIZE: usize = 4;
+/* Longest path accepted by evaluate() and find(), in bytes of name segments */
+const MAX_PATH_LENGTH: usize = 16 * NAME_SEG_SIZE;
+
+/* Returned by the Revision opcode */
+const INTERPRETER_REVISION: u64 = 0x20160101;
+
+/*
+ * Opcodes.
+ */
+
+const ZERO_OP: u8 =               0x00;
+const ONE_OP: u8 =                0x01;
+const ALIAS_OP: u8 =              0x06;
+const NAME_OP: u8 =               0x08;
+const BYTE_PREFIX: u8 =           0x0A;
+const WORD_PREFIX: u8 =           0x0B;
+const DWORD_PREFIX: u8 =          0x0C;
+const STRING_PREFIX: u8 =         0x0D;
+const QWORD_PREFIX: u8 =          0x0E;
+const SCOPE_OP: u8 =              0x10;
+const BUFFER_OP: u8 =             0x11;
+const PACKAGE_OP: u8 =            0x12;
+const VAR_PACKAGE_OP: u8 =        0x13;
+const METHOD_OP: u8 =             0x14;
+const EXTERNAL_OP: u8 =           0x15;
+const DUAL_NAME_PREFIX: u8 =      0x2E;
+const MULTI_NAME_PREFIX: u8 =     0x2F;
+const EXT_OP_PREFIX: u8 =         0x5B;
+const ROOT_CHAR: u8 =             b'\\';
+const PARENT_PREFIX: u8 =         b'^';
+const LOCAL0_OP: u8 =             0x60;
+const LOCAL7_OP: u8 =             0x67;
+const ARG0_OP: u8 =               0x68;
+const ARG6_OP: u8 =               0x6E;
+const STORE_OP: u8 =              0x70;
+const REF_OF_OP: u8 =             0x71;
+const ADD_OP: u8 =                0x72;
+const CONCAT_OP: u8 =             0x73;
+const SUBTRACT_OP: u8 =           0x74;
+const INCREMENT_OP: u8 =          0x75;
+const DECREMENT_OP: u8 =          0x76;
+const MULTIPLY_OP: u8 =           0x77;
+const DIVIDE_OP: u8 =             0x78;
+const SHIFT_LEFT_OP: u8 =         0x79;
+const SHIFT_RIGHT_OP: u8 =        0x7A;
+const AND_OP: u8 =                0x7B;
+const NAND_OP: u8 =               0x7C;
+const OR_OP: u8 =                 0x7D;
+const NOR_OP: u8 =                0x7E;
+const XOR_OP: u8 =                0x7F;
+const NOT_OP: u8 =                0x80;
+const FIND_SET_LEFT_BIT_OP: u8 =  0x81;
+const FIND_SET_RIGHT_BIT_OP: u8 = 0x82;
+const DEREF_OF_OP: u8 =           0x83;
+const CONCAT_RES_OP: u8 =         0x84;
+const MOD_OP: u8 =                0x85;
+const NOTIFY_OP: u8 =             0x86;
+const SIZE_OF_OP: u8 =            0x87;
+const INDEX_OP: u8 =              0x88;
+const MATCH_OP: u8 =              0x89;
+const CREATE_DWORD_FIELD_OP: u8 = 0x8A;
+const CREATE_WORD_FIELD_OP: u8 =  0x8B;
+const CREATE_BYTE_FIELD_OP: u8 =  0x8C;
+const CREATE_BIT_FIELD_OP: u8 =   0x8D;
+const OBJECT_TYPE_OP: u8 =        0x8E;
+const CREATE_QWORD_FIELD_OP: u8 = 0x8F;
+const LAND_OP: u8 =               0x90;
+const LOR_OP: u8 =                0x91;
+const LNOT_OP: u8 =               0x92;
+const LEQUAL_OP: u8 =             0x93;
+const LGREATER_OP: u8 =           0x94;
+const LLESS_OP: u8 =              0x95;
+const TO_BUFFER_OP: u8 =          0x96;
+const TO_DECIMAL_STRING_OP: u8 =  0x97;
+const TO_HEX_STRING_OP: u8 =      0x98;
+const TO_INTEGER_OP: u8 =         0x99;
+const TO_STRING_OP: u8 =          0x9C;
+const COPY_OBJECT_OP: u8 =        0x9D;
+const MID_OP: u8 =                0x9E;
+const CONTINUE_OP: u8 =           0x9F;
+const IF_OP: u8 =                 0xA0;
+const ELSE_OP: u8 =               0xA1;
+const WHILE_OP: u8 =              0xA2;
+const NOOP_OP: u8 =               0xA3;
+const RETURN_OP: u8 =             0xA4;
+const BREAK_OP: u8 =              0xA5;
+const BREAKPOINT_OP: u8 =         0xCC;
+const ONES_OP: u8 =               0xFF;
+
+/* Extended opcodes following EXT_OP_PREFIX */
+const EXT_MUTEX_OP: u8 =          0x01;
+const EXT_EVENT_OP: u8 =          0x02;
+const EXT_COND_REF_OF_OP: u8 =    0x12;
+const EXT_CREATE_FIELD_OP: u8 =   0x13;
+const EXT_STALL_OP: u8 =          0x21;
+const EXT_SLEEP_OP: u8 =          0x22;
+const EXT_ACQUIRE_OP: u8 =        0x23;
+const EXT_SIGNAL_OP: u8 =         0x24;
+const EXT_WAIT_OP: u8 =           0x25;
+const EXT_RESET_OP: u8 =          0x26;
+const EXT_RELEASE_OP: u8 =        0x27;
+const EXT_FROM_BCD_OP: u8 =       0x28;
+const EXT_TO_BCD_OP: u8 =         0x29;
+const EXT_REVISION_OP: u8 =       0x30;
+const EXT_DEBUG_OP: u8 =          0x31;
+const EXT_FATAL_OP: u8 =          0x32;
+const EXT_REGION_OP: u8 =         0x80;
+const EXT_FIELD_OP: u8 =          0x81;
+const EXT_DEVICE_OP: u8 =         0x82;
+const EXT_PROCESSOR_OP: u8 =      0x83;
+const EXT_POWER_RES_OP: u8 =      0x84;
+const EXT_THERMAL_ZONE_OP: u8 =   0x85;
+const EXT_INDEX_FIELD_OP: u8 =    0x86;
+
+/*
+ * Operation region spaces.
+ */
+
+pub const SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const SPACE_SYSTEM_IO: u8 =     1;
+pub const SPACE_PCI_CONFIG: u8 =    2;
+
+/*
+ * Field flags.
+ */
+
+const FIELD_ACCESS_MASK: u8 =          0x0F;
+const FIELD_ACCESS_WORD: u8 =          2;
+const FIELD_ACCESS_DWORD: u8 =         3;
+const FIELD_ACCESS_QWORD: u8 =         4;
+const FIELD_UPDATE_MASK: u8 =          0x60;
+const FIELD_UPDATE_WRITE_AS_ONES: u8 = 0x20;
+const FIELD_UPDATE_WRITE_AS_ZEROS: u8 = 0x40;
+
+/* Field list entries which are not named fields */
+const FIELD_RESERVED: u8 =        0x00;
+const FIELD_ACCESS: u8 =          0x01;
+const FIELD_CONNECT: u8 =         0x02;
+const FIELD_EXTENDED_ACCESS: u8 = 0x03;
+
+const METHOD_ARGS_MASK: u8 = 0x07;
+
+/*
+ * Bits of the _STA result.
+ */
+
+pub const STA_PRESENT: u64 =     1 << 0;
+pub const STA_FUNCTIONING: u64 = 1 << 3;
+
+/* Interfaces _OSI reports as supported */
+const OSI_STRINGS: &'static [&'static str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001 SP2",
+    "Windows 2006",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+    "Extended Address Space Descriptor",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /* The code ends in the middle of a term */
+    UnexpectedEnd,
+    /* The opcode is unknown or not allowed there */
+    InvalidOpcode(u16),
+    /* The opcode is valid but not implemented */
+    Unsupported(u16),
+    UnsupportedSpace(u8),
+    InvalidName,
+    NotFound,
+    AlreadyExists,
+    /* An operand has a wrong type */
+    TypeMismatch,
+    IndexOutOfRange,
+    DivisionByZero,
+    /* No room for more nodes or values */
+    NoSpace,
+    CallDepth,
+    LoopLimit,
+    /* Release of a mutex which hasn't been acquired */
+    MutexNotAcquired,
+    /* The handler has failed to access an operation region */
+    RegionAccess,
+    /* The code has executed Fatal */
+    Fatal,
+}
+
+/* Address of an operation region access */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Address {
+    Memory(u64),
+    Io(u16),
+    PciConfig { segment: u16, bus: u8, device: u8, function: u8, offset: u16 },
+}
+
+/* Hardware access for operation regions, widths are 1, 2, 4 or 8 bytes */
+pub trait Handler {
+    fn read(&self, address: Address, width: u8) -> Option<u64>;
+    /* Returns false if the address is not accessible */
+    fn write(&self, address: Address, width: u8, value: u64) -> bool;
+    fn sleep(&self, milliseconds: u64);
+    fn stall(&self, microseconds: u64);
+}
+
+pub type NodeId = u16;
+
+pub const ROOT_NODE: NodeId = 0;
+const NO_NODE: NodeId = 0xFFFF;
+
+/* Range of the bytes or values array of the namespace */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Slice {
+    start: u32,
+    len:   u32,
+}
+
+impl Slice {
+    fn range(&self) -> ::core::ops::Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Uninitialized,
+    Integer(u64),
+    String(Slice),
+    Buffer(Slice),
+    Package(Slice),
+    /* A named object, e.g. a device in a package or the result of RefOf */
+    Reference(NodeId),
+    /* Results of Index: an element of a package or a byte of a buffer */
+    Element(u32),
+    Byte(u32),
+}
+
+#[derive(Clone, Copy)]
+enum Object {
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+    Name(Value),
+    Method { body: &'static [u8], flags: u8 },
+    /* _OSI is implemented by the interpreter */
+    Osi,
+    Region { space: u8, offset: u64, length: u64 },
+    Field { region: NodeId, bit_offset: u32, bit_length: u32, flags: u8 },
+    IndexField { index: NodeId, data: NodeId, bit_offset: u32, bit_length: u32, flags: u8 },
+    BufferField { bytes: Slice, bit_offset: u32, bit_length: u32 },
+    Mutex { sync_level: u8, acquired: u16 },
+    Event,
+    Alias(NodeId),
+}
+
+#[derive(Clone, Copy)]
+struct Node {
+    name:   [u8; 4],
+    parent: NodeId,
+    /* The first child and the next sibling */
+    child:  NodeId,
+    next:   NodeId,
+    object: Object,
+}
+
+const EMPTY_NODE: Node = Node {
+    name:   [0; 4],
+    parent: NO_NODE,
+    child:  NO_NODE,
+    next:   NO_NODE,
+    object: Object::Scope,
+};
+
+#[derive(Clone, Copy)]
+struct Path<'a> {
+    absolute: bool,
+    /* Number of ^ prefixes */
+    parents:  usize,
+    /* Name segments, 4 bytes each */
+    segments: &'a [u8],
+}
+
+impl<'a> Path<'a> {
+    /* Parses a path like \_SB.PCI0._PRT, segments shorter than 4 characters are padded with '_' */
+    fn parse(string: &str, buffer: &'a mut [u8; MAX_PATH_LENGTH]) -> Result<Path<'a>, Error> {
+        let mut bytes = string.as_bytes();
+        let mut absolute = false;
+        let mut parents = 0;
+        if bytes.first() == Some(&ROOT_CHAR) {
+            absolute = true;
+            bytes = &bytes[1..];
+        }
+        while bytes.first() == Some(&PARENT_PREFIX) {
+            parents += 1;
+            bytes = &bytes[1..];
+        }
+        let mut length = 0;
+        if !bytes.is_empty() {
+            for segment in bytes.split(|byte| *byte == b'.') {
+                if segment.is_empty() || segment.len() > NAME_SEG_SIZE || length == MAX_PATH_LENGTH {
+                    return Err(Error::InvalidName);
+                }
+                for i in 0..NAME_SEG_SIZE {
+                    buffer[length + i] = if i < segment.len() { segment[i] } else { b'_' };
+                }
+                length += NAME_SEG_SIZE;
+            }
+        }
+        Ok(Path {
+            absolute: absolute,
+            parents:  parents,
+            segments: &buffer[..length],
+        })
+    }
+
+    fn segments_count(&self) -> usize {
+        self.segments.len() / NAME_SEG_SIZE
+    }
+}
+
+/* A name in a package which is not defined yet */
+#[derive(Clone, Copy)]
+struct PendingName {
+    /* Index of the package element */
+    element: u32,
+    scope:   NodeId,
+    path:    Path<'static>,
+}
+
+const EMPTY_PENDING_NAME: PendingName = PendingName {
+    element: 0,
+    scope:   ROOT_NODE,
+    path:    Path {
+        absolute: false,
+        parents:  0,
+        segments: &[],
+    },
+};
+
+fn is_name_lead(byte: u8) -> bool {
+    match byte {
+        b'A'...b'Z' | b'_' | ROOT_CHAR | PARENT_PREFIX | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX => true,
+        _ => false
+    }
+}
+
+/* Position in AML code */
+#[derive(Clone, Copy)]
+struct Stream {
+    aml: &'static [u8],
+    pos: usize,
+}
+
+impl Stream {
+    fn new(aml: &'static [u8]) -> Stream {
+        Stream {
+            aml: aml,
+            pos: 0,
+        }
+    }
+
+    fn peek_at(&self, offset: usize) -> Result<u8, Error> {
+        self.aml.get(self.pos + offset).cloned().ok_or(Error::UnexpectedEnd)
+    }
+
+    fn peek(&self) -> Result<u8, Error> {
+        self.peek_at(0)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = try!(self.peek());
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'static [u8], Error> {
+        if self.pos + count > self.aml.len() {
+            return Err(Error::UnexpectedEnd);
+        }
+        let bytes = &self.aml[self.pos..self.pos + count];
+        self.pos += count;
+        Ok(bytes)
+    }
+
+    /* Little endian integer of the size in bytes */
+    fn integer(&mut self, size: usize) -> Result<u64, Error> {
+        let bytes = try!(self.bytes(size));
+        Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64))
+    }
+
+    /* Bits 6-7 of the lead byte tell how many bytes follow it. With them
+     * only the low 4 bits of the lead byte are a part of the length. */
+    fn pkg_length(&mut self) -> Result<usize, Error> {
+        let lead = try!(self.byte());
+        let count = (lead >> 6) as usize;
+        if count == 0 {
+            return Ok((lead & 0x3F) as usize);
+        }
+        let mut length = (lead & 0x0F) as usize;
+        for i in 0..count {
+            length |= (try!(self.byte()) as usize) << (4 + 8 * i);
+        }
+        Ok(length)
+    }
+
+    /* Returns where the package ends, its length includes the PkgLength itself */
+    fn pkg_end(&mut self) -> Result<usize, Error> {
+        let start = self.pos;
+        let end = start + try!(self.pkg_length());
+        if end < self.pos || end > self.aml.len() {
+            return Err(Error::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    fn name_seg(&mut self) -> Result<[u8; 4], Error> {
+        let bytes = try!(self.bytes(NAME_SEG_SIZE));
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    fn name_string(&mut self) -> Result<Path<'static>, Error> {
+        let mut absolute = false;
+        let mut parents = 0;
+        if try!(self.peek()) == ROOT_CHAR {
+            absolute = true;
+            self.pos += 1;
+        } else {
+            while try!(self.peek()) == PARENT_PREFIX {
+                parents += 1;
+                self.pos += 1;
+            }
+        }
+        let count = match try!(self.peek()) {
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            },
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                try!(self.byte()) as usize
+            },
+            /* NullName */
+            0 => {
+                self.pos += 1;
+                0
+            },
+            _ => 1
+        };
+        Ok(Path {
+            absolute: absolute,
+            parents:  parents,
+            segments: try!(self.bytes(count * NAME_SEG_SIZE)),
+        })
+    }
+}
+
+/* Where a result is stored */
+#[derive(Clone, Copy)]
+enum Target {
+    None,
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Node(NodeId),
+    Element(u32),
+    Byte(u32),
+}
+
+/* State of a method invocation, the top level code of tables has one too */
+struct Frame {
+    scope:  NodeId,
+    locals: [Value; LOCALS_COUNT],
+    args:   [Value; ARGS_COUNT],
+}
+
+impl Frame {
+    fn new(scope: NodeId) -> Frame {
+        Frame {
+            scope:  scope,
+            locals: [Value::Uninitialized; LOCALS_COUNT],
+            args:   [Value::Uninitialized; ARGS_COUNT],
+        }
+    }
+}
+
+/* What to do after a term */
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Value),
+}
+
+/* What fields of a field list access */
+#[derive(Clone, Copy)]
+enum FieldSource {
+    Region(NodeId),
+    Index(NodeId, NodeId),
+}
+
+pub struct Namespace {
+    nodes:                [Node; MAX_NODES],
+    nodes_count:          usize,
+    /* Nodes from this one on have been created by methods being evaluated */
+    first_temporary_node: usize,
+    /* Permanent values are allocated from the start, temporary ones from the end */
+    values:               [Value; MAX_VALUES],
+    values_low:           usize,
+    values_high:          usize,
+    bytes:                [u8; MAX_BYTES],
+    bytes_low:            usize,
+    bytes_high:           usize,
+    pending:              [PendingName; MAX_PENDING_NAMES],
+    pending_count:        usize,
+    /* A table is being loaded, everything allocated is permanent */
+    loading:              bool,
+    tables_count:         usize,
+    /* Integers are 32-bit if the DSDT revision is below 2 */
+    integer_mask:         u64,
+    depth:                usize,
+}
+
+impl Namespace {
+    pub const fn new() -> Namespace {
+        Namespace {
+            nodes:                [EMPTY_NODE; MAX_NODES],
+            nodes_count:          0,
+            first_temporary_node: 0,
+            values:               [Value::Uninitialized; MAX_VALUES],
+            values_low:           0,
+            values_high:          MAX_VALUES,
+            bytes:                [0; MAX_BYTES],
+            bytes_low:            0,
+            bytes_high:           MAX_BYTES,
+            pending:              [EMPTY_PENDING_NAME; MAX_PENDING_NAMES],
+            pending_count:        0,
+            loading:              false,
+            tables_count:         0,
+            integer_mask:         !0,
+            depth:                0,
+        }
+    }
+
+    /* Removes everything loaded */
+    pub fn reset(&mut self) {
+        self.nodes_count = 0;
+        self.first_temporary_node = 0;
+        self.values_low = 0;
+        self.values_high = MAX_VALUES;
+        self.bytes_low = 0;
+        self.bytes_high = MAX_BYTES;
+        self.pending_count = 0;
+        self.loading = false;
+        self.tables_count = 0;
+        self.integer_mask = !0;
+        self.depth = 0;
+    }
+
+    /* The root and objects the specification predefines */
+    fn create_predefined(&mut self) -> Result<(), Error> {
+        self.nodes[ROOT_NODE as usize] = Node {
+            name: *b"\\___",
+            ..EMPTY_NODE
+        };
+        self.nodes_count = 1;
+        for name in [b"_GPE", b"_PR_", b"_SB_", b"_SI_", b"_TZ_"].iter() {
+            try!(self.add_node(ROOT_NODE, **name, Object::Scope));
+        }
+        let os = try!(self.alloc_copy(b"Microsoft Windows NT"));
+        try!(self.add_node(ROOT_NODE, *b"_OS_", Object::Name(Value::String(os))));
+        try!(self.add_node(ROOT_NODE, *b"_REV", Object::Name(Value::Integer(2))));
+        try!(self.add_node(ROOT_NODE, *b"_OSI", Object::Osi));
+        try!(self.add_node(ROOT_NODE, *b"_GL_", Object::Mutex { sync_level: 0, acquired: 0 }));
+        Ok(())
+    }
+
+    /* Executes the AML code of a definition block (the table without its header).
+     * The revision of the first table, the DSDT, sets the width of integers. */
+    pub fn load(&mut self, aml: &'static [u8], revision: u8, handler: &Handler) -> Result<(), Error> {
+        self.reset_temporary();
+        if self.nodes_count == 0 {
+            try!(self.create_predefined());
+        }
+        if self.tables_count == 0 && revision < 2 {
+            self.integer_mask = 0xFFFFFFFF;
+        }
+        self.tables_count += 1;
+
+        self.loading = true;
+        self.pending_count = 0;
+        let mut frame = Frame::new(ROOT_NODE);
+        let mut stream = Stream::new(aml);
+        let result = self.term_list(&mut stream, aml.len(), &mut frame, handler);
+        self.resolve_pending();
+        self.loading = false;
+        self.first_temporary_node = self.nodes_count;
+        result.map(|_| ())
+    }
+
+    fn resolve_pending(&mut self) {
+        for i in 0..self.pending_count {
+            let pending = self.pending[i];
+            if let Ok(node) = self.resolve(&pending.path, pending.scope) {
+                self.values[pending.element as usize] = Value::Reference(node);
+            }
+        }
+        self.pending_count = 0;
+    }
+
+    /* Evaluates the object, e.g. \_SB.PCI0._PRT. The result may refer to temporary
+     * values which are valid until the next call. */
+    pub fn evaluate(&mut self, path: &str, args: &[Value], handler: &Handler) -> Result<Value, Error> {
+        let node = try!(self.find(path).ok_or(Error::NotFound));
+        self.evaluate_node(node, args, handler)
+    }
+
+    pub fn evaluate_node(&mut self, node: NodeId, args: &[Value], handler: &Handler) -> Result<Value, Error> {
+        if args.len() > ARGS_COUNT {
+            return Err(Error::IndexOutOfRange);
+        }
+        self.reset_temporary();
+        self.invoke(node, args, handler)
+    }
+
+    fn reset_temporary(&mut self) {
+        self.values_high = MAX_VALUES;
+        self.bytes_high = MAX_BYTES;
+        self.depth = 0;
+    }
+
+    /* Finds the object by its absolute path or a path relative to the root */
+    pub fn find(&self, path: &str) -> Option<NodeId> {
+        if self.nodes_count == 0 {
+            return None;
+        }
+        let mut buffer = [0; MAX_PATH_LENGTH];
+        match Path::parse(path, &mut buffer) {
+            Ok(path) => self.resolve(&path, ROOT_NODE).ok(),
+            Err(_) => None
+        }
+    }
+
+    pub fn nodes_count(&self) -> usize {
+        self.nodes_count
+    }
+
+    pub fn node_name(&self, node: NodeId) -> [u8; 4] {
+        self.nodes[node as usize].name
+    }
+
+    pub fn parent(&self, node: NodeId) -> Option<NodeId> {
+        match self.nodes[node as usize].parent {
+            NO_NODE => None,
+            parent => Some(parent)
+        }
+    }
+
+    pub fn child(&self, parent: NodeId, name: &[u8]) -> Option<NodeId> {
+        let mut child = self.nodes[parent as usize].child;
+        while child != NO_NODE {
+            if &self.nodes[child as usize].name[..] == name {
+                return Some(child);
+            }
+            child = self.nodes[child as usize].next;
+        }
+        None
+    }
+
+    pub fn is_device(&self, node: NodeId) -> bool {
+        match self.nodes[node as usize].object {
+            Object::Device | Object::Processor => true,
+            _ => false
+        }
+    }
+
+    pub fn node_type(&self, node: NodeId) -> &'static str {
+        match self.nodes[node as usize].object {
+            Object::Scope => "Scope",
+            Object::Device => "Device",
+            Object::Processor => "Processor",
+            Object::PowerResource => "PowerResource",
+            Object::ThermalZone => "ThermalZone",
+            Object::Name(_) => "Name",
+            Object::Method { .. } | Object::Osi => "Method",
+            Object::Region { .. } => "OperationRegion",
+            Object::Field { .. } | Object::IndexField { .. } => "Field",
+            Object::BufferField { .. } => "BufferField",
+            Object::Mutex { .. } => "Mutex",
+            Object::Event => "Event",
+            Object::Alias(_) => "Alias",
+        }
+    }
+
+    /* Calls f for the node and all its descendants with their depth, parents go first */
+    pub fn walk<F: FnMut(NodeId, usize)>(&self, node: NodeId, depth: usize, f: &mut F) {
+        f(node, depth);
+        let mut child = self.nodes[node as usize].child;
+        while child != NO_NODE {
+            self.walk(child, depth + 1, f);
+            child = self.nodes[child as usize].next;
+        }
+    }
+
+    /*
+     * Accessors of evaluation results.
+     */
+
+    pub fn integer(&mut self, value: Value, handler: &Handler) -> Result<u64, Error> {
+        self.to_integer(value, handler)
+    }
+
+    pub fn package_len(&self, value: Value) -> Option<usize> {
+        match self.element_value(value) {
+            Value::Package(slice) => Some(slice.len as usize),
+            _ => None
+        }
+    }
+
+    pub fn package_element(&self, value: Value, index: usize) -> Option<Value> {
+        match self.element_value(value) {
+            Value::Package(slice) if index < slice.len as usize => {
+                Some(self.element_value(self.values[slice.start as usize + index]))
+            },
+            _ => None
+        }
+    }
+
+    /* Content of a string or a buffer */
+    pub fn bytes(&self, value: Value) -> Option<&[u8]> {
+        match self.element_value(value) {
+            Value::String(slice) | Value::Buffer(slice) => Some(&self.bytes[slice.range()]),
+            _ => None
+        }
+    }
+
+    /* Runs _INI of present devices, parents before children as the specification
+     * requires. Returns the number of present devices and of failed methods. */
+    pub fn initialize_devices(&mut self, handler: &Handler) -> (usize, usize) {
+        let mut counts = (0, 0);
+        if self.nodes_count == 0 {
+            return counts;
+        }
+        if let Some(sb) = self.child(ROOT_NODE, b"_SB_") {
+            self.run_ini(sb, &mut counts, handler);
+        }
+        self.initialize_children(ROOT_NODE, &mut counts, handler);
+        counts
+    }
+
+    fn initialize_children(&mut self, node: NodeId, counts: &mut (usize, usize), handler: &Handler) {
+        let mut child = self.nodes[node as usize].child;
+        while child != NO_NODE {
+            let object = self.nodes[child as usize].object;
+            match object {
+                Object::Device | Object::Processor => {
+                    let status = self.device_status(child, counts, handler);
+                    if status & STA_PRESENT != 0 {
+                        counts.0 += 1;
+                        self.run_ini(child, counts, handler);
+                    }
+                    /* Children of a device which is not present but functioning are still checked */
+                    if status & (STA_PRESENT | STA_FUNCTIONING) != 0 {
+                        self.initialize_children(child, counts, handler);
+                    }
+                },
+                Object::Scope | Object::ThermalZone | Object::PowerResource => {
+                    self.initialize_children(child, counts, handler);
+                },
+                _ => {}
+            }
+            child = self.nodes[child as usize].next;
+        }
+    }
+
+    /* Result of _STA, devices without it are present and functioning */
+    fn device_status(&mut self, device: NodeId, counts: &mut (usize, usize), handler: &Handler) -> u64 {
+        let sta = match self.child(device, b"_STA") {
+            Some(sta) => sta,
+            None => return STA_PRESENT | STA_FUNCTIONING
+        };
+        let status = self.evaluate_node(sta, &[], handler).and_then(|value| self.to_integer(value, handler));
+        match status {
+            Ok(status) => status,
+            Err(_) => {
+                counts.1 += 1;
+                0
+            }
+        }
+    }
+
+    fn run_ini(&mut self, device: NodeId, counts: &mut (usize, usize), handler: &Handler) {
+        if let Some(ini) = self.child(device, b"_INI") {
+            if self.evaluate_node(ini, &[], handler).is_err() {
+                counts.1 += 1;
+            }
+        }
+    }
+
+    /*
+     * Nodes.
+     */
+
+    /* Appends a child to the node, so children are kept in the order of definition */
+    fn add_node(&mut self, parent: NodeId, name: [u8; 4], object: Object) -> Result<NodeId, Error> {
+        if self.child(parent, &name).is_some() {
+            return Err(Error::AlreadyExists);
+        }
+        if self.nodes_count == MAX_NODES {
+            return Err(Error::NoSpace);
+        }
+        let id = self.nodes_count as NodeId;
+        self.nodes[id as usize] = Node {
+            name:   name,
+            parent: parent,
+            child:  NO_NODE,
+            next:   NO_NODE,
+            object: object,
+        };
+        self.nodes_count += 1;
+
+        let mut last = self.nodes[parent as usize].child;
+        if last == NO_NODE {
+            self.nodes[parent as usize].child = id;
+        } else {
+            while self.nodes[last as usize].next != NO_NODE {
+                last = self.nodes[last as usize].next;
+            }
+            self.nodes[last as usize].next = id;
+        }
+        Ok(id)
+    }
+
+    /* Removes nodes created after the first one, newer ones go first */
+    fn remove_nodes_from(&mut self, first: usize) {
+        while self.nodes_count > first {
+            let id = (self.nodes_count - 1) as NodeId;
+            let parent = self.nodes[id as usize].parent;
+            let next = self.nodes[id as usize].next;
+            if self.nodes[parent as usize].child == id {
+                self.nodes[parent as usize].child = next;
+            } else {
+                let mut previous = self.nodes[parent as usize].child;
+                while self.nodes[previous as usize].next != id {
+                    previous = self.nodes[previous as usize].next;
+                }
+                self.nodes[previous as usize].next = next;
+            }
+            self.nodes_count -= 1;
+        }
+    }
+
+    fn follow_alias(&self, node: NodeId) -> NodeId {
+        match self.nodes[node as usize].object {
+            Object::Alias(target) => target,
+            _ => node
+        }
+    }
+
+    /* The node a path starts from, the scope moved up for ^ prefixes */
+    fn path_start(&self, path: &Path, scope: NodeId) -> Result<NodeId, Error> {
+        if path.absolute {
+            return Ok(ROOT_NODE);
+        }
+        let mut node = scope;
+        for _ in 0..path.parents {
+            node = try!(self.parent(node).ok_or(Error::NotFound));
+        }
+        Ok(node)
+    }
+
+    fn resolve(&self, path: &Path, scope: NodeId) -> Result<NodeId, Error> {
+        if path.segments_count() == 1 && !path.absolute && path.parents == 0 {
+            /* A single name is searched in the scope and then up to the root */
+            let mut node = scope;
+            loop {
+                if let Some(child) = self.child(node, path.segments) {
+                    return Ok(self.follow_alias(child));
+                }
+                node = try!(self.parent(node).ok_or(Error::NotFound));
+            }
+        }
+        let mut node = try!(self.path_start(path, scope));
+        for segment in path.segments.chunks(NAME_SEG_SIZE) {
+            node = self.follow_alias(try!(self.child(node, segment).ok_or(Error::NotFound)));
+        }
+        Ok(node)
+    }
+
+    /* Creates the last segment of the path in the node the rest refers to */
+    fn create(&mut self, path: &Path, scope: NodeId, object: Object) -> Result<NodeId, Error> {
+        let count = path.segments_count();
+        if count == 0 {
+            return Err(Error::InvalidName);
+        }
+        let mut parent = try!(self.path_start(path, scope));
+        for segment in path.segments[..(count - 1) * NAME_SEG_SIZE].chunks(NAME_SEG_SIZE) {
+            parent = self.follow_alias(try!(self.child(parent, segment).ok_or(Error::NotFound)));
+        }
+        let last = &path.segments[(count - 1) * NAME_SEG_SIZE..];
+        self.add_node(parent, [last[0], last[1], last[2], last[3]], object)
+    }
+
+    fn is_permanent_node(&self, node: NodeId) -> bool {
+        self.loading || (node as usize) < self.first_temporary_node
+    }
+
+    /*
+     * Values.
+     */
+
+    fn alloc_values(&mut self, count: usize, permanent: bool) -> Result<u32, Error> {
+        if self.values_high - self.values_low < count {
+            return Err(Error::NoSpace);
+        }
+        let start = if permanent {
+            self.values_low += count;
+            self.values_low - count
+        } else {
+            self.values_high -= count;
+            self.values_high
+        };
+        for value in self.values[start..start + count].iter_mut() {
+            *value = Value::Uninitialized;
+        }
+        Ok(start as u32)
+    }
+
+    fn alloc_bytes(&mut self, count: usize, permanent: bool) -> Result<Slice, Error> {
+        if self.bytes_high - self.bytes_low < count {
+            return Err(Error::NoSpace);
+        }
+        let start = if permanent {
+            self.bytes_low += count;
+            self.bytes_low - count
+        } else {
+            self.bytes_high -= count;
+            self.bytes_high
+        };
+        for byte in self.bytes[start..start + count].iter_mut() {
+            *byte = 0;
+        }
+        Ok(Slice {
+            start: start as u32,
+            len:   count as u32,
+        })
+    }
+
+    fn alloc_copy(&mut self, bytes: &[u8]) -> Result<Slice, Error> {
+        let permanent = self.loading;
+        let slice = try!(self.alloc_bytes(bytes.len(), permanent));
+        self.bytes[slice.range()].copy_from_slice(bytes);
+        Ok(slice)
+    }
+
+    /* Copies a part of the bytes array to a new place */
+    fn copy_bytes(&mut self, source: Slice, permanent: bool) -> Result<Slice, Error> {
+        let slice = try!(self.alloc_bytes(source.len as usize, permanent));
+        for i in 0..source.len as usize {
+            self.bytes[slice.start as usize + i] = self.bytes[source.start as usize + i];
+        }
+        Ok(slice)
+    }
+
+    /* Moves the value to the permanent part if it is temporary */
+    fn persist(&mut self, value: Value) -> Result<Value, Error> {
+        match value {
+            Value::String(slice) if slice.start as usize >= self.bytes_high =>
+                Ok(Value::String(try!(self.copy_bytes(slice, true)))),
+            Value::Buffer(slice) if slice.start as usize >= self.bytes_high =>
+                Ok(Value::Buffer(try!(self.copy_bytes(slice, true)))),
+            Value::Package(slice) if slice.start as usize >= self.values_high => {
+                let start = try!(self.alloc_values(slice.len as usize, true));
+                for i in 0..slice.len {
+                    let element = self.values[(slice.start + i) as usize];
+                    self.values[(start + i) as usize] = try!(self.persist(element));
+                }
+                Ok(Value::Package(Slice {
+                    start: start,
+                    len:   slice.len,
+                }))
+            },
+            _ => Ok(value)
+        }
+    }
+
+    /* Replaces a package element returned by Index with the element itself */
+    fn element_value(&self, value: Value) -> Value {
+        match value {
+            Value::Element(index) => self.values[index as usize],
+            Value::Byte(index) => Value::Integer(self.bytes[index as usize] as u64),
+            _ => value
+        }
+    }
+
+    fn ones(&self) -> u64 {
+        self.integer_mask
+    }
+
+    fn boolean(&self, value: bool) -> Value {
+        Value::Integer(if value { self.ones() } else { 0 })
+    }
+
+    fn to_integer(&mut self, value: Value, handler: &Handler) -> Result<u64, Error> {
+        match self.element_value(value) {
+            Value::Integer(value) => Ok(value & self.integer_mask),
+            Value::Buffer(slice) => {
+                let size = if self.integer_mask == !0 { 8 } else { 4 };
+                let bytes = &self.bytes[slice.start as usize..(slice.start + min(slice.len, size)) as usize];
+                Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u64))
+            },
+            /* Implicit conversion of strings is from hexadecimal */
+            Value::String(slice) => Ok(parse_integer(&self.bytes[slice.range()], 16) & self.integer_mask),
+            Value::Reference(node) => {
+                let value = try!(self.read_node(node, handler));
+                match value {
+                    Value::Reference(_) => Err(Error::TypeMismatch),
+                    _ => self.to_integer(value, handler)
+                }
+            },
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+
+    /* Bytes of a buffer or a string, integers are converted to buffers */
+    fn to_buffer(&mut self, value: Value, handler: &Handler) -> Result<Slice, Error> {
+        match self.element_value(value) {
+            Value::Buffer(slice) | Value::String(slice) => Ok(slice),
+            value => {
+                let integer = try!(self.to_integer(value, handler));
+                let size = if self.integer_mask == !0 { 8 } else { 4 };
+                let slice = try!(self.alloc_bytes(size, false));
+                for i in 0..size {
+                    self.bytes[slice.start as usize + i] = (integer >> (i * 8)) as u8;
+                }
+                Ok(slice)
+            }
+        }
+    }
+
+    /* Integers become hexadecimal strings of the integer width */
+    fn to_string_bytes(&mut self, value: Value, handler: &Handler) -> Result<Slice, Error> {
+        match self.element_value(value) {
+            Value::String(slice) => Ok(slice),
+            Value::Buffer(slice) => Ok(slice),
+            value => {
+                let integer = try!(self.to_integer(value, handler));
+                let digits = if self.integer_mask == !0 { 16 } else { 8 };
+                let slice = try!(self.alloc_bytes(digits, false));
+                for i in 0..digits {
+                    self.bytes[slice.start as usize + i] = hex_digit((integer >> ((digits - 1 - i) * 4)) as u8);
+                }
+                Ok(slice)
+            }
+        }
+    }
+
+    fn new_string(&mut self, bytes: &[u8]) -> Result<Value, Error> {
+        self.alloc_copy(bytes).map(Value::String)
+    }
+
+    /* Concatenates two parts of the bytes array */
+    fn join(&mut self, first: Slice, second: Slice) -> Result<Slice, Error> {
+        let permanent = self.loading;
+        let slice = try!(self.alloc_bytes((first.len + second.len) as usize, permanent));
+        for i in 0..first.len as usize {
+            self.bytes[slice.start as usize + i] = self.bytes[first.start as usize + i];
+        }
+        for i in 0..second.len as usize {
+            self.bytes[(slice.start + first.len) as usize + i] = self.bytes[second.start as usize + i];
+        }
+        Ok(slice)
+    }
+
+    fn compare(&mut self, first: Value, second: Value, handler: &Handler) -> Result<Ordering, Error> {
+        match self.element_value(first) {
+            Value::String(first) | Value::Buffer(first) => {
+                let second = try!(self.to_buffer(second, handler));
+                Ok(self.bytes[first.range()].cmp(&self.bytes[second.range()]))
+            },
+            first => {
+                let first = try!(self.to_integer(first, handler));
+                let second = try!(self.to_integer(second, handler));
+                Ok(first.cmp(&second))
+            }
+        }
+    }
+
+    /*
+     * Named objects.
+     */
+
+    /* Value of the object, methods are invoked without arguments */
+    fn read_node(&mut self, node: NodeId, handler: &Handler) -> Result<Value, Error> {
+        let object = self.nodes[node as usize].object;
+        match object {
+            Object::Name(value) => Ok(value),
+            Object::Method { .. } | Object::Osi => self.invoke(node, &[], handler),
+            Object::Field { bit_length, .. } |
+            Object::IndexField { bit_length, .. } |
+            Object::BufferField { bit_length, .. } => self.read_field(node, bit_length, handler),
+            _ => Ok(Value::Reference(node))
+        }
+    }
+
+    fn store_node(&mut self, node: NodeId, value: Value, handler: &Handler) -> Result<(), Error> {
+        let object = self.nodes[node as usize].object;
+        match object {
+            Object::Name(old) => {
+                /* Integer objects keep their type */
+                let value = match old {
+                    Value::Integer(_) => Value::Integer(try!(self.to_integer(value, handler))),
+                    _ => self.element_value(value)
+                };
+                let value = if self.is_permanent_node(node) { try!(self.persist(value)) } else { value };
+                self.nodes[node as usize].object = Object::Name(value);
+                Ok(())
+            },
+            Object::Field { bit_length, .. } |
+            Object::IndexField { bit_length, .. } |
+            Object::BufferField { bit_length, .. } => self.write_field(node, bit_length, value, handler),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+
+    fn invoke(&mut self, node: NodeId, args: &[Value], handler: &Handler) -> Result<Value, Error> {
+        let object = self.nodes[node as usize].object;
+        let body = match object {
+            Object::Method { body, .. } => body,
+            Object::Osi => return self.osi(args),
+            _ => return self.read_node(node, handler)
+        };
+        if self.depth == MAX_CALL_DEPTH {
+            return Err(Error::CallDepth);
+        }
+        let mut frame = Frame::new(node);
+        for (i, arg) in args.iter().enumerate() {
+            frame.args[i] = *arg;
+        }
+
+        /* Objects the method creates are removed when it returns */
+        let first_node = self.nodes_count;
+        self.depth += 1;
+        let mut stream = Stream::new(body);
+        let result = self.term_list(&mut stream, body.len(), &mut frame, handler);
+        self.depth -= 1;
+        self.remove_nodes_from(first_node);
+
+        match try!(result) {
+            Flow::Return(value) => Ok(value),
+            _ => Ok(Value::Uninitialized)
+        }
+    }
+
+    fn osi(&mut self, args: &[Value]) -> Result<Value, Error> {
+        let supported = match args.first().map(|arg| self.element_value(*arg)) {
+            Some(Value::String(slice)) => {
+                let interface = &self.bytes[slice.range()];
+                OSI_STRINGS.iter().any(|osi| osi.as_bytes() == interface)
+            },
+            _ => return Err(Error::TypeMismatch)
+        };
+        Ok(self.boolean(supported))
+    }
+
+    /*
+     * Terms.
+     */
+
+    fn term_list(&mut self, s: &mut Stream, end: usize, frame: &mut Frame, h: &Handler) -> Result<Flow, Error> {
+        while s.pos < end {
+            match try!(self.term(s, end, frame, h)) {
+                Flow::Next => {},
+                flow => return Ok(flow)
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /* Executes a term of a list ending at end */
+    fn term(&mut self, s: &mut Stream, end: usize, frame: &mut Frame, h: &Handler) -> Result<Flow, Error> {
+        let op = try!(s.peek());
+        match op {
+            NAME_OP => {
+                s.pos += 1;
+                let path = try!(s.name_string());
+                let value = try!(self.term_arg(s, frame, h));
+                try!(self.create(&path, frame.scope, Object::Name(value)));
+            },
+            SCOPE_OP => {
+                s.pos += 1;
+                let scope_end = try!(s.pkg_end());
+                let path = try!(s.name_string());
+                let node = try!(self.resolve(&path, frame.scope));
+                try!(self.scope_body(node, s, scope_end, frame, h));
+            },
+            METHOD_OP => {
+                s.pos += 1;
+                let method_end = try!(s.pkg_end());
+                let path = try!(s.name_string());
+                let flags = try!(s.byte());
+                let body = &s.aml[s.pos..method_end];
+                s.pos = method_end;
+                try!(self.create(&path, frame.scope, Object::Method {
+                    body:  body,
+                    flags: flags,
+                }));
+            },
+            EXTERNAL_OP => {
+                /* NameString ObjectType ArgumentCount */
+                s.pos += 1;
+                try!(s.name_string());
+                try!(s.bytes(2));
+            },
+            ALIAS_OP => {
+                s.pos += 1;
+                let source = try!(s.name_string());
+                let alias = try!(s.name_string());
+                let target = try!(self.resolve(&source, frame.scope));
+                try!(self.create(&alias, frame.scope, Object::Alias(target)));
+            },
+            IF_OP => {
+                s.pos += 1;
+                let if_end = try!(s.pkg_end());
+                let predicate = try!(self.integer_arg(s, frame, h)) != 0;
+                let mut flow = Flow::Next;
+                if predicate {
+                    flow = try!(self.term_list(s, if_end, frame, h));
+                }
+                s.pos = if_end;
+                if s.pos < end && try!(s.peek()) == ELSE_OP {
+                    s.pos += 1;
+                    let else_end = try!(s.pkg_end());
+                    if !predicate {
+                        flow = try!(self.term_list(s, else_end, frame, h));
+                    }
+                    s.pos = else_end;
+                }
+                return Ok(flow);
+            },
+            WHILE_OP => {
+                s.pos += 1;
+                let while_end = try!(s.pkg_end());
+                let predicate_pos = s.pos;
+                let mut iterations = 0;
+                loop {
+                    s.pos = predicate_pos;
+                    if try!(self.integer_arg(s, frame, h)) == 0 {
+                        break;
+                    }
+                    iterations += 1;
+                    if iterations > MAX_LOOP_ITERATIONS {
+                        return Err(Error::LoopLimit);
+                    }
+                    match try!(self.term_list(s, while_end, frame, h)) {
+                        Flow::Break => break,
+                        Flow::Return(value) => return Ok(Flow::Return(value)),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                }
+                s.pos = while_end;
+            },
+            RETURN_OP => {
+                s.pos += 1;
+                let value = try!(self.term_arg(s, frame, h));
+                return Ok(Flow::Return(value));
+            },
+            BREAK_OP => {
+                s.pos += 1;
+                return Ok(Flow::Break);
+            },
+            CONTINUE_OP => {
+                s.pos += 1;
+                return Ok(Flow::Continue);
+            },
+            NOOP_OP | BREAKPOINT_OP => s.pos += 1,
+            NOTIFY_OP => {
+                /* Nobody listens to notifications */
+                s.pos += 1;
+                try!(self.super_name(s, frame, h));
+                try!(self.term_arg(s, frame, h));
+            },
+            CREATE_BIT_FIELD_OP | CREATE_BYTE_FIELD_OP | CREATE_WORD_FIELD_OP |
+            CREATE_DWORD_FIELD_OP | CREATE_QWORD_FIELD_OP => {
+                s.pos += 1;
+                let source = try!(self.term_arg(s, frame, h));
+                let index = try!(self.integer_arg(s, frame, h));
+                let path = try!(s.name_string());
+                let (bit_offset, bit_length) = match op {
+                    CREATE_BIT_FIELD_OP => (index, 1),
+                    CREATE_BYTE_FIELD_OP => (index * 8, 8),
+                    CREATE_WORD_FIELD_OP => (index * 8, 16),
+                    CREATE_DWORD_FIELD_OP => (index * 8, 32),
+                    _ => (index * 8, 64)
+                };
+                try!(self.create_buffer_field(source, bit_offset, bit_length, &path, frame.scope));
+            },
+            EXT_OP_PREFIX => {
+                let ext = try!(s.peek_at(1));
+                match ext {
+                    EXT_MUTEX_OP | EXT_EVENT_OP | EXT_CREATE_FIELD_OP | EXT_STALL_OP | EXT_SLEEP_OP |
+                    EXT_SIGNAL_OP | EXT_RESET_OP | EXT_RELEASE_OP | EXT_FATAL_OP | EXT_REGION_OP |
+                    EXT_FIELD_OP | EXT_DEVICE_OP | EXT_PROCESSOR_OP | EXT_POWER_RES_OP |
+                    EXT_THERMAL_ZONE_OP | EXT_INDEX_FIELD_OP => {
+                        s.pos += 2;
+                        try!(self.ext_term(ext, s, frame, h));
+                    },
+                    _ => {
+                        try!(self.term_arg(s, frame, h));
+                    }
+                }
+            },
+            _ => {
+                /* An expression with its result discarded */
+                try!(self.term_arg(s, frame, h));
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /* Terms with extended opcodes which don't return values */
+    fn ext_term(&mut self, ext: u8, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<(), Error> {
+        match ext {
+            EXT_MUTEX_OP => {
+                let path = try!(s.name_string());
+                let flags = try!(s.byte());
+                try!(self.create(&path, frame.scope, Object::Mutex {
+                    sync_level: flags & 0x0F,
+                    acquired:   0,
+                }));
+            },
+            EXT_EVENT_OP => {
+                let path = try!(s.name_string());
+                try!(self.create(&path, frame.scope, Object::Event));
+            },
+            EXT_CREATE_FIELD_OP => {
+                let source = try!(self.term_arg(s, frame, h));
+                let bit_offset = try!(self.integer_arg(s, frame, h));
+                let bit_length = try!(self.integer_arg(s, frame, h));
+                let path = try!(s.name_string());
+                try!(self.create_buffer_field(source, bit_offset, bit_length, &path, frame.scope));
+            },
+            EXT_STALL_OP => {
+                let microseconds = try!(self.integer_arg(s, frame, h));
+                h.stall(microseconds);
+            },
+            EXT_SLEEP_OP => {
+                let milliseconds = try!(self.integer_arg(s, frame, h));
+                h.sleep(milliseconds);
+            },
+            EXT_SIGNAL_OP | EXT_RESET_OP => {
+                try!(self.super_name(s, frame, h));
+            },
+            EXT_RELEASE_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                let node = try!(self.mutex_node(target, frame));
+                if let Object::Mutex { sync_level, acquired } = self.nodes[node as usize].object {
+                    if acquired == 0 {
+                        return Err(Error::MutexNotAcquired);
+                    }
+                    self.nodes[node as usize].object = Object::Mutex {
+                        sync_level: sync_level,
+                        acquired:   acquired - 1,
+                    };
+                }
+            },
+            EXT_FATAL_OP => {
+                /* FatalType FatalCode FatalArg */
+                try!(s.bytes(5));
+                try!(self.term_arg(s, frame, h));
+                return Err(Error::Fatal);
+            },
+            EXT_REGION_OP => {
+                let path = try!(s.name_string());
+                let space = try!(s.byte());
+                let offset = try!(self.integer_arg(s, frame, h));
+                let length = try!(self.integer_arg(s, frame, h));
+                try!(self.create(&path, frame.scope, Object::Region {
+                    space:  space,
+                    offset: offset,
+                    length: length,
+                }));
+            },
+            EXT_FIELD_OP => {
+                let end = try!(s.pkg_end());
+                let region_path = try!(s.name_string());
+                let region = try!(self.resolve(&region_path, frame.scope));
+                let flags = try!(s.byte());
+                try!(self.field_list(s, end, frame.scope, FieldSource::Region(region), flags));
+            },
+            EXT_INDEX_FIELD_OP => {
+                let end = try!(s.pkg_end());
+                let index_path = try!(s.name_string());
+                let data_path = try!(s.name_string());
+                let index = try!(self.resolve(&index_path, frame.scope));
+                let data = try!(self.resolve(&data_path, frame.scope));
+                let flags = try!(s.byte());
+                try!(self.field_list(s, end, frame.scope, FieldSource::Index(index, data), flags));
+            },
+            EXT_DEVICE_OP | EXT_PROCESSOR_OP | EXT_POWER_RES_OP | EXT_THERMAL_ZONE_OP => {
+                let end = try!(s.pkg_end());
+                let path = try!(s.name_string());
+                let object = match ext {
+                    EXT_DEVICE_OP => Object::Device,
+                    EXT_PROCESSOR_OP => {
+                        /* ProcID PblkAddr PblkLen */
+                        try!(s.bytes(6));
+                        Object::Processor
+                    },
+                    EXT_POWER_RES_OP => {
+                        /* SystemLevel ResourceOrder */
+                        try!(s.bytes(3));
+                        Object::PowerResource
+                    },
+                    _ => Object::ThermalZone
+                };
+                let node = try!(self.create(&path, frame.scope, object));
+                try!(self.scope_body(node, s, end, frame, h));
+            },
+            _ => return Err(Error::InvalidOpcode((EXT_OP_PREFIX as u16) << 8 | ext as u16))
+        }
+        Ok(())
+    }
+
+    /* Executes the terms of a scope, a device or a similar object */
+    fn scope_body(&mut self, node: NodeId, s: &mut Stream, end: usize, frame: &mut Frame, h: &Handler) -> Result<(), Error> {
+        let scope = frame.scope;
+        frame.scope = node;
+        let result = self.term_list(s, end, frame, h);
+        frame.scope = scope;
+        s.pos = end;
+        result.map(|_| ())
+    }
+
+    fn field_list(&mut self, s: &mut Stream, end: usize, scope: NodeId, source: FieldSource, flags: u8) -> Result<(), Error> {
+        let mut flags = flags;
+        let mut bit_offset = 0;
+        while s.pos < end {
+            match try!(s.peek()) {
+                FIELD_RESERVED => {
+                    s.pos += 1;
+                    bit_offset += try!(s.pkg_length()) as u32;
+                },
+                FIELD_ACCESS => {
+                    /* AccessType AccessAttrib */
+                    s.pos += 1;
+                    let access = try!(s.byte());
+                    try!(s.byte());
+                    flags = (flags & !FIELD_ACCESS_MASK) | (access & FIELD_ACCESS_MASK);
+                },
+                FIELD_EXTENDED_ACCESS => {
+                    /* AccessType ExtendedAccessAttrib AccessLength */
+                    s.pos += 1;
+                    let access = try!(s.byte());
+                    try!(s.bytes(2));
+                    flags = (flags & !FIELD_ACCESS_MASK) | (access & FIELD_ACCESS_MASK);
+                },
+                FIELD_CONNECT => return Err(Error::Unsupported(FIELD_CONNECT as u16)),
+                _ => {
+                    let name = try!(s.name_seg());
+                    let bit_length = try!(s.pkg_length()) as u32;
+                    let object = match source {
+                        FieldSource::Region(region) => Object::Field {
+                            region:     region,
+                            bit_offset: bit_offset,
+                            bit_length: bit_length,
+                            flags:      flags,
+                        },
+                        FieldSource::Index(index, data) => Object::IndexField {
+                            index:      index,
+                            data:       data,
+                            bit_offset: bit_offset,
+                            bit_length: bit_length,
+                            flags:      flags,
+                        },
+                    };
+                    try!(self.add_node(scope, name, object));
+                    bit_offset += bit_length;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn create_buffer_field(&mut self, source: Value, bit_offset: u64, bit_length: u64, path: &Path, scope: NodeId) -> Result<(), Error> {
+        let bytes = match self.element_value(source) {
+            Value::Buffer(bytes) => bytes,
+            _ => return Err(Error::TypeMismatch)
+        };
+        if bit_length == 0 || bit_offset + bit_length > bytes.len as u64 * 8 {
+            return Err(Error::IndexOutOfRange);
+        }
+        try!(self.create(path, scope, Object::BufferField {
+            bytes:      bytes,
+            bit_offset: bit_offset as u32,
+            bit_length: bit_length as u32,
+        }));
+        Ok(())
+    }
+
+    fn integer_arg(&mut self, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<u64, Error> {
+        let value = try!(self.term_arg(s, frame, h));
+        self.to_integer(value, h)
+    }
+
+    /* Evaluates an expression */
+    fn term_arg(&mut self, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        let op = try!(s.byte());
+        match op {
+            ZERO_OP => Ok(Value::Integer(0)),
+            ONE_OP => Ok(Value::Integer(1)),
+            ONES_OP => Ok(Value::Integer(self.ones())),
+            BYTE_PREFIX => s.integer(1).map(Value::Integer),
+            WORD_PREFIX => s.integer(2).map(Value::Integer),
+            DWORD_PREFIX => s.integer(4).map(Value::Integer),
+            QWORD_PREFIX => s.integer(8).map(Value::Integer),
+            STRING_PREFIX => {
+                let start = s.pos;
+                while try!(s.byte()) != 0 {}
+                self.new_string(&s.aml[start..s.pos - 1])
+            },
+            BUFFER_OP => {
+                let end = try!(s.pkg_end());
+                let size = try!(self.integer_arg(s, frame, h)) as usize;
+                let initializer = &s.aml[s.pos..end];
+                s.pos = end;
+                let permanent = self.loading;
+                let slice = try!(self.alloc_bytes(max(size, initializer.len()), permanent));
+                self.bytes[slice.start as usize..slice.start as usize + initializer.len()].copy_from_slice(initializer);
+                Ok(Value::Buffer(slice))
+            },
+            PACKAGE_OP | VAR_PACKAGE_OP => self.package(op, s, frame, h),
+            _ if op >= LOCAL0_OP && op <= LOCAL7_OP => Ok(frame.locals[(op - LOCAL0_OP) as usize]),
+            _ if op >= ARG0_OP && op <= ARG6_OP => Ok(frame.args[(op - ARG0_OP) as usize]),
+            STORE_OP | COPY_OBJECT_OP => {
+                let value = try!(self.term_arg(s, frame, h));
+                let target = try!(self.super_name(s, frame, h));
+                try!(self.store(target, value, frame, h));
+                Ok(value)
+            },
+            REF_OF_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                match target {
+                    Target::Node(node) => Ok(Value::Reference(node)),
+                    Target::Element(index) => Ok(Value::Element(index)),
+                    Target::Byte(index) => Ok(Value::Byte(index)),
+                    Target::Local(index) => Ok(frame.locals[index]),
+                    Target::Arg(index) => Ok(frame.args[index]),
+                    Target::None | Target::Debug => Err(Error::TypeMismatch)
+                }
+            },
+            ADD_OP | SUBTRACT_OP | MULTIPLY_OP | SHIFT_LEFT_OP | SHIFT_RIGHT_OP | AND_OP |
+            NAND_OP | OR_OP | NOR_OP | XOR_OP | MOD_OP => {
+                let a = try!(self.integer_arg(s, frame, h));
+                let b = try!(self.integer_arg(s, frame, h));
+                let result = match op {
+                    ADD_OP => a.wrapping_add(b),
+                    SUBTRACT_OP => a.wrapping_sub(b),
+                    MULTIPLY_OP => a.wrapping_mul(b),
+                    SHIFT_LEFT_OP => if b >= 64 { 0 } else { a << b },
+                    SHIFT_RIGHT_OP => if b >= 64 { 0 } else { a >> b },
+                    AND_OP => a & b,
+                    NAND_OP => !(a & b),
+                    OR_OP => a | b,
+                    NOR_OP => !(a | b),
+                    XOR_OP => a ^ b,
+                    _ => {
+                        if b == 0 {
+                            return Err(Error::DivisionByZero);
+                        }
+                        a % b
+                    }
+                };
+                let result = Value::Integer(result & self.integer_mask);
+                self.store_result(result, s, frame, h)
+            },
+            DIVIDE_OP => {
+                let dividend = try!(self.integer_arg(s, frame, h));
+                let divisor = try!(self.integer_arg(s, frame, h));
+                if divisor == 0 {
+                    return Err(Error::DivisionByZero);
+                }
+                try!(self.store_result(Value::Integer(dividend % divisor), s, frame, h));
+                self.store_result(Value::Integer(dividend / divisor), s, frame, h)
+            },
+            NOT_OP | FIND_SET_LEFT_BIT_OP | FIND_SET_RIGHT_BIT_OP | TO_INTEGER_OP => {
+                let operand = try!(self.term_arg(s, frame, h));
+                let result = match op {
+                    TO_INTEGER_OP => match self.element_value(operand) {
+                        /* Explicit conversion of strings is from decimal unless they start with 0x */
+                        Value::String(slice) => parse_integer(&self.bytes[slice.range()], 10),
+                        operand => try!(self.to_integer(operand, h))
+                    },
+                    _ => {
+                        let operand = try!(self.to_integer(operand, h));
+                        match op {
+                            NOT_OP => !operand,
+                            /* One-based bit numbers, zero if no bits are set */
+                            FIND_SET_LEFT_BIT_OP => 64 - operand.leading_zeros() as u64,
+                            _ => if operand == 0 { 0 } else { operand.trailing_zeros() as u64 + 1 }
+                        }
+                    }
+                };
+                let result = Value::Integer(result & self.integer_mask);
+                self.store_result(result, s, frame, h)
+            },
+            INCREMENT_OP | DECREMENT_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                let value = try!(self.read_target(target, frame, h));
+                let value = try!(self.to_integer(value, h));
+                let result = if op == INCREMENT_OP { value.wrapping_add(1) } else { value.wrapping_sub(1) };
+                let result = Value::Integer(result & self.integer_mask);
+                try!(self.store(target, result, frame, h));
+                Ok(result)
+            },
+            CONCAT_OP | CONCAT_RES_OP => {
+                let first = try!(self.term_arg(s, frame, h));
+                let second = try!(self.term_arg(s, frame, h));
+                let result = try!(self.concat(op, first, second, h));
+                self.store_result(result, s, frame, h)
+            },
+            DEREF_OF_OP => {
+                let value = try!(self.term_arg(s, frame, h));
+                match value {
+                    Value::Reference(node) => self.read_node(node, h),
+                    Value::String(slice) => {
+                        /* A path in a string */
+                        let mut buffer = [0; MAX_PATH_LENGTH];
+                        let node = {
+                            let string = try!(::core::str::from_utf8(&self.bytes[slice.range()]).map_err(|_| Error::InvalidName));
+                            let path = try!(Path::parse(string, &mut buffer));
+                            try!(self.resolve(&path, frame.scope))
+                        };
+                        self.read_node(node, h)
+                    },
+                    _ => Ok(self.element_value(value))
+                }
+            },
+            SIZE_OF_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                let value = try!(self.read_target(target, frame, h));
+                match self.element_value(value) {
+                    Value::String(slice) | Value::Buffer(slice) | Value::Package(slice) =>
+                        Ok(Value::Integer(slice.len as u64)),
+                    _ => Err(Error::TypeMismatch)
+                }
+            },
+            INDEX_OP => {
+                let source = try!(self.term_arg(s, frame, h));
+                let index = try!(self.integer_arg(s, frame, h));
+                let result = match self.element_value(source) {
+                    Value::Package(slice) if index < slice.len as u64 => Value::Element(slice.start + index as u32),
+                    Value::Buffer(slice) | Value::String(slice) if index < slice.len as u64 =>
+                        Value::Byte(slice.start + index as u32),
+                    Value::Package(_) | Value::Buffer(_) | Value::String(_) => return Err(Error::IndexOutOfRange),
+                    _ => return Err(Error::TypeMismatch)
+                };
+                self.store_result(result, s, frame, h)
+            },
+            MATCH_OP => self.match_package(s, frame, h),
+            OBJECT_TYPE_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                let object_type = match target {
+                    Target::Node(node) => self.object_type(node),
+                    _ => {
+                        let value = try!(self.read_target(target, frame, h));
+                        value_type(self.element_value(value))
+                    }
+                };
+                Ok(Value::Integer(object_type))
+            },
+            LAND_OP | LOR_OP => {
+                let a = try!(self.integer_arg(s, frame, h)) != 0;
+                let b = try!(self.integer_arg(s, frame, h)) != 0;
+                Ok(self.boolean(if op == LAND_OP { a && b } else { a || b }))
+            },
+            LNOT_OP => {
+                let operand = try!(self.integer_arg(s, frame, h));
+                Ok(self.boolean(operand == 0))
+            },
+            LEQUAL_OP | LGREATER_OP | LLESS_OP => {
+                let a = try!(self.term_arg(s, frame, h));
+                let b = try!(self.term_arg(s, frame, h));
+                let ordering = try!(self.compare(a, b, h));
+                Ok(self.boolean(ordering == match op {
+                    LEQUAL_OP => Ordering::Equal,
+                    LGREATER_OP => Ordering::Greater,
+                    _ => Ordering::Less
+                }))
+            },
+            TO_BUFFER_OP => {
+                let operand = try!(self.term_arg(s, frame, h));
+                let result = match self.element_value(operand) {
+                    Value::String(slice) => {
+                        /* The terminating zero is a part of the buffer */
+                        let terminator = try!(self.alloc_bytes(1, false));
+                        Value::Buffer(try!(self.join(slice, terminator)))
+                    },
+                    operand => Value::Buffer(try!(self.to_buffer(operand, h)))
+                };
+                self.store_result(result, s, frame, h)
+            },
+            TO_DECIMAL_STRING_OP | TO_HEX_STRING_OP => {
+                let operand = try!(self.term_arg(s, frame, h));
+                let result = try!(self.format(op == TO_HEX_STRING_OP, operand, h));
+                self.store_result(result, s, frame, h)
+            },
+            TO_STRING_OP => {
+                let operand = try!(self.term_arg(s, frame, h));
+                let length = try!(self.integer_arg(s, frame, h));
+                let buffer = try!(self.to_buffer(operand, h));
+                let length = {
+                    let bytes = &self.bytes[buffer.range()];
+                    let length = min(length, bytes.len() as u64) as usize;
+                    bytes[..length].iter().position(|byte| *byte == 0).unwrap_or(length)
+                };
+                let permanent = self.loading;
+                let result = Value::String(try!(self.copy_bytes(Slice { start: buffer.start, len: length as u32 }, permanent)));
+                self.store_result(result, s, frame, h)
+            },
+            MID_OP => {
+                let source = try!(self.term_arg(s, frame, h));
+                let index = try!(self.integer_arg(s, frame, h));
+                let length = try!(self.integer_arg(s, frame, h));
+                let source = self.element_value(source);
+                let bytes = match source {
+                    Value::String(slice) | Value::Buffer(slice) => slice,
+                    _ => return Err(Error::TypeMismatch)
+                };
+                let start = min(index, bytes.len as u64) as u32;
+                let length = min(length, (bytes.len - start) as u64) as u32;
+                let permanent = self.loading;
+                let copy = try!(self.copy_bytes(Slice { start: bytes.start + start, len: length }, permanent));
+                let result = match source {
+                    Value::String(_) => Value::String(copy),
+                    _ => Value::Buffer(copy)
+                };
+                self.store_result(result, s, frame, h)
+            },
+            EXT_OP_PREFIX => self.ext_term_arg(s, frame, h),
+            _ if is_name_lead(op) => {
+                s.pos -= 1;
+                let path = try!(s.name_string());
+                let node = try!(self.resolve(&path, frame.scope));
+                let object = self.nodes[node as usize].object;
+                let args_count = match object {
+                    Object::Method { flags, .. } => (flags & METHOD_ARGS_MASK) as usize,
+                    Object::Osi => 1,
+                    _ => return self.read_node(node, h)
+                };
+                let mut args = [Value::Uninitialized; ARGS_COUNT];
+                for arg in args[..args_count].iter_mut() {
+                    *arg = try!(self.term_arg(s, frame, h));
+                }
+                self.invoke(node, &args[..args_count], h)
+            },
+            _ => Err(Error::InvalidOpcode(op as u16))
+        }
+    }
+
+    fn ext_term_arg(&mut self, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        let ext = try!(s.byte());
+        match ext {
+            EXT_COND_REF_OF_OP => {
+                /* Like RefOf, but the object may be missing */
+                let found = if is_name_lead(try!(s.peek())) {
+                    let path = try!(s.name_string());
+                    self.resolve(&path, frame.scope).ok().map(Value::Reference)
+                } else {
+                    match try!(self.super_name(s, frame, h)) {
+                        Target::Node(node) => Some(Value::Reference(node)),
+                        Target::None => None,
+                        _ => return Err(Error::Unsupported((EXT_OP_PREFIX as u16) << 8 | ext as u16))
+                    }
+                };
+                let target = try!(self.super_name(s, frame, h));
+                match found {
+                    Some(reference) => {
+                        try!(self.store(target, reference, frame, h));
+                        Ok(self.boolean(true))
+                    },
+                    None => Ok(self.boolean(false))
+                }
+            },
+            EXT_ACQUIRE_OP => {
+                let target = try!(self.super_name(s, frame, h));
+                /* Timeout */
+                try!(s.integer(2));
+                let node = try!(self.mutex_node(target, frame));
+                /* There is only one thread of AML execution, a mutex is never held by someone else */
+                if let Object::Mutex { sync_level, acquired } = self.nodes[node as usize].object {
+                    self.nodes[node as usize].object = Object::Mutex {
+                        sync_level: sync_level,
+                        acquired:   acquired + 1,
+                    };
+                }
+                Ok(Value::Integer(0))
+            },
+            EXT_WAIT_OP => {
+                /* Nothing signals events, so the wait times out */
+                try!(self.super_name(s, frame, h));
+                try!(self.term_arg(s, frame, h));
+                Ok(self.boolean(true))
+            },
+            EXT_FROM_BCD_OP | EXT_TO_BCD_OP => {
+                let operand = try!(self.integer_arg(s, frame, h));
+                let result = if ext == EXT_FROM_BCD_OP { from_bcd(operand) } else { to_bcd(operand) };
+                self.store_result(Value::Integer(result), s, frame, h)
+            },
+            EXT_REVISION_OP => Ok(Value::Integer(INTERPRETER_REVISION)),
+            _ => Err(Error::InvalidOpcode((EXT_OP_PREFIX as u16) << 8 | ext as u16))
+        }
+    }
+
+    fn package(&mut self, op: u8, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        let end = try!(s.pkg_end());
+        let count = if op == VAR_PACKAGE_OP {
+            try!(self.integer_arg(s, frame, h)) as usize
+        } else {
+            try!(s.byte()) as usize
+        };
+        let permanent = self.loading;
+        let start = try!(self.alloc_values(count, permanent));
+        let mut index = 0;
+        while s.pos < end {
+            let element = if is_name_lead(try!(s.peek())) {
+                /* Names are references, they may be defined later in the table */
+                let path = try!(s.name_string());
+                match self.resolve(&path, frame.scope) {
+                    Ok(node) => Value::Reference(node),
+                    Err(_) => {
+                        if self.loading && index < count && self.pending_count < MAX_PENDING_NAMES {
+                            self.pending[self.pending_count] = PendingName {
+                                element: start + index as u32,
+                                scope:   frame.scope,
+                                path:    path,
+                            };
+                            self.pending_count += 1;
+                        }
+                        Value::Uninitialized
+                    }
+                }
+            } else {
+                try!(self.term_arg(s, frame, h))
+            };
+            if index < count {
+                self.values[start as usize + index] = element;
+            }
+            index += 1;
+        }
+        Ok(Value::Package(Slice {
+            start: start,
+            len:   count as u32,
+        }))
+    }
+
+    /* Match (SearchPackage, Op1, Object1, Op2, Object2, StartIndex) */
+    fn match_package(&mut self, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        let package = try!(self.term_arg(s, frame, h));
+        let first_op = try!(s.byte());
+        let first = try!(self.term_arg(s, frame, h));
+        let second_op = try!(s.byte());
+        let second = try!(self.term_arg(s, frame, h));
+        let start = try!(self.integer_arg(s, frame, h));
+        let slice = match self.element_value(package) {
+            Value::Package(slice) => slice,
+            _ => return Err(Error::TypeMismatch)
+        };
+        for index in start..slice.len as u64 {
+            let element = self.values[(slice.start as u64 + index) as usize];
+            if let Value::Uninitialized = element {
+                continue;
+            }
+            if try!(self.match_element(element, first_op, first, h)) &&
+               try!(self.match_element(element, second_op, second, h)) {
+                return Ok(Value::Integer(index));
+            }
+        }
+        Ok(Value::Integer(self.ones()))
+    }
+
+    fn match_element(&mut self, element: Value, op: u8, object: Value, h: &Handler) -> Result<bool, Error> {
+        if op == 0 {
+            return Ok(true);
+        }
+        let ordering = match self.compare(element, object, h) {
+            Ok(ordering) => ordering,
+            Err(Error::TypeMismatch) => return Ok(false),
+            Err(error) => return Err(error)
+        };
+        Ok(match op {
+            1 => ordering == Ordering::Equal,
+            2 => ordering != Ordering::Greater,
+            3 => ordering == Ordering::Less,
+            4 => ordering != Ordering::Less,
+            5 => ordering == Ordering::Greater,
+            _ => false
+        })
+    }
+
+    fn concat(&mut self, op: u8, first: Value, second: Value, h: &Handler) -> Result<Value, Error> {
+        let first = self.element_value(first);
+        if op == CONCAT_RES_OP {
+            /* Resource templates: the end tag (2 bytes) of the first one is dropped */
+            let first = try!(self.to_buffer(first, h));
+            let second = try!(self.to_buffer(second, h));
+            let first = Slice {
+                start: first.start,
+                len:   first.len.saturating_sub(2),
+            };
+            return self.join(first, second).map(Value::Buffer);
+        }
+        match first {
+            Value::String(first) => {
+                let second = try!(self.to_string_bytes(second, h));
+                self.join(first, second).map(Value::String)
+            },
+            first => {
+                let first = try!(self.to_buffer(first, h));
+                let second = try!(self.to_buffer(second, h));
+                self.join(first, second).map(Value::Buffer)
+            }
+        }
+    }
+
+    /* Converts an integer or a buffer for ToDecimalString or ToHexString */
+    fn format(&mut self, hex: bool, value: Value, h: &Handler) -> Result<Value, Error> {
+        let mut text = [0; 64];
+        let length;
+        match self.element_value(value) {
+            Value::String(_) => return Ok(value),
+            Value::Buffer(slice) => {
+                /* Bytes separated by commas */
+                let mut pos = 0;
+                for i in 0..slice.len as usize {
+                    if pos + 6 > text.len() {
+                        break;
+                    }
+                    if i != 0 {
+                        text[pos] = b',';
+                        pos += 1;
+                    }
+                    pos += format_integer(self.bytes[slice.start as usize + i] as u64, hex, &mut text[pos..]);
+                }
+                length = pos;
+            },
+            value => {
+                let integer = try!(self.to_integer(value, h));
+                length = format_integer(integer, hex, &mut text);
+            }
+        }
+        self.new_string(&text[..length])
+    }
+
+    /* ObjectType codes of the specification */
+    fn object_type(&self, node: NodeId) -> u64 {
+        match self.nodes[node as usize].object {
+            Object::Name(value) => value_type(value),
+            Object::Field { .. } | Object::IndexField { .. } => 5,
+            Object::Device => 6,
+            Object::Event => 7,
+            Object::Method { .. } | Object::Osi => 8,
+            Object::Mutex { .. } => 9,
+            Object::Region { .. } => 10,
+            Object::PowerResource => 11,
+            Object::Processor => 12,
+            Object::ThermalZone => 13,
+            Object::BufferField { .. } => 14,
+            Object::Scope | Object::Alias(_) => 0,
+        }
+    }
+
+    /*
+     * Targets.
+     */
+
+    fn super_name(&mut self, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Target, Error> {
+        let op = try!(s.peek());
+        match op {
+            /* NullName */
+            0 => {
+                s.pos += 1;
+                Ok(Target::None)
+            },
+            _ if op >= LOCAL0_OP && op <= LOCAL7_OP => {
+                s.pos += 1;
+                Ok(Target::Local((op - LOCAL0_OP) as usize))
+            },
+            _ if op >= ARG0_OP && op <= ARG6_OP => {
+                s.pos += 1;
+                Ok(Target::Arg((op - ARG0_OP) as usize))
+            },
+            EXT_OP_PREFIX if try!(s.peek_at(1)) == EXT_DEBUG_OP => {
+                s.pos += 2;
+                Ok(Target::Debug)
+            },
+            _ if is_name_lead(op) => {
+                let path = try!(s.name_string());
+                self.resolve(&path, frame.scope).map(Target::Node)
+            },
+            _ => {
+                /* Index, DerefOf or RefOf returning a reference */
+                let value = try!(self.term_arg(s, frame, h));
+                match value {
+                    Value::Reference(node) => Ok(Target::Node(node)),
+                    Value::Element(index) => Ok(Target::Element(index)),
+                    Value::Byte(index) => Ok(Target::Byte(index)),
+                    _ => Err(Error::TypeMismatch)
+                }
+            }
+        }
+    }
+
+    fn read_target(&mut self, target: Target, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        match target {
+            Target::Local(index) => Ok(frame.locals[index]),
+            Target::Arg(index) => match frame.args[index] {
+                Value::Reference(node) => self.read_node(node, h),
+                value => Ok(value)
+            },
+            Target::Node(node) => self.read_node(node, h),
+            Target::Element(index) => Ok(self.values[index as usize]),
+            Target::Byte(index) => Ok(Value::Integer(self.bytes[index as usize] as u64)),
+            Target::None | Target::Debug => Ok(Value::Uninitialized)
+        }
+    }
+
+    fn store(&mut self, target: Target, value: Value, frame: &mut Frame, h: &Handler) -> Result<(), Error> {
+        match target {
+            Target::None | Target::Debug => Ok(()),
+            Target::Local(index) => {
+                frame.locals[index] = value;
+                Ok(())
+            },
+            /* Arguments passed by RefOf are stored through */
+            Target::Arg(index) => match frame.args[index] {
+                Value::Reference(node) => self.store_node(node, value, h),
+                _ => {
+                    frame.args[index] = value;
+                    Ok(())
+                }
+            },
+            Target::Node(node) => self.store_node(node, value, h),
+            Target::Element(index) => {
+                let value = self.element_value(value);
+                let value = if (index as usize) < self.values_low { try!(self.persist(value)) } else { value };
+                self.values[index as usize] = value;
+                Ok(())
+            },
+            Target::Byte(index) => {
+                let value = try!(self.to_integer(value, h));
+                self.bytes[index as usize] = value as u8;
+                Ok(())
+            }
+        }
+    }
+
+    fn store_result(&mut self, value: Value, s: &mut Stream, frame: &mut Frame, h: &Handler) -> Result<Value, Error> {
+        let target = try!(self.super_name(s, frame, h));
+        try!(self.store(target, value, frame, h));
+        Ok(value)
+    }
+
+    fn mutex_node(&self, target: Target, frame: &Frame) -> Result<NodeId, Error> {
+        let node = match target {
+            Target::Node(node) => node,
+            Target::Local(index) => match frame.locals[index] {
+                Value::Reference(node) => node,
+                _ => return Err(Error::TypeMismatch)
+            },
+            Target::Arg(index) => match frame.args[index] {
+                Value::Reference(node) => node,
+                _ => return Err(Error::TypeMismatch)
+            },
+            _ => return Err(Error::TypeMismatch)
+        };
+        match self.nodes[node as usize].object {
+            Object::Mutex { .. } => Ok(node),
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+
+    /*
+     * Fields.
+     */
+
+    fn read_field(&mut self, node: NodeId, bit_length: u32, h: &Handler) -> Result<Value, Error> {
+        if bit_length <= 64 {
+            return self.read_bits(node, 0, bit_length, h).map(Value::Integer);
+        }
+        /* Longer fields are read to buffers 64 bits at a time */
+        let permanent = self.loading;
+        let buffer = try!(self.alloc_bytes(((bit_length + 7) / 8) as usize, permanent));
+        let mut bit = 0;
+        while bit < bit_length {
+            let count = min(64, bit_length - bit);
+            let value = try!(self.read_bits(node, bit, count, h));
+            for i in 0..(count as usize + 7) / 8 {
+                self.bytes[buffer.start as usize + bit as usize / 8 + i] = (value >> (i * 8)) as u8;
+            }
+            bit += count;
+        }
+        Ok(Value::Buffer(buffer))
+    }
+
+    fn write_field(&mut self, node: NodeId, bit_length: u32, value: Value, h: &Handler) -> Result<(), Error> {
+        let value = self.element_value(value);
+        if bit_length <= 64 {
+            let value = match value {
+                Value::Integer(value) => value,
+                value => try!(self.to_integer(value, h))
+            };
+            return self.write_bits(node, 0, bit_length, value, h);
+        }
+        let bytes = try!(self.to_buffer(value, h));
+        let mut bit = 0;
+        while bit < bit_length {
+            let count = min(64, bit_length - bit);
+            let mut chunk = 0;
+            for i in 0..8 {
+                let index = bit as usize / 8 + i;
+                if index < bytes.len as usize {
+                    chunk |= (self.bytes[bytes.start as usize + index] as u64) << (i * 8);
+                }
+            }
+            try!(self.write_bits(node, bit, count, chunk, h));
+            bit += count;
+        }
+        Ok(())
+    }
+
+    /* Reads count (at most 64) bits of the field starting from its bit first */
+    fn read_bits(&mut self, node: NodeId, first: u32, count: u32, h: &Handler) -> Result<u64, Error> {
+        let object = self.nodes[node as usize].object;
+        let (bit_offset, flags) = match object {
+            Object::BufferField { bytes, bit_offset, .. } => {
+                let mut value = 0;
+                for i in 0..count {
+                    let bit = (bit_offset + first + i) as usize;
+                    if self.bytes[bytes.start as usize + bit / 8] & (1 << (bit % 8)) != 0 {
+                        value |= 1 << i;
+                    }
+                }
+                return Ok(value);
+            },
+            Object::Field { bit_offset, flags, .. } | Object::IndexField { bit_offset, flags, .. } => (bit_offset, flags),
+            _ => return Err(Error::TypeMismatch)
+        };
+
+        let width = access_width(flags);
+        let unit_bits = width as u32 * 8;
+        let begin = bit_offset + first;
+        let end = begin + count;
+        let mut value = 0;
+        let mut unit = begin / unit_bits;
+        while unit * unit_bits < end {
+            let raw = try!(self.read_unit(node, unit * width as u32, width, h));
+            let unit_begin = unit * unit_bits;
+            let low = max(begin, unit_begin);
+            let high = min(end, unit_begin + unit_bits);
+            value |= ((raw >> (low - unit_begin)) & bit_mask(high - low)) << (low - begin);
+            unit += 1;
+        }
+        Ok(value)
+    }
+
+    fn write_bits(&mut self, node: NodeId, first: u32, count: u32, value: u64, h: &Handler) -> Result<(), Error> {
+        let object = self.nodes[node as usize].object;
+        let (bit_offset, flags) = match object {
+            Object::BufferField { bytes, bit_offset, .. } => {
+                for i in 0..count {
+                    let bit = (bit_offset + first + i) as usize;
+                    let byte = &mut self.bytes[bytes.start as usize + bit / 8];
+                    if value & (1 << i) != 0 {
+                        *byte |= 1 << (bit % 8);
+                    } else {
+                        *byte &= !(1 << (bit % 8));
+                    }
+                }
+                return Ok(());
+            },
+            Object::Field { bit_offset, flags, .. } | Object::IndexField { bit_offset, flags, .. } => (bit_offset, flags),
+            _ => return Err(Error::TypeMismatch)
+        };
+
+        let width = access_width(flags);
+        let unit_bits = width as u32 * 8;
+        let begin = bit_offset + first;
+        let end = begin + count;
+        let mut unit = begin / unit_bits;
+        while unit * unit_bits < end {
+            let unit_begin = unit * unit_bits;
+            let low = max(begin, unit_begin);
+            let high = min(end, unit_begin + unit_bits);
+            let mask = bit_mask(high - low) << (low - unit_begin);
+            /* The update rule tells what to write to bits of the unit outside of the field */
+            let raw = if high - low == unit_bits {
+                0
+            } else {
+                match flags & FIELD_UPDATE_MASK {
+                    FIELD_UPDATE_WRITE_AS_ONES => !0,
+                    FIELD_UPDATE_WRITE_AS_ZEROS => 0,
+                    _ => try!(self.read_unit(node, unit * width as u32, width, h))
+                }
+            };
+            let raw = (raw & !mask) | ((value >> (low - begin)) << (low - unit_begin) & mask);
+            try!(self.write_unit(node, unit * width as u32, width, raw, h));
+            unit += 1;
+        }
+        Ok(())
+    }
+
+    /* Reads an access unit at the byte offset of the field's region */
+    fn read_unit(&mut self, node: NodeId, offset: u32, width: u8, h: &Handler) -> Result<u64, Error> {
+        let object = self.nodes[node as usize].object;
+        match object {
+            Object::Field { region, .. } => {
+                let address = try!(self.region_address(region, offset as u64, h));
+                h.read(address, width).ok_or(Error::RegionAccess)
+            },
+            Object::IndexField { index, data, .. } => {
+                try!(self.store_node(index, Value::Integer(offset as u64), h));
+                let value = try!(self.read_node(data, h));
+                self.to_integer(value, h)
+            },
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+
+    fn write_unit(&mut self, node: NodeId, offset: u32, width: u8, value: u64, h: &Handler) -> Result<(), Error> {
+        let object = self.nodes[node as usize].object;
+        match object {
+            Object::Field { region, .. } => {
+                let address = try!(self.region_address(region, offset as u64, h));
+                if h.write(address, width, value) {
+                    Ok(())
+                } else {
+                    Err(Error::RegionAccess)
+                }
+            },
+            Object::IndexField { index, data, .. } => {
+                try!(self.store_node(index, Value::Integer(offset as u64), h));
+                self.store_node(data, Value::Integer(value), h)
+            },
+            _ => Err(Error::TypeMismatch)
+        }
+    }
+
+    fn region_address(&mut self, region: NodeId, offset: u64, h: &Handler) -> Result<Address, Error> {
+        let (space, base) = match self.nodes[region as usize].object {
+            Object::Region { space, offset: base, length } => {
+                if offset >= length {
+                    return Err(Error::IndexOutOfRange);
+                }
+                (space, base)
+            },
+            _ => return Err(Error::TypeMismatch)
+        };
+        let addr = base + offset;
+        match space {
+            SPACE_SYSTEM_MEMORY => Ok(Address::Memory(addr)),
+            SPACE_SYSTEM_IO => Ok(Address::Io(addr as u16)),
+            SPACE_PCI_CONFIG => {
+                let (segment, bus, device, function) = try!(self.pci_device(region, h));
+                Ok(Address::PciConfig {
+                    segment:  segment,
+                    bus:      bus,
+                    device:   device,
+                    function: function,
+                    offset:   addr as u16,
+                })
+            },
+            _ => Err(Error::UnsupportedSpace(space))
+        }
+    }
+
+    /* PCI device of a region: _ADR of the device it is defined in, _SEG and _BBN
+     * of the host bridge above. Devices behind PCI bridges are not supported. */
+    fn pci_device(&mut self, region: NodeId, h: &Handler) -> Result<(u16, u8, u8, u8), Error> {
+        let mut address = None;
+        let mut segment = 0;
+        let mut bus = 0;
+        let mut node = self.parent(region);
+        while let Some(current) = node {
+            if address.is_none() {
+                if let Some(adr) = self.child(current, b"_ADR") {
+                    address = Some(try!(self.read_integer_node(adr, h)));
+                }
+            } else {
+                if let Some(bbn) = self.child(current, b"_BBN") {
+                    bus = try!(self.read_integer_node(bbn, h));
+                }
+                if let Some(seg) = self.child(current, b"_SEG") {
+                    segment = try!(self.read_integer_node(seg, h));
+                }
+            }
+            node = self.parent(current);
+        }
+        let address = try!(address.ok_or(Error::NotFound));
+        Ok((segment as u16, bus as u8, (address >> 16) as u8, address as u8))
+    }
+
+    fn read_integer_node(&mut self, node: NodeId, h: &Handler) -> Result<u64, Error> {
+        let value = try!(self.read_node(node, h));
+        self.to_integer(value, h)
+    }
+}
+
+fn value_type(value: Value) -> u64 {
+    match value {
+        Value::Uninitialized => 0,
+        Value::Integer(_) => 1,
+        Value::String(_) => 2,
+        Value::Buffer(_) => 3,
+        Value::Package(_) => 4,
+        Value::Reference(_) | Value::Element(_) | Value::Byte(_) => 20,
+    }
+}
+
+fn access_width(flags: u8) -> u8 {
+    match flags & FIELD_ACCESS_MASK {
+        FIELD_ACCESS_WORD => 2,
+        FIELD_ACCESS_DWORD => 4,
+        FIELD_ACCESS_QWORD => 8,
+        /* Any, byte and buffer accesses */
+        _ => 1
+    }
+}
+
+fn bit_mask(bits: u32) -> u64 {
+    if bits >= 64 { !0 } else { (1 << bits) - 1 }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789ABCDEF"[(value & 0xF) as usize]
+}
+
+/* Parses digits stopping at the first invalid one, the 0x prefix makes the radix 16 */
+fn parse_integer(bytes: &[u8], radix: u64) -> u64 {
+    let (bytes, radix) = if bytes.len() > 2 && bytes[0] == b'0' && (bytes[1] == b'x' || bytes[1] == b'X') {
+        (&bytes[2..], 16)
+    } else {
+        (bytes, radix)
+    };
+    let mut value: u64 = 0;
+    for byte in bytes.iter().skip_while(|byte| **byte == b' ') {
+        let digit = match *byte {
+            b'0'...b'9' => byte - b'0',
+            b'a'...b'f' => byte - b'a' + 10,
+            b'A'...b'F' => byte - b'A' + 10,
+            _ => break
+        } as u64;
+        if digit >= radix {
+            break;
+        }
+        value = value.wrapping_mul(radix).wrapping_add(digit);
+    }
+    value
+}
+
+/* Writes the integer in hexadecimal with the 0x prefix or in decimal. Returns the length */
+fn format_integer(value: u64, hex: bool, text: &mut [u8]) -> usize {
+    let mut digits = [0; 20];
+    let mut count = 0;
+    let mut rest = value;
+    let radix = if hex { 16 } else { 10 };
+    loop {
+        digits[count] = hex_digit((rest % radix) as u8);
+        count += 1;
+        rest /= radix;
+        if rest == 0 {
+            break;
+        }
+    }
+    let mut pos = 0;
+    if hex {
+        text[0] = b'0';
+        text[1] = b'x';
+        pos = 2;
+    }
+    for digit in digits[..count].iter().rev() {
+        text[pos] = *digit;
+        pos += 1;
+    }
+    pos
+}
+
+fn from_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut multiplier = 1;
+    for i in 0..16 {
+        result += ((value >> (i * 4)) & 0xF) * multiplier;
+        multiplier *= 10;
+    }
+    result
+}
+
+fn to_bcd(value: u64) -> u64 {
+    let mut result = 0;
+    let mut rest = value;
+    for i in 0..16 {
+        result |= (rest % 10) << (i * 4);
+        rest /= 10;
+    }
+    result
+}
+
+/*
+ * Self-test on a table assembled from:
+ *
+ * DefinitionBlock ("", "SSDT", 2, "", "", 0)
+ * {
+ *     Name (INT1, 0x10)
+ *     Name (STR1, "ab")
+ *     Name (PKG1, Package (0x03) { One, "x", DEV1 })
+ *     OperationRegion (TREG, SystemMemory, 0x1000, 0x04)
+ *     Field (TREG, ByteAcc, NoLock, Preserve) { TF0, 4, TF1, 12, , 8, TF2, 8 }
+ *     Mutex (TMTX, 0)
+ *     Device (DEV1)
+ *     {
+ *         Name (_ADR, 0x00010002)
+ *         Method (_STA) { Return (0x0F) }
+ *         Method (_INI) { Increment (\INT1) }
+ *     }
+ *     Method (ADD2, 2) { Return (Add (Arg0, Arg1)) }
+ *     Method (LOOP, 1)
+ *     {
+ *         Store (Zero, Local0)
+ *         Store (Zero, Local1)
+ *         While (LLess (Local0, Arg0))
+ *         {
+ *             Add (Local1, Local0, Local1)
+ *             Increment (Local0)
+ *             If (LEqual (Local0, 0x05)) { Break }
+ *         }
+ *         Return (Local1)
+ *     }
+ *     Method (FLDS)
+ *     {
+ *         Acquire (TMTX, 0xFFFF)
+ *         Store (0x0ABC, TF1)
+ *         Store (0x5A, TF2)
+ *         Release (TMTX)
+ *         Return (TF1)
+ *     }
+ *     Method (OSIT)
+ *     {
+ *         If (_OSI ("Windows 2009")) { If (LNot (_OSI ("Linux"))) { Return (One) } }
+ *         Return (Zero)
+ *     }
+ *     Method (BUFT)
+ *     {
+ *         Name (BUF0, Buffer (0x04) { 0x01, 0x02, 0x03, 0x04 })
+ *         CreateWordField (BUF0, One, WRD0)
+ *         Return (WRD0)
+ *     }
+ *     Method (PKGT)
+ *     {
+ *         Store (0x55, Index (PKG1, Zero))
+ *         Return (DerefOf (Index (PKG1, Zero)))
+ *     }
+ *     Method (STRT) { Return (Concatenate (STR1, "cd")) }
+ * }
+ */
+static TEST_AML: [u8; 326] = [
+    0x08, 0x49, 0x4E, 0x54, 0x31, 0x0A, 0x10, 0x08, 0x53, 0x54, 0x52, 0x31, 0x0D, 0x61, 0x62, 0x00,
+    0x08, 0x50, 0x4B, 0x47, 0x31, 0x12, 0x0A, 0x03, 0x01, 0x0D, 0x78, 0x00, 0x44, 0x45, 0x56, 0x31,
+    0x5B, 0x80, 0x54, 0x52, 0x45, 0x47, 0x00, 0x0B, 0x00, 0x10, 0x0A, 0x04, 0x5B, 0x81, 0x17, 0x54,
+    0x52, 0x45, 0x47, 0x01, 0x54, 0x46, 0x30, 0x5F, 0x04, 0x54, 0x46, 0x31, 0x5F, 0x0C, 0x00, 0x08,
+    0x54, 0x46, 0x32, 0x5F, 0x08, 0x5B, 0x01, 0x54, 0x4D, 0x54, 0x58, 0x00, 0x5B, 0x82, 0x26, 0x44,
+    0x45, 0x56, 0x31, 0x08, 0x5F, 0x41, 0x44, 0x52, 0x0C, 0x02, 0x00, 0x01, 0x00, 0x14, 0x09, 0x5F,
+    0x53, 0x54, 0x41, 0x00, 0xA4, 0x0A, 0x0F, 0x14, 0x0C, 0x5F, 0x49, 0x4E, 0x49, 0x00, 0x75, 0x5C,
+    0x49, 0x4E, 0x54, 0x31, 0x14, 0x0B, 0x41, 0x44, 0x44, 0x32, 0x02, 0xA4, 0x72, 0x68, 0x69, 0x00,
+    0x14, 0x20, 0x4C, 0x4F, 0x4F, 0x50, 0x01, 0x70, 0x00, 0x60, 0x70, 0x00, 0x61, 0xA2, 0x11, 0x95,
+    0x60, 0x68, 0x72, 0x61, 0x60, 0x61, 0x75, 0x60, 0xA0, 0x06, 0x93, 0x60, 0x0A, 0x05, 0xA5, 0xA4,
+    0x61, 0x14, 0x28, 0x46, 0x4C, 0x44, 0x53, 0x00, 0x5B, 0x23, 0x54, 0x4D, 0x54, 0x58, 0xFF, 0xFF,
+    0x70, 0x0B, 0xBC, 0x0A, 0x54, 0x46, 0x31, 0x5F, 0x70, 0x0A, 0x5A, 0x54, 0x46, 0x32, 0x5F, 0x5B,
+    0x27, 0x54, 0x4D, 0x54, 0x58, 0xA4, 0x54, 0x46, 0x31, 0x5F, 0x14, 0x2C, 0x4F, 0x53, 0x49, 0x54,
+    0x00, 0xA0, 0x23, 0x5F, 0x4F, 0x53, 0x49, 0x0D, 0x57, 0x69, 0x6E, 0x64, 0x6F, 0x77, 0x73, 0x20,
+    0x32, 0x30, 0x30, 0x39, 0x00, 0xA0, 0x0F, 0x92, 0x5F, 0x4F, 0x53, 0x49, 0x0D, 0x4C, 0x69, 0x6E,
+    0x75, 0x78, 0x00, 0xA4, 0x01, 0xA4, 0x00, 0x14, 0x22, 0x42, 0x55, 0x46, 0x54, 0x00, 0x08, 0x42,
+    0x55, 0x46, 0x30, 0x11, 0x07, 0x0A, 0x04, 0x01, 0x02, 0x03, 0x04, 0x8B, 0x42, 0x55, 0x46, 0x30,
+    0x01, 0x57, 0x52, 0x44, 0x30, 0xA4, 0x57, 0x52, 0x44, 0x30, 0x14, 0x19, 0x50, 0x4B, 0x47, 0x54,
+    0x00, 0x70, 0x0A, 0x55, 0x88, 0x50, 0x4B, 0x47, 0x31, 0x00, 0x00, 0xA4, 0x83, 0x88, 0x50, 0x4B,
+    0x47, 0x31, 0x00, 0x00, 0x14, 0x11, 0x53, 0x54, 0x52, 0x54, 0x00, 0xA4, 0x73, 0x53, 0x54, 0x52,
+    0x31, 0x0D, 0x63, 0x64, 0x00, 0x00
+];
+
+const TEST_REGION_ADDR: u64 = 0x1000;
+
+/* Emulates the 4 bytes of the test region */
+struct TestHandler {
+    registers: Cell<[u8; 4]>,
+}
+
+impl Handler for TestHandler {
+    fn read(&self, address: Address, width: u8) -> Option<u64> {
+        match address {
+            Address::Memory(addr) if addr >= TEST_REGION_ADDR && addr + width as u64 <= TEST_REGION_ADDR + 4 => {
+                let offset = (addr - TEST_REGION_ADDR) as usize;
+                let registers = self.registers.get();
+                Some(registers[offset..offset + width as usize].iter().rev()
+                    .fold(0, |value, byte| value << 8 | *byte as u64))
+            },
+            _ => None
+        }
+    }
+
+    fn write(&self, address: Address, width: u8, value: u64) -> bool {
+        match address {
+            Address::Memory(addr) if addr >= TEST_REGION_ADDR && addr + width as u64 <= TEST_REGION_ADDR + 4 => {
+                let offset = (addr - TEST_REGION_ADDR) as usize;
+                let mut registers = self.registers.get();
+                for i in 0..width as usize {
+                    registers[offset + i] = (value >> (i * 8)) as u8;
+                }
+                self.registers.set(registers);
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn sleep(&self, _milliseconds: u64) {
+    }
+
+    fn stall(&self, _microseconds: u64) {
+    }
+}
+
+/* Loads the test table into the namespace, which is empty afterwards */
+pub fn aml_test(namespace: &mut Namespace) {
+    let handler = TestHandler {
+        registers: Cell::new([0x05, 0, 0, 0]),
+    };
+    namespace.reset();
+    assert_eq!(Ok(()), namespace.load(&TEST_AML, 2, &handler));
+    assert_eq!((1, 0), namespace.initialize_devices(&handler));
+    assert_eq!(Ok(Value::Integer(0x11)), namespace.evaluate("INT1", &[], &handler));
+
+    /* The reference to DEV1 is resolved after the table is loaded */
+    let package = namespace.evaluate("\\PKG1", &[], &handler).unwrap();
+    assert_eq!(Some(3), namespace.package_len(package));
+    assert_eq!(Some(&b"x"[..]), namespace.package_element(package, 1).and_then(|x| namespace.bytes(x)));
+    assert_eq!(namespace.find("\\DEV1").map(Value::Reference), namespace.package_element(package, 2));
+
+    assert_eq!(Ok(Value::Integer(7)), namespace.evaluate("ADD2", &[Value::Integer(3), Value::Integer(4)], &handler));
+    assert_eq!(Ok(Value::Integer(10)), namespace.evaluate("LOOP", &[Value::Integer(10)], &handler));
+    assert_eq!(Ok(Value::Integer(3)), namespace.evaluate("LOOP", &[Value::Integer(3)], &handler));
+    assert_eq!(Err(Error::TypeMismatch), namespace.evaluate("LOOP", &[], &handler));
+
+    /* TF0 is preserved */
+    assert_eq!(Ok(Value::Integer(0xABC)), namespace.evaluate("FLDS", &[], &handler));
+    assert_eq!([0xC5, 0xAB, 0, 0x5A], handler.registers.get());
+
+    assert_eq!(Ok(Value::Integer(1)), namespace.evaluate("OSIT", &[], &handler));
+    assert_eq!(Ok(Value::Integer(0x0302)), namespace.evaluate("BUFT", &[], &handler));
+    assert_eq!(Ok(Value::Integer(0x55)), namespace.evaluate("PKGT", &[], &handler));
+    let string = namespace.evaluate("STRT", &[], &handler).unwrap();
+    assert_eq!(Some(&b"abcd"[..]), namespace.bytes(string));
+
+    /* Objects created by methods are gone */
+    assert!(namespace.find("BUFT.BUF0").is_none());
+    assert_eq!(Err(Error::NotFound), namespace.evaluate("\\_SB.NONE", &[], &handler));
+    namespace.reset();
+    assert!(namespace.find("\\").is_none());
+}
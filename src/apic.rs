@@ -0,0 +1,359 @@
+/*
+ * Local APIC.
+ *
+ * Every CPU has a local APIC receiving interrupts from I/O APICs and other CPUs.
+ * Its registers are accessed through memory (xAPIC mode) or, if the CPU supports
+ * it, through MSRs (x2APIC mode) which is preferred then. Register offsets are
+ * given for the xAPIC mode, the MSR of a register is IA32_X2APIC_BASE + offset / 16.
+ * The mode is chosen once by the bootstrap processor, others follow it.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use acpi::{self, Madt, MadtEntry};
+use cpuid;
+use idt;
+use memory::PAGE_SIZE;
+use msr::{self, rdmsr, wrmsr};
+use paging;
+use params::{Param, Value};
+
+kernel_param! {
+    pub static NOAPIC: Param = Param::new("noapic", Value::Bool(false),
+        "Use the legacy PIC instead of the APIC");
+
+    pub static NOX2APIC: Param = Param::new("nox2apic", Value::Bool(false),
+        "Use the xAPIC mode even if x2APIC is supported");
+}
+
+/* Spurious interrupts are sent to this vector, the entry is in traps.S */
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+/* Vector of the timer interrupt, above IRQs for a higher priority.
+ * Should be synchronized with APIC_TIMER_VECTOR in kernel.h */
+pub const TIMER_VECTOR: u8 = 0xF0;
+
+/* Vector of TLB shootdown IPIs, see paging::invalidate_page_everywhere().
+ * Should be synchronized with TLB_SHOOTDOWN_VECTOR in kernel.h */
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xF1;
+
+/*
+ * Bits of the IA32_APIC_BASE MSR.
+ */
+
+const APIC_BASE_X2APIC: u64 =    1 << 10;
+const APIC_BASE_ENABLE: u64 =    1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000FFFFFFFFFF000;
+
+/*
+ * Registers.
+ */
+
+const REG_ID: u32 =         0x20;
+const REG_VERSION: u32 =    0x30;
+const REG_TPR: u32 =        0x80;
+const REG_EOI: u32 =        0xB0;
+const REG_SVR: u32 =        0xF0;
+const REG_ESR: u32 =        0x280;
+const REG_ICR_LOW: u32 =    0x300;
+const REG_ICR_HIGH: u32 =   0x310;
+const REG_LVT_TIMER: u32 =  0x320;
+const REG_LVT_LINT0: u32 =  0x350;
+const REG_LVT_LINT1: u32 =  0x360;
+const REG_LVT_ERROR: u32 =  0x370;
+const REG_TIMER_INITIAL: u32 = 0x380;
+const REG_TIMER_CURRENT: u32 = 0x390;
+const REG_TIMER_DIVIDE: u32 =  0x3E0;
+
+/* Software enable bit of the spurious interrupt vector register */
+const SVR_ENABLE: u32 = 1 << 8;
+
+/*
+ * Bits of the interrupt command register.
+ */
+
+const ICR_FIXED: u32 =        0 << 8;
+const ICR_INIT: u32 =         5 << 8;
+const ICR_STARTUP: u32 =      6 << 8;
+const ICR_PENDING: u32 =      1 << 12;
+const ICR_ASSERT: u32 =       1 << 14;
+const ICR_LEVEL: u32 =        1 << 15;
+
+/*
+ * Bits of local vector table entries.
+ */
+
+const LVT_NMI: u32 =        4 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 =      1 << 15;
+const LVT_MASKED: u32 =     1 << 16;
+const LVT_TIMER_ONE_SHOT: u32 =    0 << 17;
+const LVT_TIMER_PERIODIC: u32 =    1 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 2 << 17;
+
+/* The timer counts at the bus frequency divided by 16 */
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Disabled,
+    XApic,
+    X2Apic,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerMode {
+    /* Fires once when the count set by arm_timer() reaches zero */
+    OneShot,
+    /* Reloads the count set by arm_timer() and fires every time */
+    Periodic,
+    /* Fires once when the TSC reaches the value set by arm_timer_deadline() */
+    TscDeadline,
+}
+
+/* Kept in atomics instead of a mutex as eoi() is called from interrupt handlers */
+static MODE: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Physical and virtual addresses of the registers in the xAPIC mode */
+static PHYS_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+static MMIO_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern {
+    /* From traps.S */
+    fn spurious_interrupt();
+    fn apic_timer_interrupt();
+    fn tlb_shootdown_interrupt();
+}
+
+/* Enables the local APIC of the bootstrap processor. Returns false if it is unusable */
+pub fn init(madt: &Madt) -> bool {
+    if NOAPIC.get_bool() {
+        return false;
+    }
+    let cpu_info = cpuid::get_cpu_info();
+    if cpu_info.features1 & cpuid::CPU_FEAT1_APIC == 0 || cpu_info.features1 & cpuid::CPU_FEAT1_MSR == 0 {
+        return false;
+    }
+
+    if cpu_info.features2 & cpuid::CPU_FEAT2_X2APIC != 0 && !NOX2APIC.get_bool() {
+        MODE.store(Mode::X2Apic as usize, Ordering::SeqCst);
+    } else {
+        let mut phys_addr = madt.local_apic_addr();
+        if phys_addr == 0 {
+            let base = unsafe { rdmsr(msr::IA32_APIC_BASE) };
+            phys_addr = (base & APIC_BASE_ADDR_MASK) as usize;
+        }
+        PHYS_BASE.store(phys_addr, Ordering::SeqCst);
+        MMIO_BASE.store(paging::map_mmio(phys_addr, PAGE_SIZE), Ordering::SeqCst);
+        MODE.store(Mode::XApic as usize, Ordering::SeqCst);
+    }
+    enable();
+
+    idt::set_gate(SPURIOUS_VECTOR, spurious_interrupt as usize, 0, false);
+    idt::set_gate(TIMER_VECTOR, apic_timer_interrupt as usize, 0, false);
+    idt::set_gate(TLB_SHOOTDOWN_VECTOR, tlb_shootdown_interrupt as usize, 0, false);
+    setup_local(madt);
+    true
+}
+
+/* Enables the local APIC of an application processor in the mode chosen by init() */
+pub fn init_ap() -> bool {
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return false
+    };
+    if mode() == Mode::Disabled {
+        return false;
+    }
+    enable();
+    setup_local(&madt);
+    true
+}
+
+/* Switches the local APIC of the current processor to the mode */
+fn enable() {
+    unsafe {
+        let base = rdmsr(msr::IA32_APIC_BASE);
+        match mode() {
+            Mode::X2Apic => {
+                /* x2APIC can only be entered from the enabled xAPIC mode */
+                wrmsr(msr::IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+                wrmsr(msr::IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+            },
+            _ => {
+                let phys_addr = PHYS_BASE.load(Ordering::SeqCst) as u64;
+                wrmsr(msr::IA32_APIC_BASE, (base & !APIC_BASE_ADDR_MASK) | phys_addr | APIC_BASE_ENABLE);
+            }
+        }
+    }
+}
+
+/* Programs registers of the current processor's local APIC */
+fn setup_local(madt: &Madt) {
+    /* Accept all priorities, mask local interrupts until someone needs them */
+    write(REG_TPR, 0);
+    write(REG_LVT_TIMER, LVT_MASKED);
+    write(REG_LVT_LINT0, LVT_MASKED);
+    write(REG_LVT_LINT1, LVT_MASKED);
+    write(REG_LVT_ERROR, LVT_MASKED);
+    set_nmi_lines(madt);
+
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    /* The error status is updated by writes */
+    write(REG_ESR, 0);
+    write(REG_ESR, 0);
+    eoi();
+}
+
+/* Configures LINT pins the MADT reports as connected to NMI for this processor */
+fn set_nmi_lines(madt: &Madt) {
+    let apic_id = id();
+    let processor_id = madt.entries().filter_map(|entry| match entry {
+        MadtEntry::LocalApic { processor_id, apic_id: id, .. } if id == apic_id => Some(processor_id),
+        _ => None
+    }).next();
+
+    for entry in madt.entries() {
+        if let MadtEntry::LocalApicNmi { processor_id: target, lint, flags } = entry {
+            if target != acpi::MADT_ALL_PROCESSORS && Some(target) != processor_id {
+                continue;
+            }
+            let mut lvt = LVT_NMI;
+            if acpi::is_active_low(flags) {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if acpi::is_level_triggered(flags) {
+                lvt |= LVT_LEVEL;
+            }
+            match lint {
+                0 => write(REG_LVT_LINT0, lvt),
+                1 => write(REG_LVT_LINT1, lvt),
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::SeqCst) {
+        1 => Mode::XApic,
+        2 => Mode::X2Apic,
+        _ => Mode::Disabled
+    }
+}
+
+/* APIC id of the current processor */
+pub fn id() -> u32 {
+    match mode() {
+        Mode::X2Apic => read(REG_ID),
+        _ => read(REG_ID) >> 24
+    }
+}
+
+pub fn version() -> u32 {
+    read(REG_VERSION) & 0xFF
+}
+
+/* Signals the end of the interrupt being serviced */
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/* Makes the timer count down from the maximum without interrupts, the speed
+ * is measured by reading timer_count() against a known clock */
+pub fn start_timer_calibration() {
+    write(REG_LVT_TIMER, LVT_MASKED | LVT_TIMER_ONE_SHOT | TIMER_VECTOR as u32);
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_TIMER_INITIAL, 0xFFFFFFFF);
+}
+
+/* Current count of the timer */
+pub fn timer_count() -> u32 {
+    read(REG_TIMER_CURRENT)
+}
+
+/* Unmasks the timer interrupt in the mode, the timer starts with arm_timer*() */
+pub fn set_timer_mode(mode: TimerMode) {
+    let lvt = match mode {
+        TimerMode::OneShot => LVT_TIMER_ONE_SHOT,
+        TimerMode::Periodic => LVT_TIMER_PERIODIC,
+        TimerMode::TscDeadline => LVT_TIMER_TSC_DEADLINE
+    };
+    write(REG_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(REG_LVT_TIMER, lvt | TIMER_VECTOR as u32);
+    if mode == TimerMode::TscDeadline {
+        /* The MSR write must not pass the LVT write in the xAPIC mode */
+        unsafe {
+            asm!("mfence" : : : "memory" : "volatile");
+        }
+    }
+}
+
+/* Starts the timer in the one-shot or periodic mode, 0 stops it */
+pub fn arm_timer(count: u32) {
+    write(REG_TIMER_INITIAL, count);
+}
+
+/* Sets the TSC value the timer fires at in the TSC-deadline mode, 0 disarms it */
+pub fn arm_timer_deadline(tsc: u64) {
+    unsafe {
+        wrmsr(msr::IA32_TSC_DEADLINE, tsc);
+    }
+}
+
+pub fn stop_timer() {
+    write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, 0);
+}
+
+/* Resets the processor with the APIC id into the wait-for-SIPI state */
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT | ICR_LEVEL);
+}
+
+/* Starts the processor waiting for SIPI in real mode at page * 4Kb */
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/* Raises the interrupt vector on the processor with the APIC id */
+pub fn send_fixed(apic_id: u32, vector: u8) {
+    send_ipi(apic_id, ICR_FIXED | ICR_ASSERT | vector as u32);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    match mode() {
+        /* The whole ICR is one MSR with the destination in the high half */
+        Mode::X2Apic => unsafe {
+            wrmsr(msr::IA32_X2APIC_BASE + REG_ICR_LOW / 16, ((apic_id as u64) << 32) | command as u64);
+        },
+        _ => {
+            write(REG_ICR_HIGH, apic_id << 24);
+            write(REG_ICR_LOW, command);
+            while read(REG_ICR_LOW) & ICR_PENDING != 0 {
+                unsafe {
+                    asm!("pause" : : : "memory" : "volatile");
+                }
+            }
+        }
+    }
+}
+
+fn read(reg: u32) -> u32 {
+    match mode() {
+        Mode::X2Apic => unsafe { rdmsr(msr::IA32_X2APIC_BASE + reg / 16) as u32 },
+        Mode::XApic => unsafe {
+            ::core::ptr::read_volatile((MMIO_BASE.load(Ordering::SeqCst) + reg as usize) as *const u32)
+        },
+        Mode::Disabled => panic!("Local APIC is not enabled")
+    }
+}
+
+fn write(reg: u32, value: u32) {
+    match mode() {
+        Mode::X2Apic => unsafe { wrmsr(msr::IA32_X2APIC_BASE + reg / 16, value as u64) },
+        Mode::XApic => unsafe {
+            ::core::ptr::write_volatile((MMIO_BASE.load(Ordering::SeqCst) + reg as usize) as *mut u32, value)
+        },
+        Mode::Disabled => panic!("Local APIC is not enabled")
+    }
+}
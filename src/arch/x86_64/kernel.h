@@ -1,8 +1,9 @@
 #ifndef __KERNEL_H__
 #define __KERNEL_H__ 1
 
-// The kernel is linked to this virtual address.
-// linker.ld should be updated accordingly if changed.
+// The kernel is linked to this virtual address, the bootstrap moves it
+// up by a random offset (KASLR). linker.ld and layout.rs should be updated
+// accordingly if changed, tools/relocs.py reads the value from the kernel.
 #define KERNEL_VIRTUAL_BASE 0xFFFFFFFF80000000
 
 // Size of the kernel stack
@@ -10,7 +11,27 @@
 
 #define GDT_ENTRIES_COUNT 3
 
-#define IDT_ENTRIES_COUNT 0x30
+#define IDT_ENTRIES_COUNT 0x100
+
+// Vector of IRQ 0, the 16 PIC lines are remapped to IRQ_BASE_VECTOR and above
+#define IRQ_BASE_VECTOR 0x20
+
+// Vector of the local APIC timer, see apic.rs
+#define APIC_TIMER_VECTOR 0xF0
+
+// Vector of TLB shootdown requests between processors, see apic.rs
+#define TLB_SHOOTDOWN_VECTOR 0xF1
+
+// Vector of the int 0x80 system call gate, see syscall.rs
+#define SYSCALL_VECTOR 0x80
+
+// User mode segment selectors, see gdt.rs
+#define USER_DATA_SELECTOR (0x18 | 3)
+#define USER_CODE_SELECTOR (0x20 | 3)
+
+// Offsets of fields in the per-CPU area reached through %gs, see percpu.rs
+#define PERCPU_KERNEL_STACK 0x20
+#define PERCPU_USER_RSP     0x28
 
 // Returns physical address of a pointer located in the higher half
 #define PHYS_ADDR(x) (x - KERNEL_VIRTUAL_BASE)
@@ -164,8 +185,34 @@ extern void trap_17 ( void );
 extern void trap_18 ( void );
 extern void trap_19 ( void );
 extern void trap_20 ( void );
-
-extern void interrupt_entry ( void );
+extern void trap_21 ( void );
+extern void trap_22 ( void );
+extern void trap_23 ( void );
+extern void trap_24 ( void );
+extern void trap_25 ( void );
+extern void trap_26 ( void );
+extern void trap_27 ( void );
+extern void trap_28 ( void );
+extern void trap_29 ( void );
+extern void trap_30 ( void );
+extern void trap_31 ( void );
+
+extern void irq_0 ( void );
+extern void irq_1 ( void );
+extern void irq_2 ( void );
+extern void irq_3 ( void );
+extern void irq_4 ( void );
+extern void irq_5 ( void );
+extern void irq_6 ( void );
+extern void irq_7 ( void );
+extern void irq_8 ( void );
+extern void irq_9 ( void );
+extern void irq_10 ( void );
+extern void irq_11 ( void );
+extern void irq_12 ( void );
+extern void irq_13 ( void );
+extern void irq_14 ( void );
+extern void irq_15 ( void );
 
 void kprint(const char *, ...);
 
@@ -0,0 +1,25 @@
+#ifndef MULTIBOOT2_HEADER
+#define MULTIBOOT2_HEADER 1
+
+/* Multiboot2 definitions used by the bootstrap (see the Multiboot2 specification). */
+
+/* The magic field of the header should contain this. */
+#define MULTIBOOT2_HEADER_MAGIC                 0xe85250d6
+
+/* This should be in %eax when the kernel is started. */
+#define MULTIBOOT2_BOOTLOADER_MAGIC             0x36d76289
+
+/* Header tags are aligned on this boundary. */
+#define MULTIBOOT2_TAG_ALIGN                    8
+
+#define MULTIBOOT2_ARCHITECTURE_I386            0
+
+#define MULTIBOOT2_HEADER_TAG_END               0
+#define MULTIBOOT2_HEADER_TAG_INFORMATION_REQUEST 1
+#define MULTIBOOT2_HEADER_TAG_ADDRESS           2
+#define MULTIBOOT2_HEADER_TAG_ENTRY_ADDRESS     3
+
+/* Boot information tags */
+#define MULTIBOOT2_TAG_TYPE_CMDLINE             1
+
+#endif /* MULTIBOOT2_HEADER */
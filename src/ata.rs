@@ -0,0 +1,201 @@
+/*
+ * ATA disks on the legacy IDE controller, PIO mode with 28-bit LBA.
+ */
+
+use spin::Mutex;
+
+use block::{BlockDevice, BlockError, SECTOR_SIZE};
+use port::{inb, outb, inw, outw};
+
+/* Register offsets from the I/O base */
+const REG_DATA: u16          = 0;
+const REG_SECTOR_COUNT: u16  = 2;
+const REG_LBA_LOW: u16       = 3;
+const REG_LBA_MID: u16       = 4;
+const REG_LBA_HIGH: u16      = 5;
+const REG_DRIVE: u16         = 6;
+const REG_STATUS: u16        = 7;
+const REG_COMMAND: u16       = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8  = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+const CMD_READ_SECTORS: u8  = 0x20;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_CACHE_FLUSH: u8   = 0xE7;
+const CMD_IDENTIFY: u8      = 0xEC;
+
+/* Largest address reachable with 28-bit LBA */
+const LBA28_LIMIT: u64 = 1 << 28;
+
+pub static PRIMARY_MASTER: AtaDrive = AtaDrive {
+    name:     "hda",
+    io_base:  0x1F0,
+    ctrl:     0x3F6,
+    slave:    false,
+    sectors:  Mutex::new(None),
+};
+
+pub struct AtaDrive {
+    name:    &'static str,
+    io_base: u16,
+    #[allow(dead_code)]
+    ctrl:    u16,
+    slave:   bool,
+    /* Amount of sectors, None until the drive is identified. The lock also
+     * serializes access to the controller ports. */
+    sectors: Mutex<Option<u64>>,
+}
+
+impl AtaDrive {
+    /* Detects the drive, returns false if there is no ATA disk */
+    pub fn identify(&self) -> bool {
+        let mut sectors = self.sectors.lock();
+        unsafe {
+            self.select(0);
+            outb(self.io_base + REG_SECTOR_COUNT, 0);
+            outb(self.io_base + REG_LBA_LOW, 0);
+            outb(self.io_base + REG_LBA_MID, 0);
+            outb(self.io_base + REG_LBA_HIGH, 0);
+            outb(self.io_base + REG_COMMAND, CMD_IDENTIFY);
+
+            if inb(self.io_base + REG_STATUS) == 0 {
+                /* No drive */
+                return false;
+            }
+            while inb(self.io_base + REG_STATUS) & STATUS_BSY != 0 {}
+
+            /* ATAPI and SATA devices put their signature here */
+            if inb(self.io_base + REG_LBA_MID) != 0 || inb(self.io_base + REG_LBA_HIGH) != 0 {
+                return false;
+            }
+            if self.wait_data().is_err() {
+                return false;
+            }
+
+            let mut identity = [0u16; 256];
+            for word in identity.iter_mut() {
+                *word = inw(self.io_base + REG_DATA);
+            }
+            *sectors = Some((identity[60] as u64) | ((identity[61] as u64) << 16));
+        }
+        true
+    }
+
+    /* Selects the drive and sets the upper bits of LBA */
+    unsafe fn select(&self, lba: u64) {
+        let drive = if self.slave { 0xF0 } else { 0xE0 };
+        outb(self.io_base + REG_DRIVE, drive | ((lba >> 24) & 0x0F) as u8);
+        /* Reading the status port 4 times gives the drive 400ns to switch */
+        for _ in 0..4 {
+            inb(self.io_base + REG_STATUS);
+        }
+    }
+
+    unsafe fn wait_data(&self) -> Result<(), BlockError> {
+        loop {
+            let status = inb(self.io_base + REG_STATUS);
+            if status & STATUS_BSY != 0 {
+                continue;
+            }
+            if status & (STATUS_ERR | STATUS_DF) != 0 {
+                return Err(BlockError::DeviceError);
+            }
+            if status & STATUS_DRQ != 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    unsafe fn wait_ready(&self) -> Result<(), BlockError> {
+        loop {
+            let status = inb(self.io_base + REG_STATUS);
+            if status & STATUS_BSY == 0 {
+                return if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    Err(BlockError::DeviceError)
+                } else {
+                    Ok(())
+                };
+            }
+        }
+    }
+
+    unsafe fn issue(&self, command: u8, lba: u64, count: usize) {
+        self.select(lba);
+        outb(self.io_base + REG_SECTOR_COUNT, count as u8);
+        outb(self.io_base + REG_LBA_LOW, lba as u8);
+        outb(self.io_base + REG_LBA_MID, (lba >> 8) as u8);
+        outb(self.io_base + REG_LBA_HIGH, (lba >> 16) as u8);
+        outb(self.io_base + REG_COMMAND, command);
+    }
+
+    /* Reads sectors, the caller holds the sectors lock */
+    fn read_locked(&self, sectors: &Option<u64>, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = try!(self.check_request(sectors, lba, buffer.len()));
+        unsafe {
+            /* Sector count of 0 means 256 sectors */
+            self.issue(CMD_READ_SECTORS, lba, count);
+            for sector in buffer.chunks_mut(SECTOR_SIZE) {
+                try!(self.wait_data());
+                for word in sector.chunks_mut(2) {
+                    let value = inw(self.io_base + REG_DATA);
+                    word[0] = value as u8;
+                    word[1] = (value >> 8) as u8;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /* Checks the request and returns amount of sectors in it */
+    fn check_request(&self, sectors: &Option<u64>, lba: u64, buffer_len: usize) -> Result<usize, BlockError> {
+        if buffer_len % SECTOR_SIZE != 0 || buffer_len / SECTOR_SIZE > 256 {
+            return Err(BlockError::BadBuffer);
+        }
+        let count = buffer_len / SECTOR_SIZE;
+        match *sectors {
+            Some(total) if lba + (count as u64) <= total && lba + (count as u64) <= LBA28_LIMIT => Ok(count),
+            _ => Err(BlockError::OutOfRange)
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn sectors_count(&self) -> u64 {
+        self.sectors.lock().unwrap_or(0)
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let sectors = self.sectors.lock();
+        self.read_locked(&*sectors, lba, buffer)
+    }
+
+    fn try_read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        match self.sectors.try_lock() {
+            Some(sectors) => self.read_locked(&*sectors, lba, buffer),
+            None => Err(BlockError::Busy)
+        }
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let sectors = self.sectors.lock();
+        let count = try!(self.check_request(&*sectors, lba, buffer.len()));
+        unsafe {
+            self.issue(CMD_WRITE_SECTORS, lba, count);
+            for sector in buffer.chunks(SECTOR_SIZE) {
+                try!(self.wait_data());
+                for word in sector.chunks(2) {
+                    outw(self.io_base + REG_DATA, (word[0] as u16) | ((word[1] as u16) << 8));
+                }
+            }
+            outb(self.io_base + REG_COMMAND, CMD_CACHE_FLUSH);
+            self.wait_ready()
+        }
+    }
+}
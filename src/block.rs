@@ -0,0 +1,32 @@
+/* Generic block device interface */
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockError {
+    /* The device reported an error */
+    DeviceError,
+    /* Requested sectors lie beyond the end of the device */
+    OutOfRange,
+    /* The buffer size is not a multiple of the sector size */
+    BadBuffer,
+    /* The device is in use, only returned by try_read_sectors() */
+    Busy,
+}
+
+/* Devices do their own locking, so they can be shared between subsystems */
+pub trait BlockDevice: Sync {
+    fn name(&self) -> &'static str;
+
+    fn sectors_count(&self) -> u64;
+
+    /* Reads buffer.len() / SECTOR_SIZE sectors starting from lba */
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /* The same as read_sectors() but fails with BlockError::Busy instead of
+     * waiting for another request, for callers which are not allowed to wait */
+    fn try_read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /* Writes buffer.len() / SECTOR_SIZE sectors starting from lba */
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+}
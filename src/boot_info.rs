@@ -0,0 +1,319 @@
+/*
+ * Boot information independent of the boot protocol.
+ *
+ * Everything the kernel needs from the boot loader structures is copied here
+ * at the very beginning, so the rest of the kernel does not care which protocol
+ * it has been booted with and the memory those structures occupy can be reused.
+ */
+
+use core::iter::Cloned;
+use core::slice;
+
+use memory::MemoryRegion;
+use multiboot;
+use multiboot2;
+
+const MAX_MEMORY_REGIONS: usize = 64;
+const MAX_MODULES: usize = 16;
+const CMDLINE_SIZE: usize = 256;
+const MODULE_CMDLINE_SIZE: usize = 64;
+/* Size of the ACPI 2.0+ RSDP, the 1.0 one is 20 bytes */
+const RSDP_MAX_SIZE: usize = 36;
+
+/*
+ * General memory information structures.
+ */
+
+pub type MemoryRegionIterator<'a> = Cloned<slice::Iter<'a, MemoryRegion>>;
+
+pub trait PhysicalMemoryMap {
+    fn available_memory_regions<'a>(&'a self) -> MemoryRegionIterator<'a>;
+
+    /* Total amount of memory available for kernel (in bytes) */
+    fn total_memory_available(&self) -> usize {
+        self.available_memory_regions()
+            .fold(0, |acc,r| acc + r.size)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootProtocol {
+    Multiboot,
+    Multiboot2,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+    pub addr:   usize,
+    pub pitch:  u32,
+    pub width:  u32,
+    pub height: u32,
+    pub bpp:    u8,
+}
+
+/* Location of ELF section headers of the kernel image */
+#[derive(Clone, Copy, Debug)]
+pub struct ElfSections {
+    pub addr:         usize,
+    pub count:        usize,
+    pub entry_size:   usize,
+    /* Index of the section with section names */
+    pub names_index:  usize,
+}
+
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    pub region:  MemoryRegion,
+    cmdline:     [u8; MODULE_CMDLINE_SIZE],
+    cmdline_len: usize,
+}
+
+impl BootModule {
+    pub fn cmdline(&self) -> &str {
+        unsafe { ::core::str::from_utf8_unchecked(&self.cmdline[..self.cmdline_len]) }
+    }
+}
+
+pub struct BootInfo {
+    pub protocol:         BootProtocol,
+    /* Amount of lower and upper memory in bytes */
+    pub lower_memory:     Option<u64>,
+    pub upper_memory:     Option<u64>,
+    memory_regions:       [MemoryRegion; MAX_MEMORY_REGIONS],
+    memory_regions_count: usize,
+    cmdline:              [u8; CMDLINE_SIZE],
+    cmdline_len:          usize,
+    modules:              [BootModule; MAX_MODULES],
+    modules_count:        usize,
+    pub framebuffer:      Option<Framebuffer>,
+    pub elf_sections:     Option<ElfSections>,
+    rsdp:                 [u8; RSDP_MAX_SIZE],
+    rsdp_len:             usize,
+}
+
+const EMPTY_REGION: MemoryRegion = MemoryRegion { addr: 0, size: 0 };
+
+const EMPTY_MODULE: BootModule = BootModule {
+    region:      EMPTY_REGION,
+    cmdline:     [0; MODULE_CMDLINE_SIZE],
+    cmdline_len: 0,
+};
+
+static mut BOOT_INFO: BootInfo = BootInfo {
+    protocol:             BootProtocol::Multiboot,
+    lower_memory:         None,
+    upper_memory:         None,
+    memory_regions:       [EMPTY_REGION; MAX_MEMORY_REGIONS],
+    memory_regions_count: 0,
+    cmdline:              [0; CMDLINE_SIZE],
+    cmdline_len:          0,
+    modules:              [EMPTY_MODULE; MAX_MODULES],
+    modules_count:        0,
+    framebuffer:          None,
+    elf_sections:         None,
+    rsdp:                 [0; RSDP_MAX_SIZE],
+    rsdp_len:             0,
+};
+
+/* Boot protocol the kernel can be started with, recognized by the magic value
+ * the boot loader leaves in %eax */
+pub struct SupportedProtocol {
+    pub protocol: BootProtocol,
+    pub magic:    u32,
+    fill:         fn(&mut BootInfo, usize),
+}
+
+static PROTOCOLS: [SupportedProtocol; 2] = [
+    SupportedProtocol {
+        protocol: BootProtocol::Multiboot,
+        magic:    multiboot::BOOTLOADER_MAGIC,
+        fill:     fill_from_multiboot,
+    },
+    SupportedProtocol {
+        protocol: BootProtocol::Multiboot2,
+        magic:    multiboot2::BOOTLOADER_MAGIC,
+        fill:     fill_from_multiboot2,
+    },
+];
+
+pub fn supported_protocols() -> &'static [SupportedProtocol] {
+    &PROTOCOLS
+}
+
+/* Collects boot information left by the boot loader. The magic value tells
+ * which protocol has been used, None is returned if it is not known. */
+pub fn init(magic: u32, info_addr: usize) -> Option<&'static BootInfo> {
+    let supported = PROTOCOLS.iter().find(|p| p.magic == magic);
+    match supported {
+        Some(supported) => {
+            let boot_info = unsafe { &mut BOOT_INFO };
+            boot_info.protocol = supported.protocol;
+            (supported.fill)(boot_info, info_addr);
+            Some(boot_info)
+        },
+        None => None
+    }
+}
+
+fn fill_from_multiboot(boot_info: &mut BootInfo, info_addr: usize) {
+    boot_info.fill_from_multiboot(unsafe { &*(info_addr as *const multiboot::Info) });
+}
+
+fn fill_from_multiboot2(boot_info: &mut BootInfo, info_addr: usize) {
+    boot_info.fill_from_multiboot2(unsafe { &*(info_addr as *const multiboot2::Info) });
+}
+
+/* Boot information collected by init() */
+pub fn get() -> &'static BootInfo {
+    unsafe { &BOOT_INFO }
+}
+
+impl BootInfo {
+    pub fn cmdline(&self) -> &str {
+        unsafe { ::core::str::from_utf8_unchecked(&self.cmdline[..self.cmdline_len]) }
+    }
+
+    pub fn modules(&self) -> &[BootModule] {
+        &self.modules[..self.modules_count]
+    }
+
+    /* Copy of the ACPI RSDP if the boot loader has provided it */
+    pub fn acpi_rsdp(&self) -> Option<&[u8]> {
+        if self.rsdp_len > 0 {
+            Some(&self.rsdp[..self.rsdp_len])
+        } else {
+            None
+        }
+    }
+
+    fn fill_from_multiboot(&mut self, info: &multiboot::Info) {
+        if info.is_memory_size_available() {
+            self.lower_memory = Some(info.get_lower_memory());
+            self.upper_memory = Some(info.get_upper_memory());
+        }
+
+        if info.is_memory_map_available() {
+            for region in info.available_memory_regions() {
+                self.add_memory_region(region);
+            }
+        }
+
+        if let Some(cmdline) = info.cmdline() {
+            self.cmdline_len = copy_str(&mut self.cmdline, skip_image_path(cmdline));
+        }
+
+        for module in info.modules() {
+            self.add_module(module.region, module.cmdline);
+        }
+
+        if let Some(elf) = info.elf_sections() {
+            self.elf_sections = Some(ElfSections {
+                addr:        elf.addr as usize,
+                count:       elf.num as usize,
+                entry_size:  elf.size as usize,
+                names_index: elf.shndx as usize,
+            });
+        }
+    }
+
+    fn fill_from_multiboot2(&mut self, info: &multiboot2::Info) {
+        if let Some(meminfo) = info.basic_meminfo() {
+            self.lower_memory = Some((meminfo.mem_lower as u64) * 1024);
+            self.upper_memory = Some((meminfo.mem_upper as u64) * 1024);
+        }
+
+        /* EFI memory map is only used when there is no regular one */
+        if info.is_memory_map_available() {
+            for region in info.available_memory_regions() {
+                self.add_memory_region(region);
+            }
+        } else if info.is_efi_memory_map_available() {
+            for region in info.efi_available_memory_regions() {
+                self.add_memory_region(region);
+            }
+        }
+
+        if let Some(cmdline) = info.cmdline() {
+            self.cmdline_len = copy_str(&mut self.cmdline, cmdline);
+        }
+
+        for module in info.modules() {
+            self.add_module(module.region, module.cmdline);
+        }
+
+        if let Some(fb) = info.framebuffer() {
+            self.framebuffer = Some(Framebuffer {
+                addr:   fb.addr as usize,
+                pitch:  fb.pitch,
+                width:  fb.width,
+                height: fb.height,
+                bpp:    fb.bpp,
+            });
+        }
+
+        if let Some((elf, headers_addr)) = info.elf_sections() {
+            self.elf_sections = Some(ElfSections {
+                addr:        headers_addr,
+                count:       elf.num as usize,
+                entry_size:  elf.entsize as usize,
+                names_index: elf.shndx as usize,
+            });
+        }
+
+        if let Some(rsdp) = info.acpi_rsdp() {
+            let len = ::core::cmp::min(rsdp.len(), RSDP_MAX_SIZE);
+            self.rsdp[..len].clone_from_slice(&rsdp[..len]);
+            self.rsdp_len = len;
+        }
+    }
+
+    fn add_memory_region(&mut self, region: MemoryRegion) {
+        if self.memory_regions_count == MAX_MEMORY_REGIONS {
+            println!("Too many memory regions, ignoring 0x{:016x} ({} bytes).", region.addr, region.size);
+            return;
+        }
+        self.memory_regions[self.memory_regions_count] = region;
+        self.memory_regions_count += 1;
+    }
+
+    fn add_module(&mut self, region: MemoryRegion, cmdline: &str) {
+        if self.modules_count == MAX_MODULES {
+            println!("Too many boot modules, ignoring \"{}\".", cmdline);
+            return;
+        }
+        {
+            let module = &mut self.modules[self.modules_count];
+            module.region = region;
+            module.cmdline_len = copy_str(&mut module.cmdline, cmdline);
+        }
+        self.modules_count += 1;
+    }
+}
+
+impl PhysicalMemoryMap for BootInfo {
+    fn available_memory_regions<'a>(&'a self) -> MemoryRegionIterator<'a> {
+        self.memory_regions[..self.memory_regions_count].iter().cloned()
+    }
+}
+
+/* GRUB puts path of the kernel image in front of the multiboot (v1) command line */
+fn skip_image_path(cmdline: &str) -> &str {
+    if cmdline.starts_with('/') || cmdline.starts_with('(') {
+        match cmdline.find(' ') {
+            Some(index) => &cmdline[index + 1..],
+            None => ""
+        }
+    } else {
+        cmdline
+    }
+}
+
+/* Copies as much of the string as fits, returns amount of bytes copied */
+fn copy_str(dest: &mut [u8], src: &str) -> usize {
+    let mut len = ::core::cmp::min(dest.len(), src.len());
+    while !src.is_char_boundary(len) {
+        len -= 1;
+    }
+    dest[..len].clone_from_slice(&src.as_bytes()[..len]);
+    len
+}
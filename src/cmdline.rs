@@ -0,0 +1,124 @@
+/*
+ * Kernel command line parsing.
+ *
+ * The command line is a whitespace separated list of arguments, every argument
+ * is either a flag ("noswap") or a "key=value" pair. Double quotes can be used
+ * to put whitespaces into an argument: key="some value". The quotes are not part
+ * of the parsed name or value.
+ */
+
+use core::iter::Iterator;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Arg<'a> {
+    pub name:  &'a str,
+    /* None for flags */
+    pub value: Option<&'a str>,
+}
+
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+pub fn args<'a>(cmdline: &'a str) -> Args<'a> {
+    Args { rest: cmdline }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = Arg<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.rest.trim_left();
+        if rest.is_empty() {
+            self.rest = rest;
+            return None;
+        }
+
+        /* The argument ends at the first whitespace outside of quotes */
+        let mut in_quotes = false;
+        let mut end = rest.len();
+        for (i, c) in rest.char_indices() {
+            if c == '"' {
+                in_quotes = !in_quotes;
+            } else if c.is_whitespace() && !in_quotes {
+                end = i;
+                break;
+            }
+        }
+        let arg = &rest[..end];
+        self.rest = &rest[end..];
+
+        let index = arg.find('=');
+        Some(match index {
+            Some(index) => Arg {
+                name:  unquote(&arg[..index]),
+                value: Some(unquote(&arg[index + 1..])),
+            },
+            None => Arg {
+                name:  unquote(arg),
+                value: None,
+            }
+        })
+    }
+}
+
+/* Strips double quotes surrounding the string, an unterminated quote is dropped as well */
+fn unquote(s: &str) -> &str {
+    let s = if s.starts_with('"') { &s[1..] } else { s };
+    if s.ends_with('"') { &s[..s.len() - 1] } else { s }
+}
+
+/* Parses a decimal or hexadecimal (0x prefixed) number, optionally followed
+ * by one of K, M or G size suffixes */
+pub fn parse_int(s: &str) -> Option<u64> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1)
+    };
+
+    let number = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        u64::from_str_radix(digits, 10)
+    };
+
+    match number {
+        Ok(number) => number.checked_mul(multiplier),
+        Err(_) => None
+    }
+}
+
+/* A flag without a value means true */
+pub fn parse_bool(s: Option<&str>) -> Option<bool> {
+    match s {
+        None => Some(true),
+        Some("1") | Some("y") | Some("yes") | Some("on") | Some("true") => Some(true),
+        Some("0") | Some("n") | Some("no") | Some("off") | Some("false") => Some(false),
+        Some(_) => None
+    }
+}
+
+pub fn cmdline_test() {
+    let mut it = args("  loglevel=4 noswap   title=\"a b  c\" \"quoted flag\" empty= ");
+    assert_eq!(Some(Arg { name: "loglevel", value: Some("4") }), it.next());
+    assert_eq!(Some(Arg { name: "noswap", value: None }), it.next());
+    assert_eq!(Some(Arg { name: "title", value: Some("a b  c") }), it.next());
+    assert_eq!(Some(Arg { name: "quoted flag", value: None }), it.next());
+    assert_eq!(Some(Arg { name: "empty", value: Some("") }), it.next());
+    assert_eq!(None, it.next());
+    assert_eq!(None, args("").next());
+
+    assert_eq!(Some(42), parse_int("42"));
+    assert_eq!(Some(0x1f), parse_int("0x1F"));
+    assert_eq!(Some(16 << 20), parse_int("16M"));
+    assert_eq!(Some(2 << 30), parse_int("2g"));
+    assert_eq!(None, parse_int(""));
+    assert_eq!(None, parse_int("12a"));
+    assert_eq!(None, parse_int("0xffffffffffffffffK"));
+
+    assert_eq!(Some(true), parse_bool(None));
+    assert_eq!(Some(false), parse_bool(Some("off")));
+    assert_eq!(None, parse_bool(Some("maybe")));
+}
@@ -0,0 +1,33 @@
+/* Kernel console: VGA text mode and/or the serial port */
+
+use core::fmt::{self, Write};
+
+use params::{Param, Value};
+use serial;
+use vga;
+
+kernel_param! {
+    pub static CONSOLE: Param = Param::new("console", Value::Str("both"),
+        "Where the kernel messages go: vga, serial or both");
+}
+
+pub fn write_fmt(args: fmt::Arguments) {
+    let console = CONSOLE.get_str();
+    if console != "serial" {
+        vga::CONSOLE.lock().write_fmt(args).unwrap();
+    }
+    if console != "vga" {
+        serial::COM1.lock().write_fmt(args).unwrap();
+    }
+}
+
+macro_rules! println {
+    ($fmt:expr) => (print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
+}
+
+macro_rules! print {
+    ($($arg:tt)*) => ({
+            $crate::console::write_fmt(format_args!($($arg)*));
+    });
+}
@@ -52,10 +52,62 @@ pub const CPU_FEAT2_SSE4_2: u32       = 1 << 20;
 pub const CPU_FEAT2_X2APIC: u32       = 1 << 21;
 pub const CPU_FEAT2_MOVBE: u32        = 1 << 22;
 pub const CPU_FEAT2_POPCNT: u32       = 1 << 23;
+pub const CPU_FEAT2_TSC_DEADLINE: u32 = 1 << 24;
 pub const CPU_FEAT2_AES: u32          = 1 << 25;
 pub const CPU_FEAT2_XSAVE: u32        = 1 << 26;
 pub const CPU_FEAT2_OSXSAVE: u32      = 1 << 27;
 pub const CPU_FEAT2_AVX: u32          = 1 << 28;
+pub const CPU_FEAT2_F16C: u32         = 1 << 29;
+pub const CPU_FEAT2_RDRAND: u32       = 1 << 30;
+pub const CPU_FEAT2_HYPERVISOR: u32   = 1 << 31;
+
+/* Extended features, leaf 0x80000001 edx */
+pub const CPU_EXT_FEAT1_SYSCALL: u32  = 1 << 11;
+pub const CPU_EXT_FEAT1_NX: u32       = 1 << 20;
+pub const CPU_EXT_FEAT1_MMXEXT: u32   = 1 << 22;
+pub const CPU_EXT_FEAT1_FXSR_OPT: u32 = 1 << 25;
+pub const CPU_EXT_FEAT1_PDPE1GB: u32  = 1 << 26;
+pub const CPU_EXT_FEAT1_RDTSCP: u32   = 1 << 27;
+pub const CPU_EXT_FEAT1_LM: u32       = 1 << 29;
+pub const CPU_EXT_FEAT1_3DNOWEXT: u32 = 1 << 30;
+pub const CPU_EXT_FEAT1_3DNOW: u32    = 1 << 31;
+
+/* Extended features, leaf 0x80000001 ecx */
+pub const CPU_EXT_FEAT2_LAHF_LM: u32  = 1 << 0;
+pub const CPU_EXT_FEAT2_SVM: u32      = 1 << 2;
+pub const CPU_EXT_FEAT2_ABM: u32      = 1 << 5;
+pub const CPU_EXT_FEAT2_SSE4A: u32    = 1 << 6;
+pub const CPU_EXT_FEAT2_PREFETCHW: u32 = 1 << 8;
+pub const CPU_EXT_FEAT2_XOP: u32      = 1 << 11;
+pub const CPU_EXT_FEAT2_FMA4: u32     = 1 << 16;
+pub const CPU_EXT_FEAT2_TOPOEXT: u32  = 1 << 22;
+
+/* Structured extended features, leaf 7 subleaf 0 ebx */
+pub const CPU_SFEAT1_FSGSBASE: u32    = 1 << 0;
+pub const CPU_SFEAT1_TSC_ADJUST: u32  = 1 << 1;
+pub const CPU_SFEAT1_BMI1: u32        = 1 << 3;
+pub const CPU_SFEAT1_HLE: u32         = 1 << 4;
+pub const CPU_SFEAT1_AVX2: u32        = 1 << 5;
+pub const CPU_SFEAT1_SMEP: u32        = 1 << 7;
+pub const CPU_SFEAT1_BMI2: u32        = 1 << 8;
+pub const CPU_SFEAT1_ERMS: u32        = 1 << 9;
+pub const CPU_SFEAT1_INVPCID: u32     = 1 << 10;
+pub const CPU_SFEAT1_RTM: u32         = 1 << 11;
+pub const CPU_SFEAT1_MPX: u32         = 1 << 14;
+pub const CPU_SFEAT1_AVX512F: u32     = 1 << 16;
+pub const CPU_SFEAT1_RDSEED: u32      = 1 << 18;
+pub const CPU_SFEAT1_ADX: u32         = 1 << 19;
+pub const CPU_SFEAT1_SMAP: u32        = 1 << 20;
+pub const CPU_SFEAT1_CLFLUSHOPT: u32  = 1 << 23;
+pub const CPU_SFEAT1_CLWB: u32        = 1 << 24;
+pub const CPU_SFEAT1_SHA: u32         = 1 << 29;
+
+/* Structured extended features, leaf 7 subleaf 0 ecx */
+pub const CPU_SFEAT2_PREFETCHWT1: u32 = 1 << 0;
+pub const CPU_SFEAT2_UMIP: u32        = 1 << 2;
+pub const CPU_SFEAT2_PKU: u32         = 1 << 3;
+pub const CPU_SFEAT2_OSPKE: u32       = 1 << 4;
+pub const CPU_SFEAT2_RDPID: u32       = 1 << 22;
 
 pub static CPU_FEATURES1_MAP: &'static [(u32, &'static str)] = &[
     (CPU_FEAT1_VME,     "vme"),
@@ -112,10 +164,66 @@ pub static CPU_FEATURES2_MAP: &'static [(u32, &'static str)] = &[
     (CPU_FEAT2_X2APIC,  "x2apic"),
     (CPU_FEAT2_MOVBE,   "movbe"),
     (CPU_FEAT2_POPCNT,  "popcnt"),
+    (CPU_FEAT2_TSC_DEADLINE, "tsc_deadline_timer"),
     (CPU_FEAT2_AES,     "aes"),
     (CPU_FEAT2_XSAVE,   "xsave"),
     (CPU_FEAT2_OSXSAVE, "osxsave"),
     (CPU_FEAT2_AVX,     "avx"),
+    (CPU_FEAT2_F16C,    "f16c"),
+    (CPU_FEAT2_RDRAND,  "rdrand"),
+    (CPU_FEAT2_HYPERVISOR, "hypervisor"),
+];
+
+pub static CPU_EXT_FEATURES1_MAP: &'static [(u32, &'static str)] = &[
+    (CPU_EXT_FEAT1_SYSCALL,  "syscall"),
+    (CPU_EXT_FEAT1_NX,       "nx"),
+    (CPU_EXT_FEAT1_MMXEXT,   "mmxext"),
+    (CPU_EXT_FEAT1_FXSR_OPT, "fxsr_opt"),
+    (CPU_EXT_FEAT1_PDPE1GB,  "pdpe1gb"),
+    (CPU_EXT_FEAT1_RDTSCP,   "rdtscp"),
+    (CPU_EXT_FEAT1_LM,       "lm"),
+    (CPU_EXT_FEAT1_3DNOWEXT, "3dnowext"),
+    (CPU_EXT_FEAT1_3DNOW,    "3dnow"),
+];
+
+pub static CPU_EXT_FEATURES2_MAP: &'static [(u32, &'static str)] = &[
+    (CPU_EXT_FEAT2_LAHF_LM,    "lahf_lm"),
+    (CPU_EXT_FEAT2_SVM,        "svm"),
+    (CPU_EXT_FEAT2_ABM,        "abm"),
+    (CPU_EXT_FEAT2_SSE4A,      "sse4a"),
+    (CPU_EXT_FEAT2_PREFETCHW,  "3dnowprefetch"),
+    (CPU_EXT_FEAT2_XOP,        "xop"),
+    (CPU_EXT_FEAT2_FMA4,       "fma4"),
+    (CPU_EXT_FEAT2_TOPOEXT,    "topoext"),
+];
+
+pub static CPU_STRUCT_FEATURES1_MAP: &'static [(u32, &'static str)] = &[
+    (CPU_SFEAT1_FSGSBASE,   "fsgsbase"),
+    (CPU_SFEAT1_TSC_ADJUST, "tsc_adjust"),
+    (CPU_SFEAT1_BMI1,       "bmi1"),
+    (CPU_SFEAT1_HLE,        "hle"),
+    (CPU_SFEAT1_AVX2,       "avx2"),
+    (CPU_SFEAT1_SMEP,       "smep"),
+    (CPU_SFEAT1_BMI2,       "bmi2"),
+    (CPU_SFEAT1_ERMS,       "erms"),
+    (CPU_SFEAT1_INVPCID,    "invpcid"),
+    (CPU_SFEAT1_RTM,        "rtm"),
+    (CPU_SFEAT1_MPX,        "mpx"),
+    (CPU_SFEAT1_AVX512F,    "avx512f"),
+    (CPU_SFEAT1_RDSEED,     "rdseed"),
+    (CPU_SFEAT1_ADX,        "adx"),
+    (CPU_SFEAT1_SMAP,       "smap"),
+    (CPU_SFEAT1_CLFLUSHOPT, "clflushopt"),
+    (CPU_SFEAT1_CLWB,       "clwb"),
+    (CPU_SFEAT1_SHA,        "sha_ni"),
+];
+
+pub static CPU_STRUCT_FEATURES2_MAP: &'static [(u32, &'static str)] = &[
+    (CPU_SFEAT2_PREFETCHWT1, "prefetchwt1"),
+    (CPU_SFEAT2_UMIP,        "umip"),
+    (CPU_SFEAT2_PKU,         "pku"),
+    (CPU_SFEAT2_OSPKE,       "ospke"),
+    (CPU_SFEAT2_RDPID,       "rdpid"),
 ];
 
 
@@ -142,6 +250,27 @@ pub struct CpuInfo {
     pub features3: u32,
 }
 
+/* features3 is ebx of leaf 1 */
+impl CpuInfo {
+    pub fn brand_index(&self) -> u32 {
+        bits::get_range(self.features3, 0..8)
+    }
+
+    /* In bytes, given in 8-byte units */
+    pub fn clflush_line_size(&self) -> u32 {
+        bits::get_range(self.features3, 8..16) * 8
+    }
+
+    /* Addressable logical processor ids in the package, valid with htt */
+    pub fn max_logical_processors(&self) -> u32 {
+        bits::get_range(self.features3, 16..24)
+    }
+
+    pub fn initial_apic_id(&self) -> u32 {
+        bits::get_range(self.features3, 24..32)
+    }
+}
+
 pub fn get_vendor_id() -> VendorId {
     let max_basic_func: u32;
     let max_extended_func: u32;
@@ -214,6 +343,278 @@ pub fn get_cpu_info() -> CpuInfo {
     }
 }
 
+/* Runs cpuid for a leaf with subleaves, returns (eax, ebx, ecx, edx) */
+pub fn leaf(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (eax, ebx, ecx, edx): (u32, u32, u32, u32);
+    unsafe {
+        asm!("cpuid"
+             : "={eax}" (eax), "={ebx}" (ebx), "={ecx}" (ecx), "={edx}" (edx)
+             : "{eax}" (leaf), "{ecx}" (subleaf));
+    };
+    (eax, ebx, ecx, edx)
+}
+
+/* Advanced power management leaf, edx bit telling the TSC runs at a constant
+ * rate in all power states */
+const CPUID_APM_LEAF: u32 = 0x80000007;
+const APM_INVARIANT_TSC: u32 = 1 << 8;
+
+pub fn has_invariant_tsc() -> bool {
+    if get_vendor_id().max_extended_func < CPUID_APM_LEAF {
+        return false;
+    }
+    leaf(CPUID_APM_LEAF, 0).3 & APM_INVARIANT_TSC != 0
+}
+
+/* Extended features leaf: edx and ecx go to ExtFeatures */
+const CPUID_EXT_FEATURES_LEAF: u32 = 0x80000001;
+
+pub struct ExtFeatures {
+    pub features1: u32,
+    pub features2: u32,
+}
+
+/* Zeros if the leaf is not supported */
+pub fn get_ext_features() -> ExtFeatures {
+    if get_vendor_id().max_extended_func < CPUID_EXT_FEATURES_LEAF {
+        return ExtFeatures { features1: 0, features2: 0 };
+    }
+    let (_, _, ecx, edx) = leaf(CPUID_EXT_FEATURES_LEAF, 0);
+    ExtFeatures {
+        features1: edx,
+        features2: ecx,
+    }
+}
+
+/* Intel only reports syscall in 64-bit mode, which is always the case here */
+pub fn has_syscall() -> bool {
+    get_ext_features().features1 & CPU_EXT_FEAT1_SYSCALL != 0
+}
+
+/* Structured extended features leaf, subleaf 0 */
+const CPUID_STRUCT_FEATURES_LEAF: u32 = 7;
+
+pub struct StructFeatures {
+    pub features1: u32,
+    pub features2: u32,
+}
+
+/* Zeros if the leaf is not supported */
+pub fn get_struct_features() -> StructFeatures {
+    if get_vendor_id().max_basic_func < CPUID_STRUCT_FEATURES_LEAF {
+        return StructFeatures { features1: 0, features2: 0 };
+    }
+    let (_, ebx, ecx, _) = leaf(CPUID_STRUCT_FEATURES_LEAF, 0);
+    StructFeatures {
+        features1: ebx,
+        features2: ecx,
+    }
+}
+
+/* Three leaves with 16 bytes of the brand string each */
+const CPUID_BRAND_LEAF: u32 = 0x80000002;
+const CPUID_BRAND_LEAVES_COUNT: u32 = 3;
+
+/* The processor name, like "Intel(R) Core(TM) i7-4770 CPU @ 3.40GHz",
+ * without the padding. None if the CPU does not report it */
+pub fn get_brand_string(buffer: &mut [u8; 48]) -> Option<&str> {
+    if get_vendor_id().max_extended_func < CPUID_BRAND_LEAF + CPUID_BRAND_LEAVES_COUNT - 1 {
+        return None;
+    }
+    for i in 0..CPUID_BRAND_LEAVES_COUNT {
+        let (eax, ebx, ecx, edx) = leaf(CPUID_BRAND_LEAF + i, 0);
+        for (j, register) in [eax, ebx, ecx, edx].iter().enumerate() {
+            for k in 0..4 {
+                buffer[i as usize * 16 + j * 4 + k] = (register >> (k * 8)) as u8;
+            }
+        }
+    }
+    let end = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    match ::core::str::from_utf8(&buffer[..end]) {
+        Ok(brand) if brand.trim().len() > 0 => Some(brand.trim()),
+        _ => None
+    }
+}
+
+/*
+ * Cache hierarchy.
+ *
+ * Intel describes caches with leaf 4, AMD with 0x8000001D (when topoext is
+ * reported) in the same format, one subleaf per cache until the type is 0.
+ */
+
+const CPUID_INTEL_CACHE_LEAF: u32 = 4;
+const CPUID_AMD_CACHE_LEAF: u32 =   0x8000001D;
+/* Guards against CPUs which never report the end */
+const MAX_CACHES_COUNT: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+pub struct CacheInfo {
+    pub level:      u32,
+    pub cache_type: CacheType,
+    /* In bytes */
+    pub size:       u32,
+    pub line_size:  u32,
+    pub ways:       u32,
+    /* Logical processors sharing the cache */
+    pub sharing:    u32,
+}
+
+impl CacheInfo {
+    pub fn short_name(&self) -> &'static str {
+        match self.cache_type {
+            CacheType::Data => "d",
+            CacheType::Instruction => "i",
+            CacheType::Unified => "",
+        }
+    }
+}
+
+pub struct Caches {
+    leaf:    u32,
+    subleaf: u32,
+}
+
+impl Iterator for Caches {
+    type Item = CacheInfo;
+
+    fn next(&mut self) -> Option<CacheInfo> {
+        if self.leaf == 0 || self.subleaf >= MAX_CACHES_COUNT {
+            return None;
+        }
+        let (eax, ebx, ecx, _) = leaf(self.leaf, self.subleaf);
+        self.subleaf += 1;
+        let cache_type = match bits::get_range(eax, 0..5) {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => {
+                self.leaf = 0;
+                return None;
+            }
+        };
+        let line_size = bits::get_range(ebx, 0..12) + 1;
+        let partitions = bits::get_range(ebx, 12..22) + 1;
+        let ways = bits::get_range(ebx, 22..32) + 1;
+        let sets = ecx + 1;
+        Some(CacheInfo {
+            level:      bits::get_range(eax, 5..8),
+            cache_type: cache_type,
+            size:       ways * partitions * line_size * sets,
+            line_size:  line_size,
+            ways:       ways,
+            sharing:    bits::get_range(eax, 14..26) + 1,
+        })
+    }
+}
+
+pub fn caches() -> Caches {
+    let vendor_id = get_vendor_id();
+    let leaf = if &vendor_id.vendor == b"GenuineIntel" && vendor_id.max_basic_func >= CPUID_INTEL_CACHE_LEAF {
+        CPUID_INTEL_CACHE_LEAF
+    } else if vendor_id.max_extended_func >= CPUID_AMD_CACHE_LEAF &&
+              get_ext_features().features2 & CPU_EXT_FEAT2_TOPOEXT != 0 {
+        CPUID_AMD_CACHE_LEAF
+    } else {
+        0
+    };
+    Caches { leaf: leaf, subleaf: 0 }
+}
+
+/*
+ * Processor topology.
+ *
+ * Leaf 0x1F (or 0xB on older CPUs) lists levels from SMT upwards, one per
+ * subleaf until the type is 0. The x2APIC id shifted right by the level's
+ * shift gives the id of the next level up.
+ */
+
+const CPUID_V2_TOPOLOGY_LEAF: u32 = 0x1F;
+const CPUID_TOPOLOGY_LEAF: u32 =    0xB;
+const MAX_TOPOLOGY_LEVELS: u32 =    8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TopologyLevelType {
+    Smt,
+    Core,
+    Module,
+    Tile,
+    Die,
+    Unknown,
+}
+
+pub struct TopologyLevel {
+    pub level_type: TopologyLevelType,
+    /* Bits of the x2APIC id taken by this level and the ones below */
+    pub shift:      u32,
+    /* Logical processors at this level */
+    pub logical_processors: u32,
+}
+
+pub struct Topology {
+    leaf:    u32,
+    subleaf: u32,
+}
+
+impl Iterator for Topology {
+    type Item = TopologyLevel;
+
+    fn next(&mut self) -> Option<TopologyLevel> {
+        if self.leaf == 0 || self.subleaf >= MAX_TOPOLOGY_LEVELS {
+            return None;
+        }
+        let (eax, ebx, ecx, _) = leaf(self.leaf, self.subleaf);
+        self.subleaf += 1;
+        let level_type = match bits::get_range(ecx, 8..16) {
+            0 => {
+                self.leaf = 0;
+                return None;
+            },
+            1 => TopologyLevelType::Smt,
+            2 => TopologyLevelType::Core,
+            3 => TopologyLevelType::Module,
+            4 => TopologyLevelType::Tile,
+            5 => TopologyLevelType::Die,
+            _ => TopologyLevelType::Unknown
+        };
+        Some(TopologyLevel {
+            level_type:         level_type,
+            shift:              bits::get_range(eax, 0..5),
+            logical_processors: bits::get_range(ebx, 0..16),
+        })
+    }
+}
+
+/* The leaf with topology levels, 0 if there is none */
+fn topology_leaf() -> u32 {
+    let max_basic_func = get_vendor_id().max_basic_func;
+    for &leaf_number in [CPUID_V2_TOPOLOGY_LEAF, CPUID_TOPOLOGY_LEAF].iter() {
+        /* A leaf is valid if its first level has processors */
+        if max_basic_func >= leaf_number && leaf(leaf_number, 0).1 != 0 {
+            return leaf_number;
+        }
+    }
+    0
+}
+
+pub fn topology() -> Topology {
+    Topology { leaf: topology_leaf(), subleaf: 0 }
+}
+
+/* The full x2APIC id of the current processor from the topology leaf */
+pub fn x2apic_id() -> Option<u32> {
+    match topology_leaf() {
+        0 => None,
+        leaf_number => Some(leaf(leaf_number, 0).3)
+    }
+}
+
 pub fn print_cpu_features(features: u32, map: &[(u32, &str)]) {
     for &(flag,desc) in map.iter() {
         if features & flag == flag {
@@ -0,0 +1,49 @@
+/*
+ * ELF64 structures needed to inspect the kernel image.
+ */
+
+/*
+ * Constants for SectionHeader::section_type.
+ */
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+
+/*
+ * Constants for the lower half of Symbol::info.
+ */
+
+pub const STT_FUNC: u8 = 2;
+
+#[repr(C)]
+pub struct SectionHeader {
+    pub name:         u32,
+    pub section_type: u32,
+    pub flags:        u64,
+    /* Set by the boot loader to the physical address for sections it has loaded */
+    pub addr:         u64,
+    pub offset:       u64,
+    pub size:         u64,
+    /* Index of the associated section (the string table for a symbol table) */
+    pub link:         u32,
+    pub info:         u32,
+    pub addralign:    u64,
+    pub entsize:      u64,
+}
+
+#[repr(C)]
+pub struct Symbol {
+    /* Offset of the name in the string table */
+    pub name:  u32,
+    pub info:  u8,
+    pub other: u8,
+    pub shndx: u16,
+    pub value: u64,
+    pub size:  u64,
+}
+
+impl Symbol {
+    pub fn symbol_type(&self) -> u8 {
+        self.info & 0xf
+    }
+}
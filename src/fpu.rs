@@ -0,0 +1,336 @@
+/*
+ * x87, SSE and AVX state.
+ *
+ * The bootstrap enables SSE as the compiler uses SSE and SSE2 registers, other
+ * extensions are disabled in the Makefile. Kernel code using them explicitly
+ * must run between kernel_fpu_begin() and kernel_fpu_end(), which save the
+ * registers of whoever owns them, give the kernel a clean state and restore
+ * the registers afterwards. Interrupts are disabled meanwhile.
+ *
+ * Contexts are switched eagerly: the scheduler saves the state of the outgoing
+ * thread and restores the incoming one with FpuState. The state is kept with
+ * XSAVEOPT when cpuid reports xsave and the OS has enabled it (osxsave), which
+ * skips components not modified since they were restored, otherwise with
+ * XSAVE and, on CPUs without XSAVE, with FXSAVE. XCR0 enables the x87, SSE and,
+ * where supported, AVX components.
+ */
+
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use cpuid::{self, CPU_FEAT2_OSXSAVE, CPU_FEAT2_XSAVE};
+use irq;
+
+/* Control register bits */
+const CR0_MP: u64 =      1 << 1;
+const CR0_EM: u64 =      1 << 2;
+const CR0_TS: u64 =      1 << 3;
+const CR0_NE: u64 =      1 << 5;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+/* State components in XCR0 and XSAVE masks */
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/* Subleaf 0: supported components in eax:edx and the area size for XCR0 in ebx.
+ * Subleaf 1: eax bit 0 reports XSAVEOPT */
+const CPUID_XSAVE_LEAF: u32 = 0xD;
+const CPUID_XSAVEOPT: u32 =   1 << 0;
+
+/* FXSAVE area and the XSAVE one with x87, SSE and AVX take 832 bytes */
+const FXSAVE_AREA_SIZE: usize = 512;
+const MAX_AREA_SIZE: usize =    1024;
+/* XSAVE needs 64-byte alignment, FXSAVE 16 */
+const AREA_ALIGN: usize =       64;
+
+/* Fields of the legacy region set in the initial state */
+const FCW_OFFSET: usize =    0;
+const MXCSR_OFFSET: usize =  24;
+/* x87 exceptions masked, 64-bit precision */
+const FCW_DEFAULT: u16 =     0x037F;
+/* SIMD exceptions masked */
+const MXCSR_DEFAULT: u32 =   0x1F80;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Fxsave,
+    Xsave,
+    Xsaveopt,
+}
+
+static METHOD: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Enabled components, valid for the XSAVE methods */
+static XCR0: AtomicUsize = ATOMIC_USIZE_INIT;
+static AREA_SIZE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+extern {
+    /* From fpu.S */
+    fn fpu_fxsave(area: usize);
+    fn fpu_fxrstor(area: usize);
+    fn fpu_xsave(area: usize, mask: u64);
+    fn fpu_xsaveopt(area: usize, mask: u64);
+    fn fpu_xrstor(area: usize, mask: u64);
+    fn fpu_set_xcr0(xcr0: u64);
+    fn fpu_reset();
+}
+
+/* Saved registers of a thread or of the code interrupted by kernel_fpu_begin().
+ * Rust cannot align a structure to 64 bytes, so the area is placed inside the
+ * buffer at an aligned address and moved along if the structure is moved. */
+pub struct FpuState {
+    buffer: [u8; MAX_AREA_SIZE + AREA_ALIGN],
+    /* Where the area starts in the buffer */
+    offset: usize,
+}
+
+/* Arrays above 32 elements have no Clone implementation */
+impl Clone for FpuState {
+    fn clone(&self) -> FpuState {
+        *self
+    }
+}
+
+impl Copy for FpuState {}
+
+const EMPTY_FPU_STATE: FpuState = FpuState {
+    buffer: [0; MAX_AREA_SIZE + AREA_ALIGN],
+    offset: 0,
+};
+
+impl FpuState {
+    /* The initial state: empty x87 stack, zeroed registers, exceptions masked.
+     * The zero XSAVE header makes XRSTOR initialize all components. */
+    pub fn new() -> FpuState {
+        let mut state = EMPTY_FPU_STATE;
+        let area = state.area();
+        unsafe {
+            *((area + FCW_OFFSET) as *mut u16) = FCW_DEFAULT;
+            *((area + MXCSR_OFFSET) as *mut u32) = MXCSR_DEFAULT;
+        }
+        state
+    }
+
+    /* Address of the aligned area, the contents are moved to it if needed */
+    fn area(&mut self) -> usize {
+        let buffer = self.buffer.as_mut_ptr() as usize;
+        let offset = ((buffer + AREA_ALIGN - 1) & !(AREA_ALIGN - 1)) - buffer;
+        if offset != self.offset {
+            unsafe {
+                ptr::copy((buffer + self.offset) as *const u8, (buffer + offset) as *mut u8, MAX_AREA_SIZE);
+            }
+            self.offset = offset;
+        }
+        buffer + offset
+    }
+
+    /* Saves the registers of the current CPU */
+    pub fn save(&mut self) {
+        let area = self.area();
+        let mask = XCR0.load(Ordering::SeqCst) as u64;
+        unsafe {
+            match method() {
+                Method::Fxsave => fpu_fxsave(area),
+                Method::Xsave => fpu_xsave(area, mask),
+                Method::Xsaveopt => fpu_xsaveopt(area, mask),
+            }
+        }
+    }
+
+    /* Loads the saved registers. XSAVEOPT relies on the area not being changed
+     * while its registers are loaded, nothing but save() should write it. */
+    pub fn restore(&mut self) {
+        let area = self.area();
+        let mask = XCR0.load(Ordering::SeqCst) as u64;
+        unsafe {
+            match method() {
+                Method::Fxsave => fpu_fxrstor(area),
+                Method::Xsave | Method::Xsaveopt => fpu_xrstor(area, mask),
+            }
+        }
+    }
+}
+
+/* Swaps the registers of two threads, called by the scheduler */
+pub fn switch(prev: &mut FpuState, next: &mut FpuState) {
+    prev.save();
+    next.restore();
+}
+
+/* kernel_fpu_begin() saves registers here */
+#[derive(Clone, Copy)]
+struct KernelFpu {
+    active:             bool,
+    interrupts_enabled: bool,
+    saved:              FpuState,
+}
+
+const EMPTY_KERNEL_FPU: KernelFpu = KernelFpu {
+    active:             false,
+    interrupts_enabled: false,
+    saved:              EMPTY_FPU_STATE,
+};
+
+percpu! {
+    static KERNEL_FPU: KernelFpu = EMPTY_KERNEL_FPU;
+}
+
+/* Sets up the current CPU. APs should be the same as the bootstrap processor.
+ * Should be called after the IDT is loaded: enabling XSAVE might fault */
+pub fn init(cpu: usize) {
+    unsafe {
+        /* Native x87 exceptions, no emulation and no lazy switching traps */
+        write_cr0((read_cr0() | CR0_MP | CR0_NE) & !(CR0_EM | CR0_TS));
+    }
+    let method = enable_xsave();
+    if cpu == 0 {
+        METHOD.store(method as usize, Ordering::SeqCst);
+    }
+    unsafe {
+        fpu_reset();
+    }
+}
+
+/* Enables XSAVE with the supported components in XCR0. Returns the method to use */
+fn enable_xsave() -> Method {
+    if cpuid::get_cpu_info().features2 & CPU_FEAT2_XSAVE == 0 {
+        AREA_SIZE.store(FXSAVE_AREA_SIZE, Ordering::SeqCst);
+        return Method::Fxsave;
+    }
+    unsafe {
+        write_cr4(read_cr4() | CR4_OSXSAVE);
+    }
+    /* osxsave mirrors CR4.OSXSAVE, so it confirms the bit has been accepted */
+    if cpuid::get_cpu_info().features2 & CPU_FEAT2_OSXSAVE == 0 {
+        AREA_SIZE.store(FXSAVE_AREA_SIZE, Ordering::SeqCst);
+        return Method::Fxsave;
+    }
+
+    let (supported_low, _, _, supported_high) = cpuid::leaf(CPUID_XSAVE_LEAF, 0);
+    let supported = ((supported_high as u64) << 32) | supported_low as u64;
+    let xcr0 = supported & (XCR0_X87 | XCR0_SSE | XCR0_AVX);
+    unsafe {
+        fpu_set_xcr0(xcr0);
+    }
+    /* The size reported in ebx follows the components enabled in XCR0 */
+    let (_, size, _, _) = cpuid::leaf(CPUID_XSAVE_LEAF, 0);
+    assert!(size as usize <= MAX_AREA_SIZE, "XSAVE area does not fit in FpuState");
+    XCR0.store(xcr0 as usize, Ordering::SeqCst);
+    AREA_SIZE.store(size as usize, Ordering::SeqCst);
+
+    let (xsave_features, _, _, _) = cpuid::leaf(CPUID_XSAVE_LEAF, 1);
+    if xsave_features & CPUID_XSAVEOPT != 0 {
+        Method::Xsaveopt
+    } else {
+        Method::Xsave
+    }
+}
+
+pub fn method() -> Method {
+    match METHOD.load(Ordering::SeqCst) {
+        1 => Method::Xsave,
+        2 => Method::Xsaveopt,
+        _ => Method::Fxsave
+    }
+}
+
+pub fn is_avx_enabled() -> bool {
+    XCR0.load(Ordering::SeqCst) as u64 & XCR0_AVX != 0
+}
+
+/* Lets kernel code use SSE and AVX registers until kernel_fpu_end(). Disables
+ * interrupts, the section cannot be nested. */
+pub fn kernel_fpu_begin() {
+    let interrupts_enabled = irq::are_enabled();
+    irq::disable();
+    KERNEL_FPU.with(|kernel_fpu| {
+        assert!(!kernel_fpu.active, "Nested kernel_fpu_begin()");
+        kernel_fpu.active = true;
+        kernel_fpu.interrupts_enabled = interrupts_enabled;
+        kernel_fpu.saved.save();
+    });
+    unsafe {
+        fpu_reset();
+    }
+}
+
+pub fn kernel_fpu_end() {
+    let interrupts_enabled = KERNEL_FPU.with(|kernel_fpu| {
+        assert!(kernel_fpu.active, "kernel_fpu_end() without kernel_fpu_begin()");
+        kernel_fpu.saved.restore();
+        kernel_fpu.active = false;
+        kernel_fpu.interrupts_enabled
+    });
+    if interrupts_enabled {
+        irq::enable();
+    }
+}
+
+unsafe fn read_cr0() -> u64 {
+    let cr0: u64;
+    asm!("movq %cr0, $0" : "=r" (cr0) : : : "volatile");
+    cr0
+}
+
+unsafe fn write_cr0(cr0: u64) {
+    asm!("movq $0, %cr0" : : "r" (cr0) : "memory" : "volatile");
+}
+
+unsafe fn read_cr4() -> u64 {
+    let cr4: u64;
+    asm!("movq %cr4, $0" : "=r" (cr4) : : : "volatile");
+    cr4
+}
+
+unsafe fn write_cr4(cr4: u64) {
+    asm!("movq $0, %cr4" : : "r" (cr4) : "memory" : "volatile");
+}
+
+pub fn display_fpu_info() {
+    print!("FPU state: {:?}, {} bytes", method(), AREA_SIZE.load(Ordering::SeqCst));
+    if method() != Method::Fxsave {
+        print!(", XCR0 0x{:x}", XCR0.load(Ordering::SeqCst));
+    }
+    println!("{}.", if is_avx_enabled() { ", AVX enabled" } else { "" });
+}
+
+fn read_mxcsr() -> u32 {
+    let mut mxcsr: u32 = 0;
+    unsafe {
+        asm!("stmxcsr ($0)" : : "r" (&mut mxcsr) : "memory" : "volatile");
+    }
+    mxcsr
+}
+
+fn write_mxcsr(mxcsr: u32) {
+    unsafe {
+        asm!("ldmxcsr ($0)" : : "r" (&mxcsr) : "memory" : "volatile");
+    }
+}
+
+pub fn fpu_test() {
+    /* Rounding toward zero, a change easy to see in MXCSR */
+    const MXCSR_ROUND_TO_ZERO: u32 = 3 << 13;
+
+    let mxcsr = read_mxcsr();
+    write_mxcsr(mxcsr | MXCSR_ROUND_TO_ZERO);
+
+    /* The section starts from the default state and restores the previous one */
+    kernel_fpu_begin();
+    assert!(!irq::are_enabled());
+    assert_eq!(read_mxcsr(), MXCSR_DEFAULT);
+    write_mxcsr(MXCSR_DEFAULT | MXCSR_ROUND_TO_ZERO | 1);
+    kernel_fpu_end();
+    assert_eq!(read_mxcsr(), mxcsr | MXCSR_ROUND_TO_ZERO);
+
+    /* Switching to a new state and back */
+    let mut current = FpuState::new();
+    let mut initial = FpuState::new();
+    switch(&mut current, &mut initial);
+    assert_eq!(read_mxcsr(), MXCSR_DEFAULT);
+    let mut scratch = FpuState::new();
+    switch(&mut scratch, &mut current);
+    assert_eq!(read_mxcsr(), mxcsr | MXCSR_ROUND_TO_ZERO);
+
+    write_mxcsr(mxcsr);
+}
@@ -0,0 +1,194 @@
+/*
+ * Global descriptor table and the task state segment.
+ *
+ * The bootstrap GDT only has kernel code and data segments. This one adds
+ * user segments and the 64-bit TSS which holds the stack used on entering
+ * the kernel from user mode and the interrupt stack table (IST): separate
+ * stacks for exceptions which can occur when the current stack is unusable.
+ * Every processor has its own GDT, TSS and IST stacks, they are indexed by
+ * the CPU number given by smp.rs (0 for the bootstrap processor).
+ */
+
+use core::mem::size_of;
+
+use irq;
+use percpu;
+use smp::MAX_CPUS;
+
+/*
+ * Segment selectors. The user data segment precedes the user code one,
+ * as SYSRET expects them in this order. User selectors should be synchronized
+ * with kernel.h.
+ */
+
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 =   0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 =   0x20 | 3;
+pub const TSS_SELECTOR: u16 =         0x28;
+
+/*
+ * Interrupt stack table indexes (0 means the current stack is used).
+ */
+
+pub const IST_DOUBLE_FAULT: u8 =  1;
+pub const IST_NMI: u8 =           2;
+pub const IST_MACHINE_CHECK: u8 = 3;
+
+const IST_STACKS_COUNT: usize = 3;
+const IST_STACK_SIZE: usize = 0x2000;
+
+/*
+ * Bits of segment descriptors.
+ */
+
+const DESC_ACCESSED: u64 =     1 << 40;
+const DESC_READ_WRITE: u64 =   1 << 41;
+const DESC_EXECUTABLE: u64 =   1 << 43;
+const DESC_CODE_DATA: u64 =    1 << 44;
+const DESC_DPL_USER: u64 =     3 << 45;
+const DESC_PRESENT: u64 =      1 << 47;
+const DESC_LONG_MODE: u64 =    1 << 53;
+/* System descriptor type of an available 64-bit TSS */
+const DESC_TSS_AVAILABLE: u64 = 0x9 << 40;
+
+const KERNEL_CODE: u64 = DESC_PRESENT | DESC_CODE_DATA | DESC_EXECUTABLE | DESC_READ_WRITE | DESC_ACCESSED | DESC_LONG_MODE;
+const KERNEL_DATA: u64 = DESC_PRESENT | DESC_CODE_DATA | DESC_READ_WRITE | DESC_ACCESSED;
+const USER_CODE: u64 =   KERNEL_CODE | DESC_DPL_USER;
+const USER_DATA: u64 =   KERNEL_DATA | DESC_DPL_USER;
+
+/* The TSS descriptor takes two entries */
+const GDT_ENTRIES_COUNT: usize = 7;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct TaskStateSegment {
+    reserved1:   u32,
+    /* Stacks loaded on a privilege level change, only rsp[0] is used */
+    pub rsp:     [u64; 3],
+    reserved2:   u64,
+    pub ist:     [u64; 7],
+    reserved3:   u64,
+    reserved4:   u16,
+    /* No I/O permission bitmap: points beyond the segment limit */
+    iomap_base:  u16,
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base:  u64,
+}
+
+const EMPTY_TSS: TaskStateSegment = TaskStateSegment {
+    reserved1:  0,
+    rsp:        [0; 3],
+    reserved2:  0,
+    ist:        [0; 7],
+    reserved3:  0,
+    reserved4:  0,
+    iomap_base: 0,
+};
+
+static mut GDT: [[u64; GDT_ENTRIES_COUNT]; MAX_CPUS] = [[0; GDT_ENTRIES_COUNT]; MAX_CPUS];
+
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [EMPTY_TSS; MAX_CPUS];
+
+static mut IST_STACKS: [[[u8; IST_STACK_SIZE]; IST_STACKS_COUNT]; MAX_CPUS] =
+    [[[0; IST_STACK_SIZE]; IST_STACKS_COUNT]; MAX_CPUS];
+
+/* Poisons the bottom of the IST stacks of every processor */
+#[cfg(feature = "kasan")]
+pub fn poison_stack_guards() {
+    for stacks in unsafe { IST_STACKS.iter() } {
+        for stack in stacks.iter() {
+            ::kasan::poison_stack_guard(stack as *const _ as usize, ::kasan::STACK_GUARD_SIZE);
+        }
+    }
+}
+
+/* Replaces the bootstrap GDT of the processor, reloads segment registers and
+ * the task register */
+pub fn init(cpu: usize) {
+    unsafe {
+        let tss = &mut TSS[cpu];
+        /* IST entry N is kept in ist[N - 1] */
+        for i in 0..IST_STACKS_COUNT {
+            /* The stack grows down, keep its top 16-byte aligned */
+            let top = (&IST_STACKS[cpu][i] as *const _ as usize + IST_STACK_SIZE) & !0xF;
+            tss.ist[i] = top as u64;
+        }
+        tss.iomap_base = size_of::<TaskStateSegment>() as u16;
+
+        let (tss_low, tss_high) = tss_descriptor(tss as *const _ as u64, size_of::<TaskStateSegment>() as u64 - 1);
+        GDT[cpu] = [
+            0,
+            KERNEL_CODE,
+            KERNEL_DATA,
+            USER_DATA,
+            USER_CODE,
+            tss_low,
+            tss_high,
+        ];
+
+        let pointer = DescriptorTablePointer {
+            limit: (size_of::<[u64; GDT_ENTRIES_COUNT]>() - 1) as u16,
+            base:  &GDT[cpu] as *const _ as u64,
+        };
+        asm!("lgdt ($0)"
+             : /* outputs */
+             : "r" (&pointer)
+             : "memory"
+             : "volatile");
+
+        reload_segments();
+
+        asm!("ltr $0"
+             : /* outputs */
+             : "r" (TSS_SELECTOR)
+             : /* clobbers */
+             : "volatile");
+    }
+}
+
+/* Sets the stack the current CPU switches to when an interrupt or a system call
+ * comes in user mode */
+pub fn set_kernel_stack(rsp: usize) {
+    irq::without_interrupts(|| {
+        unsafe {
+            TSS[percpu::cpu_id()].rsp[0] = rsp as u64;
+        }
+        percpu::set_kernel_stack(rsp);
+    })
+}
+
+/* The code segment can only be reloaded with a far return */
+unsafe fn reload_segments() {
+    asm!("pushq $0
+          leaq 1f(%rip), %rax
+          pushq %rax
+          lretq
+          1:
+          movw $1, %ax
+          movw %ax, %ds
+          movw %ax, %es
+          movw %ax, %ss
+          xorw %ax, %ax
+          movw %ax, %fs
+          movw %ax, %gs"
+         : /* outputs */
+         : "i" (KERNEL_CODE_SELECTOR), "i" (KERNEL_DATA_SELECTOR)
+         : "rax", "memory"
+         : "volatile");
+}
+
+/* Returns both halves of the 16-byte TSS descriptor */
+fn tss_descriptor(base: u64, limit: u64) -> (u64, u64) {
+    let low = (limit & 0xFFFF) |
+              ((base & 0xFFFFFF) << 16) |
+              DESC_TSS_AVAILABLE |
+              DESC_PRESENT |
+              (((limit >> 16) & 0xF) << 48) |
+              (((base >> 24) & 0xFF) << 56);
+    (low, base >> 32)
+}
@@ -0,0 +1,102 @@
+/*
+ * High precision event timer.
+ *
+ * Only the main counter is used, as a clocksource and a reference for calibrating
+ * the TSC and the local APIC timer, comparators stay disabled. The counter is
+ * 32 or 64 bits wide, a 32-bit one is extended in software which works as long
+ * as it is read more often than it wraps (every 5 minutes at 14.318 MHz).
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use spin::Mutex;
+
+use acpi::Hpet;
+use irq;
+use paging;
+
+/* Registers occupy 1 Kb */
+const MMIO_SIZE: usize = 0x400;
+
+/*
+ * Registers.
+ */
+
+const REG_CAPABILITIES: usize = 0x00;
+const REG_CONFIG: usize =       0x10;
+const REG_MAIN_COUNTER: usize = 0xF0;
+
+/* Bits of the capabilities register */
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+const CAP_PERIOD_SHIFT: u64 =  32;
+
+/* Bits of the configuration register */
+const CONFIG_ENABLE: u64 =     1 << 0;
+const CONFIG_LEGACY: u64 =     1 << 1;
+
+/* The specification limits the period to 100 ns */
+const MAX_PERIOD_FS: u64 = 100000000;
+
+const FEMTOSECONDS_PER_SECOND: u64 = 1000000000000000;
+
+/* Virtual address of the registers, 0 if there is no usable HPET */
+static BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+static FREQUENCY: AtomicUsize = ATOMIC_USIZE_INIT;
+static COUNTER_64BIT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/* Last value of a 32-bit counter and the number of its wraps in the high half */
+static EXTENDED: Mutex<(u32, u64)> = Mutex::new((0, 0));
+
+/* Starts the main counter of the HPET described by ACPI. Returns false if it is unusable */
+pub fn init(hpet: &Hpet) -> bool {
+    let base = paging::map_mmio(hpet.addr, MMIO_SIZE);
+    let capabilities = read(base, REG_CAPABILITIES);
+    let period = capabilities >> CAP_PERIOD_SHIFT;
+    if period == 0 || period > MAX_PERIOD_FS {
+        return false;
+    }
+    FREQUENCY.store((FEMTOSECONDS_PER_SECOND / period) as usize, Ordering::SeqCst);
+    COUNTER_64BIT.store(capabilities & CAP_COUNTER_64BIT != 0, Ordering::SeqCst);
+
+    /* Legacy replacement would take IRQ 0 from the PIT */
+    let config = read(base, REG_CONFIG) & !CONFIG_LEGACY;
+    write(base, REG_CONFIG, config & !CONFIG_ENABLE);
+    write(base, REG_MAIN_COUNTER, 0);
+    write(base, REG_CONFIG, config | CONFIG_ENABLE);
+    BASE.store(base, Ordering::SeqCst);
+    true
+}
+
+pub fn is_available() -> bool {
+    BASE.load(Ordering::SeqCst) != 0
+}
+
+/* Counter frequency in Hz */
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::SeqCst) as u64
+}
+
+/* Main counter value, extended to 64 bits if the counter is narrower */
+pub fn read_counter() -> u64 {
+    let base = BASE.load(Ordering::SeqCst);
+    if COUNTER_64BIT.load(Ordering::SeqCst) {
+        return read(base, REG_MAIN_COUNTER);
+    }
+    irq::without_interrupts(|| {
+        let mut extended = EXTENDED.lock();
+        let low = read(base, REG_MAIN_COUNTER) as u32;
+        if low < extended.0 {
+            extended.1 += 1 << 32;
+        }
+        extended.0 = low;
+        extended.1 | low as u64
+    })
+}
+
+fn read(base: usize, reg: usize) -> u64 {
+    unsafe { ::core::ptr::read_volatile((base + reg) as *const u64) }
+}
+
+fn write(base: usize, reg: usize, value: u64) {
+    unsafe { ::core::ptr::write_volatile((base + reg) as *mut u64, value) }
+}
@@ -0,0 +1,114 @@
+/*
+ * Interrupt descriptor table.
+ *
+ * Vectors 0-31 are CPU exceptions, their entries are in traps.S. Others are
+ * filled by the code handling hardware interrupts. Every processor loads its
+ * own copy of the table, set_gate() updates all of them.
+ */
+
+use core::mem::size_of;
+
+use gdt;
+use smp::MAX_CPUS;
+
+pub const IDT_ENTRIES_COUNT: usize = 256;
+
+pub const EXCEPTIONS_COUNT: usize = 32;
+
+/*
+ * Values for IdtEntry::type_attr.
+ */
+
+const GATE_INTERRUPT: u8 = 0x0E;
+#[allow(dead_code)]
+const GATE_TRAP: u8 =      0x0F;
+const GATE_DPL_USER: u8 =  3 << 5;
+const GATE_PRESENT: u8 =   1 << 7;
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct IdtEntry {
+    offset_low:  u16,
+    selector:    u16,
+    /* Interrupt stack table index in the lower 3 bits */
+    ist:         u8,
+    type_attr:   u8,
+    offset_mid:  u16,
+    offset_high: u32,
+    reserved:    u32,
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base:  u64,
+}
+
+const EMPTY_ENTRY: IdtEntry = IdtEntry {
+    offset_low:  0,
+    selector:    0,
+    ist:         0,
+    type_attr:   0,
+    offset_mid:  0,
+    offset_high: 0,
+    reserved:    0,
+};
+
+static mut IDT: [[IdtEntry; IDT_ENTRIES_COUNT]; MAX_CPUS] = [[EMPTY_ENTRY; IDT_ENTRIES_COUNT]; MAX_CPUS];
+
+extern {
+    /* Addresses of the exception entries from traps.S */
+    static trap_entries: [usize; EXCEPTIONS_COUNT];
+}
+
+/* Fills exception entries and loads the IDT of the bootstrap processor.
+ * Should be called after gdt::init() */
+pub fn init() {
+    for vector in 0..EXCEPTIONS_COUNT {
+        let ist = match vector {
+            2  => gdt::IST_NMI,
+            8  => gdt::IST_DOUBLE_FAULT,
+            18 => gdt::IST_MACHINE_CHECK,
+            _  => 0
+        };
+        /* int3 and into are allowed from user mode */
+        let user = vector == 3 || vector == 4;
+        set_gate(vector as u8, unsafe { trap_entries[vector] }, ist, user);
+    }
+    load(0);
+}
+
+/* Loads the table of the processor */
+pub fn load(cpu: usize) {
+    let pointer = DescriptorTablePointer {
+        limit: (size_of::<[IdtEntry; IDT_ENTRIES_COUNT]>() - 1) as u16,
+        base:  unsafe { &IDT[cpu] as *const _ as u64 },
+    };
+    unsafe {
+        asm!("lidt ($0)"
+             : /* outputs */
+             : "r" (&pointer)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/* Sets an interrupt gate for the vector. Interrupts are disabled while
+ * the handler runs. A nonzero ist selects a stack from the TSS. */
+pub fn set_gate(vector: u8, handler: usize, ist: u8, user: bool) {
+    let dpl = if user { GATE_DPL_USER } else { 0 };
+    let entry = IdtEntry {
+        offset_low:  handler as u16,
+        selector:    gdt::KERNEL_CODE_SELECTOR,
+        ist:         ist & 0x7,
+        type_attr:   GATE_PRESENT | dpl | GATE_INTERRUPT,
+        offset_mid:  (handler >> 16) as u16,
+        offset_high: (handler >> 32) as u32,
+        reserved:    0,
+    };
+    unsafe {
+        for idt in IDT.iter_mut() {
+            idt[vector as usize] = entry;
+        }
+    }
+}
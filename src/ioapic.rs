@@ -0,0 +1,239 @@
+/*
+ * I/O APIC.
+ *
+ * I/O APICs deliver device interrupts to local APICs. Each one handles a range
+ * of global system interrupts (GSI) starting at its gsi_base. An ISA IRQ is
+ * the GSI with the same number unless the MADT has an interrupt source
+ * override for it. ISA IRQs keep the vectors the PIC uses (IRQ_BASE_VECTOR + line),
+ * so the code handling interrupts doesn't depend on the active controller.
+ */
+
+use spin::Mutex;
+
+use acpi::{self, Madt, MadtEntry};
+use apic;
+use paging;
+use pic::{IRQ_BASE_VECTOR, IRQ_LINES_COUNT};
+
+const MAX_IO_APICS_COUNT: usize = 8;
+
+/*
+ * Memory mapped registers: the index of an internal register is written
+ * to IOREGSEL, then it is accessed through IOWIN.
+ */
+
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize =    0x10;
+const MMIO_SIZE: usize = 0x20;
+
+const REG_VERSION: u32 =     0x01;
+/* Every redirection entry takes two registers */
+const REG_REDIRECTION: u32 = 0x10;
+
+/*
+ * Bits of redirection entries.
+ */
+
+const REDIR_ACTIVE_LOW: u64 =    1 << 13;
+const REDIR_LEVEL: u64 =         1 << 15;
+const REDIR_MASKED: u64 =        1 << 16;
+const REDIR_DESTINATION_SHIFT: u64 = 56;
+
+#[derive(Clone, Copy)]
+struct IoApic {
+    id:            u8,
+    /* Virtual address of the registers */
+    base:          usize,
+    gsi_base:      u32,
+    entries_count: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ::core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ::core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ::core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, reg);
+            ::core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries_count
+    }
+
+    fn read_redirection(&self, gsi: u32) -> u64 {
+        let reg = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.read(reg) as u64 | (self.read(reg + 1) as u64) << 32
+    }
+
+    fn write_redirection(&self, gsi: u32, entry: u64) {
+        let reg = REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        /* The high half first, the entry might be unmasked by the low one */
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+}
+
+/* Where an ISA IRQ is connected */
+#[derive(Clone, Copy)]
+struct IsaRoute {
+    /* None if an override has taken the GSI of the same number for another line */
+    gsi:   Option<u32>,
+    /* MPS INTI flags */
+    flags: u16,
+}
+
+struct IoApics {
+    io_apics: [Option<IoApic>; MAX_IO_APICS_COUNT],
+    routes:   [IsaRoute; IRQ_LINES_COUNT],
+}
+
+impl IoApics {
+    fn find(&self, gsi: u32) -> Option<IoApic> {
+        self.io_apics.iter().filter_map(|io_apic| *io_apic).find(|io_apic| io_apic.handles(gsi))
+    }
+}
+
+static IO_APICS: Mutex<IoApics> = Mutex::new(IoApics {
+    io_apics: [None; MAX_IO_APICS_COUNT],
+    routes:   [IsaRoute { gsi: None, flags: 0 }; IRQ_LINES_COUNT],
+});
+
+/* Finds I/O APICs in the MADT and routes ISA IRQs to the current processor with
+ * all lines masked. Returns false if there are no I/O APICs. */
+pub fn init(madt: &Madt) -> bool {
+    let mut io_apics = IO_APICS.lock();
+    let mut count = 0;
+
+    for entry in madt.entries() {
+        if let MadtEntry::IoApic { id, addr, gsi_base } = entry {
+            if count == MAX_IO_APICS_COUNT {
+                println!("Too many I/O APICs, the one with id {} is ignored.", id);
+                continue;
+            }
+            let mut io_apic = IoApic {
+                id:            id,
+                base:          paging::map_mmio(addr, MMIO_SIZE),
+                gsi_base:      gsi_base,
+                entries_count: 0,
+            };
+            io_apic.entries_count = ((io_apic.read(REG_VERSION) >> 16) & 0xFF) + 1;
+            /* Mask everything the firmware might have left enabled */
+            for gsi in gsi_base..gsi_base + io_apic.entries_count {
+                io_apic.write_redirection(gsi, REDIR_MASKED);
+            }
+            io_apics.io_apics[count] = Some(io_apic);
+            count += 1;
+        }
+    }
+    if count == 0 {
+        return false;
+    }
+
+    for line in 0..IRQ_LINES_COUNT {
+        io_apics.routes[line] = IsaRoute {
+            gsi:   Some(line as u32),
+            flags: 0,
+        };
+    }
+    let mut overridden = [false; IRQ_LINES_COUNT];
+    for entry in madt.entries() {
+        if let MadtEntry::InterruptOverride { source, gsi, flags } = entry {
+            if (source as usize) < IRQ_LINES_COUNT {
+                io_apics.routes[source as usize] = IsaRoute {
+                    gsi:   Some(gsi),
+                    flags: flags,
+                };
+                overridden[source as usize] = true;
+            }
+        }
+    }
+    /* A GSI taken by an override is not connected to the line of the same number:
+     * with the usual 0 -> 2 override for the PIT line 2 (the cascade) gets nothing,
+     * otherwise its identity entry would replace the one of the timer */
+    for line in 0..IRQ_LINES_COUNT {
+        let claimed = (0..IRQ_LINES_COUNT).any(|other| {
+            overridden[other] && other != line && io_apics.routes[other].gsi == Some(line as u32)
+        });
+        if claimed && !overridden[line] {
+            io_apics.routes[line].gsi = None;
+        }
+    }
+
+    /* Without interrupt remapping the destination is an 8-bit APIC id,
+     * in the x2APIC mode as well */
+    let destination = apic::id();
+    assert!(destination <= 0xFF, "APIC id {} of the bootstrap processor does not fit in an I/O APIC destination",
+            destination);
+    for line in 0..IRQ_LINES_COUNT {
+        let route = io_apics.routes[line];
+        let gsi = match route.gsi {
+            Some(gsi) => gsi,
+            None => continue
+        };
+        let mut entry = (IRQ_BASE_VECTOR as u64 + line as u64) | REDIR_MASKED |
+                        (destination as u64) << REDIR_DESTINATION_SHIFT;
+        if acpi::is_active_low(route.flags) {
+            entry |= REDIR_ACTIVE_LOW;
+        }
+        if acpi::is_level_triggered(route.flags) {
+            entry |= REDIR_LEVEL;
+        }
+        match io_apics.find(gsi) {
+            Some(io_apic) => io_apic.write_redirection(gsi, entry),
+            None => println!("IRQ {} is connected to GSI {} which no I/O APIC handles.", line, gsi)
+        }
+    }
+    true
+}
+
+pub fn mask(line: u8) {
+    set_masked(line, true);
+}
+
+pub fn unmask(line: u8) {
+    set_masked(line, false);
+}
+
+fn set_masked(line: u8, masked: bool) {
+    let io_apics = IO_APICS.lock();
+    let gsi = match io_apics.routes[line as usize].gsi {
+        Some(gsi) => gsi,
+        None => return
+    };
+    if let Some(io_apic) = io_apics.find(gsi) {
+        let entry = io_apic.read_redirection(gsi);
+        io_apic.write_redirection(gsi, if masked { entry | REDIR_MASKED } else { entry & !REDIR_MASKED });
+    }
+}
+
+pub fn display_io_apics_info() {
+    let io_apics = IO_APICS.lock();
+    for io_apic in io_apics.io_apics.iter().filter_map(|io_apic| *io_apic) {
+        println!("  I/O APIC {} (version 0x{:x}): GSI {} - {}",
+            io_apic.id,
+            io_apic.read(REG_VERSION) & 0xFF,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.entries_count - 1);
+    }
+    for (line, route) in io_apics.routes.iter().enumerate() {
+        let gsi = match route.gsi {
+            Some(gsi) => gsi,
+            None => {
+                println!("  IRQ {} is not connected", line);
+                continue;
+            }
+        };
+        if gsi != line as u32 || route.flags != 0 {
+            println!("  IRQ {} -> GSI {}{}{}", line, gsi,
+                if acpi::is_active_low(route.flags) { ", active low" } else { "" },
+                if acpi::is_level_triggered(route.flags) { ", level triggered" } else { "" });
+        }
+    }
+}
@@ -0,0 +1,258 @@
+/*
+ * Hardware interrupt requests.
+ *
+ * Drivers register handlers for IRQ lines with register(). A line can be shared
+ * by several devices: every handler checks its device and returns true if
+ * the interrupt came from it. The line is unmasked when the first handler
+ * is added and masked again when the last one is removed.
+ *
+ * Interrupts come through the 8259 PIC until switch_to_apic() is called,
+ * after that through the I/O APIC. Lines keep their vectors in both cases.
+ */
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use spin::Mutex;
+
+use apic;
+use idt;
+use ioapic;
+use pic::{self, IRQ_BASE_VECTOR, IRQ_LINES_COUNT};
+
+/* Returns true if the interrupt has been raised by the handler's device */
+pub type Handler = fn(line: u8) -> bool;
+
+/* Handlers sharing one line */
+const HANDLERS_PER_LINE: usize = 4;
+
+/* Bit of rflags enabling interrupts */
+pub const RFLAGS_IF: u64 = 1 << 9;
+
+#[derive(Clone, Copy)]
+struct Line {
+    handlers:  [Option<Handler>; HANDLERS_PER_LINE],
+    /* Interrupts which have come on the line */
+    count:     usize,
+    /* Interrupts no handler has claimed */
+    unhandled: usize,
+}
+
+const EMPTY_LINE: Line = Line {
+    handlers:  [None; HANDLERS_PER_LINE],
+    count:     0,
+    unhandled: 0,
+};
+
+static LINES: Mutex<[Line; IRQ_LINES_COUNT]> = Mutex::new([EMPTY_LINE; IRQ_LINES_COUNT]);
+
+static SPURIOUS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Controller {
+    Pic,
+    Apic,
+}
+
+/* Not a mutex as it is read by interrupt handlers */
+static APIC_ACTIVE: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern {
+    /* Addresses of the IRQ entries from traps.S */
+    static irq_entries: [usize; IRQ_LINES_COUNT];
+}
+
+/* Remaps the PIC and fills IDT entries of IRQ vectors. Interrupts stay disabled */
+pub fn init() {
+    pic::init();
+    for line in 0..IRQ_LINES_COUNT {
+        idt::set_gate(IRQ_BASE_VECTOR + line as u8, unsafe { irq_entries[line] }, 0, false);
+    }
+}
+
+/* Adds the handler to the line. Returns false if the line is invalid or full */
+pub fn register(line: u8, handler: Handler) -> bool {
+    if line as usize >= IRQ_LINES_COUNT {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let handlers = &mut lines[line as usize].handlers;
+        let index = handlers.iter().position(|h| h.is_none());
+        match index {
+            Some(index) => {
+                handlers[index] = Some(handler);
+                unmask(line);
+                true
+            },
+            None => false
+        }
+    })
+}
+
+/* Removes the handler from the line. Returns false if it hasn't been registered */
+pub fn unregister(line: u8, handler: Handler) -> bool {
+    if line as usize >= IRQ_LINES_COUNT {
+        return false;
+    }
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+        let handlers = &mut lines[line as usize].handlers;
+        let index = handlers.iter().position(|h| h.map_or(false, |h| h as usize == handler as usize));
+        match index {
+            Some(index) => {
+                handlers[index] = None;
+                if handlers.iter().all(|h| h.is_none()) {
+                    mask(line);
+                }
+                true
+            },
+            None => false
+        }
+    })
+}
+
+/* Called by trap_handler() for IRQ vectors */
+pub fn handle(vector: u8) {
+    let line = vector - IRQ_BASE_VECTOR;
+    let controller = controller();
+    /* The APIC has a separate vector for spurious interrupts */
+    if controller == Controller::Pic && pic::is_spurious(line) {
+        SPURIOUS.fetch_add(1, Ordering::SeqCst);
+        return;
+    }
+
+    /* Handlers are called without the lock, so they can (un)register others */
+    let handlers = {
+        let mut lines = LINES.lock();
+        lines[line as usize].count += 1;
+        lines[line as usize].handlers
+    };
+    let mut handled = false;
+    for handler in handlers.iter().filter_map(|h| *h) {
+        handled |= handler(line);
+    }
+    if !handled {
+        LINES.lock()[line as usize].unhandled += 1;
+    }
+
+    match controller {
+        Controller::Pic => pic::eoi(line),
+        Controller::Apic => apic::eoi()
+    }
+}
+
+/* Moves delivery of interrupts to the I/O APIC, which should be initialized.
+ * Lines with handlers stay unmasked. */
+pub fn switch_to_apic() {
+    without_interrupts(|| {
+        let lines = LINES.lock();
+        pic::disable();
+        APIC_ACTIVE.store(true, Ordering::SeqCst);
+        for (line, info) in lines.iter().enumerate() {
+            if info.handlers.iter().any(|h| h.is_some()) {
+                ioapic::unmask(line as u8);
+            }
+        }
+    });
+}
+
+pub fn controller() -> Controller {
+    if APIC_ACTIVE.load(Ordering::SeqCst) {
+        Controller::Apic
+    } else {
+        Controller::Pic
+    }
+}
+
+fn mask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::mask(line),
+        Controller::Apic => ioapic::mask(line)
+    }
+}
+
+fn unmask(line: u8) {
+    match controller() {
+        Controller::Pic => pic::unmask(line),
+        Controller::Apic => ioapic::unmask(line)
+    }
+}
+
+pub fn enable() {
+    unsafe {
+        asm!("sti" : : : "memory" : "volatile");
+    }
+}
+
+pub fn disable() {
+    unsafe {
+        asm!("cli" : : : "memory" : "volatile");
+    }
+}
+
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; popq $0"
+             : "=r" (rflags)
+             : /* inputs */
+             : "memory"
+             : "volatile");
+    }
+    rflags & RFLAGS_IF != 0
+}
+
+/* Runs the closure with interrupts disabled, so an interrupt handler
+ * can't spin on a lock held by the interrupted code */
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+    let result = f();
+    if enabled {
+        enable();
+    }
+    result
+}
+
+pub fn display_irq_info() {
+    println!("IRQ lines (spurious interrupts: {}):", SPURIOUS.load(Ordering::SeqCst));
+    let lines = without_interrupts(|| *LINES.lock());
+    for (line, info) in lines.iter().enumerate() {
+        let handlers = info.handlers.iter().filter(|h| h.is_some()).count();
+        if handlers != 0 || info.count != 0 {
+            println!("  IRQ {:2}: {} handlers, {} interrupts, {} unhandled",
+                line, handlers, info.count, info.unhandled);
+        }
+    }
+}
+
+static TEST_HITS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn test_handler(_line: u8) -> bool {
+    TEST_HITS.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+fn test_other_handler(_line: u8) -> bool {
+    false
+}
+
+/* Raises IRQ 5 (usually unused) by a software interrupt to check dispatching */
+pub fn irq_test() {
+    const TEST_LINE: u8 = 5;
+
+    assert!(register(TEST_LINE, test_other_handler));
+    assert!(register(TEST_LINE, test_handler));
+    let before = TEST_HITS.load(Ordering::SeqCst);
+    unsafe {
+        asm!("int $0" : : "i" (IRQ_BASE_VECTOR + TEST_LINE) : "memory" : "volatile");
+    }
+    assert_eq!(before + 1, TEST_HITS.load(Ordering::SeqCst));
+
+    assert!(unregister(TEST_LINE, test_handler));
+    assert!(!unregister(TEST_LINE, test_handler));
+    assert!(unregister(TEST_LINE, test_other_handler));
+    assert!(!register(IRQ_LINES_COUNT as u8, test_handler));
+}
@@ -0,0 +1,314 @@
+/*
+ * Kernel address sanitizer (enabled by the "kasan" cargo feature).
+ *
+ * Every 8 bytes of the kernel address space are described by a byte of shadow memory:
+ *   0            - all 8 bytes are accessible;
+ *   1..7         - only the first N bytes are accessible;
+ *   negative     - none of the bytes are accessible, the value tells why.
+ *
+ * Shadow memory is only mapped for regions explicitly added with add_region(),
+ * accesses outside of them are never reported. Loads and stores are checked
+ * by the __asan_* hooks in kasan.S which are called by compiler instrumentation
+ * (see the Makefile) or directly through check_read()/check_write(). The hooks
+ * are not instrumented themselves and read the regions and the shadow without
+ * locks, this file only calls kasan_report() from them.
+ */
+
+use core::ptr;
+use spin::Mutex;
+
+use layout;
+use memory::{self, PAGE_SIZE, MemoryRegion};
+use paging;
+use physical_memory_manager;
+use meminfo::Consumer;
+use symbols;
+
+/* Shadow byte for address A lives at KASAN_SHADOW_OFFSET + (A >> KASAN_SHADOW_SCALE).
+ * Should be synchronized with kasan.S. For the upper half of the address space this gives 0xFFFFEC0000000000..0xFFFFFC0000000000 */
+const KASAN_SHADOW_OFFSET: usize = 0xDFFFFC0000000000;
+const KASAN_SHADOW_SCALE: usize = 3;
+const KASAN_GRANULE_SIZE: usize = 1 << KASAN_SHADOW_SCALE;
+
+/* Shadow values for poisoned memory */
+pub const POISON_HEAP_REDZONE: u8  = 0xFC;
+pub const POISON_FREED: u8         = 0xFB;
+pub const POISON_STACK_LEFT: u8    = 0xF1;
+pub const POISON_STACK_MID: u8     = 0xF2;
+pub const POISON_STACK_RIGHT: u8   = 0xF3;
+pub const POISON_STACK_GUARD: u8   = 0xF5;
+pub const POISON_GLOBAL_REDZONE: u8 = 0xF9;
+
+/* Should be synchronized with KASAN_MAX_REGIONS in kasan.S */
+const MAX_REGIONS: usize = 16;
+const QUARANTINE_SIZE: usize = 64;
+
+/* Poisoned bytes at the bottom of every kernel stack */
+pub const STACK_GUARD_SIZE: usize = 256;
+
+extern {
+    /* From kasan.S, read by the hooks without locking. Regions which have shadow
+     * memory mapped are pairs of their first and last addresses, they are never
+     * removed. The count is written after the entry, so readers see it complete. */
+    static mut kasan_regions: [[usize; 2]; MAX_REGIONS];
+    static mut kasan_regions_count: usize;
+    static mut kasan_enabled: u8;
+
+    fn kasan_check(addr: usize, size: usize, is_write: usize, ip: usize);
+}
+
+/* Serializes add_region() */
+static REGIONS_LOCK: Mutex<()> = Mutex::new(());
+
+/* Freed objects are kept poisoned here for a while before they are really
+ * released, so late accesses to them are caught as use-after-free. */
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    objects: [MemoryRegion { addr: 0, size: 0 }; QUARANTINE_SIZE],
+    head:    0,
+    count:   0
+});
+
+struct Quarantine {
+    objects: [MemoryRegion; QUARANTINE_SIZE],
+    head:    usize,
+    count:   usize
+}
+
+/* Maps shadow for the kernel image and starts checking accesses */
+pub fn init() {
+    add_region(layout::virtual_kernel_placement());
+    set_enabled(true);
+    println!("KASAN: shadow memory at 0x{:016x}, checking enabled.", shadow_addr(layout::virtual_kernel_placement().addr));
+}
+
+/* Maps (zeroed, i.e. fully accessible) shadow memory for the region */
+pub fn add_region(region: MemoryRegion) {
+    let shadow = MemoryRegion {
+        addr: shadow_addr(region.addr),
+        size: (region.size + KASAN_GRANULE_SIZE - 1) >> KASAN_SHADOW_SCALE
+    }.page_align(PAGE_SIZE);
+
+    for page in shadow.pages_iter(PAGE_SIZE) {
+        if paging::translate(page.addr).is_some() {
+            /* Shared with a previously added region */
+            continue;
+        }
+        let phys_addr = physical_memory_manager::alloc_page(Consumer::KasanShadow);
+        unsafe {
+            ::rlibc::memset(layout::phys_to_virt(phys_addr) as *mut u8, 0, PAGE_SIZE);
+        }
+        paging::map(phys_addr, page.addr, paging::PAGE_WRITABLE | paging::PAGE_NO_EXECUTE);
+    }
+
+    let _lock = REGIONS_LOCK.lock();
+    unsafe {
+        let count = ptr::read_volatile(&kasan_regions_count);
+        assert!(count < MAX_REGIONS, "Too many KASAN regions");
+        ptr::write_volatile(&mut kasan_regions[count], [region.addr, region.end_addr()]);
+        ptr::write_volatile(&mut kasan_regions_count, count + 1);
+    }
+}
+
+fn set_enabled(enabled: bool) {
+    unsafe { ptr::write_volatile(&mut kasan_enabled, enabled as u8) }
+}
+
+/* Marks the memory as inaccessible, code tells the reason */
+pub fn poison(addr: usize, size: usize, code: u8) {
+    debug_assert_eq!(0, addr % KASAN_GRANULE_SIZE);
+    if !is_tracked(addr, size) {
+        return;
+    }
+    let mut granule = addr;
+    while granule < addr + size {
+        unsafe { *shadow_ptr(granule) = code; }
+        granule += KASAN_GRANULE_SIZE;
+    }
+}
+
+/* Marks the memory as accessible. The tail of the last granule stays poisoned. */
+pub fn unpoison(addr: usize, size: usize) {
+    debug_assert_eq!(0, addr % KASAN_GRANULE_SIZE);
+    if !is_tracked(addr, size) {
+        return;
+    }
+    let mut granule = addr;
+    while granule + KASAN_GRANULE_SIZE <= addr + size {
+        unsafe { *shadow_ptr(granule) = 0; }
+        granule += KASAN_GRANULE_SIZE;
+    }
+    if granule < addr + size {
+        unsafe { *shadow_ptr(granule) = (addr + size - granule) as u8; }
+    }
+}
+
+/* Called by an allocator after an object of size bytes has been placed into
+ * a slot of slot_size bytes: the rest of the slot becomes a redzone.
+ * There is no kernel heap yet, only kasan_test() calls this and free_object(). */
+pub fn alloc_object(addr: usize, size: usize, slot_size: usize) {
+    debug_assert!(size <= slot_size);
+    poison(addr, slot_size, POISON_HEAP_REDZONE);
+    unpoison(addr, size);
+}
+
+/* Called by an allocator instead of releasing the object. The object is poisoned
+ * and put into the quarantine, the object evicted from the quarantine (if any)
+ * is returned and should be really released by the caller. */
+pub fn free_object(addr: usize, slot_size: usize) -> Option<MemoryRegion> {
+    if is_tracked(addr, 1) && is_poisoned(addr, 1) {
+        report(addr, 1, true, return_address());
+    }
+    poison(addr, slot_size, POISON_FREED);
+
+    let mut quarantine = QUARANTINE.lock();
+    let slot = (quarantine.head + quarantine.count) % QUARANTINE_SIZE;
+    let evicted = if quarantine.count == QUARANTINE_SIZE {
+        let oldest = quarantine.objects[quarantine.head];
+        quarantine.head = (quarantine.head + 1) % QUARANTINE_SIZE;
+        Some(oldest)
+    } else {
+        quarantine.count += 1;
+        None
+    };
+    quarantine.objects[slot] = MemoryRegion { addr: addr, size: slot_size };
+    evicted
+}
+
+/* Poisons the lowest part of a stack so running out of it is reported
+ * instead of silently corrupting whatever lies below */
+pub fn poison_stack_guard(stack_bottom: usize, guard_size: usize) {
+    let guard = (stack_bottom + KASAN_GRANULE_SIZE - 1) & !(KASAN_GRANULE_SIZE - 1);
+    poison(guard, guard_size, POISON_STACK_GUARD);
+}
+
+pub fn check_read(addr: usize, size: usize) {
+    unsafe { kasan_check(addr, size, 0, return_address()) }
+}
+
+pub fn check_write(addr: usize, size: usize) {
+    unsafe { kasan_check(addr, size, 1, return_address()) }
+}
+
+/* Returns true if any byte of the range is not accessible */
+pub fn is_poisoned(addr: usize, size: usize) -> bool {
+    first_poisoned_byte(addr, size).is_some()
+}
+
+/* Called by kasan_check() in kasan.S for a bad access */
+#[no_mangle]
+pub extern fn kasan_report(addr: usize, size: usize, is_write: usize, ip: usize) -> ! {
+    report(addr, size, is_write != 0, ip)
+}
+
+fn report(addr: usize, size: usize, is_write: bool, ip: usize) -> ! {
+    /* Reporting itself should not be checked */
+    set_enabled(false);
+
+    let bad_addr = first_poisoned_byte(addr, size).unwrap_or(addr);
+    let shadow = unsafe { *shadow_ptr(bad_addr) };
+    println!("==================================================================");
+    println!("KASAN: {} in {} of size {} at addr 0x{:016x}",
+             bug_type(shadow),
+             if is_write { "write" } else { "read" },
+             size,
+             addr);
+    println!("  faulting instruction: 0x{:016x} {}", ip, symbols::Symbolized(ip));
+    println!("  first bad byte: 0x{:016x}, shadow byte 0x{:02x} at 0x{:016x}",
+             bad_addr, shadow, shadow_addr(bad_addr));
+    println!("==================================================================");
+    panic!("KASAN: invalid memory access");
+}
+
+fn bug_type(shadow: u8) -> &'static str {
+    match shadow {
+        POISON_HEAP_REDZONE   => "heap-out-of-bounds",
+        POISON_FREED          => "use-after-free",
+        POISON_STACK_LEFT |
+        POISON_STACK_MID |
+        POISON_STACK_RIGHT    => "stack-out-of-bounds",
+        POISON_STACK_GUARD    => "stack-overflow",
+        POISON_GLOBAL_REDZONE => "global-out-of-bounds",
+        1...7                 => "out-of-bounds",
+        _                     => "wild-memory-access"
+    }
+}
+
+fn first_poisoned_byte(addr: usize, size: usize) -> Option<usize> {
+    for byte in addr..addr + size {
+        let shadow = unsafe { *shadow_ptr(byte) } as i8;
+        if shadow != 0 && (shadow < 0 || (byte % KASAN_GRANULE_SIZE) as i8 >= shadow) {
+            return Some(byte);
+        }
+    }
+    None
+}
+
+fn is_tracked(addr: usize, size: usize) -> bool {
+    if size == 0 {
+        return false;
+    }
+    let regions = unsafe { &kasan_regions[..ptr::read_volatile(&kasan_regions_count)] };
+    regions.iter().any(|r| addr >= r[0] && addr + size - 1 <= r[1])
+}
+
+/* Return address of the function it is inlined into (relies on frame pointers) */
+#[inline(always)]
+fn return_address() -> usize {
+    let ip: usize;
+    unsafe {
+        asm!("mov 8(%rbp), $0"
+             : "=r" (ip)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+    }
+    ip
+}
+
+fn shadow_addr(addr: usize) -> usize {
+    (addr >> KASAN_SHADOW_SCALE).wrapping_add(KASAN_SHADOW_OFFSET)
+}
+
+fn shadow_ptr(addr: usize) -> *mut u8 {
+    shadow_addr(addr) as *mut u8
+}
+
+/*
+ * Entry points used by instrumented code, loads and stores are in kasan.S.
+ */
+
+#[no_mangle]
+pub extern fn __asan_poison_stack_memory(addr: usize, size: usize) {
+    poison(memory::page_addr(addr, KASAN_GRANULE_SIZE), size, POISON_STACK_MID);
+}
+
+#[no_mangle]
+pub extern fn __asan_unpoison_stack_memory(addr: usize, size: usize) {
+    unpoison(memory::page_addr(addr, KASAN_GRANULE_SIZE), size);
+}
+
+#[no_mangle]
+pub extern fn __asan_handle_no_return() {
+}
+
+pub fn kasan_test() {
+    /* Use a piece of the kernel image (which is tracked) as a fake heap slot */
+    static mut SLOT: [u64; 8] = [0; 8];
+    let addr = unsafe { &SLOT as *const _ as usize };
+
+    alloc_object(addr, 20, 64);
+    assert!(!is_poisoned(addr, 20));
+    assert!(is_poisoned(addr + 20, 1));
+    assert!(is_poisoned(addr + 24, 8));
+    assert_eq!("heap-out-of-bounds", bug_type(unsafe { *shadow_ptr(addr + 32) }));
+
+    assert!(free_object(addr, 64).is_none());
+    assert!(is_poisoned(addr, 1));
+    assert_eq!("use-after-free", bug_type(unsafe { *shadow_ptr(addr) }));
+
+    /* Leave the slot accessible again for the rest of the kernel */
+    unpoison(addr, 64);
+    let mut quarantine = QUARANTINE.lock();
+    quarantine.head = 0;
+    quarantine.count = 0;
+}
@@ -1,12 +1,37 @@
 /* Defines kernel layout in memory */
 
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
 use memory::MemoryRegion;
+use params::{Param, Value};
 
-/* Virtual memory where the kernel is loaded. Should be synchronized with linker.ld */
+/* Virtual memory where the kernel is linked. The bootstrap moves it up by a random
+ * offset (see kaslr_choose_offset in bootstrap.S). Should be synchronized with linker.ld */
 const KERNEL_VIRTUAL_BASE: usize = 0xFFFFFFFF80000000;
 
+kernel_param! {
+    /* Handled by the bootstrap, declared here so the command line knows it */
+    pub static NOKASLR: Param = Param::new("nokaslr", Value::Bool(false),
+        "Do not randomize the kernel placement");
+}
+
+/* Offset the kernel has been moved by from KERNEL_VIRTUAL_BASE */
+static KASLR_OFFSET: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Addresses below this one belong to the user space */
+pub const USER_SPACE_END: usize = 0x0000800000000000;
+
+/* Region for kernel pages which can be swapped out */
+pub const KERNEL_PAGEABLE_BASE: usize = 0xFFFFFF0000000000;
+
+/* Region where device memory is mapped (see paging::map_mmio) */
+pub const KERNEL_MMIO_BASE: usize = 0xFFFFFE8000000000;
+pub const KERNEL_MMIO_END: usize =  0xFFFFFF0000000000;
+
 // Symbols from linker
 extern {
+    static __link_bootstrap_begin: u8;
+    static __link_bootstrap_end: u8;
     static __link_kernel_begin_vaddr: u8;
     static __link_kernel_end_vaddr: u8;
     static __link_load_end: u8;
@@ -22,11 +47,25 @@ fn kernel_end_vaddr() -> usize {
 }
 
 fn kernel_begin_phys_addr() -> usize {
-    kernel_begin_vaddr() - KERNEL_VIRTUAL_BASE
+    to_physical_addr(kernel_begin_vaddr())
 }
 
 fn kernel_end_phys_addr() -> usize {
-    kernel_end_vaddr() - KERNEL_VIRTUAL_BASE
+    to_physical_addr(kernel_end_vaddr())
+}
+
+/* Should be called first thing with the offset passed by the bootstrap */
+pub fn init(kaslr_offset: usize) {
+    KASLR_OFFSET.store(kaslr_offset, Ordering::SeqCst);
+}
+
+pub fn kaslr_offset() -> usize {
+    KASLR_OFFSET.load(Ordering::Relaxed)
+}
+
+/* Virtual address the kernel has been moved to, physical 0 corresponds to it */
+pub fn kernel_virtual_base() -> usize {
+    KERNEL_VIRTUAL_BASE + kaslr_offset()
 }
 
 fn kernel_size() -> usize {
@@ -41,6 +80,17 @@ pub fn physical_kernel_placement() -> MemoryRegion {
     }
 }
 
+/* Returns region containing the bootstrap code together with the initial page tables
+ * and the stack set up by it. It is linked and placed at physical addresses. */
+pub fn physical_bootstrap_placement() -> MemoryRegion {
+    let begin = &__link_bootstrap_begin as *const u8 as usize;
+    let end = &__link_bootstrap_end as *const u8 as usize;
+    MemoryRegion {
+        addr: begin,
+        size: end - begin
+    }
+}
+
 /* Returns region containing the entire kernel in virtual memory */
 pub fn virtual_kernel_placement() -> MemoryRegion {
     MemoryRegion {
@@ -53,14 +103,18 @@ pub fn virtual_kernel_placement() -> MemoryRegion {
  * Should only be applied for kernel addresses, might be invalid
  * after remapping */
 pub fn to_physical_addr(virtual_addr: usize) -> usize {
-    virtual_addr - KERNEL_VIRTUAL_BASE
+    virtual_addr - kernel_virtual_base()
 }
 
-/* Returns region in physical memory corresponding to the specified virtual one.
- * Should only be applied to kernel-related memory which placement is known */
-pub fn to_physical_region(virtual_region: MemoryRegion) -> MemoryRegion {
-    MemoryRegion {
-        addr: to_physical_addr(virtual_region.addr),
-        size: virtual_region.size
-    }
+/* Returns virtual address the physical memory can be accessed at.
+ * The bootstrapper identity-maps the first 1Gb of the physical memory, all
+ * pages handed out by the physical memory manager are expected to lie there. */
+pub fn phys_to_virt(physical_addr: usize) -> usize {
+    debug_assert!(physical_addr < 0x40000000, "Physical address is not identity-mapped");
+    physical_addr
+}
+
+/* Inverse of phys_to_virt() */
+pub fn virt_to_phys(virtual_addr: usize) -> usize {
+    virtual_addr
 }
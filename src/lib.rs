@@ -2,69 +2,312 @@
 #![feature(const_fn)]
 #![feature(asm)]
 #![feature(zero_one)]
+#![feature(used)]
 #![no_std]
 
 extern crate rlibc;
 extern crate spin;
 
+/* Declares kernel parameters:
+ *
+ *     kernel_param! {
+ *         pub static NAME: Param = Param::new("name", Value::Int(0), "Description");
+ *     }
+ *
+ * They are placed in the .kernel_params section params.rs walks (see linker.ld),
+ * declaring a parameter is enough to make it known. Defined before the modules
+ * as the console has one too. Nothing refers to some of them by name (nokaslr is
+ * only read by the bootstrap), #[used] keeps them in the object file and KEEP()
+ * in linker.ld keeps the section when the linker collects garbage. */
+macro_rules! kernel_param {
+    () => {};
+    ($(#[$attr:meta])* pub static $name:ident: Param = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        #[used]
+        #[link_section = ".kernel_params"]
+        pub static $name: ::params::Param = $init;
+        kernel_param!($($rest)*);
+    };
+}
+
+#[macro_use]
+mod console;
+#[macro_use]
+mod percpu;
 mod bits;
 mod bochs;
 mod layout;
 mod bitmap;
 mod memory;
 mod multiboot;
-#[macro_use]
+mod multiboot2;
+mod boot_info;
 mod vga;
+mod serial;
+mod cmdline;
+mod params;
 mod cpuid;
+mod fpu;
+mod gdt;
+mod msr;
+mod idt;
+mod pic;
+mod irq;
+mod acpi;
+mod aml;
+mod apic;
+mod ioapic;
+mod pit;
+mod hpet;
+mod timer;
+mod rtc;
+mod smp;
+mod syscall;
+mod power;
+mod traps;
 mod paging;
 mod physical_memory_manager;
+mod meminfo;
+mod oom;
+mod port;
+mod block;
+mod ata;
+mod swap;
+mod elf;
+mod symbols;
+#[cfg(feature = "kasan")]
+mod kasan;
+
+use boot_info::{BootInfo, PhysicalMemoryMap};
+use params::{Param, Value};
 
-use multiboot::PhysicalMemoryMap;
+/* Messages with a level below the loglevel parameter are printed */
+const LOGLEVEL_INFO: u64 = 6;
+
+kernel_param! {
+    pub static LOGLEVEL: Param = Param::new("loglevel", Value::Int(7),
+        "Boot information is printed when greater than 6");
+
+    pub static TEST: Param = Param::new("test", Value::Str("all"),
+        "Self-tests to run: all, none or a comma separated list of names");
+}
 
 #[no_mangle]
-pub extern fn kernel_main(multiboot_info_ptr: *const multiboot::Info) -> ! {
+pub extern fn kernel_main(boot_info_addr: usize, boot_magic: u32, kaslr_offset: usize) -> ! {
     //bochs::magic_break();
+    layout::init(kaslr_offset);
 
     println!("");
+    println!("Kernel base: 0x{:016x} (KASLR offset 0x{:x}).",
+        layout::kernel_virtual_base(),
+        layout::kaslr_offset());
+
+    gdt::init(0);
+    percpu::init(0);
+    idt::init();
+    fpu::init(0);
+    syscall::init(0);
+    irq::init();
+    irq::enable();
+
     println!("Kernel placement: 0x{:016x} - 0x{:016x} ({} bytes).",
         layout::virtual_kernel_placement().addr,
         layout::virtual_kernel_placement().end_addr(),
         layout::virtual_kernel_placement().size);
 
+    let boot_info = match boot_info::init(boot_magic, boot_info_addr) {
+        Some(boot_info) => boot_info,
+        None => {
+            println!("Unsupported boot loader: unknown magic value 0x{:08x}.", boot_magic);
+            print!("The kernel should be started by a boot loader supporting one of:");
+            for protocol in boot_info::supported_protocols() {
+                print!(" {:?} (magic 0x{:08x})", protocol.protocol, protocol.magic);
+            }
+            println!(".");
+            halt();
+        }
+    };
+    params::init(boot_info.cmdline());
+
     print!("Running tests.. ");
-    bits::tests();
-    bitmap::bitmap_test();
+    if test_enabled("bits") {
+        bits::tests();
+    }
+    if test_enabled("bitmap") {
+        bitmap::bitmap_test();
+    }
+    if test_enabled("cmdline") {
+        cmdline::cmdline_test();
+    }
+    if test_enabled("traps") {
+        traps::traps_test();
+    }
+    if test_enabled("percpu") {
+        percpu::percpu_test();
+    }
+    if test_enabled("fpu") {
+        fpu::fpu_test();
+    }
+    if test_enabled("irq") {
+        irq::irq_test();
+    }
+    if test_enabled("power") {
+        power::power_test();
+    }
+    if test_enabled("aml") {
+        aml::aml_test(&mut acpi::NAMESPACE.lock());
+    }
     println!(" successfully.");
 
-    let multiboot_info = unsafe { &*multiboot_info_ptr };
-
-    display_cpu_info();
-    display_multiboot_info(multiboot_info);
+    if info_enabled() {
+        display_boot_info(boot_info);
+    }
 
     /* Initialize physical memory manager.
      * All memory allocations can be done only after this step!
      */
 
-    /* Warning: kernel stack and page tables set by bootstrap marked as free
-     * and might be allocated by the allocator.
-     * They should be reinitialized by the kernel asap.
-     */
-    physical_memory_manager::INSTANCE.lock().init(multiboot_info);
-    display_physical_memory_info();
+    /* The symbol tables are reserved by symbols::init() */
+    let symbol_tables = boot_info.elf_sections.as_ref().and_then(|sections| symbols::table_regions(sections).ok());
+    let boot_data = match symbol_tables {
+        Some(ref regions) => &regions[..],
+        None => &[][..]
+    };
+    physical_memory_manager::INSTANCE.lock().init(boot_info, boot_info.modules(), boot_data);
+    smp::reserve_trampoline();
+    if let Some(ref sections) = boot_info.elf_sections {
+        symbols::init(sections);
+    }
+    if info_enabled() {
+        display_physical_memory_info();
+    }
+
+    if !acpi::init(boot_info) {
+        println!("ACPI tables are not found.");
+    } else {
+        if info_enabled() {
+            acpi::display_acpi_info();
+        }
+        acpi::load_namespace();
+        if info_enabled() {
+            acpi::display_namespace_info();
+        }
+    }
+    init_interrupt_controller();
+    timer::init();
+    rtc::init();
+    smp::init();
+    if info_enabled() {
+        display_cpu_info();
+        fpu::display_fpu_info();
+        smp::display_smp_info();
+        syscall::display_syscall_info();
+        timer::display_timer_info();
+        rtc::display_rtc_info();
+    }
+
+    init_kasan();
+    init_swap();
 
     /* Some tests */
-    physical_memory_manager_test(multiboot_info);
+    if test_enabled("pmm") {
+        physical_memory_manager_test(boot_info);
+    }
+    if test_enabled("timer") {
+        timer::timer_test();
+    }
+    if test_enabled("rtc") {
+        rtc::rtc_test();
+    }
+    if test_enabled("syscall") {
+        syscall::syscall_test();
+    }
 
     unsafe {
         paging::reset_bootstrap_paging();
     }
 
+    if info_enabled() {
+        irq::display_irq_info();
+    }
+
     halt();
 }
 
+#[cfg(feature = "kasan")]
+fn init_kasan() {
+    kasan::init();
+    /* The boot stack lies in the lower half, which has no shadow, it is not checked */
+    gdt::poison_stack_guards();
+    syscall::poison_stack_guards();
+    smp::poison_stack_guards();
+    if test_enabled("kasan") {
+        kasan::kasan_test();
+    }
+}
+
+#[cfg(not(feature = "kasan"))]
+fn init_kasan() {
+}
+
+/* Switches from the PIC to the APIC if ACPI describes one */
+fn init_interrupt_controller() {
+    if let Some(madt) = acpi::madt() {
+        let has_io_apic = madt.entries().any(|entry| match entry {
+            acpi::MadtEntry::IoApic { .. } => true,
+            _ => false
+        });
+        /* Without an I/O APIC the PIC keeps working, the local APIC is left disabled */
+        if has_io_apic && apic::init(&madt) && ioapic::init(&madt) {
+            irq::switch_to_apic();
+        }
+    }
+    acpi::set_interrupt_model(irq::controller() == irq::Controller::Apic);
+
+    if info_enabled() {
+        match irq::controller() {
+            irq::Controller::Pic => println!("Interrupt controller: 8259 PIC."),
+            irq::Controller::Apic => {
+                println!("Interrupt controller: APIC in {:?} mode, id {}, version 0x{:x}.",
+                    apic::mode(), apic::id(), apic::version());
+                ioapic::display_io_apics_info();
+            }
+        }
+    }
+}
+
+fn init_swap() {
+    if swap::NOSWAP.get_bool() {
+        println!("Swap is disabled.");
+    } else if ata::PRIMARY_MASTER.identify() && swap::activate(&ata::PRIMARY_MASTER) {
+        if test_enabled("swap") {
+            swap::swap_test();
+        }
+    } else {
+        println!("No swap device found.");
+    }
+}
+
+fn info_enabled() -> bool {
+    LOGLEVEL.get_int() > LOGLEVEL_INFO
+}
+
+/* Checks the test parameter for the self-test name */
+fn test_enabled(name: &str) -> bool {
+    match TEST.get_str() {
+        "all" => true,
+        "none" => false,
+        tests => tests.split(',').any(|test| test == name)
+    }
+}
+
 fn display_cpu_info() {
     let vendor_id = cpuid::get_vendor_id();
     println!("CPU vendor: {}.", unsafe { ::core::str::from_utf8_unchecked(&vendor_id.vendor) });
+    let mut brand_buffer = [0; 48];
+    if let Some(brand) = cpuid::get_brand_string(&mut brand_buffer) {
+        println!("CPU model: {}.", brand);
+    }
     println!("CPUID: max basic function 0x{:x}, max extended function 0x{:x}.",
         vendor_id.max_basic_func,
         vendor_id.max_extended_func);
@@ -76,34 +319,84 @@ fn display_cpu_info() {
             cpu_info.model,
             cpu_info.family,
             cpu_info.cpu_type);
+        println!("CPU: brand index {}, clflush line {} bytes, {} logical processor ids, initial APIC id {}.",
+            cpu_info.brand_index(),
+            cpu_info.clflush_line_size(),
+            cpu_info.max_logical_processors(),
+            cpu_info.initial_apic_id());
+        let ext_features = cpuid::get_ext_features();
+        let struct_features = cpuid::get_struct_features();
         print!("CPU flags: ");
         cpuid::print_cpu_features(cpu_info.features1, cpuid::CPU_FEATURES1_MAP);
         cpuid::print_cpu_features(cpu_info.features2, cpuid::CPU_FEATURES2_MAP);
+        cpuid::print_cpu_features(ext_features.features1, cpuid::CPU_EXT_FEATURES1_MAP);
+        cpuid::print_cpu_features(ext_features.features2, cpuid::CPU_EXT_FEATURES2_MAP);
+        cpuid::print_cpu_features(struct_features.features1, cpuid::CPU_STRUCT_FEATURES1_MAP);
+        cpuid::print_cpu_features(struct_features.features2, cpuid::CPU_STRUCT_FEATURES2_MAP);
         println!("");
     }
+
+    let mut caches = cpuid::caches().peekable();
+    if caches.peek().is_some() {
+        println!("CPU caches:");
+        for cache in caches {
+            println!("  L{}{}: {} Kb, {}-way, {} byte lines, shared by {} logical processors.",
+                cache.level, cache.short_name(), cache.size / 1024, cache.ways, cache.line_size, cache.sharing);
+        }
+    }
+
+    let mut topology = cpuid::topology().peekable();
+    if topology.peek().is_some() {
+        print!("CPU topology:");
+        for level in topology {
+            print!(" {:?} ({} logical processors, x2APIC id shift {});",
+                level.level_type, level.logical_processors, level.shift);
+        }
+        println!(" x2APIC id {}.", cpuid::x2apic_id().unwrap_or(0));
+    }
 }
 
-fn display_multiboot_info(multiboot_info: &multiboot::Info) {
-    if multiboot_info.is_memory_size_available() {
-        println!("Lower memory: {}; Upper: {}; Total: {}.",
-            multiboot_info.get_lower_memory(),
-            multiboot_info.get_upper_memory(),
-            multiboot_info.get_lower_memory() + multiboot_info.get_upper_memory());
-    } else {
-        println!("No memory size available from multiboot.");
+fn display_boot_info(boot_info: &BootInfo) {
+    println!("Booted with {:?}.", boot_info.protocol);
+
+    match (boot_info.lower_memory, boot_info.upper_memory) {
+        (Some(lower), Some(upper)) => {
+            println!("Lower memory: {}; Upper: {}; Total: {}.", lower, upper, lower + upper);
+        },
+        _ => {
+            println!("No memory size available from the boot loader.");
+        }
     }
 
-    if multiboot_info.is_memory_map_available() {
+    if boot_info.available_memory_regions().next().is_some() {
         println!("Memory map:");
-        for region in multiboot_info.available_memory_regions() {
+        for region in boot_info.available_memory_regions() {
             println!("  0x{:016x} - 0x{:016x} ({} bytes): AVAILABLE",
                 region.addr,
                 region.end_addr(),
                 region.size);
         }
-        println!("Memory available in total: {} bytes.", multiboot_info.total_memory_available());
+        println!("Memory available in total: {} bytes.", boot_info.total_memory_available());
     } else {
-        println!("No memory map available from multiboot.");
+        println!("No memory map available from the boot loader.");
+    }
+
+    if !boot_info.cmdline().is_empty() {
+        println!("Command line: {}", boot_info.cmdline());
+    }
+    params::print_params();
+    for module in boot_info.modules() {
+        println!("Boot module: 0x{:016x} - 0x{:016x} ({} bytes) \"{}\".",
+            module.region.addr,
+            module.region.end_addr(),
+            module.region.size,
+            module.cmdline());
+    }
+    if let Some(fb) = boot_info.framebuffer {
+        println!("Framebuffer: 0x{:x}, {}x{}x{}.", fb.addr, fb.width, fb.height, fb.bpp);
+    }
+    if boot_info.acpi_rsdp().is_some() {
+        println!("ACPI RSDP provided by the boot loader.");
     }
 }
 
@@ -113,23 +406,37 @@ fn display_physical_memory_info() {
              mgr.total_pages_count(),
              mgr.free_pages_count(),
              mgr.total_pages_count() - mgr.free_pages_count());
+    drop(mgr);
+    meminfo::print_report();
 }
 
-fn physical_memory_manager_test(multiboot_info: &multiboot::Info) {
-    let lower_mem_pages = multiboot_info.get_lower_memory() / (memory::PAGE_SIZE as u64);
-    let mut mgr = physical_memory_manager::INSTANCE.lock();
-    for _ in 0..lower_mem_pages-1 {
-        let p = mgr.alloc_page().unwrap();
-    }
-    let p1 = mgr.alloc_page().unwrap();
-    let p2 = mgr.alloc_page().unwrap();
-    let p3 = mgr.alloc_page().unwrap();
-    let p4 = mgr.alloc_page().unwrap();
-    let p5 = mgr.alloc_page().unwrap();
+fn physical_memory_manager_test(boot_info: &BootInfo) {
+    use meminfo::Consumer;
+    use physical_memory_manager::alloc_page;
+
+    /* Boot loaders are not required to report lower memory */
+    if let Some(lower_memory) = boot_info.lower_memory {
+        let lower_mem_pages = lower_memory / (memory::PAGE_SIZE as u64);
+        for _ in 1..lower_mem_pages {
+            alloc_page(Consumer::Other);
+        }
+    }
+    let p1 = alloc_page(Consumer::Other);
+    let p2 = alloc_page(Consumer::Other);
+    let p3 = alloc_page(Consumer::Other);
+    let p4 = alloc_page(Consumer::Other);
+    let p5 = alloc_page(Consumer::Other);
     println!("pages: 0x{:x}, 0x{:x}, 0x{:x}, 0x{:x}, 0x{:x}.", p1, p2, p3, p4, p5);
 }
 
+/* Spins forever unless the halt parameter asks to power off or reboot */
 fn halt() -> ! {
+    match power::ON_HALT.get_str() {
+        "poweroff" => power::shutdown(),
+        "reboot" => power::reboot(),
+        _ => {}
+    }
+
     let syms = b"|\\-//||\\-//";
     let mut pos = 0;
     loop {
@@ -145,8 +452,9 @@ fn halt() -> ! {
 
 #[lang = "panic_fmt"]
 extern fn panic_fmt(args: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
-    use core::fmt::Write;
     println!("Panic in {}:{}", file, line);
-    vga::CONSOLE.lock().write_fmt(args).unwrap();
+    console::write_fmt(args);
+    println!("");
+    symbols::print_backtrace();
     halt()
 }
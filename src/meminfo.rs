@@ -0,0 +1,143 @@
+/*
+ * Accounting of physical memory usage per consumer.
+ *
+ * Every page handed out by the physical memory manager is charged to one of
+ * the consumers below, so the numbers can be compared between kernel builds.
+ */
+
+use spin::Mutex;
+
+use memory::PAGE_SIZE;
+use physical_memory_manager;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Consumer {
+    KernelImage = 0,
+    PmmBitmap   = 1,
+    PageTables  = 2,
+    Heap        = 3,
+    SlabCaches  = 4,
+    Stacks      = 5,
+    Dma         = 6,
+    UserPages   = 7,
+    KasanShadow = 8,
+    BootModules = 9,
+    PageCaches  = 10,
+    Other       = 11,
+}
+
+pub const CONSUMERS_COUNT: usize = 12;
+
+pub static CONSUMERS: [Consumer; CONSUMERS_COUNT] = [
+    Consumer::KernelImage,
+    Consumer::PmmBitmap,
+    Consumer::PageTables,
+    Consumer::Heap,
+    Consumer::SlabCaches,
+    Consumer::Stacks,
+    Consumer::Dma,
+    Consumer::UserPages,
+    Consumer::KasanShadow,
+    Consumer::BootModules,
+    Consumer::PageCaches,
+    Consumer::Other,
+];
+
+impl Consumer {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Consumer::KernelImage => "Kernel image",
+            Consumer::PmmBitmap   => "PMM bitmap",
+            Consumer::PageTables  => "Page tables",
+            Consumer::Heap        => "Heap",
+            Consumer::SlabCaches  => "Slab caches",
+            Consumer::Stacks      => "Stacks",
+            Consumer::Dma         => "DMA",
+            Consumer::UserPages   => "User pages",
+            Consumer::KasanShadow => "KASAN shadow",
+            Consumer::BootModules => "Boot modules",
+            Consumer::PageCaches  => "Per-CPU page caches",
+            Consumer::Other       => "Other",
+        }
+    }
+}
+
+/* Pages currently charged to every consumer */
+static USAGE: Mutex<[u64; CONSUMERS_COUNT]> = Mutex::new([0; CONSUMERS_COUNT]);
+
+/* Point-in-time view of the memory usage */
+#[derive(Clone, Copy)]
+pub struct MemInfo {
+    pub total_pages: u64,
+    pub free_pages:  u64,
+    pub usage:       [u64; CONSUMERS_COUNT],
+}
+
+impl MemInfo {
+    pub fn pages_used_by(&self, consumer: Consumer) -> u64 {
+        self.usage[consumer as usize]
+    }
+
+    /* Pages which are occupied but not charged to anybody (reserved by firmware,
+     * holes in the memory map etc.) */
+    pub fn reserved_pages(&self) -> u64 {
+        let accounted = self.usage.iter().fold(0, |acc, pages| acc + pages);
+        self.total_pages - self.free_pages - accounted
+    }
+}
+
+pub fn charge(consumer: Consumer, pages: u64) {
+    USAGE.lock()[consumer as usize] += pages;
+}
+
+pub fn uncharge(consumer: Consumer, pages: u64) {
+    let mut usage = USAGE.lock();
+    debug_assert!(usage[consumer as usize] >= pages, "Uncharging more pages than charged");
+    usage[consumer as usize] -= pages;
+}
+
+/* Moves pages from one consumer to another, the total stays the same */
+pub fn transfer(from: Consumer, to: Consumer, pages: u64) {
+    let mut usage = USAGE.lock();
+    debug_assert!(usage[from as usize] >= pages, "Transferring more pages than charged");
+    usage[from as usize] -= pages;
+    usage[to as usize] += pages;
+}
+
+pub fn pages_used_by(consumer: Consumer) -> u64 {
+    USAGE.lock()[consumer as usize]
+}
+
+pub fn snapshot() -> MemInfo {
+    /* Pages are only charged or uncharged together with allocating or freeing
+     * them under the memory manager lock. Without it they are only transferred
+     * between consumers (see transfer()), which keeps the sum. So copying the
+     * usage under the memory manager lock gives consistent counters. */
+    let mgr = physical_memory_manager::INSTANCE.lock();
+    let usage = *USAGE.lock();
+
+    MemInfo {
+        total_pages: mgr.total_pages_count(),
+        free_pages:  mgr.free_pages_count(),
+        usage:       usage,
+    }
+}
+
+pub fn print_report() {
+    let info = snapshot();
+    println!("MemTotal: {} kB, MemFree: {} kB, Reserved: {} kB.",
+             to_kb(info.total_pages),
+             to_kb(info.free_pages),
+             to_kb(info.reserved_pages()));
+    for consumer in CONSUMERS.iter() {
+        let pages = info.pages_used_by(*consumer);
+        if pages > 0 {
+            print!("{}: {} kB; ", consumer.name(), to_kb(pages));
+        }
+    }
+    println!("");
+}
+
+fn to_kb(pages: u64) -> u64 {
+    pages * (PAGE_SIZE as u64) / 1024
+}
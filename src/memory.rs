@@ -15,6 +15,11 @@ impl MemoryRegion {
         addr >= self.addr && addr < self.addr + self.size
     }
 
+    /* True if the regions have at least one byte in common */
+    pub fn overlaps(&self, other: &MemoryRegion) -> bool {
+        self.addr < other.addr + other.size && other.addr < self.addr + self.size
+    }
+
     /* Returns address of the last byte in the region */
     pub fn end_addr(&self) -> usize {
         self.addr + self.size - 1
@@ -0,0 +1,43 @@
+/* Access to model specific registers */
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+
+/* Extended features: long mode, no-execute pages, syscall */
+pub const IA32_EFER: u32 = 0xC0000080;
+
+/* Enables syscall and sysret */
+pub const EFER_SCE: u64 = 1 << 0;
+
+/* Segments, entry point and masked rflags of syscall, see syscall.rs */
+pub const IA32_STAR: u32 =  0xC0000081;
+pub const IA32_LSTAR: u32 = 0xC0000082;
+pub const IA32_FMASK: u32 = 0xC0000084;
+
+/* Base of the %gs segment and the value swapgs exchanges it with, see percpu.rs */
+pub const IA32_GS_BASE: u32 =        0xC0000101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xC0000102;
+
+/* The local APIC timer fires when the TSC reaches this value, see apic.rs */
+pub const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+/* Local APIC registers in x2APIC mode start here, see apic.rs */
+pub const IA32_X2APIC_BASE: u32 = 0x800;
+
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    asm!("rdmsr"
+         : "={eax}" (low), "={edx}" (high)
+         : "{ecx}" (msr)
+         : /* clobbers */
+         : "volatile");
+    ((high as u64) << 32) | low as u64
+}
+
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    asm!("wrmsr"
+         : /* outputs */
+         : "{ecx}" (msr), "{eax}" (value as u32), "{edx}" ((value >> 32) as u32)
+         : "memory"
+         : "volatile");
+}
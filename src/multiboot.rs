@@ -3,22 +3,11 @@ use core::iter::Iterator;
 use memory::MemoryRegion;
 
 /*
- * General memory information structures which (ideally) should be moved to a separate module.
+ * MultiBoot boot information.
  */
 
-pub trait PhysicalMemoryMap {
-    fn available_memory_regions<'a>(&'a self) -> MemoryRegionIterator<'a>;
-
-    /* Total amount of memory available for kernel (in bytes) */
-    fn total_memory_available(&self) -> usize {
-        self.available_memory_regions()
-            .fold(0, |acc,r| acc + r.size)
-    }
-}
-
-/*
- * And this is MultiBoot part.
- */
+/* This should be in %eax when the kernel is started by a multiboot loader */
+pub const BOOTLOADER_MAGIC: u32 = 0x2BADB002;
 
 /*
  * Constants for Info::flags.
@@ -30,10 +19,8 @@ const INFO_MEMORY: u32 =           0x00000001;
 #[allow(dead_code)]
 const INFO_BOOTDEV: u32 =          0x00000002;
 /* is the command-line defined? */
-#[allow(dead_code)]
 const INFO_CMDLINE: u32 =          0x00000004;
 /* are there modules to do something with? */
-#[allow(dead_code)]
 const INFO_MODS: u32 =             0x00000008;
 
 /* These next two are mutually exclusive */
@@ -42,7 +29,6 @@ const INFO_MODS: u32 =             0x00000008;
 #[allow(dead_code)]
 const INFO_AOUT_SYMS: u32 =        0x00000010;
 /* is there an ELF section header table? */
-#[allow(dead_code)]
 const INFO_ELF_SHDR: u32 =         0x00000020;
 
 /* is there a full memory map? */
@@ -75,13 +61,30 @@ const MEMORY_RESERVED: u32 =       2;
 /* The section header table for ELF. */
 #[repr(C, packed)]
 pub struct ElfSectionHeaderTable {
-    num:   u32,
-    size:  u32,
-    addr:  u32,
-    shndx: u32,
+    pub num:   u32,
+    pub size:  u32,
+    pub addr:  u32,
+    pub shndx: u32,
 }
 
 
+/* Entry of the boot modules list */
+#[repr(C)]
+struct ModuleEntry {
+    /* the memory used goes from bytes 'mod_start' to 'mod_end-1' inclusive */
+    mod_start: u32,
+    mod_end:   u32,
+    /* Module command line */
+    cmdline:   u32,
+    pad:       u32,
+}
+
+/* A boot module loaded by the boot loader */
+pub struct Module {
+    pub region:  MemoryRegion,
+    pub cmdline: &'static str,
+}
+
 #[repr(C, packed)]
 pub struct MemoryMapEntry
 {
@@ -148,6 +151,18 @@ impl Info {
         (self.flags & INFO_MEM_MAP) == INFO_MEM_MAP
     }
 
+    pub fn is_cmdline_available(&self) -> bool {
+        (self.flags & INFO_CMDLINE) == INFO_CMDLINE
+    }
+
+    pub fn are_modules_available(&self) -> bool {
+        (self.flags & INFO_MODS) == INFO_MODS
+    }
+
+    pub fn is_elf_section_header_table_available(&self) -> bool {
+        (self.flags & INFO_ELF_SHDR) == INFO_ELF_SHDR
+    }
+
     pub fn get_lower_memory(&self) -> u64 {
         (self.mem_lower as u64) * 1024
     }
@@ -155,11 +170,32 @@ impl Info {
     pub fn get_upper_memory(&self) -> u64 {
         (self.mem_upper as u64) * 1024
     }
-}
 
+    pub fn cmdline(&self) -> Option<&'static str> {
+        if self.is_cmdline_available() {
+            Some(unsafe { c_str(self.cmdline as usize) })
+        } else {
+            None
+        }
+    }
+
+    pub fn elf_sections(&self) -> Option<&ElfSectionHeaderTable> {
+        if self.is_elf_section_header_table_available() {
+            Some(&self.elf_sec)
+        } else {
+            None
+        }
+    }
 
-impl PhysicalMemoryMap for Info {
-    fn available_memory_regions<'a>(&'a self) -> MemoryRegionIterator<'a> {
+    pub fn modules(&self) -> ModuleIterator {
+        let count = if self.are_modules_available() { self.mods_count } else { 0 };
+        ModuleIterator {
+            ptr: self.mods_addr as usize,
+            end: self.mods_addr as usize + (count as usize) * ::core::mem::size_of::<ModuleEntry>()
+        }
+    }
+
+    pub fn available_memory_regions<'a>(&'a self) -> MemoryRegionIterator<'a> {
         if !self.is_memory_map_available() {
             panic!("No memory map available in multiboot info");
         }
@@ -196,3 +232,40 @@ impl<'a> Iterator for MemoryRegionIterator<'a> {
         None
     }
 }
+
+pub struct ModuleIterator {
+    ptr: usize,
+    end: usize,
+}
+
+impl Iterator for ModuleIterator {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ptr >= self.end {
+            return None;
+        }
+        let entry = unsafe { &*(self.ptr as *const ModuleEntry) };
+        self.ptr = self.ptr + ::core::mem::size_of::<ModuleEntry>();
+        Some(Module {
+            region: MemoryRegion {
+                addr: entry.mod_start as usize,
+                size: (entry.mod_end - entry.mod_start) as usize
+            },
+            cmdline: unsafe { c_str(entry.cmdline as usize) }
+        })
+    }
+}
+
+/* Returns null-terminated string located at the physical address */
+unsafe fn c_str(addr: usize) -> &'static str {
+    if addr == 0 {
+        return "";
+    }
+    let mut len = 0;
+    while *((addr + len) as *const u8) != 0 {
+        len = len + 1;
+    }
+    let bytes = ::core::slice::from_raw_parts(addr as *const u8, len);
+    ::core::str::from_utf8(bytes).unwrap_or("")
+}
@@ -0,0 +1,335 @@
+use core::iter::Iterator;
+use core::mem::size_of;
+use core::slice;
+
+use memory::MemoryRegion;
+
+/*
+ * Multiboot2 boot information: a sequence of tags following a fixed header.
+ */
+
+/* This should be in %eax when the kernel is started by a multiboot2 loader */
+pub const BOOTLOADER_MAGIC: u32 = 0x36d76289;
+
+/* Tags are aligned on this boundary */
+const TAG_ALIGN: usize = 8;
+
+/*
+ * Constants for Tag::tag_type.
+ */
+
+const TAG_TYPE_END: u32 =              0;
+pub const TAG_TYPE_CMDLINE: u32 =      1;
+pub const TAG_TYPE_BOOT_LOADER_NAME: u32 = 2;
+pub const TAG_TYPE_MODULE: u32 =       3;
+pub const TAG_TYPE_BASIC_MEMINFO: u32 = 4;
+pub const TAG_TYPE_MMAP: u32 =         6;
+pub const TAG_TYPE_FRAMEBUFFER: u32 =  8;
+pub const TAG_TYPE_ELF_SECTIONS: u32 = 9;
+pub const TAG_TYPE_ACPI_OLD: u32 =     14;
+pub const TAG_TYPE_ACPI_NEW: u32 =     15;
+pub const TAG_TYPE_EFI_MMAP: u32 =     17;
+
+/*
+ * Constants for MemoryMapEntry::mem_type.
+ */
+
+const MEMORY_AVAILABLE: u32 =      1;
+
+/*
+ * Constants for EfiMemoryDescriptor::mem_type which describe memory
+ * free for use after boot services are exited.
+ */
+
+const EFI_LOADER_CODE: u32 =           1;
+const EFI_LOADER_DATA: u32 =           2;
+const EFI_BOOT_SERVICES_CODE: u32 =    3;
+const EFI_BOOT_SERVICES_DATA: u32 =    4;
+const EFI_CONVENTIONAL_MEMORY: u32 =   7;
+
+const EFI_PAGE_SIZE: u64 = 4096;
+
+
+#[repr(C)]
+pub struct Info {
+    total_size: u32,
+    reserved:   u32,
+}
+
+#[repr(C)]
+pub struct Tag {
+    pub tag_type: u32,
+    pub size:     u32,
+}
+
+#[repr(C)]
+pub struct BasicMemInfoTag {
+    tag:       Tag,
+    pub mem_lower: u32,
+    pub mem_upper: u32,
+}
+
+#[repr(C)]
+struct MemoryMapTag {
+    tag:           Tag,
+    entry_size:    u32,
+    entry_version: u32,
+}
+
+#[repr(C, packed)]
+struct MemoryMapEntry {
+    addr:     u64,
+    len:      u64,
+    mem_type: u32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct ModuleTag {
+    tag:       Tag,
+    mod_start: u32,
+    mod_end:   u32,
+}
+
+#[repr(C, packed)]
+pub struct FramebufferTag {
+    tag:         Tag,
+    pub addr:    u64,
+    pub pitch:   u32,
+    pub width:   u32,
+    pub height:  u32,
+    pub bpp:     u8,
+    pub fb_type: u8,
+    reserved:    u16,
+}
+
+#[repr(C)]
+pub struct ElfSectionsTag {
+    tag:       Tag,
+    pub num:   u32,
+    pub entsize: u32,
+    pub shndx: u32,
+}
+
+#[repr(C)]
+struct EfiMemoryMapTag {
+    tag:          Tag,
+    descr_size:   u32,
+    descr_version: u32,
+}
+
+#[repr(C)]
+struct EfiMemoryDescriptor {
+    mem_type:        u32,
+    pad:             u32,
+    physical_start:  u64,
+    virtual_start:   u64,
+    pages_count:     u64,
+    attribute:       u64,
+}
+
+/* A boot module loaded by the boot loader */
+pub struct Module {
+    pub region:  MemoryRegion,
+    pub cmdline: &'static str,
+}
+
+impl Info {
+    pub fn tags(&self) -> TagIterator {
+        let start = self as *const Info as usize;
+        TagIterator {
+            ptr: start + size_of::<Info>(),
+            end: start + self.total_size as usize,
+        }
+    }
+
+    fn find_tag(&self, tag_type: u32) -> Option<&'static Tag> {
+        self.tags().find(|tag| tag.tag_type == tag_type)
+    }
+
+    pub fn cmdline(&self) -> Option<&'static str> {
+        self.find_tag(TAG_TYPE_CMDLINE).map(|tag| tag_string(tag, size_of::<Tag>()))
+    }
+
+    pub fn boot_loader_name(&self) -> Option<&'static str> {
+        self.find_tag(TAG_TYPE_BOOT_LOADER_NAME).map(|tag| tag_string(tag, size_of::<Tag>()))
+    }
+
+    pub fn basic_meminfo(&self) -> Option<&'static BasicMemInfoTag> {
+        self.find_tag(TAG_TYPE_BASIC_MEMINFO).map(|tag| unsafe { &*(tag as *const Tag as *const BasicMemInfoTag) })
+    }
+
+    pub fn is_memory_map_available(&self) -> bool {
+        self.find_tag(TAG_TYPE_MMAP).is_some()
+    }
+
+    /* Available regions from the memory map tag */
+    pub fn available_memory_regions(&self) -> MemoryMapIterator {
+        match self.find_tag(TAG_TYPE_MMAP) {
+            Some(tag) => {
+                let mmap = unsafe { &*(tag as *const Tag as *const MemoryMapTag) };
+                let start = tag as *const Tag as usize;
+                MemoryMapIterator {
+                    ptr:        start + size_of::<MemoryMapTag>(),
+                    end:        start + tag.size as usize,
+                    entry_size: mmap.entry_size as usize,
+                }
+            },
+            None => MemoryMapIterator { ptr: 0, end: 0, entry_size: 0 }
+        }
+    }
+
+    pub fn is_efi_memory_map_available(&self) -> bool {
+        self.find_tag(TAG_TYPE_EFI_MMAP).is_some()
+    }
+
+    /* Regions usable by the kernel from the EFI memory map tag */
+    pub fn efi_available_memory_regions(&self) -> EfiMemoryMapIterator {
+        match self.find_tag(TAG_TYPE_EFI_MMAP) {
+            Some(tag) => {
+                let mmap = unsafe { &*(tag as *const Tag as *const EfiMemoryMapTag) };
+                let start = tag as *const Tag as usize;
+                EfiMemoryMapIterator {
+                    ptr:        start + size_of::<EfiMemoryMapTag>(),
+                    end:        start + tag.size as usize,
+                    descr_size: mmap.descr_size as usize,
+                }
+            },
+            None => EfiMemoryMapIterator { ptr: 0, end: 0, descr_size: 0 }
+        }
+    }
+
+    pub fn modules(&self) -> ModuleIterator {
+        ModuleIterator { tags: self.tags() }
+    }
+
+    pub fn framebuffer(&self) -> Option<&'static FramebufferTag> {
+        self.find_tag(TAG_TYPE_FRAMEBUFFER).map(|tag| unsafe { &*(tag as *const Tag as *const FramebufferTag) })
+    }
+
+    /* Returns the tag and the address of section headers following it */
+    pub fn elf_sections(&self) -> Option<(&'static ElfSectionsTag, usize)> {
+        self.find_tag(TAG_TYPE_ELF_SECTIONS).map(|tag| {
+            let elf_tag = unsafe { &*(tag as *const Tag as *const ElfSectionsTag) };
+            (elf_tag, tag as *const Tag as usize + size_of::<ElfSectionsTag>())
+        })
+    }
+
+    /* Copy of the ACPI RSDP made by the boot loader. The newer (ACPI 2.0+) one is preferred. */
+    pub fn acpi_rsdp(&self) -> Option<&'static [u8]> {
+        self.find_tag(TAG_TYPE_ACPI_NEW)
+            .or_else(|| self.find_tag(TAG_TYPE_ACPI_OLD))
+            .map(|tag| tag_bytes(tag, size_of::<Tag>()))
+    }
+}
+
+/* Returns content of the tag after the specified offset */
+fn tag_bytes(tag: &'static Tag, offset: usize) -> &'static [u8] {
+    let start = tag as *const Tag as usize + offset;
+    unsafe { slice::from_raw_parts(start as *const u8, tag.size as usize - offset) }
+}
+
+/* Returns null-terminated string stored in the tag at the specified offset */
+fn tag_string(tag: &'static Tag, offset: usize) -> &'static str {
+    let bytes = tag_bytes(tag, offset);
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    ::core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+pub struct TagIterator {
+    ptr: usize,
+    end: usize,
+}
+
+impl Iterator for TagIterator {
+    type Item = &'static Tag;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ptr + size_of::<Tag>() > self.end {
+            return None;
+        }
+        let tag = unsafe { &*(self.ptr as *const Tag) };
+        if tag.tag_type == TAG_TYPE_END {
+            return None;
+        }
+        self.ptr = (self.ptr + tag.size as usize + TAG_ALIGN - 1) & !(TAG_ALIGN - 1);
+        Some(tag)
+    }
+}
+
+pub struct MemoryMapIterator {
+    ptr:        usize,
+    end:        usize,
+    entry_size: usize,
+}
+
+impl Iterator for MemoryMapIterator {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ptr + self.entry_size <= self.end && self.entry_size > 0 {
+            let entry = unsafe { &*(self.ptr as *const MemoryMapEntry) };
+            self.ptr = self.ptr + self.entry_size;
+            if entry.mem_type == MEMORY_AVAILABLE {
+                return Some(MemoryRegion {
+                    addr: entry.addr as usize,
+                    size: entry.len as usize
+                });
+            }
+        }
+        None
+    }
+}
+
+pub struct EfiMemoryMapIterator {
+    ptr:        usize,
+    end:        usize,
+    descr_size: usize,
+}
+
+impl Iterator for EfiMemoryMapIterator {
+    type Item = MemoryRegion;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ptr + self.descr_size <= self.end && self.descr_size > 0 {
+            let descr = unsafe { &*(self.ptr as *const EfiMemoryDescriptor) };
+            self.ptr = self.ptr + self.descr_size;
+            match descr.mem_type {
+                EFI_LOADER_CODE | EFI_LOADER_DATA |
+                EFI_BOOT_SERVICES_CODE | EFI_BOOT_SERVICES_DATA |
+                EFI_CONVENTIONAL_MEMORY => {
+                    return Some(MemoryRegion {
+                        addr: descr.physical_start as usize,
+                        size: (descr.pages_count * EFI_PAGE_SIZE) as usize
+                    });
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+pub struct ModuleIterator {
+    tags: TagIterator,
+}
+
+impl Iterator for ModuleIterator {
+    type Item = Module;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(tag) = self.tags.next() {
+            if tag.tag_type == TAG_TYPE_MODULE {
+                let module = unsafe { &*(tag as *const Tag as *const ModuleTag) };
+                return Some(Module {
+                    region: MemoryRegion {
+                        addr: module.mod_start as usize,
+                        size: (module.mod_end - module.mod_start) as usize
+                    },
+                    cmdline: tag_string(tag, size_of::<ModuleTag>()),
+                });
+            }
+        }
+        None
+    }
+}
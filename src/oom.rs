@@ -0,0 +1,190 @@
+/*
+ * Memory pressure handling: shrinkers, watermarks and out-of-memory reports.
+ *
+ * Caches register shrinkers which the physical memory allocator calls when free
+ * memory drops below the low watermark (reclaiming up to the high one) and
+ * when an allocation can not be satisfied at all. Only when shrinkers can not
+ * give anything back the out-of-memory report is printed and the kernel panics.
+ */
+
+use spin::Mutex;
+
+use memory::PAGE_SIZE;
+use meminfo::{self, Consumer, CONSUMERS, CONSUMERS_COUNT};
+
+const MAX_SHRINKERS: usize = 16;
+const MAX_NOTIFIERS: usize = 8;
+
+pub struct Shrinker {
+    pub name: &'static str,
+    /* Returns amount of pages which could be reclaimed */
+    pub count: fn() -> u64,
+    /* Tries to release the requested amount of pages, returns amount of pages released.
+     * Called without any allocator locks held. */
+    pub scan: fn(pages: u64) -> u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryPressure {
+    /* Free memory dropped below the low watermark */
+    Low,
+    /* Free memory got back above the high watermark */
+    Normal,
+}
+
+struct Watermarks {
+    low:   u64,
+    high:  u64,
+    /* Set when the low watermark was crossed and the high one is not reached yet */
+    under_pressure: bool,
+}
+
+static SHRINKERS: Mutex<([Option<&'static Shrinker>; MAX_SHRINKERS], usize)> =
+    Mutex::new(([None; MAX_SHRINKERS], 0));
+
+static NOTIFIERS: Mutex<([Option<fn(MemoryPressure)>; MAX_NOTIFIERS], usize)> =
+    Mutex::new(([None; MAX_NOTIFIERS], 0));
+
+static WATERMARKS: Mutex<Watermarks> = Mutex::new(Watermarks {
+    low:            0,
+    high:           0,
+    under_pressure: false,
+});
+
+pub fn register_shrinker(shrinker: &'static Shrinker) {
+    let mut shrinkers = SHRINKERS.lock();
+    let count = shrinkers.1;
+    assert!(count < MAX_SHRINKERS, "Too many shrinkers registered");
+    shrinkers.0[count] = Some(shrinker);
+    shrinkers.1 = count + 1;
+}
+
+pub fn register_pressure_notifier(notifier: fn(MemoryPressure)) {
+    let mut notifiers = NOTIFIERS.lock();
+    let count = notifiers.1;
+    assert!(count < MAX_NOTIFIERS, "Too many memory pressure notifiers registered");
+    notifiers.0[count] = Some(notifier);
+    notifiers.1 = count + 1;
+}
+
+/* Watermarks are amounts of free pages */
+pub fn set_watermarks(low: u64, high: u64) {
+    debug_assert!(low <= high, "Low watermark is above the high one");
+    let mut watermarks = WATERMARKS.lock();
+    watermarks.low = low;
+    watermarks.high = high;
+}
+
+/* Called by the allocator after pages were released */
+pub fn pages_released(free_pages: u64) {
+    let recovered = {
+        let mut watermarks = WATERMARKS.lock();
+        let recovered = watermarks.under_pressure && free_pages >= watermarks.high;
+        if recovered {
+            watermarks.under_pressure = false;
+        }
+        recovered
+    };
+
+    if recovered {
+        notify(MemoryPressure::Normal);
+    }
+}
+
+/* Called by the allocator after pages were allocated. Might run shrinkers,
+ * so it must be called without the allocator lock held. */
+pub fn check_watermarks(free_pages: u64) {
+    let (event, reclaim_target) = {
+        let mut watermarks = WATERMARKS.lock();
+        if !watermarks.under_pressure && free_pages < watermarks.low {
+            watermarks.under_pressure = true;
+            (Some(MemoryPressure::Low), watermarks.high - free_pages)
+        } else if watermarks.under_pressure && free_pages >= watermarks.high {
+            watermarks.under_pressure = false;
+            (Some(MemoryPressure::Normal), 0)
+        } else {
+            (None, 0)
+        }
+    };
+
+    if let Some(event) = event {
+        notify(event);
+    }
+    if reclaim_target > 0 {
+        reclaim(reclaim_target);
+    }
+}
+
+/* Asks shrinkers to release the amount of pages, returns amount actually released */
+pub fn reclaim(pages: u64) -> u64 {
+    let mut released = 0;
+    let mut index = 0;
+    while released < pages {
+        /* The lock is not held while shrinking: shrinkers are free to allocate or
+         * release memory and even register other shrinkers */
+        let shrinker = {
+            let shrinkers = SHRINKERS.lock();
+            if index >= shrinkers.1 {
+                break;
+            }
+            shrinkers.0[index].unwrap()
+        };
+        if (shrinker.count)() > 0 {
+            released += (shrinker.scan)(pages - released);
+        }
+        index += 1;
+    }
+    released
+}
+
+/* Nothing could be reclaimed: describe the situation and give up */
+pub fn out_of_memory(consumer: Consumer) -> ! {
+    let info = meminfo::snapshot();
+    println!("Out of memory: allocation for {} failed, {} pages free, {} reserved.",
+             consumer.name(),
+             info.free_pages,
+             info.reserved_pages());
+
+    /* Consumers sorted by their usage, largest first */
+    let mut order = CONSUMERS;
+    for i in 1..CONSUMERS_COUNT {
+        let mut j = i;
+        while j > 0 && info.pages_used_by(order[j - 1]) < info.pages_used_by(order[j]) {
+            order.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+
+    print!("Top consumers: ");
+    for consumer in order.iter().take(5) {
+        let pages = info.pages_used_by(*consumer);
+        if pages > 0 {
+            print!("{} {} kB; ", consumer.name(), pages * (PAGE_SIZE as u64) / 1024);
+        }
+    }
+    println!("");
+
+    let shrinkers = SHRINKERS.lock();
+    for shrinker in shrinkers.0[..shrinkers.1].iter() {
+        let shrinker = shrinker.unwrap();
+        println!("  shrinker {}: {} pages reclaimable", shrinker.name, (shrinker.count)());
+    }
+
+    /* There are no processes to kill yet */
+    panic!("Out of memory");
+}
+
+fn notify(event: MemoryPressure) {
+    let mut index = 0;
+    loop {
+        let notifier = {
+            let notifiers = NOTIFIERS.lock();
+            if index >= notifiers.1 {
+                break;
+            }
+            notifiers.0[index].unwrap()
+        };
+        notifier(event);
+        index += 1;
+    }
+}
@@ -1,8 +1,32 @@
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use spin::Mutex;
+use apic;
+use layout;
+use memory::PAGE_SIZE;
+use percpu;
 use physical_memory_manager;
+use meminfo::Consumer;
+use smp::{self, MAX_CPUS};
 
 type PageTable = [PageTableEntry; 512];
 
+/* Page table entry flags (see docs/pte_formats.txt) */
+pub const PAGE_PRESENT: usize       = 1 << 0;
+pub const PAGE_WRITABLE: usize      = 1 << 1;
+pub const PAGE_USER: usize          = 1 << 2;
+pub const PAGE_WRITE_THROUGH: usize = 1 << 3;
+pub const PAGE_NO_CACHE: usize      = 1 << 4;
+pub const PAGE_ACCESSED: usize      = 1 << 5;
+pub const PAGE_DIRTY: usize         = 1 << 6;
+pub const PAGE_HUGE: usize          = 1 << 7;
+pub const PAGE_GLOBAL: usize        = 1 << 8;
+/* Software bit: the entry is not present and keeps a swap slot in the address bits */
+pub const PAGE_SWAPPED: usize       = 1 << 9;
+pub const PAGE_NO_EXECUTE: usize    = 1 << 63;
+
+/* Bits 12..51 of an entry keep the physical address */
+const PAGE_ADDR_MASK: usize = 0x000FFFFFFFFFF000;
+
 // TODO: this should be probably replaced with current_process
 // structure holding page directory together with other information.
 // Of course, only if the OS is process-based.
@@ -10,6 +34,15 @@ type PageTable = [PageTableEntry; 512];
 /* Currently active PML4 (physical address) */
 static PML4: Mutex<usize> = Mutex::new(0);
 
+/* Next free address in the MMIO region */
+static MMIO_NEXT: Mutex<usize> = Mutex::new(layout::KERNEL_MMIO_BASE);
+
+/* Serializes TLB shootdowns, the request is the address and a bit per
+ * processor which has not invalidated it yet */
+static SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+static SHOOTDOWN_ADDR: AtomicUsize = ATOMIC_USIZE_INIT;
+static SHOOTDOWN_PENDING: AtomicUsize = ATOMIC_USIZE_INIT;
+
 pub unsafe fn set_cr3(addr: usize) {
     asm!("mov $0, %cr3"
          : /* outputs */
@@ -19,19 +52,233 @@ pub unsafe fn set_cr3(addr: usize) {
 
 }
 
-pub fn map(physical_addr: usize, virtual_addr: usize) {
-    // TODO
+pub fn get_cr3() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("mov %cr3, $0"
+             : "=r" (addr)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+    }
+    addr
+}
+
+/* Drops TLB entry for the specified virtual address */
+pub fn invalidate_page(virtual_addr: usize) {
+    unsafe {
+        asm!("invlpg ($0)"
+             : /* outputs */
+             : "r" (virtual_addr)
+             : "memory"
+             : "volatile");
+    }
+}
+
+/* Drops TLB entries for the virtual address on all online processors and waits
+ * until they are gone. Other processors do that in an interrupt handler, so the
+ * caller should not hold locks which they might wait for with interrupts disabled. */
+pub fn invalidate_page_everywhere(virtual_addr: usize) {
+    invalidate_page(virtual_addr);
+    if smp::online_cpus_count() == 1 {
+        return;
+    }
+
+    let _guard;
+    loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            _guard = guard;
+            break;
+        }
+        /* The owner might wait for this processor with interrupts disabled */
+        answer_shootdown();
+        unsafe { asm!("pause" : : : "memory" : "volatile") };
+    }
+
+    let this_cpu = percpu::cpu_id();
+    let mut targets = 0;
+    for cpu in 0..MAX_CPUS {
+        if cpu != this_cpu && smp::online_apic_id(cpu).is_some() {
+            targets |= 1 << cpu;
+        }
+    }
+    SHOOTDOWN_ADDR.store(virtual_addr, Ordering::SeqCst);
+    SHOOTDOWN_PENDING.store(targets, Ordering::SeqCst);
+    for cpu in 0..MAX_CPUS {
+        if targets & (1 << cpu) != 0 {
+            apic::send_fixed(smp::online_apic_id(cpu).unwrap(), apic::TLB_SHOOTDOWN_VECTOR);
+        }
+    }
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) != 0 {
+        unsafe { asm!("pause" : : : "memory" : "volatile") };
+    }
+}
+
+/* Called by trap_handler() for the TLB shootdown IPI */
+pub fn handle_shootdown() {
+    answer_shootdown();
+    apic::eoi();
+}
+
+fn answer_shootdown() {
+    let bit = 1 << percpu::cpu_id();
+    if SHOOTDOWN_PENDING.load(Ordering::SeqCst) & bit != 0 {
+        invalidate_page(SHOOTDOWN_ADDR.load(Ordering::SeqCst));
+        SHOOTDOWN_PENDING.fetch_and(!bit, Ordering::SeqCst);
+    }
+}
+
+/* Maps a single page at virtual_addr to physical_addr in the active address space.
+ * Missing intermediate tables are allocated from the physical memory manager,
+ * so the caller must not hold its lock. */
+pub fn map(physical_addr: usize, virtual_addr: usize, flags: usize) {
+    debug_assert_eq!(0, physical_addr % PAGE_SIZE);
+    debug_assert_eq!(0, virtual_addr % PAGE_SIZE);
+
+    let mut spare_table = None;
+    loop {
+        {
+            let mut pml4 = PML4.lock();
+            if let Some(pte) = unsafe { walk(&mut *pml4, virtual_addr, Some(&mut spare_table), flags & PAGE_USER) } {
+                pte.set(physical_addr, flags | PAGE_PRESENT);
+                invalidate_page(virtual_addr);
+                break;
+            }
+        }
+        /* One more table is required. It is allocated without the lock held
+         * because reclaiming memory might need to modify page tables too. */
+        spare_table = Some(physical_memory_manager::alloc_page(Consumer::PageTables));
+    }
+
+    if let Some(table_addr) = spare_table {
+        physical_memory_manager::free_page(table_addr, Consumer::PageTables);
+    }
+}
+
+/* Maps device registers uncached to the MMIO region. Returns the virtual address
+ * corresponding to physical_addr. Mappings are never removed. */
+pub fn map_mmio(physical_addr: usize, size: usize) -> usize {
+    map_to_mmio_region(physical_addr, size,
+                       PAGE_WRITABLE | PAGE_NO_CACHE | PAGE_WRITE_THROUGH | PAGE_NO_EXECUTE | PAGE_GLOBAL)
+}
+
+/* Maps data the firmware has left in memory (like ACPI tables) read-only and
+ * cached to the MMIO region. The memory might lie anywhere, not only in the
+ * identity-mapped part. Mappings are never removed. */
+pub fn map_firmware(physical_addr: usize, size: usize) -> usize {
+    map_to_mmio_region(physical_addr, size, PAGE_NO_EXECUTE | PAGE_GLOBAL)
+}
+
+fn map_to_mmio_region(physical_addr: usize, size: usize, flags: usize) -> usize {
+    let first_page = physical_addr & !(PAGE_SIZE - 1);
+    let pages_size = (physical_addr + size - first_page + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    let virtual_base = {
+        let mut next = MMIO_NEXT.lock();
+        let base = *next;
+        assert!(base + pages_size <= layout::KERNEL_MMIO_END, "MMIO region is exhausted");
+        *next += pages_size;
+        base
+    };
+
+    let mut offset = 0;
+    while offset < pages_size {
+        map(first_page + offset, virtual_base + offset, flags);
+        offset += PAGE_SIZE;
+    }
+    virtual_base + (physical_addr - first_page)
 }
 
 pub fn unmap(virtual_addr: usize) {
-    // TODO
+    debug_assert_eq!(0, virtual_addr % PAGE_SIZE);
+
+    let mut pml4 = PML4.lock();
+    if let Some(pte) = unsafe { walk(&mut *pml4, virtual_addr, None, 0) } {
+        pte.clear();
+        invalidate_page(virtual_addr);
+    }
+}
+
+/* Returns physical address the virtual one is mapped to, if it is mapped */
+pub fn translate(virtual_addr: usize) -> Option<usize> {
+    let mut pml4 = PML4.lock();
+    match unsafe { walk(&mut *pml4, virtual_addr, None, 0) } {
+        Some(ref pte) if pte.is_present() => Some(pte.phys_addr() + virtual_addr % PAGE_SIZE),
+        _ => None
+    }
+}
+
+/* Runs the function on the last level entry for the mapped virtual address.
+ * Returns None if there is no page table for the address. */
+pub fn update_pte<F, R>(virtual_addr: usize, f: F) -> Option<R> where F: FnOnce(&mut PageTableEntry) -> R {
+    let mut pml4 = PML4.lock();
+    let result = unsafe { walk(&mut *pml4, virtual_addr, None, 0) }.map(f);
+    invalidate_page(virtual_addr);
+    result
+}
+
+/* The same as update_pte() but returns None without waiting if the page tables
+ * are locked, for the page fault handler which might interrupt their owner.
+ * Some(None) means that the page is not mapped. */
+pub fn try_update_pte<F, R>(virtual_addr: usize, f: F) -> Option<Option<R>> where F: FnOnce(&mut PageTableEntry) -> R {
+    let mut pml4 = match PML4.try_lock() {
+        Some(pml4) => pml4,
+        None => return None
+    };
+    let result = unsafe { walk(&mut *pml4, virtual_addr, None, 0) }.map(f);
+    invalidate_page(virtual_addr);
+    Some(result)
+}
+
+/* Index of an entry for the virtual address in a page table of the specified level
+ * (0 is the page table itself, 3 is PML4) */
+fn table_index(virtual_addr: usize, level: usize) -> usize {
+    (virtual_addr >> (12 + 9 * level)) & 0x1ff
+}
+
+unsafe fn table_at(physical_addr: usize) -> &'static mut PageTable {
+    &mut *(layout::phys_to_virt(physical_addr) as *mut PageTable)
+}
+
+/* Walks the active page tables down to the last level entry for the virtual address.
+ * A missing table is created from the spare page if one is given (the spare is
+ * taken), otherwise None is returned as soon as a non-present table is met. */
+unsafe fn walk(pml4: &mut usize, virtual_addr: usize, mut spare_table: Option<&mut Option<usize>>, user: usize) -> Option<&'static mut PageTableEntry> {
+    if *pml4 == 0 {
+        *pml4 = get_cr3() & PAGE_ADDR_MASK;
+    }
+
+    let mut table = table_at(*pml4);
+    for level in (1..4).rev() {
+        let next_table_addr = {
+            let entry = &mut table[table_index(virtual_addr, level)];
+            if !entry.is_present() {
+                let table_addr = match spare_table {
+                    Some(ref mut spare) => match spare.take() {
+                        Some(table_addr) => table_addr,
+                        None => return None
+                    },
+                    None => return None
+                };
+                for pte in table_at(table_addr).iter_mut() {
+                    pte.clear();
+                }
+                entry.set(table_addr, PAGE_PRESENT | PAGE_WRITABLE | user);
+            } else if entry.is_huge() {
+                panic!("Virtual address 0x{:016x} is already covered by a huge page", virtual_addr);
+            }
+            entry.phys_addr()
+        };
+        table = table_at(next_table_addr);
+    }
+
+    Some(&mut table[table_index(virtual_addr, 0)])
 }
 
 /* Resets the identity memory mapping prepared for us by the bootstrapper.
  * After this function finishes the kernel and the physical memory allocator
  * will only be mapped to their virtual placements. */
 pub unsafe fn reset_bootstrap_paging() {
-    let pml4_addr = physical_memory_manager::INSTANCE.lock().alloc_page().unwrap();
+    let pml4_addr = physical_memory_manager::alloc_page(Consumer::PageTables);
     let mut pml4 = &mut *(pml4_addr as *mut PageTable);
 
     /* XXX: here it is assumed that the allocated pages lie in the memory which
@@ -57,7 +304,13 @@ impl PageTableEntry {
     }
 
     pub fn clear(&mut self) {
+        let &mut PageTableEntry(ref mut pte) = self;
+        *pte = 0;
+    }
 
+    pub fn set(&mut self, phys_address: usize, flags: usize) {
+        let &mut PageTableEntry(ref mut pte) = self;
+        *pte = (phys_address & PAGE_ADDR_MASK) | flags;
     }
 
     pub fn is_present(&self) -> bool {
@@ -65,6 +318,11 @@ impl PageTableEntry {
         *pte & 1 == 1
     }
 
+    pub fn is_huge(&self) -> bool {
+        let &PageTableEntry(ref pte) = self;
+        *pte & PAGE_HUGE == PAGE_HUGE
+    }
+
     pub fn present(&mut self) {
         let &mut PageTableEntry(ref mut pte) = self;
         *pte = *pte | 1;
@@ -75,6 +333,43 @@ impl PageTableEntry {
         *pte = *pte & !1;
     }
 
+    pub fn is_accessed(&self) -> bool {
+        let &PageTableEntry(ref pte) = self;
+        *pte & PAGE_ACCESSED == PAGE_ACCESSED
+    }
+
+    pub fn clear_accessed(&mut self) {
+        let &mut PageTableEntry(ref mut pte) = self;
+        *pte = *pte & !PAGE_ACCESSED;
+    }
+
+    pub fn flags(&self) -> usize {
+        let &PageTableEntry(ref pte) = self;
+        *pte & !PAGE_ADDR_MASK
+    }
+
+    /* Swapped out page: not present, the slot is kept instead of the address,
+     * the original flags are preserved to restore the mapping on swap in */
+    pub fn is_swapped(&self) -> bool {
+        let &PageTableEntry(ref pte) = self;
+        *pte & (PAGE_PRESENT | PAGE_SWAPPED) == PAGE_SWAPPED
+    }
+
+    pub fn set_swapped(&mut self, slot: usize) {
+        let &mut PageTableEntry(ref mut pte) = self;
+        *pte = (*pte & !PAGE_ADDR_MASK & !PAGE_PRESENT) | PAGE_SWAPPED | ((slot << 12) & PAGE_ADDR_MASK);
+    }
+
+    pub fn swap_slot(&self) -> usize {
+        let &PageTableEntry(ref pte) = self;
+        (*pte & PAGE_ADDR_MASK) >> 12
+    }
+
+    pub fn phys_addr(&self) -> usize {
+        let &PageTableEntry(ref pte) = self;
+        *pte & PAGE_ADDR_MASK
+    }
+
     pub fn set_phys_addr(&mut self, phys_address: usize) {
         let &mut PageTableEntry(ref mut pte) = self;
         *pte = (*pte & ((1 << 12) - 1)) | phys_address;
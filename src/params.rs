@@ -0,0 +1,127 @@
+/*
+ * Kernel parameters set from the command line.
+ *
+ * A subsystem declares its parameter as a static Param with a default value
+ * using kernel_param! (see lib.rs). The linker collects all of them in one
+ * section, so there is no table to maintain. init() assigns values found in
+ * the command line, before that the defaults are seen.
+ */
+
+use core::mem;
+use core::slice;
+
+use spin::Mutex;
+
+use cmdline;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Int(u64),
+    /* Points into the command line kept by boot_info */
+    Str(&'static str),
+}
+
+pub struct Param {
+    pub name: &'static str,
+    pub help: &'static str,
+    value:    Mutex<Value>,
+}
+
+extern {
+    /* Defined by linker.ld around the .kernel_params section */
+    static __kernel_params_begin: u8;
+    static __kernel_params_end: u8;
+}
+
+impl Param {
+    pub const fn new(name: &'static str, default: Value, help: &'static str) -> Param {
+        Param {
+            name:  name,
+            help:  help,
+            value: Mutex::new(default),
+        }
+    }
+
+    pub fn get(&self) -> Value {
+        *self.value.lock()
+    }
+
+    pub fn get_bool(&self) -> bool {
+        match self.get() {
+            Value::Bool(value) => value,
+            _ => panic!("Parameter {} is not a boolean", self.name)
+        }
+    }
+
+    pub fn get_int(&self) -> u64 {
+        match self.get() {
+            Value::Int(value) => value,
+            _ => panic!("Parameter {} is not an integer", self.name)
+        }
+    }
+
+    pub fn get_str(&self) -> &'static str {
+        match self.get() {
+            Value::Str(value) => value,
+            _ => panic!("Parameter {} is not a string", self.name)
+        }
+    }
+
+    /* Parses the value according to the type of the default one.
+     * Returns false if it does not fit. */
+    fn set(&self, value: Option<&'static str>) -> bool {
+        let mut current = self.value.lock();
+        let parsed = match (*current, value) {
+            (Value::Bool(_), _) => cmdline::parse_bool(value).map(Value::Bool),
+            (Value::Int(_), Some(value)) => cmdline::parse_int(value).map(Value::Int),
+            (Value::Str(_), Some(value)) => Some(Value::Str(value)),
+            (_, None) => None
+        };
+        match parsed {
+            Some(parsed) => {
+                *current = parsed;
+                true
+            },
+            None => false
+        }
+    }
+}
+
+/* All parameters known to the kernel */
+fn all() -> &'static [Param] {
+    unsafe {
+        let begin = &__kernel_params_begin as *const u8 as usize;
+        let end = &__kernel_params_end as *const u8 as usize;
+        slice::from_raw_parts(begin as *const Param, (end - begin) / mem::size_of::<Param>())
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Param> {
+    all().iter().find(|param| param.name == name)
+}
+
+/* Assigns parameters from the command line, unknown and malformed ones are reported and ignored */
+pub fn init(cmdline: &'static str) {
+    for arg in cmdline::args(cmdline) {
+        match find(arg.name) {
+            Some(param) => {
+                if !param.set(arg.value) {
+                    println!("Invalid value \"{}\" of kernel parameter \"{}\" ignored.", arg.value.unwrap_or(""), arg.name);
+                }
+            },
+            None => println!("Unknown kernel parameter \"{}\" ignored.", arg.name)
+        }
+    }
+}
+
+pub fn print_params() {
+    println!("Kernel parameters:");
+    for param in all() {
+        match param.get() {
+            Value::Bool(value) => println!("  {}={} ({})", param.name, value, param.help),
+            Value::Int(value) => println!("  {}={} ({})", param.name, value, param.help),
+            Value::Str(value) => println!("  {}=\"{}\" ({})", param.name, value, param.help),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+/*
+ * Per-CPU data.
+ *
+ * Every CPU has an area starting with struct CpuArea, IA32_GS_BASE points to
+ * it while the CPU runs kernel code. IA32_KERNEL_GS_BASE keeps the user value
+ * which swapgs exchanges with the kernel one on entries from user mode. Fields
+ * of the area are read and written with single %gs-relative instructions, so
+ * neither interrupts nor moving to another CPU can split the access.
+ *
+ * Other modules declare their per-CPU variables with percpu!, such a variable
+ * is an array with a slot for every CPU indexed by the CPU number taken from
+ * the area. Slots are only accessed through with() which disables interrupts
+ * for the time the closure runs.
+ */
+
+use core::cell::UnsafeCell;
+
+use irq;
+use msr::{self, wrmsr};
+use smp::MAX_CPUS;
+
+/* Should be synchronized with the offsets below and PERCPU_* in kernel.h.
+ * Fields are mostly accessed through %gs, not by name */
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct CpuArea {
+    /* Address of the area itself, %gs-relative addressing cannot give it */
+    self_addr:    usize,
+    cpu:          usize,
+    /* Address of the task running on the CPU, 0 until there is a scheduler */
+    current_task: usize,
+    /* Hardware interrupt handlers being run */
+    irq_nesting:  usize,
+    /* Top of the stack the syscall entry switches to (see syscall.rs) */
+    kernel_stack: usize,
+    /* User %rsp saved by the syscall entry */
+    user_rsp:     usize,
+}
+
+const SELF_ADDR_OFFSET: usize =    0;
+const CPU_OFFSET: usize =          8;
+const CURRENT_TASK_OFFSET: usize = 16;
+const IRQ_NESTING_OFFSET: usize =  24;
+const KERNEL_STACK_OFFSET: usize = 32;
+
+const EMPTY_AREA: CpuArea = CpuArea {
+    self_addr:    0,
+    cpu:          0,
+    current_task: 0,
+    irq_nesting:  0,
+    kernel_stack: 0,
+    user_rsp:     0,
+};
+
+static mut AREAS: [CpuArea; MAX_CPUS] = [EMPTY_AREA; MAX_CPUS];
+
+/* Declares statics with a separate value for every CPU:
+ *
+ *     percpu! {
+ *         static NAME: Type = INITIAL_VALUE;
+ *     }
+ *
+ * The initial value is copied to every slot, so the type must be Copy. */
+macro_rules! percpu {
+    () => {};
+    ($(#[$attr:meta])* static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        static $name: ::percpu::PerCpu<$t> = ::percpu::PerCpu::new([$init; ::smp::MAX_CPUS]);
+        percpu!($($rest)*);
+    };
+    ($(#[$attr:meta])* pub static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        pub static $name: ::percpu::PerCpu<$t> = ::percpu::PerCpu::new([$init; ::smp::MAX_CPUS]);
+        percpu!($($rest)*);
+    };
+}
+
+pub struct PerCpu<T> {
+    values:   UnsafeCell<[T; MAX_CPUS]>,
+    /* Set while with() runs on the CPU, catches nested access to the same slot */
+    borrowed: UnsafeCell<[bool; MAX_CPUS]>,
+}
+
+/* A CPU only touches its own slot and only with interrupts disabled */
+unsafe impl<T> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> PerCpu<T> {
+        PerCpu {
+            values:   UnsafeCell::new(values),
+            borrowed: UnsafeCell::new([false; MAX_CPUS]),
+        }
+    }
+
+    /* Runs the closure on the value of the current CPU. Interrupts are disabled
+     * meanwhile, so neither handlers nor other tasks can see the value changing.
+     * Accessing the same variable from inside the closure panics. */
+    pub fn with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        match self.try_with(f) {
+            Some(result) => result,
+            None => panic!("Nested access to a per-CPU variable")
+        }
+    }
+
+    /* The same as with() but returns None if the value is already being accessed
+     * on this CPU, e.g. by the code an exception handler has interrupted */
+    pub fn try_with<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        irq::without_interrupts(|| {
+            let cpu = cpu_id();
+            unsafe {
+                {
+                    let borrowed = &mut (*self.borrowed.get())[cpu];
+                    if *borrowed {
+                        return None;
+                    }
+                    *borrowed = true;
+                }
+                let result = f(&mut (*self.values.get())[cpu]);
+                (*self.borrowed.get())[cpu] = false;
+                Some(result)
+            }
+        })
+    }
+
+    /* Runs the closure on the value of every CPU in turn. with() does not guard
+     * values of other CPUs: the caller must exclude all other accesses to the
+     * variable meanwhile, e.g. with a lock its users take. */
+    pub unsafe fn for_each<F: FnMut(&mut T)>(&self, mut f: F) {
+        for value in (*self.values.get()).iter_mut() {
+            f(value);
+        }
+    }
+}
+
+impl<T: Copy> PerCpu<T> {
+    pub fn get(&self) -> T {
+        self.with(|value| *value)
+    }
+
+    pub fn set(&self, value: T) {
+        self.with(|slot| *slot = value)
+    }
+}
+
+/* Points GS base of the current CPU to its area. Must be called before
+ * anything per-CPU is used: right after the GDT is loaded, since loading
+ * segment registers might reset the base. */
+pub fn init(cpu: usize) {
+    assert!(cpu < MAX_CPUS);
+    unsafe {
+        let area = &mut AREAS[cpu];
+        area.self_addr = area as *mut CpuArea as usize;
+        area.cpu = cpu;
+        wrmsr(msr::IA32_GS_BASE, area.self_addr as u64);
+        /* Nothing runs in user mode yet, its GS base is 0 */
+        wrmsr(msr::IA32_KERNEL_GS_BASE, 0);
+    }
+}
+
+/* Number of the current CPU, 0 is the bootstrap processor (see smp.rs).
+ * The task can move to another CPU right after, unless interrupts are disabled. */
+pub fn cpu_id() -> usize {
+    unsafe { read(CPU_OFFSET) }
+}
+
+pub fn current_task() -> usize {
+    unsafe { read(CURRENT_TASK_OFFSET) }
+}
+
+pub fn set_current_task(task: usize) {
+    unsafe { write(CURRENT_TASK_OFFSET, task) }
+}
+
+/* Use gdt::set_kernel_stack() which also updates the TSS */
+pub fn set_kernel_stack(rsp: usize) {
+    unsafe { write(KERNEL_STACK_OFFSET, rsp) }
+}
+
+/* Called by the trap handler around hardware interrupt handlers */
+pub fn irq_enter() {
+    unsafe {
+        asm!("incq %gs:($0)"
+             : /* outputs */
+             : "r" (IRQ_NESTING_OFFSET)
+             : "memory"
+             : "volatile");
+    }
+}
+
+pub fn irq_exit() {
+    debug_assert!(irq_nesting() > 0, "Leaving an interrupt handler never entered");
+    unsafe {
+        asm!("decq %gs:($0)"
+             : /* outputs */
+             : "r" (IRQ_NESTING_OFFSET)
+             : "memory"
+             : "volatile");
+    }
+}
+
+pub fn irq_nesting() -> usize {
+    unsafe { read(IRQ_NESTING_OFFSET) }
+}
+
+/* True inside a hardware interrupt handler */
+pub fn in_interrupt() -> bool {
+    irq_nesting() > 0
+}
+
+unsafe fn read(offset: usize) -> usize {
+    let value: usize;
+    asm!("movq %gs:($1), $0"
+         : "=r" (value)
+         : "r" (offset)
+         : "memory"
+         : "volatile");
+    value
+}
+
+unsafe fn write(offset: usize, value: usize) {
+    asm!("movq $0, %gs:($1)"
+         : /* outputs */
+         : "r" (value), "r" (offset)
+         : "memory"
+         : "volatile");
+}
+
+pub fn percpu_test() {
+    percpu! {
+        static TEST_COUNTER: usize = 0;
+    }
+
+    assert_eq!(cpu_id(), 0);
+    let area = unsafe { read(SELF_ADDR_OFFSET) };
+    assert_eq!(area, unsafe { &AREAS[0] as *const CpuArea as usize });
+
+    assert!(!in_interrupt());
+    irq_enter();
+    irq_enter();
+    assert_eq!(irq_nesting(), 2);
+    irq_exit();
+    irq_exit();
+    assert!(!in_interrupt());
+
+    let task = current_task();
+    set_current_task(0x1234);
+    assert_eq!(unsafe { AREAS[0].current_task }, 0x1234);
+    set_current_task(task);
+
+    assert_eq!(TEST_COUNTER.get(), 0);
+    TEST_COUNTER.with(|counter| *counter += 5);
+    TEST_COUNTER.set(TEST_COUNTER.get() * 2);
+    assert_eq!(TEST_COUNTER.get(), 10);
+    assert_eq!(TEST_COUNTER.with(|_| TEST_COUNTER.try_with(|_| ())), None);
+    assert_eq!(unsafe { (*TEST_COUNTER.values.get())[1] }, 0);
+
+    let mut total = 0;
+    unsafe {
+        TEST_COUNTER.for_each(|counter| total += *counter);
+    }
+    assert_eq!(total, 10);
+}
@@ -3,12 +3,20 @@
  *
  */
 
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 use layout;
 use memory::{self, PAGE_SIZE, MemoryRegion};
-use multiboot::PhysicalMemoryMap;
+use boot_info::{BootModule, PhysicalMemoryMap};
 use bitmap::Bitmap;
+use meminfo::{self, Consumer};
+use oom;
+use params::{Param, Value};
+
+kernel_param! {
+    pub static MEM_LIMIT: Param = Param::new("mem", Value::Int(0),
+        "Physical memory above this address is not used, 0 for no limit");
+}
 
 pub static INSTANCE: Mutex<PhysicalMemoryManager> = Mutex::new(PhysicalMemoryManager {
     bitmap:            None,
@@ -16,6 +24,228 @@ pub static INSTANCE: Mutex<PhysicalMemoryManager> = Mutex::new(PhysicalMemoryMan
     free_pages_count:  0
 });
 
+/* Allocates a page for the consumer. When memory is short shrinkers are asked
+ * to reclaim some, if that does not help the out-of-memory report is printed
+ * and the kernel panics. Must be called without the INSTANCE lock held. */
+pub fn alloc_page(consumer: Consumer) -> usize {
+    match try_alloc_page(consumer) {
+        Some(addr) => addr,
+        None => oom::out_of_memory(consumer)
+    }
+}
+
+/* The same as alloc_page() but returns None if nothing could be reclaimed */
+pub fn try_alloc_page(consumer: Consumer) -> Option<usize> {
+    loop {
+        /* The allocator lock is only taken when the cache is empty */
+        let (page, free_pages) = PAGE_CACHE.with(|cache| {
+            let _caches = PAGE_CACHES_LOCK.read();
+            let mut free_pages = None;
+            if cache.count == 0 {
+                let mut mgr = INSTANCE.lock();
+                cache.refill(&mut mgr);
+                free_pages = Some(available_pages(&mgr));
+            }
+            (cache.take(consumer), free_pages)
+        });
+
+        if let Some(free_pages) = free_pages {
+            oom::check_watermarks(free_pages);
+        }
+        if page.is_some() {
+            return page;
+        }
+
+        /* Pages cached by other CPUs are the last resort */
+        if oom::reclaim(1) == 0 && drain_page_caches() == 0 {
+            return None;
+        }
+    }
+}
+
+/* Releases a page allocated with alloc_page() */
+pub fn free_page(addr: usize, consumer: Consumer) {
+    let free_pages = PAGE_CACHE.with(|cache| {
+        let _caches = PAGE_CACHES_LOCK.read();
+        let mut free_pages = None;
+        if cache.count == PAGE_CACHE_SIZE {
+            let mut mgr = INSTANCE.lock();
+            cache.drain(&mut mgr);
+            free_pages = Some(available_pages(&mgr));
+        }
+        cache.put(addr, consumer);
+        free_pages
+    });
+    /* Never reclaims, so it is safe to release pages from shrinkers */
+    if let Some(free_pages) = free_pages {
+        oom::pages_released(free_pages);
+    }
+}
+
+/* For the page fault handler, which might interrupt the allocator itself: never
+ * waits for a lock and does not reclaim memory, returns None instead */
+pub fn alloc_page_nowait(consumer: Consumer) -> Option<usize> {
+    let page = PAGE_CACHE.try_with(|cache| {
+        let _caches = match PAGE_CACHES_LOCK.try_read() {
+            Some(guard) => guard,
+            None => return None
+        };
+        if cache.count == 0 {
+            match INSTANCE.try_lock() {
+                Some(mut mgr) => cache.refill(&mut mgr),
+                None => return None
+            }
+        }
+        cache.take(consumer)
+    });
+    page.and_then(|page| page)
+}
+
+/* Releases a page allocated with alloc_page_nowait() without waiting.
+ * Returns false if that was not possible, the page stays allocated then. */
+pub fn free_page_nowait(addr: usize, consumer: Consumer) -> bool {
+    let freed = PAGE_CACHE.try_with(|cache| {
+        let _caches = match PAGE_CACHES_LOCK.try_read() {
+            Some(guard) => guard,
+            None => return false
+        };
+        if cache.count == PAGE_CACHE_SIZE {
+            match INSTANCE.try_lock() {
+                Some(mut mgr) => cache.drain(&mut mgr),
+                None => return false
+            }
+        }
+        cache.put(addr, consumer);
+        true
+    });
+    freed == Some(true)
+}
+
+/* Returns pages cached by all CPUs to the bitmap, returns amount of pages
+ * released. Must be called without the INSTANCE lock held. */
+pub fn drain_page_caches() -> u64 {
+    let (drained, free_pages) = {
+        let _caches = PAGE_CACHES_LOCK.write();
+        let mut mgr = INSTANCE.lock();
+        let mut drained = 0;
+        /* Other CPUs only touch their caches under the read lock */
+        unsafe {
+            PAGE_CACHE.for_each(|cache| drained += cache.drain_all(&mut mgr));
+        }
+        (drained, available_pages(&mgr))
+    };
+    if drained > 0 {
+        oom::pages_released(free_pages);
+    }
+    drained
+}
+
+/* Cached pages are counted as free for the watermarks: they are handed out
+ * without any reclaim and drained before giving up on an allocation */
+fn available_pages(mgr: &PhysicalMemoryManager) -> u64 {
+    mgr.free_pages_count() + meminfo::pages_used_by(Consumer::PageCaches)
+}
+
+/*
+ * Per-CPU page caches.
+ *
+ * Single pages are allocated from and freed to a small cache of the current
+ * CPU, the bitmap is only touched to move a batch of pages in or out. Cached
+ * pages are occupied in the bitmap and charged to Consumer::PageCaches.
+ *
+ * A CPU works with its own cache holding PAGE_CACHES_LOCK for reading, so CPUs
+ * do not wait for each other. Taking it for writing excludes them all and lets
+ * the caches of every CPU be drained when memory runs out.
+ */
+
+const PAGE_CACHE_SIZE: usize =  16;
+/* Pages moved between the cache and the bitmap at once */
+const PAGE_CACHE_BATCH: usize = 8;
+
+#[derive(Clone, Copy)]
+struct PageCache {
+    pages: [usize; PAGE_CACHE_SIZE],
+    count: usize,
+}
+
+const EMPTY_PAGE_CACHE: PageCache = PageCache {
+    pages: [0; PAGE_CACHE_SIZE],
+    count: 0,
+};
+
+percpu! {
+    static PAGE_CACHE: PageCache = EMPTY_PAGE_CACHE;
+}
+
+static PAGE_CACHES_LOCK: RwLock<()> = RwLock::new(());
+
+impl PageCache {
+    fn refill(&mut self, mgr: &mut PhysicalMemoryManager) {
+        while self.count < PAGE_CACHE_BATCH {
+            match mgr.alloc_page(Consumer::PageCaches) {
+                Some(page) => {
+                    self.pages[self.count] = page;
+                    self.count += 1;
+                },
+                None => break
+            }
+        }
+    }
+
+    fn drain(&mut self, mgr: &mut PhysicalMemoryManager) {
+        for _ in 0..PAGE_CACHE_BATCH {
+            self.count -= 1;
+            mgr.free_page(self.pages[self.count], Consumer::PageCaches);
+        }
+    }
+
+    fn drain_all(&mut self, mgr: &mut PhysicalMemoryManager) -> u64 {
+        let drained = self.count as u64;
+        while self.count > 0 {
+            self.count -= 1;
+            mgr.free_page(self.pages[self.count], Consumer::PageCaches);
+        }
+        drained
+    }
+
+    /* Hands the most recently cached page over to the consumer. The allocator
+     * is not locked here, the page is transferred in one step so the amount
+     * of charged pages stays the same (see meminfo::snapshot()) */
+    fn take(&mut self, consumer: Consumer) -> Option<usize> {
+        if self.count == 0 {
+            return None;
+        }
+        self.count -= 1;
+        meminfo::transfer(Consumer::PageCaches, consumer, 1);
+        Some(self.pages[self.count])
+    }
+
+    fn put(&mut self, page: usize, consumer: Consumer) {
+        meminfo::transfer(consumer, Consumer::PageCaches, 1);
+        self.pages[self.count] = page;
+        self.count += 1;
+    }
+}
+
+/* Returns physical region of the given size for the allocator bitmap: the first
+ * one after the kernel which does not overlap any of the boot modules or data */
+fn place_bitmap(size: usize, modules: &[BootModule], boot_data: &[MemoryRegion]) -> MemoryRegion {
+    let mut region = layout::physical_kernel_placement()
+                     .page_align(PAGE_SIZE)
+                     .next_adjacent(size);
+    loop {
+        let occupied = modules.iter().map(|module| module.region)
+                              .chain(boot_data.iter().cloned())
+                              .find(|occupied| occupied.size != 0 && occupied.overlaps(&region));
+        match occupied {
+            Some(occupied) => {
+                region.addr = memory::next_page_addr(occupied.end_addr(), PAGE_SIZE);
+            },
+            None => return region
+        }
+    }
+}
+
 // IDEA: keep separate allocators for every available memory region
 
 pub struct PhysicalMemoryManager {
@@ -28,12 +258,15 @@ pub struct PhysicalMemoryManager {
 
 impl PhysicalMemoryManager {
 
-    // Warning: kernel stack and page tables set up by bootstrapper are not marked as occupied!
-    pub fn init(&mut self, mem_map: &PhysicalMemoryMap) {
+    /* Boot modules are reserved here as well: nothing must be placed over them.
+     * Other data left by the boot loader in boot_data is reserved by its users
+     * later, the allocator only keeps its bitmap off it. */
+    pub fn init(&mut self, mem_map: &PhysicalMemoryMap, modules: &[BootModule], boot_data: &[MemoryRegion]) {
         /* Find the end of the available physical memory (end address of the last available memory region).
          * The allocator will use a region of 0..<end_address> for allocations. The region can have reserved areas
          * in it, they will be marked as already allocated in the allocator */
         let last_avail_region = mem_map.available_memory_regions()
+                                       .filter_map(|region| limit_region(region))
                                        .last()
                                        .unwrap();
 
@@ -46,15 +279,13 @@ impl PhysicalMemoryManager {
             bitmap_bytes = bitmap_bytes + 1;
         }
 
-        /* The bitmap is placed immediately after the kernel aligned on a page boundary.
-           XXX: It is assumed that there is enough available memory after the kernel. */
-        // TODO: is alignment really required?
-        let bitmap_region = layout::virtual_kernel_placement()
-                            .page_align(PAGE_SIZE)
-                            .next_adjacent(bitmap_bytes as usize);
+        /* The bitmap is placed after the kernel aligned on a page boundary, boot
+           modules and data the loader might have put there are skipped.
+           XXX: It is assumed that there is enough available memory after them. */
+        let bitmap_region = place_bitmap(bitmap_bytes as usize, modules, boot_data);
 
         println!("bitmap_region: {:016x}, size: {}", bitmap_region.addr, bitmap_region.size);
-        let mut bitmap = Bitmap::from_raw_addr(bitmap_region.addr, total_phys_pages as usize);
+        let mut bitmap = Bitmap::from_raw_addr(layout::phys_to_virt(bitmap_region.addr), total_phys_pages as usize);
         bitmap.clear();
 
         self.bitmap = Some(bitmap);
@@ -66,15 +297,45 @@ impl PhysicalMemoryManager {
         self.mark_region(available_memory.page_align(PAGE_SIZE), true);
 
         /* Mark all available regions from memory map as free */
-        for region in mem_map.available_memory_regions() {
+        for region in mem_map.available_memory_regions().filter_map(|region| limit_region(region)) {
             self.mark_region(region, false);
         }
 
         /* Mark kernel location as occupied */
-        self.mark_region(layout::physical_kernel_placement().page_align(PAGE_SIZE), true);
+        let kernel_pages = self.mark_region(layout::physical_kernel_placement().page_align(PAGE_SIZE), true);
+        meminfo::charge(Consumer::KernelImage, kernel_pages);
+
+        /* Bootstrap code, its page tables and the stack are still in use */
+        let bootstrap_pages = self.mark_region(layout::physical_bootstrap_placement().page_align(PAGE_SIZE), true);
+        meminfo::charge(Consumer::KernelImage, bootstrap_pages);
 
         /* Mark bitmap location as occupied */
-        self.mark_region(layout::to_physical_region(bitmap_region).page_align(PAGE_SIZE), true);
+        let bitmap_pages = self.mark_region(bitmap_region.page_align(PAGE_SIZE), true);
+        meminfo::charge(Consumer::PmmBitmap, bitmap_pages);
+
+        /* Modules overlapping the kernel or lying outside of the available memory
+           would be overwritten sooner or later. Memory above the mem= limit is
+           never handed out, only the part of a module below it is reserved */
+        let managed_end = total_phys_pages as usize * PAGE_SIZE;
+        for module in modules {
+            let mut region = module.region;
+            if region.end_addr() >= managed_end {
+                println!("Boot module \"{}\" at {:016x}-{:016x} lies above the memory limit.",
+                         module.cmdline(), module.region.addr, module.region.end_addr());
+                if region.addr >= managed_end {
+                    continue;
+                }
+                region.size = managed_end - region.addr;
+            }
+            if !self.reserve_region(region, Consumer::BootModules) {
+                panic!("Boot module \"{}\" at {:016x}-{:016x} overlaps memory in use",
+                       module.cmdline(), module.region.addr, module.region.end_addr());
+            }
+        }
+
+        /* Start reclaiming when less than 1/64 of the memory is left */
+        let low_watermark = ::core::cmp::max(self.free_pages_count / 64, 16);
+        oom::set_watermarks(low_watermark, low_watermark * 2);
     }
 
     pub fn total_pages_count(&self) -> u64 {
@@ -85,26 +346,53 @@ impl PhysicalMemoryManager {
         self.free_pages_count
     }
 
-    pub fn alloc_page(&mut self) -> Option<usize> {
-        let bitmap = self.bitmap.as_mut().unwrap();
-        match bitmap.find_first_zero() {
+    /* Allocates a page and charges it to the consumer.
+     * Prefer the module-level alloc_page() which handles memory pressure. */
+    pub fn alloc_page(&mut self, consumer: Consumer) -> Option<usize> {
+        let free_page = self.bitmap.as_ref().unwrap().find_first_zero();
+        match free_page {
             Some(bit) => {
-                bitmap.set_bit(bit);
+                self.mark_page(bit * PAGE_SIZE, true);
+                meminfo::charge(consumer, 1);
                 Some(bit * PAGE_SIZE)
             },
             None => None
         }
     }
 
-    pub fn free_page(&mut self, addr: usize) {
-        let bit = addr / PAGE_SIZE;
-        self.mark_page(bit, false);
+    /* Releases a page previously allocated for the consumer */
+    pub fn free_page(&mut self, addr: usize, consumer: Consumer) {
+        self.mark_page(addr, false);
+        meminfo::uncharge(consumer, 1);
     }
 
-    fn mark_region(&mut self, region: MemoryRegion, occupied: bool) {
+    /* Marks pages of the region which are still free as occupied and charges
+     * them to the consumer. Used for memory the boot loader has placed data in.
+     * Returns false if some of the pages were already occupied. */
+    pub fn reserve_region(&mut self, region: MemoryRegion, consumer: Consumer) -> bool {
+        let mut pages = 0;
+        let mut all_free = true;
+        for page in region.page_align(PAGE_SIZE).pages_iter(PAGE_SIZE) {
+            let bit = page.addr / PAGE_SIZE;
+            if (bit as u64) < self.total_pages_count && !self.bitmap.as_ref().unwrap().is_bit_set(bit) {
+                self.mark_page(page.addr, true);
+                pages = pages + 1;
+            } else {
+                all_free = false;
+            }
+        }
+        meminfo::charge(consumer, pages);
+        all_free
+    }
+
+    /* Returns amount of pages in the region */
+    fn mark_region(&mut self, region: MemoryRegion, occupied: bool) -> u64 {
+        let mut pages = 0;
         for page in region.pages_iter(PAGE_SIZE) {
             self.mark_page(page.addr, occupied);
+            pages = pages + 1;
         }
+        pages
     }
 
     fn mark_page(&mut self, addr: usize, occupied: bool) {
@@ -123,3 +411,15 @@ impl PhysicalMemoryManager {
         }
     }
 }
+
+/* Cuts off part of the region above the memory limit */
+fn limit_region(region: MemoryRegion) -> Option<MemoryRegion> {
+    let limit = MEM_LIMIT.get_int() as usize;
+    if limit == 0 || region.end_addr() < limit {
+        Some(region)
+    } else if region.addr < limit {
+        Some(MemoryRegion { addr: region.addr, size: limit - region.addr })
+    } else {
+        None
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Legacy 8259 programmable interrupt controllers.
+ *
+ * Two chips are cascaded: lines 8-15 of the slave come to line 2 of the master.
+ * After the BIOS lines 0-7 are delivered as vectors 0x08-0x0F which clash
+ * with CPU exceptions, so the controllers are remapped to 0x20-0x2F.
+ */
+
+use spin::Mutex;
+
+use port::{inb, outb, io_wait};
+
+/* Should be synchronized with IRQ_BASE_VECTOR in kernel.h */
+pub const IRQ_BASE_VECTOR: u8 = 0x20;
+
+pub const IRQ_LINES_COUNT: usize = 16;
+
+/* Line of the master the slave is connected to */
+const CASCADE_LINE: u8 = 2;
+
+/*
+ * Ports of the controllers.
+ */
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 =    0x21;
+const SLAVE_COMMAND: u16 =  0xA0;
+const SLAVE_DATA: u16 =     0xA1;
+
+/*
+ * Initialization command words.
+ */
+
+/* Edge triggered, cascade mode, ICW4 follows */
+const ICW1_INIT: u8 =  0x11;
+const ICW4_8086: u8 =  0x01;
+
+/*
+ * Operation command words.
+ */
+
+const OCW2_EOI: u8 =      0x20;
+/* The next read from the command port returns the in-service register */
+const OCW3_READ_ISR: u8 = 0x0B;
+
+/* Lines 7 and 15 are reported when the interrupt goes away before acknowledge */
+const SPURIOUS_LINE: u8 = 7;
+
+/* Masks of both controllers, the slave in the high byte. Set bits disable lines */
+static MASK: Mutex<u16> = Mutex::new(0xFFFF);
+
+/* Remaps both controllers and masks all lines except the cascade one */
+pub fn init() {
+    let mut mask = MASK.lock();
+    *mask = !(1 << CASCADE_LINE);
+    unsafe {
+        outb(MASTER_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(SLAVE_COMMAND, ICW1_INIT);
+        io_wait();
+        /* ICW2: vector offsets */
+        outb(MASTER_DATA, IRQ_BASE_VECTOR);
+        io_wait();
+        outb(SLAVE_DATA, IRQ_BASE_VECTOR + 8);
+        io_wait();
+        /* ICW3: the master gets a bit mask of slave lines, the slave its identity */
+        outb(MASTER_DATA, 1 << CASCADE_LINE);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_LINE);
+        io_wait();
+        outb(MASTER_DATA, ICW4_8086);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        io_wait();
+    }
+    write_mask(*mask);
+}
+
+/* Masks all lines, used when interrupts are routed elsewhere */
+pub fn disable() {
+    let mut mask = MASK.lock();
+    *mask = 0xFFFF;
+    write_mask(*mask);
+}
+
+pub fn mask(line: u8) {
+    let mut mask = MASK.lock();
+    *mask |= 1 << line;
+    write_mask(*mask);
+}
+
+pub fn unmask(line: u8) {
+    let mut mask = MASK.lock();
+    *mask &= !(1 << line);
+    write_mask(*mask);
+}
+
+/* Signals the end of interrupt. The slave needs it as well as the master */
+pub fn eoi(line: u8) {
+    unsafe {
+        if line >= 8 {
+            outb(SLAVE_COMMAND, OCW2_EOI);
+        }
+        outb(MASTER_COMMAND, OCW2_EOI);
+    }
+}
+
+/*
+ * A spurious interrupt is not in service and shouldn't be acknowledged.
+ * The master still has the cascade line in service for a spurious one
+ * coming from the slave.
+ */
+pub fn is_spurious(line: u8) -> bool {
+    if line & 0x7 != SPURIOUS_LINE {
+        return false;
+    }
+    let in_service = unsafe {
+        if line >= 8 {
+            outb(SLAVE_COMMAND, OCW3_READ_ISR);
+            inb(SLAVE_COMMAND)
+        } else {
+            outb(MASTER_COMMAND, OCW3_READ_ISR);
+            inb(MASTER_COMMAND)
+        }
+    };
+    if in_service & (1 << SPURIOUS_LINE) != 0 {
+        return false;
+    }
+    if line >= 8 {
+        unsafe {
+            outb(MASTER_COMMAND, OCW2_EOI);
+        }
+    }
+    true
+}
+
+fn write_mask(mask: u16) {
+    unsafe {
+        outb(MASTER_DATA, mask as u8);
+        outb(SLAVE_DATA, (mask >> 8) as u8);
+    }
+}
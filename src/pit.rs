@@ -0,0 +1,89 @@
+/*
+ * 8254 programmable interval timer.
+ *
+ * Three counters are driven by a 1.193182 MHz clock. Channel 0 is connected
+ * to IRQ 0 and serves as a periodic tick when nothing better is available.
+ * Channel 2 is gated by bit 0 of port 0x61 and its output can be read back
+ * from bit 5, which makes it usable for calibrating other clocks without
+ * interrupts. Channel 1 used to refresh DRAM and is left alone.
+ */
+
+use port::{inb, outb};
+
+/* Frequency of the input clock in Hz */
+pub const FREQUENCY: u64 = 1193182;
+
+/*
+ * Ports.
+ */
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 =       0x43;
+/* Keyboard controller port B: channel 2 gate and output, PC speaker */
+const PORT_B: u16 =        0x61;
+
+/*
+ * Bits of the command register.
+ */
+
+const SELECT_CHANNEL0: u8 = 0 << 6;
+const SELECT_CHANNEL2: u8 = 2 << 6;
+const ACCESS_LATCH: u8 =    0 << 4;
+const ACCESS_LOHI: u8 =     3 << 4;
+/* Interrupt on terminal count */
+const MODE_ONE_SHOT: u8 =   0 << 1;
+/* Rate generator */
+const MODE_PERIODIC: u8 =   2 << 1;
+
+/*
+ * Bits of port B.
+ */
+
+const PORT_B_GATE2: u8 =   1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_OUT2: u8 =    1 << 5;
+
+/* Polls of the channel 2 output before deciding there is no PIT, a poll
+ * takes about a microsecond and the longest wait is about 55 ms */
+const MAX_POLLS: usize = 1000000;
+
+/* Makes channel 0 raise IRQ 0 every divisor ticks of the input clock */
+pub fn start_periodic(divisor: u16) {
+    unsafe {
+        outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LOHI | MODE_PERIODIC);
+        outb(CHANNEL0_DATA, divisor as u8);
+        outb(CHANNEL0_DATA, (divisor >> 8) as u8);
+    }
+}
+
+/* Current value of channel 0 counting down from the divisor. Should be called
+ * with interrupts disabled, as the two bytes are read separately. */
+pub fn read_count() -> u16 {
+    unsafe {
+        outb(COMMAND, SELECT_CHANNEL0 | ACCESS_LATCH);
+        let low = inb(CHANNEL0_DATA) as u16;
+        let high = inb(CHANNEL0_DATA) as u16;
+        (high << 8) | low
+    }
+}
+
+/* Busy-waits for the number of input clock ticks using channel 2.
+ * Returns false if the output never changes, i.e. the PIT is missing. */
+pub fn wait(ticks: u16) -> bool {
+    unsafe {
+        let port_b = inb(PORT_B) & !PORT_B_SPEAKER;
+        outb(PORT_B, port_b & !PORT_B_GATE2);
+        outb(COMMAND, SELECT_CHANNEL2 | ACCESS_LOHI | MODE_ONE_SHOT);
+        outb(CHANNEL2_DATA, ticks as u8);
+        outb(CHANNEL2_DATA, (ticks >> 8) as u8);
+        /* Counting starts on the rising edge of the gate */
+        outb(PORT_B, port_b | PORT_B_GATE2);
+        for _ in 0..MAX_POLLS {
+            if inb(PORT_B) & PORT_B_OUT2 != 0 {
+                return true;
+            }
+        }
+    }
+    false
+}
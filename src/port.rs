@@ -0,0 +1,61 @@
+/* Access to x86 I/O ports */
+
+pub unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    asm!("inb %dx, %al"
+         : "={al}" (value)
+         : "{dx}" (port)
+         : /* clobbers */
+         : "volatile");
+    value
+}
+
+pub unsafe fn outb(port: u16, value: u8) {
+    asm!("outb %al, %dx"
+         : /* outputs */
+         : "{dx}" (port), "{al}" (value)
+         : /* clobbers */
+         : "volatile");
+}
+
+pub unsafe fn inw(port: u16) -> u16 {
+    let value: u16;
+    asm!("inw %dx, %ax"
+         : "={ax}" (value)
+         : "{dx}" (port)
+         : /* clobbers */
+         : "volatile");
+    value
+}
+
+pub unsafe fn outw(port: u16, value: u16) {
+    asm!("outw %ax, %dx"
+         : /* outputs */
+         : "{dx}" (port), "{ax}" (value)
+         : /* clobbers */
+         : "volatile");
+}
+
+pub unsafe fn inl(port: u16) -> u32 {
+    let value: u32;
+    asm!("inl %dx, %eax"
+         : "={eax}" (value)
+         : "{dx}" (port)
+         : /* clobbers */
+         : "volatile");
+    value
+}
+
+pub unsafe fn outl(port: u16, value: u32) {
+    asm!("outl %eax, %dx"
+         : /* outputs */
+         : "{dx}" (port), "{eax}" (value)
+         : /* clobbers */
+         : "volatile");
+}
+
+/* Gives slow devices some time to process the previous command.
+ * Port 0x80 is used by BIOS for POST codes and is safe to write to. */
+pub unsafe fn io_wait() {
+    outb(0x80, 0);
+}
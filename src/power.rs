@@ -0,0 +1,260 @@
+/*
+ * Powering off and rebooting the machine.
+ *
+ * Power off puts the system into the S5 (soft off) sleep state: the sleep type
+ * from the \_S5 object of the ACPI namespace is written to the PM1 control registers
+ * described by the FADT. Reboot tries the FADT reset register, then the
+ * keyboard controller and as the last resort a triple fault.
+ */
+
+use acpi::{self, Fadt, GenericAddress};
+use irq;
+use params::{Param, Value};
+use port::{inb, outb};
+
+kernel_param! {
+    pub static ON_HALT: Param = Param::new("halt", Value::Str("spin"),
+        "What to do when the kernel is done or panics: spin, poweroff or reboot");
+}
+
+/*
+ * Bits of PM1 control registers.
+ */
+
+const PM1_SCI_EN: u64 =         1 << 0;
+const PM1_SLP_TYP_SHIFT: u64 =  10;
+const PM1_SLP_TYP_MASK: u64 =   0x7 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u64 =         1 << 13;
+
+/* Attempts to wait for the hardware to react */
+const WAIT_ITERATIONS: usize = 0x100000;
+
+/*
+ * 8042 keyboard controller.
+ */
+
+const KBC_STATUS_PORT: u16 =       0x64;
+const KBC_COMMAND_PORT: u16 =      0x64;
+const KBC_STATUS_INPUT_FULL: u8 =  1 << 1;
+const KBC_COMMAND_RESET: u8 =      0xFE;
+
+/*
+ * AML encoding used to find \_S5 without interpreting the DSDT.
+ */
+
+const AML_NAME_OP: u8 =     0x08;
+const AML_ROOT_CHAR: u8 =   b'\\';
+const AML_PACKAGE_OP: u8 =  0x12;
+const AML_ZERO_OP: u8 =     0x00;
+const AML_ONE_OP: u8 =      0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+/* Powers the machine off through ACPI. Returns only if it hasn't worked */
+pub fn shutdown() -> ! {
+    irq::disable();
+    match try_shutdown() {
+        Ok(()) => println!("The machine has not powered off."),
+        Err(reason) => println!("Unable to power off: {}.", reason)
+    }
+    stop()
+}
+
+fn try_shutdown() -> Result<(), &'static str> {
+    let fadt = try!(acpi::fadt().ok_or("no FADT"));
+    if fadt.flags & acpi::FADT_HW_REDUCED_ACPI != 0 {
+        return Err("hardware-reduced ACPI is not supported");
+    }
+    let (sleep_type_a, sleep_type_b) = try!(s5_sleep_type().ok_or("no \\_S5 object in the DSDT"));
+
+    let pm1a = fadt.pm1a_control();
+    if !pm1a.is_valid() {
+        return Err("no PM1a control register");
+    }
+    try!(enable_acpi(&fadt, &pm1a));
+
+    let pm1b = fadt.pm1b_control();
+    if pm1b.is_valid() {
+        try!(enter_sleep_state(&pm1b, sleep_type_b));
+    }
+    try!(enter_sleep_state(&pm1a, sleep_type_a));
+
+    for _ in 0..WAIT_ITERATIONS {
+        pause();
+    }
+    Ok(())
+}
+
+/* Switches from legacy to ACPI mode if the firmware hasn't done it yet */
+fn enable_acpi(fadt: &Fadt, pm1a: &GenericAddress) -> Result<(), &'static str> {
+    let control = try!(pm1a.read().ok_or("unsupported PM1a control register"));
+    if control & PM1_SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return Ok(());
+    }
+    unsafe {
+        outb(fadt.smi_command as u16, fadt.acpi_enable);
+    }
+    for _ in 0..WAIT_ITERATIONS {
+        if pm1a.read().map_or(false, |control| control & PM1_SCI_EN != 0) {
+            return Ok(());
+        }
+        pause();
+    }
+    Err("ACPI mode can't be enabled")
+}
+
+fn enter_sleep_state(register: &GenericAddress, sleep_type: u8) -> Result<(), &'static str> {
+    let control = try!(register.read().ok_or("unsupported PM1 control register"));
+    let control = (control & !PM1_SLP_TYP_MASK) | (sleep_type as u64) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN;
+    if register.write(control) {
+        Ok(())
+    } else {
+        Err("unsupported PM1 control register")
+    }
+}
+
+/* Sleep types for PM1a and PM1b registers from the \_S5 package */
+fn s5_sleep_type() -> Option<(u8, u8)> {
+    evaluate_s5().or_else(|| acpi::dsdt().and_then(|dsdt| find_s5_sleep_type(dsdt.data())))
+}
+
+/* Gets the package from the namespace. It is not waited for, as the power off
+ * can be requested by a panic while the namespace is locked. */
+fn evaluate_s5() -> Option<(u8, u8)> {
+    let mut namespace = match acpi::NAMESPACE.try_lock() {
+        Some(namespace) => namespace,
+        None => return None
+    };
+    let package = match namespace.evaluate("\\_S5", &[], &acpi::HANDLER) {
+        Ok(package) => package,
+        Err(_) => return None
+    };
+    let mut values = [0u8; 2];
+    for (i, value) in values.iter_mut().enumerate() {
+        let element = match namespace.package_element(package, i) {
+            Some(element) => element,
+            None => return None
+        };
+        match namespace.integer(element, &acpi::HANDLER) {
+            Ok(integer) => *value = integer as u8,
+            Err(_) => return None
+        }
+    }
+    Some((values[0], values[1]))
+}
+
+/*
+ * Looks for the package without interpreting AML, if the namespace has not been loaded:
+ *   NameOp [\] "_S5_" PackageOp PkgLength NumElements SLP_TYPa SLP_TYPb ...
+ */
+fn find_s5_sleep_type(aml: &[u8]) -> Option<(u8, u8)> {
+    let name = b"_S5_";
+    let mut pos = 1;
+    while pos + name.len() < aml.len() {
+        if &aml[pos..pos + name.len()] == name {
+            let defined = aml[pos - 1] == AML_NAME_OP ||
+                          (pos >= 2 && aml[pos - 1] == AML_ROOT_CHAR && aml[pos - 2] == AML_NAME_OP);
+            if defined {
+                if let Some(sleep_type) = parse_s5_package(&aml[pos + name.len()..]) {
+                    return Some(sleep_type);
+                }
+            }
+        }
+        pos += 1;
+    }
+    None
+}
+
+fn parse_s5_package(aml: &[u8]) -> Option<(u8, u8)> {
+    if aml.len() < 2 || aml[0] != AML_PACKAGE_OP {
+        return None;
+    }
+    /* Bits 6-7 of the PkgLength lead byte tell how many bytes follow it */
+    let mut pos = 1 + 1 + (aml[1] >> 6) as usize;
+    /* NumElements */
+    pos += 1;
+
+    let mut values = [0u8; 2];
+    for value in values.iter_mut() {
+        if pos >= aml.len() {
+            return None;
+        }
+        match aml[pos] {
+            AML_ZERO_OP => *value = 0,
+            AML_ONE_OP => *value = 1,
+            AML_BYTE_PREFIX if pos + 1 < aml.len() => {
+                *value = aml[pos + 1];
+                pos += 1;
+            },
+            _ => return None
+        }
+        pos += 1;
+    }
+    Some((values[0], values[1]))
+}
+
+/* Resets the machine */
+pub fn reboot() -> ! {
+    irq::disable();
+
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(register) = fadt.reset_register() {
+            register.write(fadt.reset_value as u64);
+            for _ in 0..WAIT_ITERATIONS {
+                pause();
+            }
+        }
+    }
+
+    /* Pulse the reset line through the keyboard controller */
+    unsafe {
+        for _ in 0..WAIT_ITERATIONS {
+            if inb(KBC_STATUS_PORT) & KBC_STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            pause();
+        }
+        outb(KBC_COMMAND_PORT, KBC_COMMAND_RESET);
+    }
+    for _ in 0..WAIT_ITERATIONS {
+        pause();
+    }
+
+    /* With an empty IDT (a zero limit and base) any exception becomes a triple fault */
+    let empty_idt = [0u8; 10];
+    unsafe {
+        asm!("lidt ($0)
+              int3"
+             : /* outputs */
+             : "r" (&empty_idt)
+             : "memory"
+             : "volatile");
+    }
+    stop()
+}
+
+fn pause() {
+    unsafe {
+        asm!("pause" : : : : "volatile");
+    }
+}
+
+fn stop() -> ! {
+    loop {
+        unsafe {
+            asm!("cli
+                  hlt" : : : : "volatile");
+        }
+    }
+}
+
+pub fn power_test() {
+    /* Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero }) */
+    let aml = [0x10, 0x08, 0x5C, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00];
+    assert_eq!(Some((5, 0)), find_s5_sleep_type(&aml));
+    /* Name (_S5, Package (0x02) { One, 0x07 }) */
+    let aml = [0x08, 0x5F, 0x53, 0x35, 0x5F, 0x12, 0x06, 0x02, 0x01, 0x0A, 0x07];
+    assert_eq!(Some((1, 7)), find_s5_sleep_type(&aml));
+    /* A method named _S5_ is not a package */
+    let aml = [0x14, 0x5F, 0x53, 0x35, 0x5F, 0x00];
+    assert_eq!(None, find_s5_sleep_type(&aml));
+}
@@ -0,0 +1,479 @@
+/*
+ * CMOS real-time clock.
+ *
+ * The RTC keeps the date and time in CMOS registers, in BCD or binary and
+ * in the 12 or 24-hour format as status register B says. It is updated once
+ * a second, registers are inconsistent while the update-in-progress flag is
+ * set, so they are read after it clears until two reads match. The century
+ * register is optional, its index is given by the FADT.
+ *
+ * The RTC is read once at boot, after that the wall-clock time is derived
+ * from the monotonic clock. It is assumed to keep UTC. The chip can also raise
+ * IRQ 8 at a programmable rate and when its alarm time is reached.
+ */
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+
+use spin::Mutex;
+
+use acpi;
+use irq;
+use port::{inb, outb};
+use timer::{self, Duration};
+
+const IRQ_LINE: u8 = 8;
+
+/*
+ * Ports.
+ */
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 =  0x71;
+
+/*
+ * Registers.
+ */
+
+const REG_SECONDS: u8 =        0x00;
+const REG_SECONDS_ALARM: u8 =  0x01;
+const REG_MINUTES: u8 =        0x02;
+const REG_MINUTES_ALARM: u8 =  0x03;
+const REG_HOURS: u8 =          0x04;
+const REG_HOURS_ALARM: u8 =    0x05;
+const REG_DAY: u8 =            0x07;
+const REG_MONTH: u8 =          0x08;
+const REG_YEAR: u8 =           0x09;
+const REG_STATUS_A: u8 =       0x0A;
+const REG_STATUS_B: u8 =       0x0B;
+const REG_STATUS_C: u8 =       0x0C;
+
+/* Bits of status register A */
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 =          0x0F;
+
+/* Bits of status register B */
+const STATUS_B_24_HOUR: u8 =       1 << 1;
+const STATUS_B_BINARY: u8 =        1 << 2;
+const STATUS_B_ALARM_IRQ: u8 =     1 << 5;
+const STATUS_B_PERIODIC_IRQ: u8 =  1 << 6;
+
+/* Bits of status register C, reading it acknowledges the interrupt */
+const STATUS_C_ALARM: u8 =    1 << 5;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const STATUS_C_IRQ: u8 =      1 << 7;
+
+/* Set in the hours register for PM in the 12-hour format */
+const HOURS_PM: u8 = 1 << 7;
+
+/* The base frequency of periodic interrupts divided by two rate - 1 times */
+const BASE_FREQUENCY: u32 = 32768;
+const MIN_PERIODIC_FREQUENCY: u32 = 2;
+const MAX_PERIODIC_FREQUENCY: u32 = 8192;
+
+/* An update takes under 2 ms, a poll about a microsecond */
+const MAX_UPDATE_POLLS: usize = 10000;
+
+const SECONDS_PER_DAY: u64 = 86400;
+
+/* Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar */
+const UNIX_EPOCH_DAYS: u64 = 719468;
+const DAYS_PER_ERA: u64 = 146097;
+
+/* Called from the RTC interrupt handler */
+pub type Callback = fn();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year:   u16,
+    pub month:  u8,
+    pub day:    u8,
+    pub hour:   u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /* Seconds since 1970-01-01 00:00:00, earlier dates are not expected */
+    pub fn to_unix_seconds(&self) -> u64 {
+        let month = self.month as u64;
+        let year = self.year as u64 - if month <= 2 { 1 } else { 0 };
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+        let day_of_year = (153 * shifted_month + 2) / 5 + self.day as u64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+        days * SECONDS_PER_DAY + self.hour as u64 * 3600 + self.minute as u64 * 60 + self.second as u64
+    }
+
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let days = seconds / SECONDS_PER_DAY + UNIX_EPOCH_DAYS;
+        let era = days / DAYS_PER_ERA;
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year:   year as u16,
+            month:  month as u8,
+            day:    (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u8,
+            hour:   (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+/* Time registers as read from the chip */
+#[derive(Clone, Copy, PartialEq)]
+struct RawTime {
+    second:  u8,
+    minute:  u8,
+    hour:    u8,
+    day:     u8,
+    month:   u8,
+    year:    u8,
+    /* Zero if there is no century register */
+    century: u8,
+}
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    alarm:    Option<Callback>,
+    periodic: Option<Callback>,
+}
+
+static AVAILABLE: AtomicBool = ATOMIC_BOOL_INIT;
+/* CMOS index of the century register, 0 if there is none */
+static CENTURY_REG: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Wall-clock time at the start of the monotonic clock in nanoseconds since the epoch */
+static BOOT_TIME: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Value of status register B, which tells the format of the time registers */
+static STATUS_B: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Serializes index and data port accesses */
+static CMOS: Mutex<()> = Mutex::new(());
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks { alarm: None, periodic: None });
+static ALARMS: AtomicUsize = ATOMIC_USIZE_INIT;
+static PERIODIC_INTERRUPTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Reads the RTC and seeds the wall-clock time. Should be called after timer::init() */
+pub fn init() {
+    let mut century = 0;
+    if let Some(fadt) = acpi::fadt() {
+        if fadt.boot_arch_flags & acpi::BOOT_ARCH_NO_CMOS_RTC != 0 {
+            println!("ACPI reports there is no CMOS RTC.");
+            return;
+        }
+        century = fadt.century;
+    }
+    CENTURY_REG.store(century as usize, Ordering::SeqCst);
+    STATUS_B.store(read_register(REG_STATUS_B) as usize, Ordering::SeqCst);
+
+    let raw = match read_raw_time() {
+        Some(raw) => raw,
+        None => {
+            println!("The RTC is not updating.");
+            return;
+        }
+    };
+    let time = decode(raw, STATUS_B.load(Ordering::SeqCst) as u8);
+    let boot_time = Duration::from_secs(time.to_unix_seconds()) - timer::monotonic_now();
+    BOOT_TIME.store(boot_time.as_nanos() as usize, Ordering::SeqCst);
+
+    /* Interrupts stay disabled in the chip until someone asks for them */
+    update_register(REG_STATUS_B, STATUS_B_ALARM_IRQ | STATUS_B_PERIODIC_IRQ, 0);
+    read_register(REG_STATUS_C);
+    irq::register(IRQ_LINE, rtc_interrupt);
+    AVAILABLE.store(true, Ordering::SeqCst);
+}
+
+pub fn is_available() -> bool {
+    AVAILABLE.load(Ordering::SeqCst)
+}
+
+/* Time since the Unix epoch, zero if the RTC is unavailable */
+pub fn realtime_now() -> Duration {
+    if !is_available() {
+        return Duration::from_nanos(0);
+    }
+    Duration::from_nanos(BOOT_TIME.load(Ordering::SeqCst) as u64) + timer::monotonic_now()
+}
+
+pub fn realtime_date() -> DateTime {
+    DateTime::from_unix_seconds(realtime_now().as_secs())
+}
+
+/* Reads the date and time from the chip */
+pub fn read_time() -> Option<DateTime> {
+    if !is_available() {
+        return None;
+    }
+    read_raw_time().map(|raw| decode(raw, STATUS_B.load(Ordering::SeqCst) as u8))
+}
+
+/* Reads the time registers twice in a row and retries until the values match */
+fn read_raw_time() -> Option<RawTime> {
+    let mut last = match read_raw_time_once() {
+        Some(raw) => raw,
+        None => return None
+    };
+    loop {
+        let raw = match read_raw_time_once() {
+            Some(raw) => raw,
+            None => return None
+        };
+        if raw == last {
+            return Some(raw);
+        }
+        last = raw;
+    }
+}
+
+fn read_raw_time_once() -> Option<RawTime> {
+    let century_reg = CENTURY_REG.load(Ordering::SeqCst) as u8;
+    irq::without_interrupts(|| {
+        let _lock = CMOS.lock();
+        let mut polls = 0;
+        while unsafe { read_cmos(REG_STATUS_A) } & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            polls += 1;
+            if polls == MAX_UPDATE_POLLS {
+                return None;
+            }
+        }
+        unsafe {
+            Some(RawTime {
+                second:  read_cmos(REG_SECONDS),
+                minute:  read_cmos(REG_MINUTES),
+                hour:    read_cmos(REG_HOURS),
+                day:     read_cmos(REG_DAY),
+                month:   read_cmos(REG_MONTH),
+                year:    read_cmos(REG_YEAR),
+                century: if century_reg != 0 { read_cmos(century_reg) } else { 0 },
+            })
+        }
+    })
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+    let year = convert(raw.year) as u16;
+    /* Without the century register years 70-99 are taken as 1970-1999 */
+    let century = if raw.century != 0 {
+        convert(raw.century) as u16
+    } else if year >= 70 {
+        19
+    } else {
+        20
+    };
+    DateTime {
+        year:   century * 100 + year,
+        month:  convert(raw.month),
+        day:    convert(raw.day),
+        hour:   decode_hour(raw.hour, status_b),
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/* The PM bit is kept in binary mode too, 12 AM is midnight */
+fn decode_hour(raw: u8, status_b: u8) -> u8 {
+    let hour = raw & !HOURS_PM;
+    let hour = if status_b & STATUS_B_BINARY != 0 { hour } else { from_bcd(hour) };
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return hour;
+    }
+    hour % 12 + if raw & HOURS_PM != 0 { 12 } else { 0 }
+}
+
+fn encode(value: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_BINARY != 0 { value } else { to_bcd(value) }
+}
+
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+    if status_b & STATUS_B_24_HOUR != 0 {
+        return encode(hour, status_b);
+    }
+    let pm = if hour >= 12 { HOURS_PM } else { 0 };
+    let hour = if hour % 12 == 0 { 12 } else { hour % 12 };
+    encode(hour, status_b) | pm
+}
+
+unsafe fn read_cmos(reg: u8) -> u8 {
+    outb(CMOS_INDEX, reg);
+    inb(CMOS_DATA)
+}
+
+unsafe fn write_cmos(reg: u8, value: u8) {
+    outb(CMOS_INDEX, reg);
+    outb(CMOS_DATA, value);
+}
+
+fn read_register(reg: u8) -> u8 {
+    irq::without_interrupts(|| {
+        let _lock = CMOS.lock();
+        unsafe { read_cmos(reg) }
+    })
+}
+
+/* Clears the mask bits of the register and sets the value bits */
+fn update_register(reg: u8, mask: u8, value: u8) {
+    irq::without_interrupts(|| {
+        let _lock = CMOS.lock();
+        unsafe {
+            let old = read_cmos(reg);
+            write_cmos(reg, (old & !mask) | value);
+        }
+    })
+}
+
+/* Calls the callback every day at the time (UTC), replacing the previous alarm */
+pub fn set_alarm(hour: u8, minute: u8, second: u8, callback: Callback) -> bool {
+    if !is_available() || hour > 23 || minute > 59 || second > 59 {
+        return false;
+    }
+    let status_b = STATUS_B.load(Ordering::SeqCst) as u8;
+    irq::without_interrupts(|| {
+        CALLBACKS.lock().alarm = Some(callback);
+        {
+            let _lock = CMOS.lock();
+            unsafe {
+                write_cmos(REG_SECONDS_ALARM, encode(second, status_b));
+                write_cmos(REG_MINUTES_ALARM, encode(minute, status_b));
+                write_cmos(REG_HOURS_ALARM, encode_hour(hour, status_b));
+            }
+        }
+        update_register(REG_STATUS_B, 0, STATUS_B_ALARM_IRQ);
+    });
+    true
+}
+
+pub fn cancel_alarm() {
+    if !is_available() {
+        return;
+    }
+    irq::without_interrupts(|| {
+        update_register(REG_STATUS_B, STATUS_B_ALARM_IRQ, 0);
+        CALLBACKS.lock().alarm = None;
+    });
+}
+
+/* Calls the callback at the frequency, which should be a power of two from 2 to 8192 Hz */
+pub fn start_periodic(frequency: u32, callback: Callback) -> bool {
+    if !is_available() || !frequency.is_power_of_two() ||
+        frequency < MIN_PERIODIC_FREQUENCY || frequency > MAX_PERIODIC_FREQUENCY {
+        return false;
+    }
+    let rate = (BASE_FREQUENCY / frequency).trailing_zeros() as u8 + 1;
+    irq::without_interrupts(|| {
+        CALLBACKS.lock().periodic = Some(callback);
+        update_register(REG_STATUS_A, STATUS_A_RATE_MASK, rate);
+        update_register(REG_STATUS_B, 0, STATUS_B_PERIODIC_IRQ);
+    });
+    true
+}
+
+pub fn stop_periodic() {
+    if !is_available() {
+        return;
+    }
+    irq::without_interrupts(|| {
+        update_register(REG_STATUS_B, STATUS_B_PERIODIC_IRQ, 0);
+        CALLBACKS.lock().periodic = None;
+    });
+}
+
+fn rtc_interrupt(_line: u8) -> bool {
+    let flags = read_register(REG_STATUS_C);
+    let callbacks = *CALLBACKS.lock();
+    if flags & STATUS_C_PERIODIC != 0 {
+        PERIODIC_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        if let Some(callback) = callbacks.periodic {
+            callback();
+        }
+    }
+    if flags & STATUS_C_ALARM != 0 {
+        ALARMS.fetch_add(1, Ordering::SeqCst);
+        if let Some(callback) = callbacks.alarm {
+            callback();
+        }
+    }
+    flags & STATUS_C_IRQ != 0
+}
+
+pub fn display_rtc_info() {
+    if !is_available() {
+        println!("No RTC, the wall-clock time is unknown.");
+        return;
+    }
+    let status_b = STATUS_B.load(Ordering::SeqCst) as u8;
+    println!("Wall-clock time: {} UTC (RTC in {} {}-hour format{}).",
+        realtime_date(),
+        if status_b & STATUS_B_BINARY != 0 { "binary" } else { "BCD" },
+        if status_b & STATUS_B_24_HOUR != 0 { 24 } else { 12 },
+        if CENTURY_REG.load(Ordering::SeqCst) != 0 { ", with century" } else { "" });
+}
+
+static TEST_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn test_periodic() {
+    TEST_TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/* Checks the format conversions and, if there is an RTC, periodic interrupts */
+pub fn rtc_test() {
+    assert_eq!(from_bcd(0x59), 59);
+    assert_eq!(to_bcd(47), 0x47);
+
+    /* 12:30 AM and PM in BCD 12-hour format, 23 in binary 24-hour format */
+    assert_eq!(decode_hour(0x12, 0), 0);
+    assert_eq!(decode_hour(0x12 | HOURS_PM, 0), 12);
+    assert_eq!(decode_hour(0x11 | HOURS_PM, 0), 23);
+    assert_eq!(decode_hour(23, STATUS_B_BINARY | STATUS_B_24_HOUR), 23);
+    for hour in 0..24 {
+        assert_eq!(decode_hour(encode_hour(hour, 0), 0), hour);
+        assert_eq!(decode_hour(encode_hour(hour, STATUS_B_BINARY), STATUS_B_BINARY), hour);
+    }
+
+    let raw = RawTime { second: 0x08, minute: 0x14, hour: 0x03, day: 0x19, month: 0x01, year: 0x38, century: 0 };
+    let time = decode(raw, STATUS_B_24_HOUR);
+    assert_eq!(time, DateTime { year: 2038, month: 1, day: 19, hour: 3, minute: 14, second: 8 });
+    assert_eq!(time.to_unix_seconds(), 1 << 31);
+    assert_eq!(DateTime::from_unix_seconds(1 << 31), time);
+    assert_eq!(DateTime::from_unix_seconds(0).to_unix_seconds(), 0);
+    /* A leap day */
+    let leap = DateTime { year: 2000, month: 2, day: 29, hour: 0, minute: 0, second: 0 };
+    assert_eq!(leap.to_unix_seconds(), 951782400);
+    assert_eq!(DateTime::from_unix_seconds(951782400), leap);
+
+    if !is_available() {
+        return;
+    }
+    assert!(realtime_now() <= realtime_now());
+    assert!(!start_periodic(3, test_periodic));
+    assert!(start_periodic(1024, test_periodic));
+    timer::sleep(Duration::from_millis(20));
+    stop_periodic();
+    assert!(TEST_TICKS.load(Ordering::SeqCst) > 0);
+}
@@ -0,0 +1,99 @@
+/* Output to a 16550 compatible serial port */
+
+use spin::Mutex;
+
+use port::{inb, outb};
+
+pub static COM1: Mutex<SerialPort> = Mutex::new(SerialPort {
+    base:  0x3F8,
+    state: State::Uninitialized,
+});
+
+/*
+ * Register offsets from the base port.
+ */
+
+const REG_DATA: u16 =          0;
+const REG_INT_ENABLE: u16 =    1;
+/* Divisor latch, accessible when LCR_DLAB is set */
+const REG_DIVISOR_LOW: u16 =   0;
+const REG_DIVISOR_HIGH: u16 =  1;
+const REG_FIFO_CONTROL: u16 =  2;
+const REG_LINE_CONTROL: u16 =  3;
+const REG_MODEM_CONTROL: u16 = 4;
+const REG_LINE_STATUS: u16 =   5;
+const REG_SCRATCH: u16 =       7;
+
+const LCR_8N1: u8 =            0x03;
+const LCR_DLAB: u8 =           0x80;
+/* Enable and clear FIFOs, 14 bytes interrupt threshold */
+const FCR_ENABLE_CLEAR: u8 =   0xC7;
+/* DTR, RTS and OUT2 */
+const MCR_READY: u8 =          0x0B;
+/* Transmitter holding register is empty */
+const LSR_THR_EMPTY: u8 =      0x20;
+
+/* 115200 / 115200 */
+const BAUD_DIVISOR: u16 = 1;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Uninitialized,
+    Ready,
+    /* There is no UART at the port */
+    Absent,
+}
+
+pub struct SerialPort {
+    base:  u16,
+    state: State,
+}
+
+impl SerialPort {
+    /* Configures the port for 115200 8N1 with interrupts disabled */
+    fn init(&mut self) {
+        unsafe {
+            /* A missing UART reads back 0xFF */
+            outb(self.base + REG_SCRATCH, 0x5A);
+            if inb(self.base + REG_SCRATCH) != 0x5A {
+                self.state = State::Absent;
+                return;
+            }
+
+            outb(self.base + REG_INT_ENABLE, 0);
+            outb(self.base + REG_LINE_CONTROL, LCR_DLAB);
+            outb(self.base + REG_DIVISOR_LOW, (BAUD_DIVISOR & 0xFF) as u8);
+            outb(self.base + REG_DIVISOR_HIGH, (BAUD_DIVISOR >> 8) as u8);
+            outb(self.base + REG_LINE_CONTROL, LCR_8N1);
+            outb(self.base + REG_FIFO_CONTROL, FCR_ENABLE_CLEAR);
+            outb(self.base + REG_MODEM_CONTROL, MCR_READY);
+        }
+        self.state = State::Ready;
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        /* Initialized on the first use, so even the earliest messages get through */
+        if self.state == State::Uninitialized {
+            self.init();
+        }
+        if self.state == State::Absent {
+            return;
+        }
+        unsafe {
+            while inb(self.base + REG_LINE_STATUS) & LSR_THR_EMPTY == 0 {}
+            outb(self.base + REG_DATA, byte);
+        }
+    }
+}
+
+impl ::core::fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+/*
+ * Starting application processors.
+ *
+ * The bootstrap processor (BSP) starts the others (APs) listed in the MADT with
+ * the INIT-SIPI-SIPI sequence. A startup IPI makes the AP run in real mode at
+ * the page given in the IPI, which must lie below 1 Mb: the trampoline from
+ * trampoline.S is copied there. It switches to long mode with the control
+ * registers of the BSP and calls ap_main() on the stack prepared for the AP,
+ * which loads the AP's own GDT, TSS and IDT, sets up its FPU state and system
+ * call entry and enables its local APIC.
+ *
+ * APs are started one at a time, so they share the trampoline. CPU numbers
+ * are indexes in the CPUS table, the BSP is always 0.
+ */
+
+use core::ptr;
+use core::sync::atomic::{fence, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use spin::Mutex;
+
+use acpi::{self, MadtEntry};
+use apic;
+use fpu;
+use gdt;
+use idt;
+use irq;
+use memory::{MemoryRegion, PAGE_SIZE};
+use meminfo::Consumer;
+use msr::{self, rdmsr};
+use percpu;
+use physical_memory_manager;
+use syscall;
+use timer::{self, Duration};
+
+/* Processors above this number are left halted */
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_SIZE: usize = 0x4000;
+
+/* The trampoline page is searched in the conventional memory, skipping page 0
+ * with the real mode interrupt table */
+const TRAMPOLINE_SEARCH_START: usize = 0x1000;
+const TRAMPOLINE_SEARCH_END: usize =   0xA0000;
+
+/* Delays of the startup sequence from the MultiProcessor specification */
+const INIT_DELAY: Duration =    Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+/* Time an AP has to reach ap_main() */
+const CHECK_IN_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+struct Cpu {
+    apic_id:      u32,
+    /* ACPI processor id */
+    processor_id: u32,
+    online:       bool,
+}
+
+const EMPTY_CPU: Cpu = Cpu {
+    apic_id:      0,
+    processor_id: 0,
+    online:       false,
+};
+
+/* Should be synchronized with the data at ap_trampoline_data in trampoline.S */
+#[repr(C, packed)]
+struct TrampolineData {
+    gdt:             [u64; 4],
+    gdt_limit:       u16,
+    /* The following three are offsets in the trampoline until fixed up */
+    gdt_base:        u32,
+    jump_32_offset:  u32,
+    jump_32_segment: u16,
+    jump_64_offset:  u32,
+    jump_64_segment: u16,
+    cr0:             u64,
+    cr3:             u64,
+    cr4:             u64,
+    efer:            u64,
+    stack:           u64,
+    entry:           u64,
+    cpu:             u64,
+}
+
+static CPUS: Mutex<[Cpu; MAX_CPUS]> = Mutex::new([EMPTY_CPU; MAX_CPUS]);
+/* Processors enabled in the MADT, up to MAX_CPUS */
+static CPUS_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Number of the last AP which has reached ap_main() */
+static CHECKED_IN: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Physical address of the page reserved for the trampoline, 0 if none */
+static TRAMPOLINE: AtomicUsize = ATOMIC_USIZE_INIT;
+
+static mut AP_STACKS: [[u8; AP_STACK_SIZE]; MAX_CPUS] = [[0; AP_STACK_SIZE]; MAX_CPUS];
+
+extern {
+    /* From trampoline.S */
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/* Reserves a page below 1 Mb for the trampoline. Should be called right after
+ * the physical memory manager is initialized, before low pages are handed out */
+pub fn reserve_trampoline() {
+    let mut mgr = physical_memory_manager::INSTANCE.lock();
+    let mut addr = TRAMPOLINE_SEARCH_START;
+    while addr < TRAMPOLINE_SEARCH_END {
+        if mgr.reserve_region(MemoryRegion { addr: addr, size: PAGE_SIZE }, Consumer::KernelImage) {
+            TRAMPOLINE.store(addr, Ordering::SeqCst);
+            return;
+        }
+        addr += PAGE_SIZE;
+    }
+}
+
+/* Finds processors in the MADT and starts the APs. Should be called after the
+ * local APIC and the timer of the BSP are initialized */
+pub fn init() {
+    CPUS_COUNT.store(1, Ordering::SeqCst);
+    let madt = match acpi::madt() {
+        Some(madt) => madt,
+        None => return
+    };
+    if apic::mode() == apic::Mode::Disabled {
+        println!("Application processors are not started: the local APIC is disabled.");
+        return;
+    }
+
+    let bsp_apic_id = apic::id();
+    let mut count = 1;
+    {
+        let mut cpus = CPUS.lock();
+        for entry in madt.entries() {
+            if let MadtEntry::LocalApic { processor_id, apic_id, enabled: true } = entry {
+                let cpu = if apic_id == bsp_apic_id {
+                    0
+                } else if count < MAX_CPUS {
+                    count += 1;
+                    count - 1
+                } else {
+                    println!("Processor with APIC id {} is ignored: only {} are supported.", apic_id, MAX_CPUS);
+                    continue;
+                };
+                cpus[cpu] = Cpu {
+                    apic_id:      apic_id,
+                    processor_id: processor_id,
+                    online:       cpu == 0,
+                };
+            }
+        }
+        cpus[0].apic_id = bsp_apic_id;
+        cpus[0].online = true;
+    }
+    CPUS_COUNT.store(count, Ordering::SeqCst);
+    if count == 1 {
+        return;
+    }
+
+    let trampoline = TRAMPOLINE.load(Ordering::SeqCst);
+    if trampoline == 0 {
+        println!("Application processors are not started: no memory for the trampoline.");
+        return;
+    }
+    copy_trampoline(trampoline);
+    for cpu in 1..count {
+        let apic_id = CPUS.lock()[cpu].apic_id;
+        if !start_ap(cpu, apic_id, trampoline) {
+            println!("CPU {} (APIC id {}) has not started.", cpu, apic_id);
+        }
+    }
+}
+
+/* Copies the trampoline to the reserved page and fills the data shared by all APs */
+fn copy_trampoline(trampoline: usize) {
+    let start = &ap_trampoline_start as *const u8 as usize;
+    let size = &ap_trampoline_end as *const u8 as usize - start;
+    assert!(size <= PAGE_SIZE, "The AP trampoline does not fit in a page");
+
+    let data_offset = &ap_trampoline_data as *const u8 as usize - start;
+    let (cr0, cr3, cr4): (u64, u64, u64);
+    unsafe {
+        ptr::copy_nonoverlapping(start as *const u8, trampoline as *mut u8, size);
+        asm!("movq %cr0, $0; movq %cr3, $1; movq %cr4, $2"
+             : "=r" (cr0), "=r" (cr3), "=r" (cr4)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+
+        let data = &mut *((trampoline + data_offset) as *mut TrampolineData);
+        data.gdt_base += trampoline as u32;
+        data.jump_32_offset += trampoline as u32;
+        data.jump_64_offset += trampoline as u32;
+        data.cr0 = cr0;
+        data.cr3 = cr3;
+        data.cr4 = cr4;
+        data.efer = rdmsr(msr::IA32_EFER);
+        data.entry = ap_main as usize as u64;
+    }
+}
+
+/* Runs the INIT-SIPI-SIPI sequence and waits for the AP to check in */
+fn start_ap(cpu: usize, apic_id: u32, trampoline: usize) -> bool {
+    let data_offset = &ap_trampoline_data as *const u8 as usize - &ap_trampoline_start as *const u8 as usize;
+    unsafe {
+        let data = &mut *((trampoline + data_offset) as *mut TrampolineData);
+        /* The stack grows down, keep its top 16-byte aligned */
+        data.stack = ((&AP_STACKS[cpu] as *const _ as usize + AP_STACK_SIZE) & !0xF) as u64;
+        data.cpu = cpu as u64;
+    }
+    fence(Ordering::SeqCst);
+
+    apic::send_init(apic_id);
+    timer::delay(INIT_DELAY);
+    /* The second startup IPI is ignored if the first one has worked */
+    for _ in 0..2 {
+        apic::send_startup(apic_id, (trampoline / PAGE_SIZE) as u8);
+        timer::delay(STARTUP_DELAY);
+    }
+
+    let deadline = timer::monotonic_now() + CHECK_IN_TIMEOUT;
+    while timer::monotonic_now() < deadline {
+        if CHECKED_IN.load(Ordering::SeqCst) == cpu {
+            return true;
+        }
+    }
+    false
+}
+
+/* Called by the trampoline on the AP's stack */
+extern "C" fn ap_main(cpu: usize) -> ! {
+    gdt::init(cpu);
+    percpu::init(cpu);
+    /* Exceptions raised while setting up the FPU should be reported, not triple-fault */
+    idt::load(cpu);
+    fpu::init(cpu);
+    syscall::init(cpu);
+    apic::init_ap();
+    irq::without_interrupts(|| CPUS.lock()[cpu].online = true);
+    CHECKED_IN.store(cpu, Ordering::SeqCst);
+
+    /* Nothing is scheduled on APs yet, they only answer TLB shootdown requests */
+    irq::enable();
+    loop {
+        unsafe {
+            asm!("hlt" : : : "memory" : "volatile");
+        }
+    }
+}
+
+/* Poisons the bottom of the AP stacks */
+#[cfg(feature = "kasan")]
+pub fn poison_stack_guards() {
+    for stack in unsafe { AP_STACKS.iter() } {
+        ::kasan::poison_stack_guard(stack as *const _ as usize, ::kasan::STACK_GUARD_SIZE);
+    }
+}
+
+/* APIC id of the processor if it is online */
+pub fn online_apic_id(cpu: usize) -> Option<u32> {
+    irq::without_interrupts(|| {
+        let cpus = CPUS.lock();
+        if cpus[cpu].online { Some(cpus[cpu].apic_id) } else { None }
+    })
+}
+
+/* Processors enabled in the MADT */
+pub fn cpus_count() -> usize {
+    CPUS_COUNT.load(Ordering::SeqCst)
+}
+
+pub fn online_cpus_count() -> usize {
+    irq::without_interrupts(|| CPUS.lock().iter().filter(|cpu| cpu.online).count())
+}
+
+pub fn display_smp_info() {
+    println!("CPUs: {} online of {}.", online_cpus_count(), cpus_count());
+    let cpus = irq::without_interrupts(|| *CPUS.lock());
+    for (number, cpu) in cpus.iter().take(cpus_count()).enumerate() {
+        println!("  CPU {}: APIC id {}, ACPI processor id {}{}.",
+            number, cpu.apic_id, cpu.processor_id, if cpu.online { "" } else { ", offline" });
+    }
+}
@@ -0,0 +1,350 @@
+/*
+ * Swapping pageable memory out to a block device.
+ *
+ * Pages allocated with alloc_pageable() are put into a clock list. Under memory
+ * pressure the list is scanned: recently accessed pages get their accessed bit
+ * cleared and a second chance, the others are written into a free swap slot and
+ * their page table entries are turned into swap entries (see PageTableEntry::set_swapped).
+ * A page fault on such an entry brings the page back with handle_page_fault().
+ */
+
+use spin::Mutex;
+
+use bitmap::Bitmap;
+use block::{BlockDevice, BlockError, SECTOR_SIZE};
+use layout;
+use memory::PAGE_SIZE;
+use meminfo::{self, Consumer};
+use oom::{self, Shrinker};
+use paging;
+use params::{Param, Value};
+use physical_memory_manager;
+
+kernel_param! {
+    pub static NOSWAP: Param = Param::new("noswap", Value::Bool(false),
+        "Do not use the swap device");
+}
+
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+/* The slot bitmap occupies a single page */
+const MAX_SLOTS: usize = PAGE_SIZE * 8;
+
+/* Written by mkswap at the end of the first page of the device */
+const SWAP_SIGNATURE: &'static [u8] = b"SWAPSPACE2";
+
+/* Maximal amount of pageable pages tracked for eviction */
+const MAX_PAGEABLE: usize = 1024;
+
+struct SwapArea {
+    device:     Option<&'static BlockDevice>,
+    slots:      Option<Bitmap<'static>>,
+    slots_count: usize,
+    free_slots: usize,
+    /* Virtual addresses of resident pageable pages, scanned in a round robin (clock) order */
+    pageable:   [Option<usize>; MAX_PAGEABLE],
+    clock_hand: usize,
+    /* A page the page fault handler has failed to free, used for the next swap-in */
+    spare_page: Option<(usize, Consumer)>,
+}
+
+static SWAP: Mutex<SwapArea> = Mutex::new(SwapArea {
+    device:      None,
+    slots:       None,
+    slots_count: 0,
+    free_slots:  0,
+    pageable:    [None; MAX_PAGEABLE],
+    clock_hand:  0,
+    spare_page:  None,
+});
+
+static SWAP_SHRINKER: Shrinker = Shrinker {
+    name:  "swap",
+    count: reclaimable_pages,
+    scan:  evict_pages,
+};
+
+/* Starts using the device as the swap area. The device should be formatted
+ * with mkswap: the first page is the header with the signature at its end. */
+pub fn activate(device: &'static BlockDevice) -> bool {
+    let bitmap_page = physical_memory_manager::alloc_page(Consumer::Other);
+
+    /* The bitmap page serves as a buffer for the header before it is cleared */
+    let header = unsafe { page_buffer(bitmap_page) };
+    if device.read_sectors(0, header).is_err() || &header[PAGE_SIZE - 10..] != SWAP_SIGNATURE {
+        physical_memory_manager::free_page(bitmap_page, Consumer::Other);
+        return false;
+    }
+
+    let slots_count = ::core::cmp::min((device.sectors_count() / SECTORS_PER_SLOT) as usize, MAX_SLOTS);
+    {
+        let mut swap = SWAP.lock();
+        assert!(swap.device.is_none(), "Swap is already active");
+
+        let mut slots = Bitmap::from_raw_addr(layout::phys_to_virt(bitmap_page), slots_count);
+        slots.clear();
+        /* The header is never overwritten */
+        slots.set_bit(0);
+
+        swap.device = Some(device);
+        swap.slots = Some(slots);
+        swap.slots_count = slots_count;
+        swap.free_slots = slots_count - 1;
+    }
+
+    oom::register_shrinker(&SWAP_SHRINKER);
+    println!("Swap: {} ({} slots, {} kB).", device.name(), slots_count, slots_count * PAGE_SIZE / 1024);
+    true
+}
+
+/* Allocates a page which can be swapped out and maps it at virtual_addr */
+pub fn alloc_pageable(virtual_addr: usize, flags: usize) {
+    let phys_addr = physical_memory_manager::alloc_page(consumer_of(virtual_addr));
+    paging::map(phys_addr, virtual_addr, flags);
+    track(virtual_addr);
+}
+
+/* What the page fault handler should do with a fault on a non-present page */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapIn {
+    /* The page is back, the faulting instruction can run again */
+    Done,
+    /* The page tables, the allocator, the swap area or the device are in use:
+     * the instruction should run again to fault once more */
+    Retry,
+    /* The page has not been swapped out */
+    NotSwapped,
+}
+
+/* Called from the page fault handler for a non-present page. Nothing here
+ * waits for a lock: the fault might have interrupted its owner, so waiting
+ * could never end. Contention is reported as SwapIn::Retry instead. */
+pub fn handle_page_fault(virtual_addr: usize) -> Result<SwapIn, BlockError> {
+    let page_addr = virtual_addr & !(PAGE_SIZE - 1);
+    let mut swap = match SWAP.try_lock() {
+        Some(swap) => swap,
+        None => return Ok(SwapIn::Retry)
+    };
+    let slot = match paging::try_update_pte(page_addr, |pte| if pte.is_swapped() { Some(pte.swap_slot()) } else { None }) {
+        Some(Some(Some(slot))) => slot,
+        Some(_) => return Ok(SwapIn::NotSwapped),
+        None => return Ok(SwapIn::Retry)
+    };
+    let device = match swap.device {
+        Some(device) => device,
+        None => return Ok(SwapIn::NotSwapped)
+    };
+
+    /* Memory is not reclaimed here, the swap area is locked */
+    let consumer = consumer_of(page_addr);
+    let phys_addr = match swap.spare_page.take() {
+        Some((phys_addr, spare_consumer)) => {
+            meminfo::transfer(spare_consumer, consumer, 1);
+            phys_addr
+        },
+        None => match physical_memory_manager::alloc_page_nowait(consumer) {
+            Some(phys_addr) => phys_addr,
+            None => return Ok(SwapIn::Retry)
+        }
+    };
+
+    let buffer = unsafe { page_buffer(phys_addr) };
+    match device.try_read_sectors(slot as u64 * SECTORS_PER_SLOT, buffer) {
+        Ok(()) => {},
+        Err(BlockError::Busy) => {
+            swap.release_page(phys_addr, consumer);
+            return Ok(SwapIn::Retry);
+        },
+        Err(err) => {
+            swap.release_page(phys_addr, consumer);
+            return Err(err);
+        }
+    }
+    let mapped = paging::try_update_pte(page_addr, |pte| {
+        let flags = pte.flags() & !paging::PAGE_SWAPPED;
+        pte.set(phys_addr, flags | paging::PAGE_PRESENT);
+    });
+    if mapped.is_none() {
+        swap.release_page(phys_addr, consumer);
+        return Ok(SwapIn::Retry);
+    }
+    swap.slots.as_mut().unwrap().clear_bit(slot);
+    swap.free_slots += 1;
+    swap.track(page_addr);
+    Ok(SwapIn::Done)
+}
+
+/* Swaps out the specific page, returns false if that was not possible */
+pub fn evict(virtual_addr: usize) -> bool {
+    let victim = {
+        let mut swap = SWAP.lock();
+        let index = swap.pageable.iter().position(|e| *e == Some(virtual_addr));
+        match index {
+            Some(index) => swap.evict_entry(index),
+            None => None
+        }
+    };
+    match victim {
+        Some((phys_addr, consumer)) => {
+            physical_memory_manager::free_page(phys_addr, consumer);
+            true
+        },
+        None => false
+    }
+}
+
+fn track(virtual_addr: usize) {
+    SWAP.lock().track(virtual_addr);
+}
+
+/* Pageable pages of the user space and the kernel are charged differently */
+fn consumer_of(virtual_addr: usize) -> Consumer {
+    if virtual_addr < layout::USER_SPACE_END {
+        Consumer::UserPages
+    } else {
+        Consumer::Heap
+    }
+}
+
+fn reclaimable_pages() -> u64 {
+    let swap = SWAP.lock();
+    if swap.device.is_none() {
+        return 0;
+    }
+    let resident = swap.pageable.iter().filter(|e| e.is_some()).count();
+    ::core::cmp::min(resident, swap.free_slots) as u64
+}
+
+/* Evicted pages are released in batches after the swap lock is dropped:
+ * releasing pages notifies the out-of-memory code, which might call shrinkers */
+const EVICT_BATCH: usize = 16;
+
+/* Shrinker callback: runs the clock over pageable pages */
+fn evict_pages(pages: u64) -> u64 {
+    let mut evicted = 0;
+    /* Two full rounds: the first one might only clear accessed bits */
+    let mut steps = MAX_PAGEABLE * 2;
+    while evicted < pages && steps > 0 {
+        let mut victims = [(0, Consumer::Other); EVICT_BATCH];
+        let mut count = 0;
+        {
+            let mut swap = SWAP.lock();
+            while count < EVICT_BATCH && evicted + (count as u64) < pages && steps > 0 && swap.free_slots > 0 {
+                steps -= 1;
+                let index = swap.clock_hand;
+                swap.clock_hand = (index + 1) % MAX_PAGEABLE;
+
+                let virtual_addr = match swap.pageable[index] {
+                    Some(virtual_addr) => virtual_addr,
+                    None => continue
+                };
+                let accessed = paging::update_pte(virtual_addr, |pte| {
+                    let accessed = pte.is_accessed();
+                    pte.clear_accessed();
+                    accessed
+                });
+                if accessed != Some(false) {
+                    continue;
+                }
+                if let Some(victim) = swap.evict_entry(index) {
+                    victims[count] = victim;
+                    count += 1;
+                }
+            }
+        }
+
+        for &(phys_addr, consumer) in &victims[..count] {
+            physical_memory_manager::free_page(phys_addr, consumer);
+        }
+        if count == 0 {
+            break;
+        }
+        evicted += count as u64;
+    }
+    evicted
+}
+
+impl SwapArea {
+    fn track(&mut self, virtual_addr: usize) {
+        match self.pageable.iter().position(|e| e.is_none()) {
+            Some(index) => self.pageable[index] = Some(virtual_addr),
+            /* Not tracked pages simply stay resident */
+            None => {}
+        }
+    }
+
+    /* Gives back a page allocated by the page fault handler. If the allocator
+     * is in use, the page is kept for the next swap-in instead of leaking it. */
+    fn release_page(&mut self, phys_addr: usize, consumer: Consumer) {
+        if !physical_memory_manager::free_page_nowait(phys_addr, consumer) {
+            self.spare_page = Some((phys_addr, consumer));
+        }
+    }
+
+    /* Writes the page out and returns its physical address and consumer,
+     * the caller should release it after dropping the swap lock.
+     * Other processors might still write to the page through their TLBs, so it
+     * is unmapped everywhere first. Until the swap lock is dropped, faults on it
+     * are retried, see handle_page_fault(). */
+    fn evict_entry(&mut self, index: usize) -> Option<(usize, Consumer)> {
+        let virtual_addr = self.pageable[index].unwrap();
+        let device = match self.device {
+            Some(device) => device,
+            None => return None
+        };
+        let slot = match self.slots.as_ref().unwrap().find_first_zero() {
+            Some(slot) if slot < self.slots_count => slot,
+            _ => return None
+        };
+        let mapping = paging::update_pte(virtual_addr, |pte| {
+            if !pte.is_present() {
+                return None;
+            }
+            let mapping = (pte.phys_addr(), pte.flags());
+            pte.set_swapped(slot);
+            Some(mapping)
+        });
+        let (phys_addr, flags) = match mapping {
+            Some(Some(mapping)) => mapping,
+            _ => return None
+        };
+        paging::invalidate_page_everywhere(virtual_addr);
+
+        let buffer = unsafe { page_buffer(phys_addr) };
+        if device.write_sectors(slot as u64 * SECTORS_PER_SLOT, buffer).is_err() {
+            paging::update_pte(virtual_addr, |pte| pte.set(phys_addr, flags));
+            return None;
+        }
+        self.slots.as_mut().unwrap().set_bit(slot);
+        self.free_slots -= 1;
+        self.pageable[index] = None;
+        Some((phys_addr, consumer_of(virtual_addr)))
+    }
+}
+
+unsafe fn page_buffer(phys_addr: usize) -> &'static mut [u8] {
+    ::core::slice::from_raw_parts_mut(layout::phys_to_virt(phys_addr) as *mut u8, PAGE_SIZE)
+}
+
+/* Pushes a page out to the swap device and brings it back.
+ * Only runs if a swap device has been activated. */
+pub fn swap_test() {
+    const TEST_ADDR: usize = layout::KERNEL_PAGEABLE_BASE;
+
+    if SWAP.lock().device.is_none() {
+        return;
+    }
+
+    alloc_pageable(TEST_ADDR, paging::PAGE_WRITABLE | paging::PAGE_NO_EXECUTE);
+    let page = unsafe { ::core::slice::from_raw_parts_mut(TEST_ADDR as *mut u64, PAGE_SIZE / 8) };
+    for (i, word) in page.iter_mut().enumerate() {
+        *word = (i as u64) * 0x0101010101010101;
+    }
+
+    assert!(evict(TEST_ADDR));
+    assert_eq!(Some(true), paging::update_pte(TEST_ADDR, |pte| pte.is_swapped()));
+    /* The page fault handler brings the page back on the first access */
+    for (i, word) in page.iter().enumerate() {
+        assert_eq!((i as u64) * 0x0101010101010101, *word);
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Kernel symbols for printing function names instead of raw addresses.
+ *
+ * The boot loader loads the symbol and string tables of the kernel image and
+ * passes the section headers which tell where they are. The tables stay in place,
+ * init() reserves their pages in the physical memory manager, which keeps its
+ * own data off them. Where the symbol is unknown the raw address is printed.
+ */
+
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+use spin::Mutex;
+
+use boot_info::ElfSections;
+use elf::{self, SectionHeader, Symbol};
+use layout;
+use memory::MemoryRegion;
+use meminfo::Consumer;
+use physical_memory_manager;
+
+/* Backtraces are cut after this amount of frames */
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+struct SymbolTable {
+    symbols: &'static [Symbol],
+    strings: &'static [u8],
+}
+
+static TABLE: Mutex<Option<SymbolTable>> = Mutex::new(None);
+
+/* Returns physical regions of the symbol and string tables the boot loader has
+ * loaded, the message tells why they are not available otherwise */
+pub fn table_regions(sections: &ElfSections) -> Result<[MemoryRegion; 2], &'static str> {
+    let headers = unsafe {
+        slice::from_raw_parts(layout::phys_to_virt(sections.addr) as *const SectionHeader, sections.count)
+    };
+    debug_assert_eq!(size_of::<SectionHeader>(), sections.entry_size);
+
+    let symtab = match headers.iter().find(|h| h.section_type == elf::SHT_SYMTAB) {
+        Some(symtab) => symtab,
+        None => return Err("No kernel symbol table found.")
+    };
+    let strtab = &headers[symtab.link as usize];
+    if symtab.addr == 0 || strtab.addr == 0 || strtab.section_type != elf::SHT_STRTAB {
+        return Err("Kernel symbol table is not loaded.");
+    }
+    Ok([MemoryRegion { addr: symtab.addr as usize, size: symtab.size as usize },
+        MemoryRegion { addr: strtab.addr as usize, size: strtab.size as usize }])
+}
+
+/* Finds the symbol table through the section headers. Should be called after
+ * the physical memory manager is initialized. */
+pub fn init(sections: &ElfSections) {
+    let (symtab_region, strtab_region) = match table_regions(sections) {
+        Ok(regions) => (regions[0], regions[1]),
+        Err(message) => {
+            println!("{}", message);
+            return;
+        }
+    };
+    let reserved = {
+        let mut mgr = physical_memory_manager::INSTANCE.lock();
+        /* Both are reserved, so do not short-circuit */
+        mgr.reserve_region(symtab_region, Consumer::KernelImage) &
+        mgr.reserve_region(strtab_region, Consumer::KernelImage)
+    };
+    if !reserved {
+        println!("Kernel symbol table overlaps with memory in use, symbols are not available.");
+        return;
+    }
+
+    *TABLE.lock() = Some(SymbolTable {
+        symbols: unsafe {
+            slice::from_raw_parts(layout::phys_to_virt(symtab_region.addr) as *const Symbol,
+                                  symtab_region.size / size_of::<Symbol>())
+        },
+        strings: unsafe {
+            slice::from_raw_parts(layout::phys_to_virt(strtab_region.addr) as *const u8, strtab_region.size)
+        },
+    });
+}
+
+/* Returns name of the function containing the address and offset of the address in it.
+ * Gives up if the table is locked: backtraces are printed on panics, which might
+ * happen while the lock is held. */
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    /* The symbol table has link addresses, the kernel might be moved since */
+    let addr = addr.wrapping_sub(layout::kaslr_offset());
+    let table = match TABLE.try_lock() {
+        Some(table) => table,
+        None => return None
+    };
+    let table = match *table {
+        Some(ref table) => table,
+        None => return None
+    };
+
+    let symbols: &'static [Symbol] = table.symbols;
+    let mut best: Option<&'static Symbol> = None;
+    for symbol in symbols.iter() {
+        let start = symbol.value as usize;
+        if symbol.symbol_type() != elf::STT_FUNC || start > addr {
+            continue;
+        }
+        if symbol.size != 0 && addr >= start + symbol.size as usize {
+            continue;
+        }
+        if best.map_or(true, |best| best.value < symbol.value) {
+            best = Some(symbol);
+        }
+    }
+
+    best.map(|symbol| (table.name(symbol), addr - symbol.value as usize))
+}
+
+impl SymbolTable {
+    fn name(&self, symbol: &Symbol) -> &'static str {
+        let start = symbol.name as usize;
+        if start >= self.strings.len() {
+            return "";
+        }
+        let bytes = &self.strings[start..];
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        str::from_utf8(&bytes[..len]).unwrap_or("")
+    }
+}
+
+/* Formats the address as "function+0xoffset" */
+pub struct Symbolized(pub usize);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some((name, offset)) => write!(f, "{}+0x{:x}", Demangled(name), offset),
+            None => write!(f, "??")
+        }
+    }
+}
+
+/* Formats a mangled Rust name (_ZN<len><part>...E) as a path, the hash part is skipped */
+struct Demangled<'a>(&'a str);
+
+impl<'a> fmt::Display for Demangled<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.0;
+        if !name.starts_with("_ZN") || !name.ends_with("E") {
+            return write!(f, "{}", name);
+        }
+
+        let mut rest = &name[3..name.len() - 1];
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|b| (*b as char).is_digit(10)).count();
+            let len = match usize::from_str_radix(&rest[..digits], 10) {
+                Ok(len) if digits + len <= rest.len() => len,
+                _ => return write!(f, "{}", name)
+            };
+            let part = &rest[digits..digits + len];
+            rest = &rest[digits + len..];
+
+            if rest.is_empty() && is_hash(part) {
+                break;
+            }
+            if !first {
+                try!(write!(f, "::"));
+            }
+            try!(write!(f, "{}", part));
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+fn is_hash(part: &str) -> bool {
+    part.len() == 17 && part.starts_with('h') && part[1..].chars().all(|c| c.is_digit(16))
+}
+
+/* Prints return addresses found by following the frame pointers.
+ * The chain is terminated by the zero frame pointer set by the bootstrap. */
+#[inline(never)]
+pub fn print_backtrace() {
+    let mut rbp: usize;
+    unsafe {
+        asm!("mov %rbp, $0"
+             : "=r" (rbp)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+    }
+
+    println!("Backtrace:");
+    for _ in 0..MAX_BACKTRACE_FRAMES {
+        if rbp == 0 || rbp % size_of::<usize>() != 0 {
+            break;
+        }
+        let (next_rbp, ip) = unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        if ip == 0 {
+            break;
+        }
+        println!("  0x{:016x} {}", ip, Symbolized(ip));
+        /* The stack grows down, callers' frames are above */
+        if next_rbp <= rbp {
+            break;
+        }
+        rbp = next_rbp;
+    }
+}
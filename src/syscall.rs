@@ -0,0 +1,236 @@
+/*
+ * System calls.
+ *
+ * User code enters the kernel with the syscall instruction or with int 0x80,
+ * which is slower but handy for debugging and works from kernel mode too.
+ * Both entries in traps.S save registers as struct trap_regs and come to
+ * handle() through trap_handler(). The call number is passed in rax and up
+ * to six arguments in rdi, rsi, rdx, r10, r8 and r9 (rcx and r11 are taken
+ * by the syscall instruction). The result is returned in rax, errors as
+ * negated errno values like in Linux: -4095..-1 is never a valid result.
+ *
+ * The syscall entry switches to the kernel stack of the current thread kept
+ * in the per-CPU area. Until there are threads every CPU has a static one.
+ */
+
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+
+use cpuid;
+use gdt::{self, KERNEL_CODE_SELECTOR, USER_DATA_SELECTOR};
+use idt;
+use irq;
+use layout::USER_SPACE_END;
+use msr::{self, rdmsr, wrmsr};
+use percpu;
+use rtc;
+use smp::MAX_CPUS;
+use timer::{self, Duration};
+use traps::TrapRegs;
+
+/* Vector of the int 0x80 gate, should be synchronized with SYSCALL_VECTOR in kernel.h */
+pub const VECTOR: u8 = 0x80;
+
+/*
+ * Call numbers.
+ */
+
+pub const SYS_WRITE: usize =         0;
+pub const SYS_GETCPU: usize =        1;
+pub const SYS_CLOCK_GETTIME: usize = 2;
+pub const SYS_NANOSLEEP: usize =     3;
+
+const SYSCALLS_COUNT: usize = 4;
+
+pub const ARGS_COUNT: usize = 6;
+
+/* Errors returned to user code, values are the same as in Linux */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Errno {
+    EBADF  = 9,
+    EFAULT = 14,
+    EINVAL = 22,
+    ENOSYS = 38,
+}
+
+/* File descriptors sys_write() accepts, both go to the kernel console */
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+/* Clocks of sys_clock_gettime() */
+const CLOCK_REALTIME: usize =  0;
+const CLOCK_MONOTONIC: usize = 1;
+
+/* Bits of rflags cleared on the syscall entry: interrupts, single-stepping,
+ * direction and alignment check */
+const RFLAGS_TF: u64 = 1 << 8;
+const RFLAGS_DF: u64 = 1 << 10;
+const RFLAGS_AC: u64 = 1 << 18;
+const SYSCALL_RFLAGS_MASK: u64 = irq::RFLAGS_IF | RFLAGS_TF | RFLAGS_DF | RFLAGS_AC;
+
+const ENTRY_STACK_SIZE: usize = 0x2000;
+
+pub type Args = [usize; ARGS_COUNT];
+
+type Handler = fn(&Args) -> Result<usize, Errno>;
+
+static SYSCALLS: [Handler; SYSCALLS_COUNT] = [
+    sys_write,
+    sys_getcpu,
+    sys_clock_gettime,
+    sys_nanosleep,
+];
+
+static SYSCALL_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+static mut ENTRY_STACKS: [[u8; ENTRY_STACK_SIZE]; MAX_CPUS] = [[0; ENTRY_STACK_SIZE]; MAX_CPUS];
+
+extern {
+    /* From traps.S */
+    fn syscall_entry();
+    fn syscall_interrupt();
+}
+
+/* Poisons the bottom of the entry stacks of every processor */
+#[cfg(feature = "kasan")]
+pub fn poison_stack_guards() {
+    for stack in unsafe { ENTRY_STACKS.iter() } {
+        ::kasan::poison_stack_guard(stack as *const _ as usize, ::kasan::STACK_GUARD_SIZE);
+    }
+}
+
+/* Sets up both entries on the current CPU. Should be called after percpu::init()
+ * and, on the bootstrap processor, before APs are started as they copy its EFER */
+pub fn init(cpu: usize) {
+    let stack_top = unsafe { (&ENTRY_STACKS[cpu] as *const _ as usize + ENTRY_STACK_SIZE) & !0xF };
+    gdt::set_kernel_stack(stack_top);
+    if cpu == 0 {
+        idt::set_gate(VECTOR, syscall_interrupt as usize, 0, true);
+    }
+
+    if !cpuid::has_syscall() {
+        return;
+    }
+    /* sysret loads user segments from the base in the upper word: SS is
+     * base + 8, 64-bit CS is base + 16, see the GDT layout in gdt.rs */
+    let star = ((USER_DATA_SELECTOR as u64 - 8) << 48) | ((KERNEL_CODE_SELECTOR as u64) << 32);
+    unsafe {
+        wrmsr(msr::IA32_STAR, star);
+        wrmsr(msr::IA32_LSTAR, syscall_entry as usize as u64);
+        wrmsr(msr::IA32_FMASK, SYSCALL_RFLAGS_MASK);
+        wrmsr(msr::IA32_EFER, rdmsr(msr::IA32_EFER) | msr::EFER_SCE);
+    }
+    SYSCALL_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/* Called by trap_handler() for both entries */
+pub fn handle(regs: &mut TrapRegs) {
+    let number = regs.rax as usize;
+    let args = [regs.rdi as usize, regs.rsi as usize, regs.rdx as usize,
+                regs.r10 as usize, regs.r8 as usize, regs.r9 as usize];
+
+    /* Interrupts are enabled during the call if the caller had them enabled */
+    if regs.rflags & irq::RFLAGS_IF != 0 {
+        irq::enable();
+    }
+    let result = match SYSCALLS.get(number) {
+        Some(handler) => handler(&args),
+        None => Err(Errno::ENOSYS)
+    };
+    irq::disable();
+
+    regs.rax = match result {
+        Ok(value) => value as u64,
+        Err(errno) => -(errno as i64) as u64
+    };
+}
+
+/* Checks that the buffer lies in user space. The memory is not checked to be
+ * mapped: a fault on it is not handled yet and stops the kernel. */
+fn user_slice<'a>(addr: usize, len: usize) -> Result<&'a [u8], Errno> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    match addr.checked_add(len) {
+        Some(end) if addr != 0 && end <= USER_SPACE_END => {
+            Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+        },
+        _ => Err(Errno::EFAULT)
+    }
+}
+
+/* write(fd, buf, count) -> count */
+fn sys_write(args: &Args) -> Result<usize, Errno> {
+    let (fd, buf, count) = (args[0], args[1], args[2]);
+    if fd != STDOUT && fd != STDERR {
+        return Err(Errno::EBADF);
+    }
+    let bytes = try!(user_slice(buf, count));
+    let text = try!(str::from_utf8(bytes).map_err(|_| Errno::EINVAL));
+    print!("{}", text);
+    Ok(count)
+}
+
+/* getcpu() -> number of the CPU the caller runs on */
+fn sys_getcpu(_: &Args) -> Result<usize, Errno> {
+    Ok(percpu::cpu_id())
+}
+
+/* clock_gettime(clock) -> nanoseconds */
+fn sys_clock_gettime(args: &Args) -> Result<usize, Errno> {
+    let time = match args[0] {
+        CLOCK_REALTIME => {
+            if !rtc::is_available() {
+                return Err(Errno::EINVAL);
+            }
+            rtc::realtime_now()
+        },
+        CLOCK_MONOTONIC => timer::monotonic_now(),
+        _ => return Err(Errno::EINVAL)
+    };
+    Ok(time.as_nanos() as usize)
+}
+
+/* nanosleep(nanoseconds) -> 0 */
+fn sys_nanosleep(args: &Args) -> Result<usize, Errno> {
+    timer::sleep(Duration::from_nanos(args[0] as u64));
+    Ok(0)
+}
+
+pub fn display_syscall_info() {
+    if SYSCALL_ENABLED.load(Ordering::SeqCst) {
+        println!("System calls: syscall instruction and int 0x{:x}.", VECTOR);
+    } else {
+        println!("System calls: int 0x{:x} only, syscall is not supported.", VECTOR);
+    }
+}
+
+/* Makes a call through the int 0x80 gate, which works from kernel mode */
+fn int80(number: usize, arg0: usize, arg1: usize, arg2: usize) -> isize {
+    let result: usize;
+    unsafe {
+        asm!("int $$0x80"
+             : "={rax}" (result)
+             : "{rax}" (number), "{rdi}" (arg0), "{rsi}" (arg1), "{rdx}" (arg2)
+             : "memory"
+             : "volatile");
+    }
+    result as isize
+}
+
+pub fn syscall_test() {
+    assert_eq!(int80(SYS_GETCPU, 0, 0, 0), 0);
+    assert_eq!(int80(SYSCALLS_COUNT, 0, 0, 0), -(Errno::ENOSYS as isize));
+
+    let text = b"test";
+    let kernel_buf = text.as_ptr() as usize;
+    assert_eq!(int80(SYS_WRITE, STDOUT, kernel_buf, text.len()), -(Errno::EFAULT as isize));
+    assert_eq!(int80(SYS_WRITE, 3, kernel_buf, text.len()), -(Errno::EBADF as isize));
+    assert_eq!(int80(SYS_WRITE, STDOUT, 0, 0), 0);
+
+    let first = int80(SYS_CLOCK_GETTIME, CLOCK_MONOTONIC, 0, 0);
+    let second = int80(SYS_CLOCK_GETTIME, CLOCK_MONOTONIC, 0, 0);
+    assert!(first > 0 && second >= first);
+    assert_eq!(int80(SYS_CLOCK_GETTIME, 7, 0, 0), -(Errno::EINVAL as isize));
+}
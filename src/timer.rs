@@ -0,0 +1,576 @@
+/*
+ * Time keeping and timers.
+ *
+ * A clocksource is a free running counter the time is read from: the TSC if it
+ * runs at a constant rate, otherwise the HPET main counter, otherwise PIT
+ * channel 0 extended by counting its interrupts. The TSC and the local APIC
+ * timer have no documented frequency, both are calibrated against the HPET or,
+ * without one, against PIT channel 2.
+ *
+ * A clockevent device interrupts when timers expire: the local APIC timer in
+ * the TSC-deadline, one-shot or periodic mode, or PIT channel 0 raising IRQ 0
+ * periodically. One-shot devices are programmed for the nearest timer, but
+ * fire at least every MAX_EVENT_DELAY_NS. Timer callbacks run in the interrupt
+ * handler with interrupts disabled.
+ */
+
+use core::cmp;
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use spin::Mutex;
+
+use acpi;
+use apic::{self, TimerMode};
+use cpuid;
+use hpet;
+use irq;
+use params::{Param, Value};
+use pit;
+use port::io_wait;
+
+kernel_param! {
+    pub static CLOCKSOURCE: Param = Param::new("clocksource", Value::Str("auto"),
+        "Clock to read the time from: auto, tsc, hpet or pit");
+
+    pub static LAPIC_TIMER: Param = Param::new("lapic_timer", Value::Str("auto"),
+        "Local APIC timer mode: auto, deadline, oneshot, periodic or off to use the PIT");
+}
+
+/* Frequency of interrupts of the periodic clockevent devices */
+pub const TICK_HZ: u64 = 1000;
+
+const PIT_IRQ: u8 = 0;
+const PIT_DIVISOR: u64 = (pit::FREQUENCY + TICK_HZ / 2) / TICK_HZ;
+
+const NANOS_PER_SEC: u64 = 1000000000;
+
+/* The TSC and the local APIC timer are counted during this interval of the
+ * reference clock, the lowest result of several rounds is taken as delays
+ * (e.g. SMIs or a busy host) only make the counts bigger */
+const CALIBRATION_MS: u64 = 10;
+const CALIBRATION_ROUNDS: usize = 3;
+
+/* Limits of the interval a one-shot device is programmed for */
+const MAX_EVENT_DELAY_NS: u64 = 100000000;
+const MIN_EVENT_DELAY_NS: u64 = 1000;
+
+const MAX_TIMERS: usize = 32;
+
+/* Time span with nanosecond precision, also used for the time since boot */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    nanos: u64,
+}
+
+impl Duration {
+    pub const fn from_nanos(nanos: u64) -> Duration {
+        Duration { nanos: nanos }
+    }
+
+    pub const fn from_micros(micros: u64) -> Duration {
+        Duration { nanos: micros * 1000 }
+    }
+
+    pub const fn from_millis(millis: u64) -> Duration {
+        Duration { nanos: millis * 1000000 }
+    }
+
+    pub const fn from_secs(secs: u64) -> Duration {
+        Duration { nanos: secs * NANOS_PER_SEC }
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    pub fn as_micros(&self) -> u64 {
+        self.nanos / 1000
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.nanos / 1000000
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.nanos / NANOS_PER_SEC
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, other: Duration) -> Duration {
+        Duration::from_nanos(self.nanos + other.nanos)
+    }
+}
+
+/* Saturates at zero, so a difference of two readings is never negative */
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, other: Duration) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(other.nanos))
+    }
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:09} s", self.nanos / NANOS_PER_SEC, self.nanos % NANOS_PER_SEC)
+    }
+}
+
+/* Prints a frequency in Hz as MHz with three decimals */
+struct Frequency(u64);
+
+impl fmt::Display for Frequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:03} MHz", self.0 / 1000000, self.0 / 1000 % 1000)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clocksource {
+    None,
+    Tsc,
+    Hpet,
+    Pit,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clockevent {
+    None,
+    TscDeadline,
+    LapicOneShot,
+    LapicPeriodic,
+    Pit,
+}
+
+/* Called from the timer interrupt when the timer expires */
+pub type Callback = fn(data: usize);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerId(usize);
+
+#[derive(Clone, Copy)]
+struct Timer {
+    id:       usize,
+    /* Time since boot in nanoseconds */
+    expires:  u64,
+    callback: Callback,
+    data:     usize,
+}
+
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+static NEXT_TIMER_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Kept in atomics instead of a mutex as they are read by interrupt handlers */
+static SOURCE: AtomicUsize = ATOMIC_USIZE_INIT;
+static SOURCE_FREQUENCY: AtomicUsize = ATOMIC_USIZE_INIT;
+static EVENT_DEVICE: AtomicUsize = ATOMIC_USIZE_INIT;
+static TSC_FREQUENCY: AtomicUsize = ATOMIC_USIZE_INIT;
+static LAPIC_FREQUENCY: AtomicUsize = ATOMIC_USIZE_INIT;
+/* TSC value the monotonic time counts from */
+static TSC_BASE: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Interrupts of PIT channel 0 */
+static PIT_TICKS: AtomicUsize = ATOMIC_USIZE_INIT;
+/* Last value of the PIT clocksource, it must not go back */
+static PIT_LAST: Mutex<u64> = Mutex::new(0);
+/* Interrupts of the clockevent device */
+static EVENTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Calibrates clocks and chooses the clocksource and the clockevent device.
+ * Should be called after the interrupt controller is set up. */
+pub fn init() {
+    if let Some(hpet) = acpi::hpet() {
+        if !hpet::init(&hpet) {
+            println!("HPET at 0x{:x} is unusable.", hpet.addr);
+        }
+    }
+    let (tsc_frequency, lapic_frequency) = if cpuid::get_cpu_info().features1 & cpuid::CPU_FEAT1_TSC != 0 {
+        calibrate()
+    } else {
+        (0, 0)
+    };
+    TSC_FREQUENCY.store(tsc_frequency as usize, Ordering::SeqCst);
+    LAPIC_FREQUENCY.store(lapic_frequency as usize, Ordering::SeqCst);
+
+    let source = choose_clocksource(tsc_frequency);
+    let event = choose_clockevent(tsc_frequency, lapic_frequency);
+    if source == Clocksource::Pit || event == Clockevent::Pit {
+        pit::start_periodic(PIT_DIVISOR as u16);
+        irq::register(PIT_IRQ, pit_interrupt);
+    }
+
+    let source_frequency = match source {
+        Clocksource::Tsc => tsc_frequency,
+        Clocksource::Hpet => hpet::frequency(),
+        _ => pit::FREQUENCY
+    };
+    TSC_BASE.store(rdtsc() as usize, Ordering::SeqCst);
+    SOURCE_FREQUENCY.store(source_frequency as usize, Ordering::SeqCst);
+    SOURCE.store(source as usize, Ordering::SeqCst);
+
+    match event {
+        Clockevent::TscDeadline => apic::set_timer_mode(TimerMode::TscDeadline),
+        Clockevent::LapicOneShot => apic::set_timer_mode(TimerMode::OneShot),
+        Clockevent::LapicPeriodic => {
+            apic::set_timer_mode(TimerMode::Periodic);
+            apic::arm_timer((lapic_frequency / TICK_HZ) as u32);
+        },
+        _ => {}
+    }
+    EVENT_DEVICE.store(event as usize, Ordering::SeqCst);
+    irq::without_interrupts(|| program_next_event());
+}
+
+fn choose_clocksource(tsc_frequency: u64) -> Clocksource {
+    match CLOCKSOURCE.get_str() {
+        "tsc" if tsc_frequency != 0 => return Clocksource::Tsc,
+        "hpet" if hpet::is_available() => return Clocksource::Hpet,
+        "pit" => return Clocksource::Pit,
+        "auto" => {},
+        name => println!("Clocksource {} is unavailable, choosing another one.", name)
+    }
+    if tsc_frequency != 0 && cpuid::has_invariant_tsc() {
+        Clocksource::Tsc
+    } else if hpet::is_available() {
+        Clocksource::Hpet
+    } else {
+        Clocksource::Pit
+    }
+}
+
+/* The local APIC timer is preferred, the PIT is used if the APIC is disabled */
+fn choose_clockevent(tsc_frequency: u64, lapic_frequency: u64) -> Clockevent {
+    if lapic_frequency == 0 {
+        return Clockevent::Pit;
+    }
+    let has_deadline = tsc_frequency != 0 &&
+        cpuid::get_cpu_info().features2 & cpuid::CPU_FEAT2_TSC_DEADLINE != 0;
+    match LAPIC_TIMER.get_str() {
+        "off" => Clockevent::Pit,
+        "periodic" => Clockevent::LapicPeriodic,
+        "oneshot" => Clockevent::LapicOneShot,
+        _ if has_deadline => Clockevent::TscDeadline,
+        _ => Clockevent::LapicOneShot
+    }
+}
+
+/* Measures frequencies of the TSC and the local APIC timer (0 if the APIC is disabled) */
+fn calibrate() -> (u64, u64) {
+    let lapic = apic::mode() != apic::Mode::Disabled;
+    let mut result = (0, 0);
+    for _ in 0..CALIBRATION_ROUNDS {
+        let (tsc_frequency, lapic_frequency) = irq::without_interrupts(|| calibration_round(lapic));
+        if tsc_frequency == 0 {
+            println!("Unable to calibrate the TSC: no reference clock.");
+            return (0, 0);
+        }
+        if result.0 == 0 || tsc_frequency < result.0 {
+            result = (tsc_frequency, lapic_frequency);
+        }
+    }
+    result
+}
+
+fn calibration_round(lapic: bool) -> (u64, u64) {
+    if lapic {
+        apic::start_timer_calibration();
+    }
+    let lapic_start = if lapic { apic::timer_count() } else { 0 };
+    let tsc_start = rdtsc();
+
+    let nanos = if hpet::is_available() {
+        let frequency = hpet::frequency();
+        let start = hpet::read_counter();
+        let end = start + frequency * CALIBRATION_MS / 1000;
+        let mut now = start;
+        while now < end {
+            now = hpet::read_counter();
+        }
+        ticks_to_nanos(now - start, frequency)
+    } else {
+        let ticks = pit::FREQUENCY * CALIBRATION_MS / 1000;
+        if !pit::wait(ticks as u16) {
+            return (0, 0);
+        }
+        ticks_to_nanos(ticks, pit::FREQUENCY)
+    };
+
+    let tsc_end = rdtsc();
+    let lapic_end = if lapic { apic::timer_count() } else { 0 };
+    if lapic {
+        apic::stop_timer();
+    }
+    ((tsc_end - tsc_start) * NANOS_PER_SEC / nanos,
+     (lapic_start - lapic_end) as u64 * NANOS_PER_SEC / nanos)
+}
+
+pub fn clocksource() -> Clocksource {
+    match SOURCE.load(Ordering::SeqCst) {
+        1 => Clocksource::Tsc,
+        2 => Clocksource::Hpet,
+        3 => Clocksource::Pit,
+        _ => Clocksource::None
+    }
+}
+
+pub fn clockevent() -> Clockevent {
+    match EVENT_DEVICE.load(Ordering::SeqCst) {
+        1 => Clockevent::TscDeadline,
+        2 => Clockevent::LapicOneShot,
+        3 => Clockevent::LapicPeriodic,
+        4 => Clockevent::Pit,
+        _ => Clockevent::None
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc"
+             : "={eax}" (low), "={edx}" (high)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/* Time since init(), zero before it */
+pub fn monotonic_now() -> Duration {
+    let ticks = match clocksource() {
+        Clocksource::Tsc => rdtsc() - TSC_BASE.load(Ordering::SeqCst) as u64,
+        Clocksource::Hpet => hpet::read_counter(),
+        Clocksource::Pit => pit_counter(),
+        Clocksource::None => return Duration::from_nanos(0)
+    };
+    Duration::from_nanos(ticks_to_nanos(ticks, SOURCE_FREQUENCY.load(Ordering::SeqCst) as u64))
+}
+
+/* Ticks of the PIT input clock since IRQ 0 has been set up */
+fn pit_counter() -> u64 {
+    irq::without_interrupts(|| {
+        let mut last = PIT_LAST.lock();
+        let count = pit::read_count() as u64;
+        let mut counter = PIT_TICKS.load(Ordering::SeqCst) as u64 * PIT_DIVISOR + PIT_DIVISOR - count;
+        /* The count has wrapped, but IRQ 0 is not handled yet */
+        if counter < *last {
+            counter = *last;
+        }
+        *last = counter;
+        counter
+    })
+}
+
+/* Split so that the multiplication doesn't overflow for frequencies up to 18 GHz */
+fn ticks_to_nanos(ticks: u64, frequency: u64) -> u64 {
+    ticks / frequency * NANOS_PER_SEC + ticks % frequency * NANOS_PER_SEC / frequency
+}
+
+fn nanos_to_ticks(nanos: u64, frequency: u64) -> u64 {
+    nanos / NANOS_PER_SEC * frequency + nanos % NANOS_PER_SEC * frequency / NANOS_PER_SEC
+}
+
+/* Busy-waits. The TSC is used once calibrated, until then I/O port writes
+ * taking about a microsecond each. */
+pub fn delay(duration: Duration) {
+    let frequency = TSC_FREQUENCY.load(Ordering::SeqCst) as u64;
+    if frequency == 0 {
+        for _ in 0..duration.as_micros() {
+            unsafe {
+                io_wait();
+            }
+        }
+        return;
+    }
+    let end = rdtsc() + nanos_to_ticks(duration.as_nanos(), frequency);
+    while rdtsc() < end {
+        unsafe {
+            asm!("pause" : : : "memory" : "volatile");
+        }
+    }
+}
+
+fn wake_up(_data: usize) {
+}
+
+/* Halts the CPU until the time passes. Busy-waits if interrupts are
+ * disabled or there is no timer to wake the CPU. */
+pub fn sleep(duration: Duration) {
+    if clockevent() == Clockevent::None || !irq::are_enabled() {
+        delay(duration);
+        return;
+    }
+    let end = monotonic_now() + duration;
+    if add_timer(duration, wake_up, 0).is_none() {
+        delay(duration);
+        return;
+    }
+    loop {
+        irq::disable();
+        if monotonic_now() >= end {
+            break;
+        }
+        /* Interrupts are enabled after the instruction following sti,
+         * so the timer can't fire between the check and hlt */
+        unsafe {
+            asm!("sti; hlt" : : : "memory" : "volatile");
+        }
+    }
+    irq::enable();
+}
+
+/* Calls the callback with the data from the timer interrupt after the delay.
+ * Returns None if there are too many timers. */
+pub fn add_timer(delay: Duration, callback: Callback, data: usize) -> Option<TimerId> {
+    let timer = Timer {
+        id:       NEXT_TIMER_ID.fetch_add(1, Ordering::SeqCst),
+        expires:  (monotonic_now() + delay).as_nanos(),
+        callback: callback,
+        data:     data,
+    };
+    irq::without_interrupts(|| {
+        let added = {
+            let mut timers = TIMERS.lock();
+            match timers.iter().position(|t| t.is_none()) {
+                Some(index) => {
+                    timers[index] = Some(timer);
+                    true
+                },
+                None => false
+            }
+        };
+        if added {
+            program_next_event();
+            Some(TimerId(timer.id))
+        } else {
+            None
+        }
+    })
+}
+
+/* Returns false if the timer has already expired or been cancelled */
+pub fn cancel_timer(id: TimerId) -> bool {
+    irq::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        match timers.iter().position(|t| t.map_or(false, |timer| timer.id == id.0)) {
+            Some(index) => {
+                timers[index] = None;
+                true
+            },
+            None => false
+        }
+    })
+}
+
+/* Programs a one-shot device for the nearest timer. Called with interrupts disabled */
+fn program_next_event() {
+    let event = clockevent();
+    if event != Clockevent::TscDeadline && event != Clockevent::LapicOneShot {
+        return;
+    }
+    let now = monotonic_now().as_nanos();
+    let nearest = TIMERS.lock().iter().filter_map(|t| t.map(|timer| timer.expires)).min();
+    let delay = match nearest {
+        Some(expires) => cmp::min(cmp::max(expires.saturating_sub(now), MIN_EVENT_DELAY_NS), MAX_EVENT_DELAY_NS),
+        None => MAX_EVENT_DELAY_NS
+    };
+    if event == Clockevent::TscDeadline {
+        let frequency = TSC_FREQUENCY.load(Ordering::SeqCst) as u64;
+        apic::arm_timer_deadline(rdtsc() + nanos_to_ticks(delay, frequency));
+    } else {
+        let frequency = LAPIC_FREQUENCY.load(Ordering::SeqCst) as u64;
+        apic::arm_timer(cmp::max(nanos_to_ticks(delay, frequency), 1) as u32);
+    }
+}
+
+/* Runs callbacks of expired timers, called from interrupt handlers */
+fn handle_event() {
+    EVENTS.fetch_add(1, Ordering::SeqCst);
+    let now = monotonic_now().as_nanos();
+    loop {
+        /* Callbacks are called without the lock, so they can add timers */
+        let expired = {
+            let mut timers = TIMERS.lock();
+            let index = timers.iter().position(|t| t.map_or(false, |timer| timer.expires <= now));
+            index.and_then(|index| timers[index].take())
+        };
+        match expired {
+            Some(timer) => (timer.callback)(timer.data),
+            None => break
+        }
+    }
+    program_next_event();
+}
+
+/* Called by trap_handler() for the local APIC timer vector */
+pub fn handle_apic_timer() {
+    handle_event();
+    apic::eoi();
+}
+
+fn pit_interrupt(_line: u8) -> bool {
+    PIT_TICKS.fetch_add(1, Ordering::SeqCst);
+    if clockevent() == Clockevent::Pit {
+        handle_event();
+    }
+    true
+}
+
+pub fn display_timer_info() {
+    let source = clocksource();
+    let frequency = SOURCE_FREQUENCY.load(Ordering::SeqCst) as u64;
+    if source == Clocksource::None {
+        println!("No clocksource.");
+        return;
+    }
+    println!("Clocksource: {:?} at {}, resolution {} ns.",
+        source, Frequency(frequency), cmp::max(NANOS_PER_SEC / frequency, 1));
+
+    let reference = if hpet::is_available() { "HPET" } else { "PIT" };
+    let tsc_frequency = TSC_FREQUENCY.load(Ordering::SeqCst) as u64;
+    let lapic_frequency = LAPIC_FREQUENCY.load(Ordering::SeqCst) as u64;
+    if lapic_frequency != 0 {
+        println!("Calibrated against the {}: TSC at {}, local APIC timer at {}.",
+            reference, Frequency(tsc_frequency), Frequency(lapic_frequency));
+    } else if tsc_frequency != 0 {
+        println!("Calibrated against the {}: TSC at {}.", reference, Frequency(tsc_frequency));
+    }
+
+    let event = clockevent();
+    let resolution = match event {
+        Clockevent::TscDeadline => NANOS_PER_SEC / tsc_frequency,
+        Clockevent::LapicOneShot => NANOS_PER_SEC / lapic_frequency,
+        _ => NANOS_PER_SEC / TICK_HZ
+    };
+    println!("Clockevent: {:?}, resolution {} ns, {} events so far.",
+        event, cmp::max(resolution, 1), EVENTS.load(Ordering::SeqCst));
+}
+
+static TEST_CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn test_callback(data: usize) {
+    TEST_CALLS.fetch_add(data, Ordering::SeqCst);
+}
+
+/* Checks that sleeping takes as long as asked and timers fire unless cancelled */
+pub fn timer_test() {
+    let start = monotonic_now();
+    sleep(Duration::from_millis(10));
+    assert!(monotonic_now() - start >= Duration::from_millis(10));
+
+    let cancelled = add_timer(Duration::from_millis(1), test_callback, 100).unwrap();
+    assert!(cancel_timer(cancelled));
+    assert!(!cancel_timer(cancelled));
+    let fired = add_timer(Duration::from_millis(1), test_callback, 1).unwrap();
+    sleep(Duration::from_millis(5));
+    assert_eq!(TEST_CALLS.load(Ordering::SeqCst), 1);
+    assert!(!cancel_timer(fired));
+
+    assert_eq!(Duration::from_secs(1), Duration::from_millis(500) + Duration::from_micros(500000));
+    assert_eq!(Duration::from_nanos(0), Duration::from_millis(1) - Duration::from_secs(1));
+}
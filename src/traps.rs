@@ -0,0 +1,288 @@
+/*
+ * CPU exception handling.
+ *
+ * Entries in traps.S save registers as struct trap_regs (see kernel.h) and call
+ * trap_handler(). Exceptions with a handler in the EXCEPTIONS table are given
+ * a chance to resolve the problem, anything else ends with a register dump.
+ * Hardware interrupts come through the same path and are passed to irq.rs,
+ * the local APIC timer to timer.rs. System calls, from both the syscall
+ * instruction and int 0x80, go to syscall.rs.
+ */
+
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use apic;
+use idt::EXCEPTIONS_COUNT;
+use irq;
+use paging;
+use percpu;
+use pic::{IRQ_BASE_VECTOR, IRQ_LINES_COUNT};
+use swap;
+use syscall;
+use symbols::Symbolized;
+use timer;
+
+/* Layout should be synchronized with struct trap_regs in kernel.h */
+#[repr(C)]
+pub struct TrapRegs {
+    pub rax:        u64,
+    pub rbx:        u64,
+    pub rcx:        u64,
+    pub rdx:        u64,
+    pub rdi:        u64,
+    pub rsi:        u64,
+    pub r8:         u64,
+    pub r9:         u64,
+    pub r10:        u64,
+    pub r11:        u64,
+    pub r12:        u64,
+    pub r13:        u64,
+    pub r14:        u64,
+    pub r15:        u64,
+    pub rbp:        u64,
+    pub ds:         u64,
+    pub es:         u64,
+    pub fs:         u64,
+    pub gs:         u64,
+    pub trap_no:    u64,
+
+    /* Stack frame set by CPU */
+    pub error_code: u64,
+    pub rip:        u64,
+    pub cs:         u64,
+    pub rflags:     u64,
+    pub rsp:        u64,
+    pub ss:         u64,
+}
+
+/* How the error code of an exception is interpreted */
+#[derive(Clone, Copy, PartialEq)]
+enum ErrorCode {
+    None,
+    Selector,
+    PageFault,
+    Other,
+}
+
+struct Exception {
+    name:       &'static str,
+    mnemonic:   &'static str,
+    error_code: ErrorCode,
+    /* Returns true if the problem is resolved and the code can be resumed */
+    handler:    Option<fn(&mut TrapRegs) -> bool>,
+}
+
+static EXCEPTIONS: [Exception; EXCEPTIONS_COUNT] = [
+    Exception { name: "Divide error",                  mnemonic: "#DE", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Debug",                         mnemonic: "#DB", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Non-maskable interrupt",        mnemonic: "NMI", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Breakpoint",                    mnemonic: "#BP", error_code: ErrorCode::None,      handler: Some(breakpoint) },
+    Exception { name: "Overflow",                      mnemonic: "#OF", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Bound range exceeded",          mnemonic: "#BR", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Invalid opcode",                mnemonic: "#UD", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Device not available",          mnemonic: "#NM", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Double fault",                  mnemonic: "#DF", error_code: ErrorCode::Other,     handler: None },
+    Exception { name: "Coprocessor segment overrun",   mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Invalid TSS",                   mnemonic: "#TS", error_code: ErrorCode::Selector,  handler: None },
+    Exception { name: "Segment not present",           mnemonic: "#NP", error_code: ErrorCode::Selector,  handler: None },
+    Exception { name: "Stack-segment fault",           mnemonic: "#SS", error_code: ErrorCode::Selector,  handler: None },
+    Exception { name: "General protection",            mnemonic: "#GP", error_code: ErrorCode::Selector,  handler: None },
+    Exception { name: "Page fault",                    mnemonic: "#PF", error_code: ErrorCode::PageFault, handler: Some(page_fault) },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "x87 floating-point error",      mnemonic: "#MF", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Alignment check",               mnemonic: "#AC", error_code: ErrorCode::Other,     handler: None },
+    Exception { name: "Machine check",                 mnemonic: "#MC", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "SIMD floating-point exception", mnemonic: "#XM", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Virtualization exception",      mnemonic: "#VE", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Control protection",            mnemonic: "#CP", error_code: ErrorCode::Other,     handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+    Exception { name: "Hypervisor injection",          mnemonic: "#HV", error_code: ErrorCode::None,      handler: None },
+    Exception { name: "VMM communication",             mnemonic: "#VC", error_code: ErrorCode::Other,     handler: None },
+    Exception { name: "Security exception",            mnemonic: "#SX", error_code: ErrorCode::Other,     handler: None },
+    Exception { name: "Reserved",                      mnemonic: "",    error_code: ErrorCode::None,      handler: None },
+];
+
+/*
+ * Bits of the page fault error code.
+ */
+
+const PF_PRESENT: u64 =     1 << 0;
+const PF_WRITE: u64 =       1 << 1;
+const PF_USER: u64 =        1 << 2;
+const PF_RESERVED: u64 =    1 << 3;
+const PF_INSTRUCTION: u64 = 1 << 4;
+const PF_PROTECTION_KEY: u64 = 1 << 5;
+
+/*
+ * Bits of the selector error code.
+ */
+
+const SEL_EXTERNAL: u64 = 1 << 0;
+const SEL_IDT: u64 =      1 << 1;
+const SEL_LDT: u64 =      1 << 2;
+
+/* Breakpoints hit so far */
+static BREAKPOINTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/* Swap-ins retried in a row. Other CPUs release the locks soon, so reaching
+ * the limit means that the faulting code holds one of them itself. */
+const MAX_SWAP_IN_RETRIES: usize = 1 << 20;
+
+percpu! {
+    static SWAP_IN_RETRIES: usize = 0;
+}
+
+/* Called from traps.S */
+#[no_mangle]
+pub extern "C" fn trap_handler(regs: &mut TrapRegs) {
+    let vector = regs.trap_no as usize;
+    if vector >= IRQ_BASE_VECTOR as usize && vector < IRQ_BASE_VECTOR as usize + IRQ_LINES_COUNT {
+        percpu::irq_enter();
+        irq::handle(vector as u8);
+        percpu::irq_exit();
+        return;
+    }
+    if vector == apic::TIMER_VECTOR as usize {
+        percpu::irq_enter();
+        timer::handle_apic_timer();
+        percpu::irq_exit();
+        return;
+    }
+    if vector == apic::TLB_SHOOTDOWN_VECTOR as usize {
+        percpu::irq_enter();
+        paging::handle_shootdown();
+        percpu::irq_exit();
+        return;
+    }
+    if vector == syscall::VECTOR as usize {
+        syscall::handle(regs);
+        return;
+    }
+    if vector < EXCEPTIONS_COUNT {
+        if let Some(handler) = EXCEPTIONS[vector].handler {
+            if handler(regs) {
+                return;
+            }
+        }
+    }
+    unhandled(regs);
+}
+
+fn unhandled(regs: &TrapRegs) -> ! {
+    let vector = regs.trap_no as usize;
+    println!("==================================================================");
+    if vector < EXCEPTIONS_COUNT {
+        let exception = &EXCEPTIONS[vector];
+        print!("Exception {} {} {}", vector, exception.mnemonic, exception.name);
+        if exception.error_code != ErrorCode::None {
+            print!(", error code 0x{:x}", regs.error_code);
+            print_error_code(exception.error_code, regs.error_code);
+        }
+        println!("");
+    } else {
+        println!("Unexpected interrupt {}", vector);
+    }
+    if vector == 14 {
+        println!("  faulting address (cr2): 0x{:016x}", read_cr2());
+    }
+    dump_regs(regs);
+    println!("==================================================================");
+    panic!("Unhandled exception");
+}
+
+fn print_error_code(kind: ErrorCode, code: u64) {
+    match kind {
+        ErrorCode::PageFault => {
+            print!(" ({}, {}, {} mode",
+                   if code & PF_PRESENT != 0 { "protection violation" } else { "page not present" },
+                   if code & PF_INSTRUCTION != 0 { "instruction fetch" }
+                   else if code & PF_WRITE != 0 { "write" } else { "read" },
+                   if code & PF_USER != 0 { "user" } else { "kernel" });
+            if code & PF_RESERVED != 0 {
+                print!(", reserved bit set");
+            }
+            if code & PF_PROTECTION_KEY != 0 {
+                print!(", protection key");
+            }
+            print!(")");
+        },
+        ErrorCode::Selector if code != 0 => {
+            print!(" (selector index {} in {}{})",
+                   (code >> 3) & 0x1FFF,
+                   if code & SEL_IDT != 0 { "IDT" } else if code & SEL_LDT != 0 { "LDT" } else { "GDT" },
+                   if code & SEL_EXTERNAL != 0 { ", external event" } else { "" });
+        },
+        _ => {}
+    }
+}
+
+fn dump_regs(regs: &TrapRegs) {
+    println!("  rip: 0x{:016x} {}", regs.rip, Symbolized(regs.rip as usize));
+    println!("  cs: 0x{:04x} rflags: 0x{:016x} rsp: 0x{:016x} ss: 0x{:04x}",
+             regs.cs, regs.rflags, regs.rsp, regs.ss);
+    println!("  rax: 0x{:016x} rbx: 0x{:016x} rcx: 0x{:016x}", regs.rax, regs.rbx, regs.rcx);
+    println!("  rdx: 0x{:016x} rsi: 0x{:016x} rdi: 0x{:016x}", regs.rdx, regs.rsi, regs.rdi);
+    println!("  rbp: 0x{:016x} r8:  0x{:016x} r9:  0x{:016x}", regs.rbp, regs.r8, regs.r9);
+    println!("  r10: 0x{:016x} r11: 0x{:016x} r12: 0x{:016x}", regs.r10, regs.r11, regs.r12);
+    println!("  r13: 0x{:016x} r14: 0x{:016x} r15: 0x{:016x}", regs.r13, regs.r14, regs.r15);
+    println!("  ds: 0x{:04x} es: 0x{:04x} fs: 0x{:04x} gs: 0x{:04x}", regs.ds, regs.es, regs.fs, regs.gs);
+}
+
+fn read_cr2() -> usize {
+    let addr: usize;
+    unsafe {
+        asm!("mov %cr2, $0"
+             : "=r" (addr)
+             : /* inputs */
+             : /* clobbers */
+             : "volatile");
+    }
+    addr
+}
+
+/* Brings back pages which have been swapped out */
+fn page_fault(regs: &mut TrapRegs) -> bool {
+    if regs.error_code & PF_PRESENT != 0 {
+        return false;
+    }
+    let result = swap::handle_page_fault(read_cr2());
+    let retries = if result == Ok(swap::SwapIn::Retry) { SWAP_IN_RETRIES.get() + 1 } else { 0 };
+    SWAP_IN_RETRIES.set(retries);
+
+    match result {
+        Ok(swap::SwapIn::Done) => true,
+        /* Returning runs the instruction again, it faults once more */
+        Ok(swap::SwapIn::Retry) if retries < MAX_SWAP_IN_RETRIES => {
+            unsafe { asm!("pause" : : : : "volatile") };
+            true
+        },
+        Ok(swap::SwapIn::Retry) => {
+            println!("Gave up bringing the page back from swap after {} retries", retries);
+            false
+        },
+        Ok(swap::SwapIn::NotSwapped) => false,
+        Err(err) => {
+            println!("Failed to read the page back from swap: {:?}", err);
+            false
+        }
+    }
+}
+
+/* int3 is only counted, the execution continues after the instruction */
+fn breakpoint(_regs: &mut TrapRegs) -> bool {
+    BREAKPOINTS.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+pub fn traps_test() {
+    let before = BREAKPOINTS.load(Ordering::SeqCst);
+    unsafe {
+        asm!("int3" : : : "memory" : "volatile");
+    }
+    assert_eq!(before + 1, BREAKPOINTS.load(Ordering::SeqCst));
+}
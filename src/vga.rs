@@ -158,15 +158,3 @@ impl ::core::fmt::Write for Terminal {
         Ok(())
     }
 }
-
-macro_rules! println {
-    ($fmt:expr) => (print!(concat!($fmt, "\n")));
-    ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
-}
-
-macro_rules! print {
-    ($($arg:tt)*) => ({
-            use ::core::fmt::Write;
-            $crate::vga::CONSOLE.lock().write_fmt(format_args!($($arg)*)).unwrap();
-    });
-}
\ No newline at end of file
@@ -0,0 +1,183 @@
+/*
+ * Runs the kernel's AML interpreter on the host.
+ *
+ * Loads dumps of the DSDT and SSDTs (e.g. made by acpidump -b or copied from
+ * /sys/firmware/acpi/tables), prints the namespace and evaluates the objects
+ * given with -e. Operation region accesses are printed and reads return zeros.
+ *
+ * Usage: amlrun [-q] [-e PATH[:ARG,...]]... DSDT [SSDT...]
+ *        amlrun -t
+ *   -t  run the interpreter's self-test
+ *   -q  don't print the namespace
+ *   -e  evaluate the object with integer arguments, e.g. -e \_SB.PCI0._PRT
+ */
+
+#![feature(const_fn)]
+
+extern crate core;
+
+#[path = "../src/aml.rs"]
+#[allow(dead_code)]
+mod aml;
+
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::process;
+use std::str;
+
+use aml::{Address, Handler, Namespace, Value, ROOT_NODE};
+
+/* Size of the table header preceding AML code */
+const HEADER_SIZE: usize = 36;
+const REVISION_OFFSET: usize = 8;
+
+struct LoggingHandler;
+
+impl Handler for LoggingHandler {
+    fn read(&self, address: Address, width: u8) -> Option<u64> {
+        println!("  read {:?}, {} bytes", address, width);
+        Some(0)
+    }
+
+    fn write(&self, address: Address, width: u8, value: u64) -> bool {
+        println!("  write {:?}, {} bytes: 0x{:x}", address, width, value);
+        true
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        println!("  sleep {} ms", milliseconds);
+    }
+
+    fn stall(&self, microseconds: u64) {
+        println!("  stall {} us", microseconds);
+    }
+}
+
+/* The namespace refers to the code of methods, so tables live until the exit */
+fn read_table(path: &str) -> &'static [u8] {
+    let mut data = Vec::new();
+    if let Err(error) = File::open(path).and_then(|mut file| file.read_to_end(&mut data)) {
+        println!("Unable to read {}: {}", path, error);
+        process::exit(1);
+    }
+    if data.len() < HEADER_SIZE {
+        println!("{} is too short for an ACPI table", path);
+        process::exit(1);
+    }
+    unsafe { &*Box::into_raw(data.into_boxed_slice()) }
+}
+
+fn print_value(namespace: &Namespace, value: Value, indent: usize) {
+    print!("{:1$}", "", indent * 2);
+    match value {
+        Value::Integer(integer) => println!("0x{:x}", integer),
+        Value::String(_) => println!("\"{}\"", String::from_utf8_lossy(namespace.bytes(value).unwrap())),
+        Value::Buffer(_) => println!("Buffer {:?}", namespace.bytes(value).unwrap()),
+        Value::Package(_) => {
+            let len = namespace.package_len(value).unwrap();
+            println!("Package ({})", len);
+            for i in 0..len {
+                print_value(namespace, namespace.package_element(value, i).unwrap(), indent + 1);
+            }
+        },
+        Value::Reference(node) => println!("Reference to {}", path_of(namespace, node)),
+        value => println!("{:?}", value)
+    }
+}
+
+fn path_of(namespace: &Namespace, node: aml::NodeId) -> String {
+    match namespace.parent(node) {
+        None => String::from("\\"),
+        Some(parent) => {
+            let mut path = path_of(namespace, parent);
+            if parent != ROOT_NODE {
+                path.push('.');
+            }
+            path.push_str(str::from_utf8(&namespace.node_name(node)).unwrap_or("????"));
+            path
+        }
+    }
+}
+
+/* PATH:ARG,... where arguments are decimal or hexadecimal with 0x */
+fn parse_evaluation(text: &str) -> (&str, Vec<Value>) {
+    let mut parts = text.splitn(2, ':');
+    let path = parts.next().unwrap();
+    let args = parts.next().map_or(Vec::new(), |args| {
+        args.split(',').map(|arg| {
+            let integer = if arg.starts_with("0x") {
+                u64::from_str_radix(&arg[2..], 16)
+            } else {
+                arg.parse()
+            };
+            Value::Integer(integer.unwrap_or_else(|_| {
+                println!("Invalid argument {}", arg);
+                process::exit(1);
+            }))
+        }).collect()
+    });
+    (path, args)
+}
+
+fn main() {
+    let mut quiet = false;
+    let mut evaluations = Vec::new();
+    let mut tables = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-t" => {
+                aml::aml_test(&mut Box::new(Namespace::new()));
+                println!("The self-test has passed.");
+                return;
+            },
+            "-q" => quiet = true,
+            "-e" => match args.next() {
+                Some(path) => evaluations.push(path),
+                None => {
+                    println!("-e requires a path");
+                    process::exit(1);
+                }
+            },
+            _ => tables.push(arg)
+        }
+    }
+    if tables.is_empty() {
+        println!("Usage: amlrun [-q] [-e PATH[:ARG,...]]... DSDT [SSDT...] or amlrun -t");
+        process::exit(1);
+    }
+
+    let handler = LoggingHandler;
+    let mut namespace = Box::new(Namespace::new());
+    for path in tables.iter() {
+        let table = read_table(path);
+        println!("Loading {} ({} bytes)", path, table.len());
+        if let Err(error) = namespace.load(&table[HEADER_SIZE..], table[REVISION_OFFSET], &handler) {
+            println!("Unable to load {}: {:?}", path, error);
+        }
+    }
+    println!("{} nodes", namespace.nodes_count());
+
+    println!("Initializing devices");
+    let (initialized, errors) = namespace.initialize_devices(&handler);
+    println!("{} devices are present, {} methods have failed", initialized, errors);
+
+    if !quiet {
+        let namespace = &*namespace;
+        namespace.walk(ROOT_NODE, 0, &mut |node, depth| {
+            println!("{:3$}{} {}", "",
+                str::from_utf8(&namespace.node_name(node)).unwrap_or("????"),
+                namespace.node_type(node), depth * 2);
+        });
+    }
+
+    for evaluation in evaluations.iter() {
+        let (path, args) = parse_evaluation(evaluation);
+        println!("Evaluating {}", path);
+        match namespace.evaluate(path, &args, &handler) {
+            Ok(value) => print_value(&namespace, value, 1),
+            Err(error) => println!("  error: {:?}", error)
+        }
+    }
+}
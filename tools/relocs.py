@@ -0,0 +1,170 @@
+#!/usr/bin/env python3
+#
+# Builds the relocation table used by the bootstrap to move the kernel to
+# a random virtual address (KASLR).
+#
+# The input is the kernel linked with --emit-relocs. Only places in the higher
+# half which depend on the kernel's own virtual placement are collected:
+#
+#   R_X86_64_64         to a kernel symbol: the 64-bit value is increased by the offset
+#   R_X86_64_32S        to a kernel symbol: the 32-bit value is increased by the offset
+#   R_X86_64_PC32/PLT32 to a symbol below the kernel (bootstrap): the 32-bit value
+#                       is decreased by the offset
+#
+# The prebuilt libcore is position independent and refers to symbols through
+# the GOT. The linker turns such references into direct ones (see relaxed_got_kind),
+# they are handled as one of the above depending on the resulting instruction.
+#
+# The kernel's virtual base is read from the KERNEL_VIRTUAL_BASE symbol linker.ld
+# defines, so the script does not keep its own copy of the value.
+#
+# The output is a sequence of 32-bit little-endian physical addresses of those
+# places in the same order, every list terminated by zero. The bootstrap walks
+# it before jumping to the kernel (see kaslr_relocate in bootstrap.S).
+#
+# Usage: relocs.py <kernel elf> <output>
+
+import struct
+import sys
+
+# The symbol defined by linker.ld, its value is taken from the kernel
+KERNEL_VIRTUAL_BASE_SYMBOL = b'KERNEL_VIRTUAL_BASE'
+
+SHT_SYMTAB = 2
+SHT_RELA = 4
+SHF_ALLOC = 0x2
+
+R_X86_64_NONE = 0
+R_X86_64_64 = 1
+R_X86_64_PC32 = 2
+R_X86_64_PLT32 = 4
+R_X86_64_GOTPCREL = 9
+R_X86_64_32 = 10
+R_X86_64_32S = 11
+R_X86_64_PC64 = 24
+R_X86_64_GOTPCRELX = 41
+R_X86_64_REX_GOTPCRELX = 42
+
+
+class Section(object):
+    def __init__(self, data, offset):
+        (self.name, self.type, self.flags, self.addr, self.offset, self.size,
+         self.link, self.info, self.addralign, self.entsize) = \
+            struct.unpack_from('<IIQQQQIIQQ', data, offset)
+
+
+def read_sections(data):
+    if data[:4] != b'\x7fELF' or data[4] != 2:
+        raise Exception('not an ELF64 file')
+    shoff, = struct.unpack_from('<Q', data, 0x28)
+    shentsize, shnum = struct.unpack_from('<HH', data, 0x3A)
+    return [Section(data, shoff + i * shentsize) for i in range(shnum)]
+
+
+def read_symbols(data, symtab):
+    return [struct.unpack_from('<IBBHQQ', data, symtab.offset + i * symtab.entsize)[4]
+            for i in range(symtab.size // symtab.entsize)]
+
+
+def find_symbol(data, sections, name):
+    for symtab in sections:
+        if symtab.type != SHT_SYMTAB:
+            continue
+        strtab = sections[symtab.link]
+        for i in range(symtab.size // symtab.entsize):
+            name_offset, _, _, _, value, _ = \
+                struct.unpack_from('<IBBHQQ', data, symtab.offset + i * symtab.entsize)
+            start = strtab.offset + name_offset
+            if data[start:data.index(b'\0', start)] == name:
+                return value
+    raise Exception('symbol %s not found' % name.decode())
+
+
+# Tells how the instruction referring to the GOT has been relaxed by the linker:
+# 'absolute' for an immediate operand (mov $sym, test $sym, ...), 'relative'
+# for a RIP-relative one (lea sym(%rip), call sym). Returns None if the GOT is
+# still used, its entries are not covered by emitted relocations.
+def relaxed_got_kind(data, section, offset):
+    pos = section.offset + (offset - section.addr)
+    opcode = data[pos - 2]
+    if opcode in (0xc7, 0xf7, 0x81):
+        return 'absolute'
+    if opcode == 0x8d or data[pos - 1] in (0xe8, 0xe9):
+        return 'relative'
+    return None
+
+
+def main():
+    if len(sys.argv) != 3:
+        sys.stderr.write('Usage: %s <kernel elf> <output>\n' % sys.argv[0])
+        sys.exit(1)
+
+    with open(sys.argv[1], 'rb') as f:
+        data = f.read()
+    sections = read_sections(data)
+    kernel_virtual_base = find_symbol(data, sections, KERNEL_VIRTUAL_BASE_SYMBOL)
+
+    def is_kernel_addr(addr):
+        return addr >= kernel_virtual_base
+
+    relocs_64 = []
+    relocs_32 = []
+    relocs_inverse_32 = []
+
+    for rela in sections:
+        if rela.type != SHT_RELA:
+            continue
+        target = sections[rela.info]
+        if not (target.flags & SHF_ALLOC) or not is_kernel_addr(target.addr):
+            # Debug info and the bootstrap are not moved
+            continue
+        symbols = read_symbols(data, sections[rela.link])
+
+        for i in range(rela.size // rela.entsize):
+            offset, info, addend = struct.unpack_from('<QQq', data, rela.offset + i * rela.entsize)
+            reloc_type = info & 0xffffffff
+            value = (symbols[info >> 32] + addend) & 0xffffffffffffffff
+            place = offset - kernel_virtual_base
+
+            if reloc_type == R_X86_64_NONE:
+                continue
+            elif reloc_type == R_X86_64_64:
+                if is_kernel_addr(value):
+                    relocs_64.append(place)
+            elif reloc_type == R_X86_64_32S:
+                if is_kernel_addr(value):
+                    relocs_32.append(place)
+            elif reloc_type in (R_X86_64_PC32, R_X86_64_PLT32):
+                if not is_kernel_addr(value):
+                    relocs_inverse_32.append(place)
+            elif reloc_type in (R_X86_64_GOTPCREL, R_X86_64_GOTPCRELX, R_X86_64_REX_GOTPCRELX):
+                # The addend compensates for the RIP-relative displacement, not needed for the symbol
+                value = symbols[info >> 32]
+                kind = relaxed_got_kind(data, target, offset)
+                if kind == 'absolute' and is_kernel_addr(value):
+                    relocs_32.append(place)
+                elif kind == 'relative' and not is_kernel_addr(value):
+                    relocs_inverse_32.append(place)
+                elif kind is None:
+                    raise Exception('GOT reference at 0x%x is not relaxed' % offset)
+            elif reloc_type == R_X86_64_32:
+                if is_kernel_addr(value):
+                    raise Exception('R_X86_64_32 to kernel address at 0x%x' % offset)
+            elif reloc_type == R_X86_64_PC64:
+                if not is_kernel_addr(value):
+                    raise Exception('R_X86_64_PC64 out of the kernel at 0x%x' % offset)
+            else:
+                raise Exception('unsupported relocation type %d at 0x%x' % (reloc_type, offset))
+
+    with open(sys.argv[2], 'wb') as f:
+        for relocs in (relocs_64, relocs_32, relocs_inverse_32):
+            for place in sorted(relocs):
+                f.write(struct.pack('<I', place))
+            f.write(struct.pack('<I', 0))
+
+    print('%s: %d 64-bit, %d 32-bit and %d inverse 32-bit relocations' %
+          (sys.argv[2], len(relocs_64), len(relocs_32), len(relocs_inverse_32)))
+
+
+if __name__ == '__main__':
+    main()